@@ -0,0 +1,266 @@
+//! Backfills in batches, for a backlog too big for one run: a plan splits the objects waiting in
+//! the sources into batches by the timestamps in their names, and writes them to a manifest;
+//! applying it crunches the batches one run each, oldest first, saving the manifest after each.
+//! So a backfill can be stopped (killed, or limited to a few batches at a time) and picked up
+//! again where it left off, and the manifest shows how far it's got.
+//!
+//! The manifest is JSON:
+//!
+//! ```json
+//! {"sources": ["gs://bucket"], "filter": {"name_time_format": "%Y-%m-%dT%H:%M:%S%.3f"},
+//!  "undated": 0, "batches": [
+//!   {"since": "2024-06-01T00:00:00", "until": "2024-06-02T00:00:00", "objects": 288,
+//!    "bytes": 73400320, "status": "done", "crunched": 288, "run_id": 12,
+//!    "finished": "2024-07-01T09:30:00Z"}
+//! ]}
+//! ```
+//!
+//! The manifest has the object filter it was planned with, and is only applied with the same:
+//! with another prefix or time format, say, the batches wouldn't have the objects planned.
+//! A batch is only done once as many objects as planned have been crunched (or skipped).
+
+use std::{collections::BTreeMap, fmt, path::Path, time::Duration};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::filter::Selection;
+
+/// How far a batch has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    Pending,
+    Done,
+    /// Crunched, but some objects failed, or the run did; applying again retries it.
+    Failed,
+}
+
+/// The objects with timestamps in a span of time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillBatch {
+    /// Inclusive start.
+    pub since: NaiveDateTime,
+    /// Exclusive end.
+    pub until: NaiveDateTime,
+    /// Objects in the batch, as planned.
+    pub objects: usize,
+    /// Their total size, as listed.
+    pub bytes: u64,
+    pub status: BatchStatus,
+    /// Objects crunched or skipped so far, over the runs that have tried the batch.
+    #[serde(default)]
+    pub crunched: usize,
+    /// The run that last crunched the batch, as in the runs table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<i64>,
+    /// When that run finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<DateTime<Utc>>,
+    /// What went wrong, if the batch failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A planned backfill.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The sources it was planned from; it's applied to the same ones.
+    pub sources: Vec<String>,
+    /// The object filter it was planned with; it's applied with the same.
+    pub filter: Selection,
+    /// Objects with no timestamp in their names, which no batch covers.
+    pub undated: usize,
+    /// The batches, oldest first.
+    pub batches: Vec<BackfillBatch>,
+}
+
+impl Manifest {
+    /// Plan batches of `span` for the objects, given the timestamps in their names (if any)
+    /// and their sizes. Batches are aligned to the Unix epoch, so days start at midnight UTC;
+    /// spans with no objects get no batch.
+    pub(crate) fn plan(
+        sources: Vec<String>,
+        filter: Selection,
+        objects: impl IntoIterator<Item = (Option<NaiveDateTime>, Option<u64>)>,
+        span: Duration,
+    ) -> anyhow::Result<Self> {
+        let seconds = span.as_secs() as i64;
+        if seconds == 0 || span.subsec_nanos() != 0 {
+            bail!("batches have to be a whole number of seconds, not {span:?}");
+        }
+        let mut undated = 0;
+        let mut batches = BTreeMap::new();
+        for (time, size) in objects {
+            let Some(time) = time else {
+                undated += 1;
+                continue;
+            };
+            let start = time.and_utc().timestamp().div_euclid(seconds) * seconds;
+            let (objects, bytes) = batches.entry(start).or_insert((0, 0));
+            *objects += 1;
+            *bytes += size.unwrap_or(0);
+        }
+        let time = |timestamp: i64| {
+            DateTime::from_timestamp(timestamp, 0)
+                .map(|time| time.naive_utc())
+                .context("batch out of range")
+        };
+        let batches = batches
+            .into_iter()
+            .map(|(start, (objects, bytes))| {
+                Ok(BackfillBatch {
+                    since: time(start)?,
+                    until: time(start + seconds)?,
+                    objects,
+                    bytes,
+                    status: BatchStatus::Pending,
+                    crunched: 0,
+                    run_id: None,
+                    finished: None,
+                    error: None,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Manifest {
+            sources,
+            filter,
+            undated,
+            batches,
+        })
+    }
+
+    /// Read a manifest written by `save`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read(path)
+            .with_context(|| format!("could not read backfill manifest {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("could not parse backfill manifest {}", path.display()))
+    }
+
+    /// Write the manifest; it's replaced whole, so a crash leaves the old one or the new one.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let contents =
+            serde_json::to_vec_pretty(self).context("could not serialize backfill manifest")?;
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, contents)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .with_context(|| format!("could not write backfill manifest {}", path.display()))
+    }
+
+    /// The oldest batch that isn't done.
+    pub(crate) fn next(&self) -> Option<usize> {
+        self.batches
+            .iter()
+            .position(|batch| batch.status != BatchStatus::Done)
+    }
+
+    /// Whether any batch failed.
+    pub fn failed(&self) -> bool {
+        self.batches
+            .iter()
+            .any(|batch| batch.status == BatchStatus::Failed)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let done = self
+            .batches
+            .iter()
+            .filter(|batch| batch.status == BatchStatus::Done)
+            .count();
+        let objects: usize = self.batches.iter().map(|batch| batch.objects).sum();
+        let bytes: u64 = self.batches.iter().map(|batch| batch.bytes).sum();
+        writeln!(
+            f,
+            "{done} of {} batches done; {objects} objects, {:.1} MiB in all",
+            self.batches.len(),
+            bytes as f64 / (1 << 20) as f64
+        )?;
+        for batch in &self.batches {
+            write!(
+                f,
+                "  {} to {}: {} objects, {:.1} MiB, ",
+                batch.since,
+                batch.until,
+                batch.objects,
+                batch.bytes as f64 / (1 << 20) as f64
+            )?;
+            match (batch.status, batch.run_id) {
+                (BatchStatus::Pending, _) => write!(f, "pending")?,
+                (BatchStatus::Done, Some(run)) => write!(f, "done in run {run}")?,
+                (BatchStatus::Done, None) => write!(f, "done")?,
+                (BatchStatus::Failed, Some(run)) => write!(f, "failed in run {run}")?,
+                (BatchStatus::Failed, None) => write!(f, "failed")?,
+            }
+            match &batch.error {
+                Some(error) => writeln!(f, ": {error}")?,
+                None => writeln!(f)?,
+            }
+        }
+        if self.undated > 0 {
+            writeln!(
+                f,
+                "{} objects have no timestamp in their names, so no batch has them",
+                self.undated
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveDateTime;
+
+    use super::{BatchStatus, Manifest};
+    use crate::filter::Selection;
+
+    #[test]
+    fn plans_batches_by_name_time() {
+        let time = |s: &str| Some(NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S").unwrap());
+        let objects = [
+            (time("2024-06-02T23:59:59"), Some(10)),
+            (time("2024-06-01T00:00:00"), Some(1)),
+            (time("2024-06-01T12:00:00"), None),
+            (None, Some(5)),
+            (time("2024-06-04T01:00:00"), Some(100)),
+        ];
+        let day = Duration::from_secs(24 * 60 * 60);
+        let sources = vec!["gs://bucket".to_string()];
+        let mut manifest = Manifest::plan(sources, Selection::default(), objects, day).unwrap();
+        assert_eq!(manifest.undated, 1);
+        let batches: Vec<_> = manifest
+            .batches
+            .iter()
+            .map(|batch| (batch.since.to_string(), batch.objects, batch.bytes))
+            .collect();
+        assert_eq!(
+            batches,
+            [
+                ("2024-06-01 00:00:00".to_string(), 2, 1),
+                ("2024-06-02 00:00:00".to_string(), 1, 10),
+                ("2024-06-04 00:00:00".to_string(), 1, 100),
+            ]
+        );
+        assert_eq!(manifest.batches[2].until.to_string(), "2024-06-05 00:00:00");
+
+        manifest.batches[0].status = BatchStatus::Done;
+        manifest.batches[1].status = BatchStatus::Failed;
+        assert_eq!(manifest.next(), Some(1));
+        assert!(manifest.failed());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backfill.json");
+        manifest.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.batches[1].status, BatchStatus::Failed);
+        assert_eq!(loaded.next(), Some(1));
+        assert!(loaded.to_string().starts_with("1 of 3 batches done"));
+
+        assert!(Manifest::plan(Vec::new(), Selection::default(), [], Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Backups of the database to object storage, from the `[backup]` section of the config file.
+//!
+//! After a run, if the last backup is older than the interval, the database is copied with
+//! SQLite's online backup API (so the copy is consistent, even if something else is writing),
+//! gzipped to a temporary file, and streamed up from there as `{prefix}{time}.db.gz`, e.g.
+//!
+//! ```toml
+//! [backup]
+//! to = "gs://my-backups"
+//! prefix = "analytics/"
+//! interval = "6h"
+//! keep = 28
+//! ```
+//!
+//! These are snapshots, not continuous replication: a lost disk loses the runs since the last
+//! backup. With cleanup on, their logs are gone, too; archive them if that matters.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use chrono::Utc;
+use serde::Deserialize;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::{
+    cruncher::Cruncher,
+    filter::parse_duration,
+    source::{ByteStream, LogSource, OpendalSource, SourceSpec},
+};
+
+/// Where and how often to back up the database.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    /// Where to upload backups: gs://bucket, s3://bucket?region=..., sftp://..., or file:///dir.
+    pub to: SourceSpec,
+    /// Prepended to each backup's name, e.g. "analytics/".
+    #[serde(default)]
+    pub prefix: String,
+    /// How long to wait between backups, e.g. "6h" or "1d".
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// How many backups to keep; older ones are deleted. By default, all are kept.
+    pub keep: Option<usize>,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn deserialize_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+const SUFFIX: &str = ".db.gz";
+
+/// Size of the chunks a backup is uploaded in, and how many are read ahead.
+const CHUNK_LEN: usize = 1 << 20;
+const CHUNKS: usize = 4;
+
+/// Back up the database, if it's been long enough since the last backup.
+pub(crate) async fn run(cruncher: &Cruncher, backup: &Backup) -> anyhow::Result<()> {
+    if !cruncher.backup_due(backup.interval)? {
+        tracing::debug!("backup not due yet");
+        return Ok(());
+    }
+    let store = OpendalSource::open(&backup.to).context("could not open backup store")?;
+    // Removed, snapshot and all, once it's dropped.
+    let dir = tempfile::tempdir().context("could not create directory for snapshot")?;
+    upload(cruncher, backup, &store, dir.path()).await?;
+    if let Some(keep) = backup.keep {
+        prune(&store, &backup.prefix, keep).await?;
+    }
+    Ok(())
+}
+
+/// Snapshot the database into `dir`, gzip it there, and upload it.
+async fn upload(
+    cruncher: &Cruncher,
+    backup: &Backup,
+    store: &OpendalSource,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let snapshot = dir.join("snapshot.db");
+    cruncher.snapshot(&snapshot)?;
+    let gzipped = dir.join("snapshot.db.gz");
+    let mut encoder = flate2::write::GzEncoder::new(
+        File::create(&gzipped).context("could not create compressed snapshot")?,
+        flate2::Compression::default(),
+    );
+    io::copy(
+        &mut File::open(&snapshot).context("could not open snapshot")?,
+        &mut encoder,
+    )
+    .context("could not compress snapshot")?;
+    let gzipped = encoder.finish().context("could not compress snapshot")?;
+    let bytes = gzipped
+        .metadata()
+        .context("could not size compressed snapshot")?
+        .len() as usize;
+    let gzipped =
+        File::open(dir.join("snapshot.db.gz")).context("could not open compressed snapshot")?;
+    // Names sort by time, for pruning.
+    let name = format!(
+        "{}{}{SUFFIX}",
+        backup.prefix,
+        Utc::now().format("%Y-%m-%dT%H-%M-%SZ")
+    );
+    store
+        .write_stream(&name, chunks(gzipped))
+        .await
+        .with_context(|| format!("could not upload backup {name}"))?;
+    cruncher.record_backup(&name, bytes)?;
+    tracing::info!("backed up the database to {name} ({bytes} bytes)");
+    Ok(())
+}
+
+/// The file's contents, read in chunks from a blocking task.
+fn chunks(mut file: File) -> ByteStream {
+    let (chunks, stream) = tokio::sync::mpsc::channel(CHUNKS);
+    tokio::task::spawn_blocking(move || loop {
+        let mut chunk = vec![0; CHUNK_LEN];
+        let chunk = match file.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(len) => {
+                chunk.truncate(len);
+                Ok(Bytes::from(chunk))
+            }
+            Err(e) => Err(anyhow::Error::new(e).context("could not read compressed snapshot")),
+        };
+        let failed = chunk.is_err();
+        // An error ends the stream, failing the upload; so does the upload stopping.
+        if chunks.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    });
+    Box::pin(ReceiverStream::new(stream))
+}
+
+/// Delete all but the newest `keep` backups under the prefix.
+async fn prune(store: &OpendalSource, prefix: &str, keep: usize) -> anyhow::Result<()> {
+    let mut names = Vec::new();
+    let mut listing = store.list(prefix, false).await?;
+    while let Some(object) = listing.next().await {
+        let name = object?.name;
+        let is_backup = name
+            .strip_prefix(prefix)
+            .is_some_and(|rest| !rest.contains('/') && rest.ends_with(SUFFIX));
+        if is_backup {
+            names.push(name);
+        }
+    }
+    names.sort();
+    for name in &names[..names.len().saturating_sub(keep)] {
+        tracing::info!("deleting old backup {name}");
+        store
+            .delete(name)
+            .await
+            .with_context(|| format!("could not delete old backup {name}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{run, Backup};
+    use crate::{config::Sqlite, cruncher::Cruncher, testdata::entry};
+
+    #[test]
+    fn backs_up_when_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let cruncher = Cruncher::open(&dir.path().join("logs.db"), &Sqlite::default()).unwrap();
+        cruncher.crunch(&[entry()]).unwrap();
+        let backup: Backup = toml::from_str(&format!(
+            r#"
+            to = "file://{}"
+            prefix = "backups/"
+            interval = "1h"
+            keep = 1
+            "#,
+            dir.path().display()
+        ))
+        .unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // The second run is within the interval, so doesn't back up again.
+        for _ in 0..2 {
+            rt.block_on(run(&cruncher, &backup)).unwrap();
+        }
+        let backups: Vec<_> = std::fs::read_dir(dir.path().join("backups"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(backups.len(), 1);
+
+        let mut restored = Vec::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&backups[0]).unwrap())
+            .read_to_end(&mut restored)
+            .unwrap();
+        let restored_path = dir.path().join("restored.db");
+        std::fs::write(&restored_path, restored).unwrap();
+        let count: usize = rusqlite::Connection::open(&restored_path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
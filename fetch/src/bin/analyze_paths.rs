@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use log_cruncher::suggest_rewrites;
+use rusqlite::Connection;
+
+/// Suggest normalization rules for high-cardinality paths in a crunched database.
+///
+/// Prints a `[dimensions.paths]` section for the config file; review it before using it.
+#[derive(Parser)]
+struct Args {
+    /// SQLite database to read from.
+    dbfile: PathBuf,
+
+    /// Suggest a rule only if it collapses at least this many distinct paths.
+    #[arg(long, default_value_t = 20)]
+    min_distinct: usize,
+
+    /// Suggest at most this many rules.
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let args = log_cruncher::parse_args::<Args>();
+
+    let conn = Connection::open(&args.dbfile).expect("could not open DB");
+    let paths: Vec<String> = conn
+        .prepare("SELECT path FROM paths")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+        .expect("could not read paths");
+    let suggestions = suggest_rewrites(paths.iter().map(String::as_str), args.min_distinct);
+    if suggestions.is_empty() {
+        eprintln!("no clusters of {} or more paths", args.min_distinct);
+        return;
+    }
+
+    println!("[dimensions.paths]");
+    println!("normalize = [");
+    for suggestion in suggestions.iter().take(args.limit) {
+        println!(
+            "    # {} paths, e.g. {}",
+            suggestion.paths, suggestion.example
+        );
+        println!(
+            "    {{ pattern = {}, replace = {} }},",
+            toml::Value::from(suggestion.rewrite.pattern.as_str()),
+            toml::Value::from(suggestion.rewrite.replace.as_str())
+        );
+    }
+    println!("]");
+}
@@ -1,18 +1,442 @@
-use std::{
-    cmp::{max, min},
-    path::Path,
+use std::{path::PathBuf, time::Duration};
+
+use chrono::NaiveDateTime;
+use clap::Parser;
+use log_cruncher::{
+    parse_bytes, parse_duration, parse_max_invalid, parse_time, CircuitBreaker, Config, Cruncher,
+    EmptyObjects, Format, MaxInvalid, ObjectFilter, Pack, SourceSpec, DEFAULT_NAME_TIME_FORMAT,
 };
 
-use log_cruncher::Cruncher;
+/// The most objects to fetch at once, however high the FD limit is.
+const MAX_CONCURRENCY: usize = 1024;
+
+/// Fetch logs from a bucket and crunch them into a database.
+#[derive(Parser)]
+struct Args {
+    /// Where to read logs from: a GCS bucket name,
+    /// gs://bucket?credentials=...&scope=...&endpoint=...,
+    /// s3://bucket?region=...&endpoint=...&access_key_id=...&secret_key_file=...,
+    /// sftp://user@host/dir?key=...&known_hosts=..., file:///local/directory,
+    /// urls:///manifest/of/urls, journald://unit?identifier=... for nginx JSON access logs,
+    /// or - to read (optionally gzipped) entries from stdin
+    source: SourceSpec,
+    /// Another source to fetch from in the same run, e.g. a second service's bucket.
+    /// May be repeated; each request records which source it came from.
+    #[arg(long = "source")]
+    sources: Vec<SourceSpec>,
+
+    /// Service account key (JSON) for GCS sources, and GCS archive and backup destinations,
+    /// rather than the default credentials.
+    #[arg(long)]
+    gcs_credentials: Option<PathBuf>,
+
+    /// OAuth scope to request for GCS sources and destinations.
+    #[arg(long)]
+    gcs_scope: Option<String>,
+
+    /// Endpoint for GCS sources and destinations, rather than Google's: e.g.
+    /// http://localhost:4443 for fake-gcs-server.
+    #[arg(long)]
+    gcs_endpoint: Option<String>,
+
+    /// What format the logs are in. nginx's logs in the journal are always read as nginx's.
+    #[arg(long, value_enum, default_value_t)]
+    format: Format,
+
+    /// Fail Fastly entries that are missing any of the fields the log format has always had,
+    /// rather than storing NULL for the missing ones (all but the client, path, status, and
+    /// start time).
+    #[arg(long)]
+    strict_fields: bool,
+
+    /// SQLite database to write to. Not needed with --writer.
+    #[arg(required_unless_present = "writer")]
+    dbfile: Option<PathBuf>,
+
+    /// Own the database, and crunch streams of entries that other invocations send to this
+    /// Unix socket with --writer, rather than fetching from the source (use -). Runs until killed.
+    #[arg(long, conflicts_with = "writer")]
+    serve: Option<PathBuf>,
+
+    /// Send entries from stdin (source -) to the process serving on this Unix socket,
+    /// rather than writing to the database directly.
+    #[arg(long)]
+    writer: Option<PathBuf>,
+
+    /// Own the database, and crunch batches of entries POSTed to this address
+    /// (e.g. "127.0.0.1:8080") by Fastly's HTTPS logging, rather than fetching from the source
+    /// (use -). Put it behind a proxy that terminates TLS. Runs until killed.
+    ///
+    /// Batches are crunched in runs every few seconds. A batch sent again, with the same
+    /// Idempotency-Key header or else the same contents, is only stored once.
+    #[arg(long, conflicts_with_all = ["serve", "writer"], requires = "listen_secret_file")]
+    listen: Option<String>,
+
+    /// With --listen, file holding the shared secret that batches have to carry in the
+    /// X-Cruncher-Secret header.
+    #[arg(long, requires = "listen")]
+    listen_secret_file: Option<PathBuf>,
+
+    /// With --listen, the ID of a Fastly service allowed to send logs, for Fastly's challenge.
+    /// May be repeated; by default, any service is allowed (if it has the secret).
+    #[arg(long, requires = "listen")]
+    fastly_service_id: Vec<String>,
+
+    /// Own the database, and crunch entries streamed to this address (e.g. "0.0.0.0:6514", or
+    /// "6514" for loopback only) by Fastly's syslog logging, one message per line, rather than
+    /// fetching from the source (use -). Runs until killed.
+    #[arg(long, conflicts_with_all = ["serve", "writer", "listen"])]
+    syslog: Option<String>,
+
+    /// With --syslog, accept connections from this address or network (e.g. "151.101.0.0/16").
+    /// May be repeated; by default, only from loopback. Syslog has no authentication, so list
+    /// only the networks that send logs, e.g. Fastly's public IP ranges.
+    #[arg(long, requires = "syslog")]
+    syslog_allow: Vec<String>,
+
+    /// With --syslog, accept TLS with this certificate (chain), in PEM.
+    #[arg(long, requires_all = ["syslog", "syslog_tls_key"])]
+    syslog_tls_cert: Option<PathBuf>,
+
+    /// With --syslog-tls-cert, the certificate's private key, in PEM (PKCS #8).
+    #[arg(long, requires = "syslog_tls_cert")]
+    syslog_tls_key: Option<PathBuf>,
+
+    /// Check the setup rather than crunching: the sources' credentials, PeeringDB and
+    /// Spamhaus, the database, and the FD limit. Prints a checklist, and fails if anything
+    /// on it did.
+    #[arg(long, conflicts_with_all = ["serve", "writer", "listen", "syslog"])]
+    doctor: bool,
+
+    /// Rather than crunching, try the enrichments a run ends with (computed columns, packs'
+    /// enrich.sql, and naming ASes) on a sample of requests, and print what they would fill,
+    /// with example values, and how long they and their API calls would take. Nothing is kept.
+    #[arg(long, conflicts_with_all = ["serve", "writer", "listen", "syslog", "doctor"])]
+    preview_enrichments: bool,
+
+    /// With --preview-enrichments, how many requests to try each on.
+    #[arg(long, default_value_t = 1000, requires = "preview_enrichments")]
+    preview_sample: usize,
+
+    /// Rather than crunching, list the sources and plan a backfill of what a run would fetch,
+    /// in batches of --backfill-batch by the timestamps in object names, and write it to this
+    /// manifest (JSON), for --apply-backfill.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "serve", "writer", "listen", "syslog", "doctor", "preview_enrichments",
+        ]
+    )]
+    plan_backfill: Option<PathBuf>,
+
+    /// With --plan-backfill, how long a span of time each batch covers, e.g. "1d" or "6h".
+    #[arg(long, value_parser = parse_duration, default_value = "1d", requires = "plan_backfill")]
+    backfill_batch: Duration,
+
+    /// Crunch the batches of the backfill planned in this manifest that aren't done, one run
+    /// each, oldest first, marking each done in the manifest as it finishes: so the backfill
+    /// can be stopped, and applied again to carry on. Stops at a batch with failed objects,
+    /// which the next apply retries.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "serve", "writer", "listen", "syslog", "doctor", "preview_enrichments",
+            "plan_backfill", "max_objects", "max_bytes",
+        ]
+    )]
+    apply_backfill: Option<PathBuf>,
+
+    /// With --apply-backfill, crunch at most this many batches, leaving the rest for later.
+    #[arg(long, requires = "apply_backfill")]
+    backfill_batches: Option<usize>,
+
+    /// Keep running: crunch, wait for --interval, and crunch again, until killed.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "serve", "writer", "listen", "syslog", "plan_backfill", "apply_backfill",
+        ]
+    )]
+    daemon: bool,
+
+    /// Keep running on a local directory: crunch, then crunch again each time a log file is
+    /// finished in it (closed after writing, or moved in by logrotate), until killed.
+    /// Use --extension or --skip to leave out files still being written to.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "serve", "writer", "daemon", "listen", "syslog", "plan_backfill", "apply_backfill",
+        ]
+    )]
+    follow: bool,
+
+    /// With --daemon, how long to wait after each run, e.g. "90s", "5m", or "1h".
+    #[arg(long, value_parser = parse_duration, default_value = "5m", requires = "daemon")]
+    interval: Duration,
+
+    /// TOML configuration file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Directory of SQL packs; each subdirectory is a pack.
+    #[arg(long)]
+    packs: Option<PathBuf>,
+
+    /// Rhai script to transform, tag, or drop each entry before it is stored.
+    #[cfg(feature = "scripting")]
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// Only fetch objects whose names end in this extension (e.g. ".log.gz").
+    /// May be repeated; by default, all objects are fetched.
+    #[arg(long = "extension")]
+    extensions: Vec<String>,
+
+    /// Skip objects whose names match this regex. May be repeated.
+    #[arg(long)]
+    skip: Vec<String>,
+
+    /// Only crunch objects whose names start with this, e.g. "2024/06/".
+    /// Other objects are left alone, not recorded as skipped.
+    #[arg(long, default_value = "")]
+    prefix: String,
+
+    /// Only crunch objects matching this glob, e.g. "*.log.gz". May be repeated.
+    /// Other objects are left alone, not recorded as skipped.
+    #[arg(long = "glob")]
+    globs: Vec<String>,
+
+    /// Only crunch objects whose names have timestamps at or after this
+    /// date (YYYY-MM-DD) or time (YYYY-MM-DDTHH:MM:SS), in UTC.
+    #[arg(long, value_parser = parse_time)]
+    since: Option<NaiveDateTime>,
+
+    /// Only crunch objects whose names have timestamps before this date or time.
+    #[arg(long, value_parser = parse_time)]
+    until: Option<NaiveDateTime>,
+
+    /// Only crunch objects whose names have timestamps in the last while, e.g. "7d" or "12h",
+    /// as of each run.
+    #[arg(long, value_parser = parse_duration)]
+    last: Option<Duration>,
+
+    /// strftime-style format of the timestamps in object names, for --since, --until,
+    /// and --last.
+    #[arg(long, default_value = DEFAULT_NAME_TIME_FORMAT)]
+    name_time_format: String,
+
+    /// What to do with empty (zero-byte) objects.
+    /// Anything other than "fetch" checks object sizes when listing.
+    #[arg(long, value_enum, default_value_t)]
+    empty_objects: EmptyObjects,
+
+    /// For truncated objects, store the entries before the truncation and
+    /// treat the object as processed, rather than failing it.
+    #[arg(long)]
+    salvage_truncated: bool,
+
+    /// Store the valid entries of objects with invalid ones, skipping the rest, rather than
+    /// failing the objects. Objects with no valid entries fail either way.
+    #[arg(long)]
+    skip_invalid_entries: bool,
+
+    /// With --skip-invalid-entries, fail objects with more invalid entries than this anyway: a
+    /// count, like "100", or a percentage of their entries, like "0.5%". Failed objects aren't
+    /// cleaned up, so a file that's mostly garbage is kept for a look, not stored in part and
+    /// deleted. Streams, like stdin, fail with more too, though what's stored is kept.
+    #[arg(
+        long,
+        value_parser = parse_max_invalid,
+        default_value = "1%",
+        requires = "skip_invalid_entries"
+    )]
+    max_invalid_entries: MaxInvalid,
+
+    /// Skip (and clean up) objects whose contents are identical to an already-crunched object.
+    #[arg(long)]
+    skip_duplicates: bool,
+
+    /// Check each object's size and MD5 (where the store has them) against the listing,
+    /// and fail objects that don't match.
+    #[arg(long)]
+    verify: bool,
+
+    /// Keep the database friendly to Datasette, Litestream, and the like running against it
+    /// live: WAL mode, short write locks, and a checkpoint after each run.
+    /// Each object is held in memory until it's been read.
+    #[arg(long)]
+    serve_friendly: bool,
+
+    /// Store no requests, only the daily rollups of them (totals, paths, statuses, countries,
+    /// and referers): a far smaller database, and faster crunches, for when only the stats
+    /// matter. Per-request queries, SLOs, computed columns, tags, and quotas need the requests.
+    #[arg(long)]
+    aggregate_only: bool,
+
+    /// Store each request only once, keyed by a hash of its client, start time, path, size,
+    /// and status, even when it's read again from another object (after objects are merged
+    /// or copied) that --skip-duplicates wouldn't catch. Requests stored before this was
+    /// added have no keys. The database keeps this setting: later runs keep requests unique
+    /// too, until --no-dedup-requests.
+    #[arg(long, conflicts_with = "aggregate_only")]
+    dedup_requests: bool,
+
+    /// Stop storing requests only once, as --dedup-requests started, dropping the unique index.
+    #[arg(long, conflicts_with = "dedup_requests")]
+    no_dedup_requests: bool,
+
+    /// Store the query strings of requests, apart from their paths. Paths are stored without
+    /// them either way, so the paths table isn't swamped by every query of each page.
+    #[arg(long, conflicts_with = "aggregate_only")]
+    store_queries: bool,
+
+    /// Store paths the same however they're written: percent-escapes decoded and runs of
+    /// slashes collapsed, so `/Foo%20Bar//` and `/Foo Bar/` are counted together. Trailing
+    /// slashes are kept. Each path as logged is kept in requests.raw_path, where this changes it.
+    #[arg(long)]
+    normalize_paths: bool,
+
+    /// Lowercase paths too, as they're normalized.
+    #[arg(long, requires = "normalize_paths")]
+    lowercase_paths: bool,
+
+    /// List every object before fetching any, and fetch the oldest first: by the timestamps in
+    /// their names (per --name-time-format), or their modification times.
+    #[arg(long)]
+    oldest_first: bool,
+
+    /// Fetch this many objects at once, rather than adjusting it by throughput and errors.
+    #[arg(long)]
+    concurrency: Option<usize>,
+
+    /// Download and parse at most this much of the objects' listed sizes at once,
+    /// e.g. "2GiB"; objects larger than this are fetched one at a time.
+    #[arg(long, value_parser = parse_bytes)]
+    max_inflight_bytes: Option<u64>,
+
+    /// Download objects larger than this in parts of this size, e.g. "64MiB", several at a
+    /// time; for multi-GB objects on links faster than a single download goes.
+    #[arg(long, value_parser = parse_bytes)]
+    range_chunk_size: Option<u64>,
+
+    /// How many parts of an object to download at a time, with --range-chunk-size.
+    #[arg(long, default_value_t = 4)]
+    range_parallelism: usize,
+
+    /// Keep each source's listing in this directory, and on later runs, only list the objects
+    /// newer than it has (on GCS and S3, when object names start with timestamps).
+    #[arg(long)]
+    listing_cache: Option<PathBuf>,
+
+    /// Let GCS decompress objects stored with "Content-Encoding: gzip" as it sends them
+    /// (decompressive transcoding), saving the CPU to decompress them here. Set it on objects
+    /// with
+    /// `gcloud storage objects update --content-encoding=gzip`. Transcoded objects can't be
+    /// checked with --verify, and reads of them that break start over.
+    #[arg(long)]
+    gcs_transcoding: bool,
+
+    /// Fetch at most this many objects; the rest are left for the next run.
+    #[arg(long)]
+    max_objects: Option<usize>,
+
+    /// Stop fetching new objects after this many bytes (e.g. "10GiB"); the rest are left for
+    /// the next run.
+    #[arg(long, value_parser = parse_bytes)]
+    max_bytes: Option<u64>,
+
+    /// Stop the run if more than this percentage of the first --abort-window objects fail
+    /// (e.g. 50), as when the log format has changed, leaving the rest in place.
+    #[arg(long)]
+    abort_failure_percent: Option<f64>,
+
+    /// How many of the first objects --abort-failure-percent judges the run by.
+    #[arg(long, default_value_t = 20, requires = "abort_failure_percent")]
+    abort_window: usize,
+
+    /// Move processed objects under this prefix (e.g. "processed/"), rather than deleting them.
+    /// Without --archive-to, they stay in the source, and the prefix is skipped when listing.
+    #[arg(long)]
+    archive_prefix: Option<String>,
+
+    /// Move processed objects to this store (gs://, s3://, sftp://, or file://),
+    /// rather than deleting them.
+    #[arg(long)]
+    archive_to: Option<SourceSpec>,
+
+    /// Recompress archived objects with zstd at this level (1 to 22; e.g. 19), archiving them
+    /// as .zst rather than as they came, to cut what it costs to keep them.
+    #[arg(long)]
+    archive_zstd_level: Option<i32>,
+
+    /// Move objects that fail to process under this prefix (e.g. "failed/"), with the error
+    /// in a `.error` file alongside, so later runs don't keep retrying them.
+    #[arg(long)]
+    dead_letter_prefix: Option<String>,
+
+    /// After any error in the run, delete, archive, or dead-letter nothing more, leaving even
+    /// objects that crunched in place. The next run fetches them again; pair this with
+    /// --skip-duplicates so they aren't counted twice.
+    #[arg(long, conflicts_with = "dead_letter_prefix")]
+    stop_deleting_on_error: bool,
+}
 
-/// Usage: (bucket) (dbfile)
 fn main() {
     tracing_subscriber::fmt::init();
 
-    let args: Vec<_> = std::env::args().collect();
-    assert_eq!(args.len(), 3, "requires arguments (gcs_path) and (dbfile)");
-    let gcs_path = args[1].clone();
-    let dbfile = Path::new(&args[2]);
+    let args = log_cruncher::parse_args::<Args>();
+    if let Some(writer) = &args.writer {
+        if args.source != SourceSpec::Stdin {
+            eprintln!("--writer only sends entries from stdin; use - as the source");
+            std::process::exit(2);
+        }
+        let count = log_cruncher::send_to_writer(writer, std::io::stdin().lock())
+            .expect("could not crunch");
+        tracing::info!("writer stored {count} entries");
+        return;
+    }
+    // Settings in a GCS spec itself win over the flags.
+    let (gcs_credentials, gcs_scope, gcs_endpoint) =
+        (args.gcs_credentials, args.gcs_scope, args.gcs_endpoint);
+    let gcs_flags = |spec| match spec {
+        SourceSpec::Gcs {
+            bucket,
+            credentials,
+            scope,
+            endpoint,
+        } => SourceSpec::Gcs {
+            bucket,
+            credentials: credentials.or(gcs_credentials.clone()),
+            scope: scope.or(gcs_scope.clone()),
+            endpoint: endpoint.or(gcs_endpoint.clone()),
+        },
+        spec => spec,
+    };
+    let mut config = args
+        .config
+        .as_deref()
+        .map(Config::load)
+        .transpose()
+        .expect("could not load config")
+        .unwrap_or_default();
+    if let Some(backup) = &mut config.backup {
+        backup.to = gcs_flags(backup.to.clone());
+    }
+    let packs = args
+        .packs
+        .as_deref()
+        .map(Pack::discover)
+        .transpose()
+        .expect("could not load packs")
+        .unwrap_or_default();
+    let filter = ObjectFilter::new(args.extensions, args.skip)
+        .and_then(|filter| filter.with_prefix(args.prefix).with_globs(args.globs))
+        .map(|filter| filter.with_time_window(args.name_time_format, args.since, args.until))
+        .map(|filter| match args.last {
+            Some(last) => filter.with_last(last),
+            None => filter,
+        })
+        .expect("could not parse object filter");
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -21,26 +445,172 @@ fn main() {
 
     // Keep the concurrency well under the FD limit,
     // so we don't run out of FDs for connections.
+    // Within that, it's adjusted as the run goes; the cap is just a backstop.
     let (soft_fd_limit, hard_fd_limit) =
         nix::sys::resource::getrlimit(nix::sys::resource::Resource::RLIMIT_NOFILE)
             .expect("could not query FD limit");
     tracing::debug!("FD limit of {soft_fd_limit} (soft) / {hard_fd_limit} (hard)");
-    // We artificially limit this, as I've been getting errors.
-    let concurrency: usize = max(1, min(soft_fd_limit.saturating_sub(100), 128))
+    let max_concurrency: usize = soft_fd_limit
+        .saturating_sub(100)
+        .clamp(1, MAX_CONCURRENCY as u64)
         .try_into()
         .expect("could not fit concurrency limit into usize");
 
-    Cruncher {
-        gcs_path,
-        database: dbfile.to_owned(),
+    let cruncher = Cruncher {
+        sources: std::iter::once(args.source)
+            .chain(args.sources)
+            .map(gcs_flags)
+            .collect(),
+        database: args.dbfile.expect("no database given"),
+        format: args.format,
+        strict_fields: args.strict_fields,
         // This seems to be the limiting factor when cleanup is enabled.
         // Tokio will handle the thread count for us;
         // this is just a memory limit. And we have a lot of memory.
         // We do have to keep it under the fd limit, though!
-        concurrency,
+        concurrency: args
+            .concurrency
+            .map_or(max_concurrency, |n| n.min(max_concurrency)),
+        adaptive_concurrency: args.concurrency.is_none(),
+        max_inflight_bytes: args.max_inflight_bytes,
+        range_chunk_size: args.range_chunk_size,
+        range_parallelism: args.range_parallelism,
+        listing_cache: args.listing_cache,
+        gcs_transcoding: args.gcs_transcoding,
         // Not convinced I'm not losing logs to this, so far.
         cleanup: true,
+        archive_prefix: args.archive_prefix,
+        archive_to: args.archive_to.map(gcs_flags),
+        archive_zstd_level: args.archive_zstd_level,
+        dead_letter_prefix: args.dead_letter_prefix,
+        stop_deleting_on_error: args.stop_deleting_on_error,
+        filter,
+        empty_objects: args.empty_objects,
+        salvage_truncated: args.salvage_truncated,
+        skip_invalid_entries: args.skip_invalid_entries,
+        max_invalid_entries: args.max_invalid_entries,
+        skip_duplicates: args.skip_duplicates,
+        verify: args.verify,
+        serve_friendly: args.serve_friendly,
+        aggregate_only: args.aggregate_only,
+        dedup_requests: match (args.dedup_requests, args.no_dedup_requests) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        store_queries: args.store_queries,
+        normalize_paths: args.normalize_paths,
+        lowercase_paths: args.lowercase_paths,
+        oldest_first: args.oldest_first,
+        max_objects: args.max_objects,
+        max_bytes: args.max_bytes,
+        circuit_breaker: args
+            .abort_failure_percent
+            .map(|max_failure_percent| CircuitBreaker {
+                objects: args.abort_window,
+                max_failure_percent,
+            }),
+        config,
+        packs,
+        #[cfg(feature = "scripting")]
+        script: args
+            .script
+            .as_deref()
+            .map(log_cruncher::Script::load)
+            .transpose()
+            .expect("could not load script"),
+    };
+    if let Err(e) = cruncher.validate() {
+        eprintln!("{e:#}");
+        std::process::exit(2);
+    }
+    if args.doctor {
+        // Against the concurrency asked for, rather than what the FD limit caps it to.
+        let cruncher = Cruncher {
+            concurrency: args.concurrency.unwrap_or(cruncher.concurrency),
+            ..cruncher
+        };
+        let checks = cruncher.doctor(&rt);
+        for check in &checks {
+            println!("{check}");
+        }
+        if !checks.iter().all(|check| check.passed()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.preview_enrichments {
+        let previews = cruncher
+            .preview_enrichments(args.preview_sample)
+            .expect("could not preview enrichments");
+        for preview in &previews {
+            print!("{preview}");
+        }
+        return;
+    }
+    if let Some(manifest) = &args.plan_backfill {
+        if manifest.exists() {
+            eprintln!(
+                "{} is already there; apply it, or delete it to plan again",
+                manifest.display()
+            );
+            std::process::exit(2);
+        }
+        let plan = cruncher
+            .plan_backfill(&rt, args.backfill_batch)
+            .expect("could not plan backfill");
+        plan.save(manifest)
+            .expect("could not write backfill manifest");
+        print!("{plan}");
+        return;
+    }
+    if let Some(manifest) = &args.apply_backfill {
+        match cruncher.apply_backfill(&rt, manifest, args.backfill_batches) {
+            Ok(manifest) => {
+                print!("{manifest}");
+                if manifest.failed() {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(addr) = &args.listen {
+        let secret_file = args.listen_secret_file.as_deref().unwrap();
+        let secret = std::fs::read_to_string(secret_file)
+            .expect("could not read --listen-secret-file")
+            .trim()
+            .to_string();
+        if secret.is_empty() {
+            panic!("--listen-secret-file is empty");
+        }
+        cruncher
+            .serve_http(&rt, addr, secret, args.fastly_service_id)
+            .unwrap();
+        return;
+    }
+    if let Some(addr) = &args.syslog {
+        let tls = args
+            .syslog_tls_cert
+            .as_deref()
+            .zip(args.syslog_tls_key.as_deref());
+        cruncher
+            .serve_syslog(&rt, addr, tls, &args.syslog_allow)
+            .unwrap();
+        return;
+    }
+    let result = match &args.serve {
+        Some(socket) => cruncher.serve(&rt, socket),
+        None if args.daemon => cruncher.watch(&rt, args.interval),
+        None if args.follow => cruncher.follow(&rt),
+        None => cruncher.crunch(&rt),
+    };
+    if let Err(e) = result {
+        eprintln!("{e:#}");
+        std::process::exit(1);
     }
-    .crunch(&rt)
-    .unwrap()
 }
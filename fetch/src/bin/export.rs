@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+/// Export a crunched database for other uses.
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a database of daily rollups, without addresses, user agents, full referers,
+    /// or individual requests, for publishing alongside the site.
+    PublicDb {
+        /// SQLite database to read from.
+        dbfile: PathBuf,
+        /// Database to write; replaced if it exists.
+        out: PathBuf,
+        /// Leave out paths, countries, and referer hosts with fewer requests than this in a day.
+        #[arg(long, default_value_t = 5)]
+        min_requests: u64,
+    },
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    match log_cruncher::parse_args::<Args>().command {
+        Command::PublicDb {
+            dbfile,
+            out,
+            min_requests,
+        } => log_cruncher::export_public_db(&dbfile, &out, min_requests)
+            .expect("could not export public database"),
+    }
+}
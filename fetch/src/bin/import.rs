@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+/// Import history from other analytics tools into a crunched database.
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Import pageviews from a goatcounter CSV export.
+    Goatcounter {
+        /// The export, unzipped.
+        csv: PathBuf,
+        /// SQLite database to write to.
+        dbfile: PathBuf,
+        /// Source to record the requests as coming from, for telling them apart in reports.
+        #[arg(long, default_value = "goatcounter")]
+        source: String,
+    },
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    match log_cruncher::parse_args::<Args>().command {
+        Command::Goatcounter {
+            csv,
+            dbfile,
+            source,
+        } => {
+            log_cruncher::import_goatcounter(&csv, &dbfile, &source)
+                .expect("could not import goatcounter export");
+        }
+    }
+}
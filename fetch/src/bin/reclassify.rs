@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use log_cruncher::Config;
+
+/// Apply the config's tagging rules to the requests already in a database, after the rules
+/// have changed (e.g. a longer list of bots): requests that match a rule now get its tag, and
+/// those that no longer do lose it. Tags no rule gives are left alone, as are those from rules
+/// on values the config's `[dimensions]` rewrote or capped.
+#[derive(Parser)]
+struct Args {
+    /// SQLite database to reclassify.
+    dbfile: PathBuf,
+
+    /// TOML configuration file with the `[[tag]]` rules.
+    #[arg(long)]
+    config: PathBuf,
+
+    /// How many requests to look at in each transaction; others can write between them.
+    #[arg(long, default_value_t = 10_000)]
+    batch_size: usize,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let args = log_cruncher::parse_args::<Args>();
+    let config = Config::load(&args.config).expect("could not load config");
+    let result = log_cruncher::reclassify(&args.dbfile, &config, args.batch_size, |progress| {
+        println!("{progress}");
+    });
+    if let Err(e) = result {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use log_cruncher::Table;
+use rusqlite::{Connection, OptionalExtension};
+
+/// Run a report query against a crunched database, and render the results.
+#[derive(Parser)]
+struct Args {
+    /// SQLite database to read from.
+    dbfile: PathBuf,
+    /// File containing the report query: a single SELECT statement.
+    query: PathBuf,
+
+    /// SQL to run before the query, e.g. reports/joins.sql for its views. May be repeated.
+    #[arg(long)]
+    init: Vec<PathBuf>,
+
+    /// Restrict the reqs view from reports/joins.sql, and so everything built on it,
+    /// to a segment from the config file.
+    #[arg(long)]
+    segment: Option<String>,
+
+    /// Output format. HTML includes a chart, for time series and top-N results,
+    /// if built with the `charts` feature.
+    #[arg(long, value_enum, default_value_t)]
+    format: Format,
+
+    /// Jinja template to render the results with, instead of --format.
+    /// The template sees `columns` and `rows`.
+    #[arg(long)]
+    template: Option<PathBuf>,
+
+    /// Instead of running the query, show how SQLite would: its query plan, the tables it
+    /// would read in full, the indexes it would use, and indexes that might speed it up.
+    #[arg(long)]
+    explain: bool,
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum Format {
+    #[default]
+    Text,
+    Html,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let args = log_cruncher::parse_args::<Args>();
+
+    let conn = Connection::open(&args.dbfile).expect("could not open DB");
+    if let Some(segment) = &args.segment {
+        let filter: String = conn
+            .query_row(
+                "SELECT filter FROM segments WHERE name = ?",
+                [segment],
+                |row| row.get(0),
+            )
+            .optional()
+            .expect("could not look up segment")
+            .unwrap_or_else(|| panic!("no segment named {segment}"));
+        // joins.sql leaves reqs alone if it's already defined.
+        conn.execute_batch(&format!(
+            "CREATE TEMP VIEW reqs AS SELECT * FROM unsegmented WHERE ({filter});"
+        ))
+        .expect("could not apply segment");
+    }
+    for init in &args.init {
+        let sql = std::fs::read_to_string(init).expect("could not read init file");
+        conn.execute_batch(&sql).expect("could not run init file");
+    }
+    let query = std::fs::read_to_string(&args.query).expect("could not read query");
+    if args.explain {
+        let plan = log_cruncher::explain(&conn, &query).expect("could not explain query");
+        print!("{}", plan.to_text());
+        return;
+    }
+    let table = Table::query(&conn, &query).expect("could not run query");
+    let output = match &args.template {
+        Some(template) => {
+            let template = std::fs::read_to_string(template).expect("could not read template");
+            table.render(&template).expect("could not render results")
+        }
+        None => match args.format {
+            Format::Text => table.to_text(),
+            Format::Html => {
+                let title = args.query.file_stem().unwrap_or_default().to_string_lossy();
+                table.to_html(&title).expect("could not render results")
+            }
+        },
+    };
+    print!("{output}");
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use log_cruncher::Setup;
+
+/// Set up a site: ask where its logs are, where the database goes, how long to keep requests,
+/// and what to enrich them with; check that the store and the database can be reached; and
+/// write a config file, with the command to crunch with it.
+#[derive(Parser)]
+struct Args {
+    /// Config file to write.
+    #[arg(default_value = "log-cruncher.toml")]
+    config: PathBuf,
+
+    /// Overwrite the config file if it's already there.
+    #[arg(long)]
+    force: bool,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let args = log_cruncher::parse_args::<Args>();
+    if args.config.exists() && !args.force {
+        eprintln!(
+            "{} is already there; use --force to overwrite it",
+            args.config.display()
+        );
+        std::process::exit(2);
+    }
+    let (mut stdin, mut stdout) = (std::io::stdin().lock(), std::io::stdout());
+    let setup = match Setup::ask(&mut stdin, &mut stdout) {
+        Ok(setup) => setup,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    println!();
+    let checks = setup.check(&rt);
+    for check in &checks {
+        println!("{check}");
+    }
+    if !checks.iter().all(|check| check.passed())
+        && !log_cruncher::confirm(&mut stdin, &mut stdout, "Write the config anyway?", false)
+            .unwrap_or(false)
+    {
+        std::process::exit(1);
+    }
+
+    std::fs::write(&args.config, setup.config(&args.config)).expect("could not write config");
+    println!("\nWrote {}. Crunch with:", args.config.display());
+    println!("  {}", setup.command(&args.config));
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use log_cruncher::SourceSpec;
+
+/// Put back the objects a run's cleanup deleted from a versioned GCS bucket, within the
+/// bucket's retention window. The next run crunches them again.
+#[derive(Parser)]
+struct Args {
+    /// SQLite database the run was recorded in.
+    dbfile: PathBuf,
+
+    /// ID of the run whose objects to restore, as in the runs table.
+    #[arg(long)]
+    run: i64,
+
+    /// Source to restore to, where the run's label for it isn't enough, e.g. to give
+    /// credentials (gs://bucket?credentials=/etc/sa.json). May be given more than once.
+    #[arg(long)]
+    source: Vec<SourceSpec>,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let args = log_cruncher::parse_args::<Args>();
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    match log_cruncher::undelete(&rt, &args.dbfile, args.run, &args.source) {
+        Ok(undeleted) => {
+            println!(
+                "restored {} objects; {} failed",
+                undeleted.restored, undeleted.failed
+            );
+            if undeleted.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    }
+}
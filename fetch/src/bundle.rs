@@ -0,0 +1,163 @@
+//! Objects that bundle several log files: tar archives (usually gzipped, as `.tar.gz`) and
+//! zip archives, as some log shippers upload them, read with the tar and zip crates.
+//!
+//! Tar archives are read as they stream in, member by member. Zip archives are spooled to a
+//! temporary file first, as their directory, with the sizes of members streamed out without
+//! them, is at the end; so one that's cut off can't be salvaged. Members that aren't regular
+//! files (directories, links) are passed over.
+
+use std::io::{self, BufRead};
+
+use anyhow::{bail, Context};
+use tar::EntryType;
+use zip::result::ZipError;
+
+/// Magic number at the start of a zip archive: its first local file header.
+pub(crate) const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+
+/// Size of tar headers, and what member data is padded to.
+pub(crate) const TAR_BLOCK: usize = 512;
+
+/// Whether the start of a stream (its first block) is a tar header.
+pub(crate) fn is_tar(head: &[u8]) -> bool {
+    head.len() >= TAR_BLOCK && &head[257..262] == b"ustar"
+}
+
+/// Pass each file in a tar archive to `member`, with its name.
+///
+/// An archive that runs out partway through fails with an I/O error.
+pub(crate) fn tar_members(
+    input: &mut impl BufRead,
+    mut member: impl FnMut(&str, &mut dyn BufRead) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(input);
+    for entry in archive.entries().context("could not read tar archive")? {
+        let entry = entry.context("could not read tar header")?;
+        if !matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        ) {
+            continue;
+        }
+        let name = entry
+            .path()
+            .context("invalid tar member name")?
+            .to_string_lossy()
+            .into_owned();
+        member(&name, &mut io::BufReader::new(entry))?;
+    }
+    Ok(())
+}
+
+/// Pass each file in a zip archive to `member`, with its name.
+///
+/// Members compressed some way the zip crate can't undo are passed over.
+pub(crate) fn zip_members(
+    input: &mut impl BufRead,
+    mut member: impl FnMut(&str, &mut dyn BufRead) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut spool = tempfile::tempfile().context("could not create file to spool zip to")?;
+    io::copy(input, &mut spool).context("could not read zip archive")?;
+    let mut archive = zip::ZipArchive::new(spool).context("could not read zip archive")?;
+    for i in 0..archive.len() {
+        let name = archive.name_for_index(i).unwrap_or_default().to_string();
+        let mut data = match archive.by_index(i) {
+            Ok(data) => data,
+            Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => {
+                bail!("zip member {name} is encrypted");
+            }
+            Err(ZipError::UnsupportedArchive(reason)) => {
+                tracing::info!("skipping zip member {name}: {reason}");
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("could not read zip member {name}")),
+        };
+        if !data.is_file() {
+            continue;
+        }
+        member(&name, &mut io::BufReader::new(&mut data))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor, Write};
+
+    use tar::EntryType;
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::{is_tar, tar_members, zip_members, TAR_BLOCK};
+
+    /// Collect the names and contents of members.
+    fn collect(
+        members: &mut Vec<(String, String)>,
+    ) -> impl FnMut(&str, &mut dyn BufRead) -> anyhow::Result<()> + '_ {
+        |name, data| {
+            let mut contents = String::new();
+            data.read_to_string(&mut contents)?;
+            members.push((name.to_string(), contents));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_tar_members() {
+        let long = format!("{}/b.log", "d".repeat(120));
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |kind, name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, name, data).unwrap();
+        };
+        append(EntryType::Directory, "d/", b"");
+        append(EntryType::Regular, "d/a.log", b"{}\n\n");
+        // A long name takes a member of its own.
+        append(EntryType::Regular, &long, b"{}\n");
+        let tar = builder.into_inner().unwrap();
+        assert!(is_tar(&tar));
+
+        let mut members = Vec::new();
+        tar_members(&mut &tar[..], collect(&mut members)).unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("d/a.log".to_string(), "{}\n\n".to_string()),
+                (long, "{}\n".to_string())
+            ]
+        );
+
+        // Cut partway through the last member.
+        let cut = &tar[..tar.len() - 3 * TAR_BLOCK + 2];
+        let mut members = Vec::new();
+        let err = tar_members(&mut &cut[..], collect(&mut members)).unwrap_err();
+        assert!(err.chain().any(|e| e.is::<std::io::Error>()));
+        assert_eq!(members.len(), 2);
+    }
+
+    #[test]
+    fn reads_zip_members() {
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.add_directory("d/", stored).unwrap();
+        writer.start_file("d/a.log", stored).unwrap();
+        writer.write_all(b"{}\n").unwrap();
+        let zip = writer.finish().unwrap().into_inner();
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file("b.log", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"{\"a\": 1}\n").unwrap();
+        let deflated = writer.finish().unwrap().into_inner();
+
+        for (zip, name, contents) in [
+            (zip, "d/a.log", "{}\n"),
+            (deflated, "b.log", "{\"a\": 1}\n"),
+        ] {
+            let mut members = Vec::new();
+            zip_members(&mut &zip[..], collect(&mut members)).unwrap();
+            assert_eq!(members, vec![(name.to_string(), contents.to_string())]);
+        }
+    }
+}
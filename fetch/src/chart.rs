@@ -0,0 +1,207 @@
+//! Charts of query results, for HTML reports.
+//!
+//! The kind of chart follows from the shape of the results:
+//! - A first column of dates or times, then numeric columns: a time series, one line per column.
+//! - A first column of labels, then a numeric column: a top-N bar chart.
+//!
+//! Anything else doesn't get a chart.
+
+use anyhow::anyhow;
+use chrono::{NaiveDate, NaiveDateTime};
+use plotters::prelude::*;
+use serde_json::Value;
+
+use crate::Table;
+
+const SIZE: (u32, u32) = (800, 400);
+
+/// Draw the results as an SVG chart, if they have a shape we know how to chart.
+pub fn render_svg(table: &Table) -> anyhow::Result<Option<String>> {
+    if table.columns.len() < 2 || table.rows.is_empty() {
+        return Ok(None);
+    }
+    let numeric = table.rows.iter().all(|row| row[1..].iter().all(is_number));
+    if !numeric {
+        return Ok(None);
+    }
+    let times: Option<Vec<i64>> = table.rows.iter().map(|row| timestamp(&row[0])).collect();
+    match times {
+        Some(times) => time_series(table, &times).map(Some),
+        None if table.columns.len() == 2 => bars(table).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn is_number(value: &Value) -> bool {
+    value.is_number() || value.is_null()
+}
+
+/// Seconds since the epoch, for a date or datetime in SQLite's formats.
+fn timestamp(value: &Value) -> Option<i64> {
+    let s = value.as_str()?;
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp());
+    }
+    let s = s.get(..19)?.replace('T', " ");
+    let time = NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S").ok()?;
+    Some(time.and_utc().timestamp())
+}
+
+fn draw_error(e: impl std::fmt::Display) -> anyhow::Error {
+    anyhow!("could not draw chart: {e}")
+}
+
+fn time_series(table: &Table, times: &[i64]) -> anyhow::Result<String> {
+    let x_min = *times.iter().min().unwrap();
+    let x_max = (*times.iter().max().unwrap()).max(x_min + 1);
+    let y_max = table
+        .rows
+        .iter()
+        .flat_map(|row| row[1..].iter().filter_map(Value::as_f64))
+        .fold(1.0, f64::max);
+    let daily = times.iter().all(|t| t % 86400 == 0);
+
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, SIZE).into_drawing_area();
+        root.fill(&WHITE).map_err(draw_error)?;
+        let mut chart = ChartBuilder::on(&root)
+            .margin(10)
+            .x_label_area_size(30)
+            .y_label_area_size(60)
+            .build_cartesian_2d(x_min..x_max, 0.0..y_max * 1.05)
+            .map_err(draw_error)?;
+        chart
+            .configure_mesh()
+            .x_labels(8)
+            .x_label_formatter(&|t| {
+                let Some(time) = chrono::DateTime::from_timestamp(*t, 0) else {
+                    return String::new();
+                };
+                if daily {
+                    time.format("%Y-%m-%d").to_string()
+                } else {
+                    time.format("%m-%d %H:%M").to_string()
+                }
+            })
+            .draw()
+            .map_err(draw_error)?;
+        for (i, column) in table.columns.iter().enumerate().skip(1) {
+            let color = Palette99::pick(i - 1).to_rgba();
+            let points = times
+                .iter()
+                .zip(&table.rows)
+                .filter_map(|(t, row)| Some((*t, row[i].as_f64()?)));
+            chart
+                .draw_series(LineSeries::new(points, color.stroke_width(2)))
+                .map_err(draw_error)?
+                .label(column)
+                .legend(move |(x, y)| PathElement::new([(x, y), (x + 20, y)], color));
+        }
+        if table.columns.len() > 2 {
+            chart
+                .configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()
+                .map_err(draw_error)?;
+        }
+        root.present().map_err(draw_error)?;
+    }
+    Ok(svg)
+}
+
+fn bars(table: &Table) -> anyhow::Result<String> {
+    let labels: Vec<String> = table
+        .rows
+        .iter()
+        .map(|row| match &row[0] {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+        .collect();
+    let values: Vec<f64> = table
+        .rows
+        .iter()
+        .map(|row| row[1].as_f64().unwrap_or(0.0))
+        .collect();
+    let x_max = values.iter().copied().fold(1.0, f64::max);
+    let n = values.len() as i32;
+    // The label area fits the longest label, within reason.
+    let label_width = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let label_width = (label_width.min(60) * 7 + 10) as u32;
+
+    let mut svg = String::new();
+    {
+        let height = SIZE.1.max(30 + 20 * n as u32);
+        let root = SVGBackend::with_string(&mut svg, (SIZE.0, height)).into_drawing_area();
+        root.fill(&WHITE).map_err(draw_error)?;
+        let mut chart = ChartBuilder::on(&root)
+            .margin(10)
+            .x_label_area_size(30)
+            .y_label_area_size(label_width)
+            .build_cartesian_2d(0.0..x_max * 1.05, (0..n).into_segmented())
+            .map_err(draw_error)?;
+        chart
+            .configure_mesh()
+            .disable_y_mesh()
+            .y_labels(labels.len())
+            .y_label_formatter(&|y| match y {
+                // The first row goes at the top.
+                SegmentValue::CenterOf(i) => labels
+                    .get((n - 1 - i) as usize)
+                    .cloned()
+                    .unwrap_or_default(),
+                _ => String::new(),
+            })
+            .draw()
+            .map_err(draw_error)?;
+        chart
+            .draw_series(values.iter().enumerate().map(|(i, v)| {
+                let y = n - 1 - i as i32;
+                Rectangle::new(
+                    [
+                        (0.0, SegmentValue::Exact(y)),
+                        (*v, SegmentValue::Exact(y + 1)),
+                    ],
+                    Palette99::pick(0).filled(),
+                )
+            }))
+            .map_err(draw_error)?;
+        root.present().map_err(draw_error)?;
+    }
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::render_svg;
+    use crate::Table;
+
+    #[test]
+    fn charts_by_shape() {
+        let series = Table {
+            columns: vec!["date".into(), "requests".into()],
+            rows: vec![
+                vec![json!("2024-07-01"), json!(10)],
+                vec![json!("2024-07-02"), json!(12)],
+            ],
+        };
+        assert!(render_svg(&series).unwrap().unwrap().contains("<svg"));
+        let top = Table {
+            columns: vec!["path".into(), "requests".into()],
+            rows: vec![
+                vec![json!("/"), json!(10)],
+                vec![json!("/about/"), json!(2)],
+            ],
+        };
+        assert!(render_svg(&top).unwrap().unwrap().contains("/about/"));
+        let other = Table {
+            columns: vec!["path".into(), "referer".into()],
+            rows: vec![vec![json!("/"), json!("https://example.com/")]],
+        };
+        assert!(render_svg(&other).unwrap().is_none());
+    }
+}
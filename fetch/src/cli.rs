@@ -0,0 +1,131 @@
+//! Shell completions and man pages for the binaries, from their clap definitions by
+//! clap_complete and clap_mangen: each prints them given only `--completions <SHELL>` or `--man`.
+
+use clap::{parser::ValueSource, Arg, ArgAction, Command, Parser};
+pub use clap_complete::Shell;
+
+/// Parse the command line into `P`; or, given only `--completions <SHELL>` or `--man`, print
+/// a completion script or man page for the command, and exit.
+pub fn parse_args<P: Parser>() -> P {
+    let mut args = std::env::args_os();
+    let mut command = P::command()
+        .arg(
+            Arg::new("completions")
+                .long("completions")
+                .value_name("SHELL")
+                .value_parser(clap::value_parser!(Shell))
+                .exclusive(true)
+                .help("Print a completion script for the shell, and exit"),
+        )
+        .arg(
+            Arg::new("man")
+                .long("man")
+                .action(ArgAction::SetTrue)
+                .exclusive(true)
+                .help("Print a man page, and exit"),
+        );
+    // As it's run, not as the package is named.
+    if let Some(name) = args
+        .next()
+        .as_deref()
+        .and_then(|arg0| std::path::Path::new(arg0).file_name())
+    {
+        command = command.bin_name(name.to_string_lossy());
+    }
+    // Before the command's own arguments are required; but only alone, as they're exclusive.
+    if let Ok(matches) = command.clone().ignore_errors(true).try_get_matches() {
+        let given = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .count();
+        if given != 1 || matches.subcommand().is_some() {
+            return parse(command);
+        }
+        if let Some(shell) = matches.get_one::<Shell>("completions") {
+            print!("{}", completions(command, *shell));
+            std::process::exit(0);
+        }
+        if matches.get_flag("man") {
+            print!("{}", man_page(command));
+            std::process::exit(0);
+        }
+    }
+    parse(command)
+}
+
+fn parse<P: Parser>(mut command: Command) -> P {
+    let matches = command.clone().get_matches();
+    P::from_arg_matches(&matches).unwrap_or_else(|e| e.format(&mut command).exit())
+}
+
+/// A completion script for the command.
+pub fn completions(mut command: Command, shell: Shell) -> String {
+    let name = bin_name(&command);
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, name, &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+/// A man page for the command, in roff.
+pub fn man_page(command: Command) -> String {
+    let name = bin_name(&command);
+    let mut page = Vec::new();
+    clap_mangen::Man::new(command.display_name(name))
+        .render(&mut page)
+        .expect("could not render man page");
+    String::from_utf8_lossy(&page).into_owned()
+}
+
+/// What the command is run as.
+fn bin_name(command: &Command) -> String {
+    command
+        .get_bin_name()
+        .unwrap_or(command.get_name())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction, Command};
+
+    use super::{completions, man_page, Shell};
+
+    fn command() -> Command {
+        Command::new("crunch")
+            .about("Crunch logs")
+            .arg(Arg::new("dbfile").required(true).help("Database to write"))
+            .arg(
+                Arg::new("format")
+                    .long("format")
+                    .value_parser(["text", "html"])
+                    .help("Output format [it's text or html]"),
+            )
+            .arg(
+                Arg::new("dry-run")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .help("Don't write"),
+            )
+            .subcommand(Command::new("verify").about("Check the database"))
+    }
+
+    #[test]
+    fn generates_completions_and_man_page() {
+        let bash = completions(command(), Shell::Bash);
+        assert!(bash.contains("--format --dry-run"), "{bash}");
+        assert!(bash.contains("complete -F _crunch"), "{bash}");
+        let zsh = completions(command(), Shell::Zsh);
+        assert!(zsh.contains("(text html)"), "{zsh}");
+        let fish = completions(command(), Shell::Fish);
+        assert!(fish.contains("-l format"), "{fish}");
+        assert!(
+            fish.contains("-a \"verify\" -d 'Check the database'"),
+            "{fish}"
+        );
+
+        let man = man_page(command());
+        assert!(man.contains(".TH crunch 1"), "{man}");
+        assert!(man.contains("crunch \\- Crunch logs"), "{man}");
+        assert!(man.contains("\\fB\\-\\-format\\fR"), "{man}");
+    }
+}
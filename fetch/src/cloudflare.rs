@@ -0,0 +1,224 @@
+//! Cloudflare's Logpush logs of HTTP requests, so a site behind Cloudflare can be crunched into
+//! the same database as one behind Fastly.
+//!
+//! Logpush writes one JSON object per request, one per line, with the fields picked for the
+//! job. These need ClientIP, ClientRequestPath, EdgeResponseStatus, and EdgeStartTimestamp;
+//! the others used (ClientASN, ClientCountry, ClientRequestHost, ClientRequestReferer,
+//! ClientRequestUserAgent, ClientRequestMethod, ClientRequestProtocol, CacheCacheStatus,
+//! EdgeResponseBytes, EdgeEndTimestamp) are left NULL if the job doesn't have them.
+//! Timestamps can be in any of Logpush's formats: RFC 3339, Unix seconds, or Unix nanoseconds.
+//!
+//! Cache statuses are stored in Fastly's style: `hit` as `HIT`, `revalidated` as
+//! `HIT-REFRESH`, `stale` and `updating` as `HIT-STALE`, `dynamic` and `bypass` as `PASS`, and
+//! the rest (`miss`, `expired`, ...) upper-cased.
+
+use std::{io::BufRead, net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    format::{json_records, EntryError, LogFormat},
+    record::{request_host, request_method, request_protocol, LogEntry},
+};
+
+/// Cloudflare's Logpush JSON.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Cloudflare;
+
+impl LogFormat for Cloudflare {
+    fn parse<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Box<dyn Iterator<Item = Result<LogEntry, EntryError>> + 'a> {
+        Box::new(
+            json_records(input)
+                .map(|record: Result<Record, _>| record?.entry().map_err(EntryError::Invalid)),
+        )
+    }
+}
+
+/// The fields of a Logpush record that are used here.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Record {
+    #[serde(rename = "ClientIP")]
+    client_ip: IpAddr,
+    #[serde(rename = "ClientASN")]
+    client_asn: Option<u32>,
+    client_country: Option<String>,
+    client_request_host: Option<String>,
+    client_request_path: String,
+    client_request_referer: Option<String>,
+    client_request_user_agent: Option<String>,
+    client_request_method: Option<String>,
+    client_request_protocol: Option<String>,
+    cache_cache_status: Option<String>,
+    edge_response_status: usize,
+    edge_response_bytes: Option<usize>,
+    edge_start_timestamp: Timestamp,
+    edge_end_timestamp: Option<Timestamp>,
+}
+
+/// A timestamp, in one of the formats Logpush can be set to use.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Timestamp {
+    Number(i64),
+    String(String),
+}
+
+impl Timestamp {
+    fn time(&self) -> anyhow::Result<DateTime<Utc>> {
+        let from_number = |i: i64| {
+            // Seconds won't reach this for a few thousand years; nanoseconds are well past it.
+            if i.abs() < 100_000_000_000 {
+                DateTime::from_timestamp(i, 0).ok_or_else(|| anyhow!("timestamp out of range"))
+            } else {
+                Ok(DateTime::from_timestamp_nanos(i))
+            }
+        };
+        match self {
+            Timestamp::Number(i) => from_number(*i),
+            Timestamp::String(s) => match s.parse() {
+                Ok(i) => from_number(i),
+                Err(_) => Ok(DateTime::parse_from_rfc3339(s)
+                    .with_context(|| format!("invalid timestamp {s}"))?
+                    .into()),
+            },
+        }
+    }
+}
+
+impl Record {
+    fn entry(self) -> anyhow::Result<LogEntry> {
+        let request_start_time = self
+            .edge_start_timestamp
+            .time()
+            .context("invalid EdgeStartTimestamp")?;
+        let response_duration = match &self.edge_end_timestamp {
+            Some(end) => Some(
+                (end.time().context("invalid EdgeEndTimestamp")? - request_start_time)
+                    .to_std()
+                    .unwrap_or(Duration::ZERO),
+            ),
+            None => None,
+        };
+        let cache_state = self
+            .cache_cache_status
+            .as_deref()
+            .map(|status| match status {
+                "hit" => "HIT".to_string(),
+                "revalidated" => "HIT-REFRESH".to_string(),
+                "stale" | "updating" => "HIT-STALE".to_string(),
+                "dynamic" | "bypass" => "PASS".to_string(),
+                status => status.to_ascii_uppercase(),
+            });
+        // Cloudflare's country codes are lower-case, and "xx" when it doesn't know.
+        let country_code = self
+            .client_country
+            .filter(|country| !country.is_empty() && country != "xx")
+            .map(|country| country.to_ascii_uppercase());
+        Ok(LogEntry {
+            client_ip: self.client_ip,
+            asn: self.client_asn,
+            country_code,
+            requests: 1,
+            ipv6: self.client_ip.is_ipv6(),
+            http2: self
+                .client_request_protocol
+                .as_ref()
+                .map(|protocol| protocol == "HTTP/2"),
+            url_path: self.client_request_path,
+            query: None,
+            referer: self.client_request_referer,
+            user_agent: self.client_request_user_agent,
+            cache_state,
+            response_status: self.edge_response_status,
+            response_bytes: self.edge_response_bytes,
+            response_duration,
+            request_start_time: request_start_time.into(),
+            language: None,
+            request_header_bytes: None,
+            request_body_bytes: None,
+            cache_age: None,
+            cache_ttl: None,
+            request_method: self
+                .client_request_method
+                .as_deref()
+                .and_then(request_method),
+            request_protocol: self
+                .client_request_protocol
+                .as_deref()
+                .and_then(request_protocol),
+            host: self.client_request_host.as_deref().and_then(request_host),
+            service_id: None,
+            raw_path: None,
+            tags: Vec::new(),
+            source: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Cloudflare;
+    use crate::format::{EntryError, LogFormat};
+
+    const LOG: &str = concat!(
+        r#"{"CacheCacheStatus":"hit","ClientASN":7922,"ClientCountry":"us","#,
+        r#""ClientIP":"2001:db8::1","ClientRequestHost":"example.com","#,
+        r#""ClientRequestMethod":"GET","ClientRequestPath":"/writing/a/","#,
+        r#""ClientRequestProtocol":"HTTP/2","#,
+        r#""ClientRequestReferer":"https://news.ycombinator.com/","#,
+        r#""ClientRequestUserAgent":"Mozilla/5.0","EdgeEndTimestamp":1717245000012000000,"#,
+        r#""EdgeResponseBytes":392,"EdgeResponseStatus":200,"#,
+        r#""EdgeStartTimestamp":1717245000000000000}"#,
+        "\n",
+        r#"{"CacheCacheStatus":"dynamic","ClientCountry":"xx","ClientIP":"192.0.2.1","#,
+        r#""ClientRequestPath":"/","ClientRequestProtocol":"HTTP/1.1","EdgeResponseStatus":304,"#,
+        r#""EdgeStartTimestamp":"2024-06-01T12:30:01Z"}"#,
+        "\n",
+    );
+
+    #[test]
+    fn parses_logpush_json() {
+        let entries: Vec<_> = Cloudflare
+            .parse(Box::new(LOG.as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        let entry = &entries[0];
+        assert_eq!(entry.asn, Some(7922));
+        assert_eq!(entry.country_code.as_deref(), Some("US"));
+        assert_eq!(entry.url_path, "/writing/a/");
+        assert_eq!(entry.cache_state.as_deref(), Some("HIT"));
+        assert_eq!(entry.response_duration, Some(Duration::from_millis(12)));
+        assert_eq!(
+            entry.request_start_time.time.to_rfc3339(),
+            "2024-06-01T12:30:00+00:00"
+        );
+        assert!(entry.ipv6 && entry.http2 == Some(true));
+        assert_eq!(entry.request_method.as_deref(), Some("GET"));
+        assert_eq!(entry.host.as_deref(), Some("example.com"));
+        let entry = &entries[1];
+        assert_eq!(entry.country_code, None);
+        assert_eq!(entry.cache_state.as_deref(), Some("PASS"));
+        assert_eq!(entry.referer, None);
+        assert_eq!(entry.response_bytes, None);
+        assert_eq!(entry.request_method, None);
+        assert_eq!(entry.request_protocol.as_deref(), Some("HTTP/1.1"));
+        assert_eq!(
+            entry.request_start_time.time.to_rfc3339(),
+            "2024-06-01T12:30:01+00:00"
+        );
+
+        let cut = &LOG[..LOG.len() - 20];
+        let mut entries = Cloudflare.parse(Box::new(cut.as_bytes()));
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(entries.next(), Some(Err(EntryError::Ended(_)))));
+    }
+}
@@ -0,0 +1,308 @@
+//! CloudFront's standard (access) logs, so a site behind CloudFront, or behind it and Fastly,
+//! can be crunched into the same database.
+//!
+//! The logs are tab-separated, one request per line, after two header lines:
+//!
+//! ```text
+//! #Version: 1.0
+//! #Fields: date time x-edge-location sc-bytes c-ip cs-method cs(Host) cs-uri-stem sc-status ...
+//! ```
+//!
+//! The columns are read by the names in `#Fields`, so logs with fields left out or added still
+//! parse, as long as they have the ones needed here. `-` is an empty field, and some fields
+//! (the path, referer, and user agent) are URL-encoded.
+//!
+//! CloudFront doesn't log the client's AS or country, so those are left NULL.
+//! Its result types are stored as cache states in Fastly's style: `Hit` as `HIT`,
+//! `RefreshHit` as `HIT-REFRESH`, and the rest (`Miss`, `Error`, ...) upper-cased.
+
+use std::{io::BufRead, net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, Context};
+use chrono::{NaiveDate, NaiveTime};
+
+use crate::{
+    format::{EntryError, LogFormat},
+    record::{request_host, request_method, request_protocol, LogEntry},
+};
+
+/// CloudFront's standard logs.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct CloudFront;
+
+impl LogFormat for CloudFront {
+    fn parse<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Box<dyn Iterator<Item = Result<LogEntry, EntryError>> + 'a> {
+        Box::new(Entries {
+            input,
+            columns: None,
+            done: false,
+        })
+    }
+
+    fn accepts_plain(&self, start: &[u8]) -> bool {
+        start.starts_with(b"#Version") || start.starts_with(b"#Fields")
+    }
+}
+
+/// Where the fields used are, per the `#Fields` header.
+struct Columns {
+    /// How many fields there are.
+    count: usize,
+    date: usize,
+    time: usize,
+    client_ip: usize,
+    path: usize,
+    status: usize,
+    bytes: usize,
+    referer: Option<usize>,
+    user_agent: Option<usize>,
+    result_type: Option<usize>,
+    time_taken: Option<usize>,
+    protocol_version: Option<usize>,
+    method: Option<usize>,
+    host: Option<usize>,
+    query: Option<usize>,
+}
+
+impl Columns {
+    fn new(header: &str) -> anyhow::Result<Self> {
+        let names: Vec<&str> = header.split_whitespace().collect();
+        let find = |name: &str| names.iter().position(|&n| n == name);
+        let require = |name: &str| find(name).ok_or_else(|| anyhow!("no {name} field"));
+        Ok(Columns {
+            count: names.len(),
+            date: require("date")?,
+            time: require("time")?,
+            client_ip: require("c-ip")?,
+            path: require("cs-uri-stem")?,
+            status: require("sc-status")?,
+            bytes: require("sc-bytes")?,
+            referer: find("cs(Referer)"),
+            user_agent: find("cs(User-Agent)"),
+            result_type: find("x-edge-result-type"),
+            time_taken: find("time-taken"),
+            protocol_version: find("cs-protocol-version"),
+            method: find("cs-method"),
+            // cs(Host) is the distribution's own domain; this is the one the client asked for.
+            host: find("x-host-header"),
+            query: find("cs-uri-query"),
+        })
+    }
+
+    fn entry(&self, line: &str) -> anyhow::Result<LogEntry> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let field = |i: usize| {
+            fields
+                .get(i)
+                .copied()
+                .ok_or_else(|| anyhow!("only {} fields", fields.len()))
+        };
+        // Optional fields are None without a column, and empty where they're `-` for "none".
+        let optional = |i: Option<usize>| {
+            i.map(|i| match fields.get(i) {
+                Some(&"-") | None => "",
+                Some(value) => value,
+            })
+        };
+
+        let date =
+            NaiveDate::parse_from_str(field(self.date)?, "%Y-%m-%d").context("invalid date")?;
+        let time =
+            NaiveTime::parse_from_str(field(self.time)?, "%H:%M:%S").context("invalid time")?;
+        let client_ip: IpAddr = field(self.client_ip)?
+            .parse()
+            .context("invalid client IP")?;
+        let response_duration = match optional(self.time_taken) {
+            None | Some("") => None,
+            Some(seconds) => Some(
+                seconds
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .context("invalid time-taken")?,
+            ),
+        };
+        let cache_state = optional(self.result_type).map(|result| match result {
+            "Hit" => "HIT".to_string(),
+            "RefreshHit" => "HIT-REFRESH".to_string(),
+            result => result.to_ascii_uppercase(),
+        });
+        Ok(LogEntry {
+            client_ip,
+            asn: None,
+            country_code: None,
+            requests: 1,
+            ipv6: client_ip.is_ipv6(),
+            http2: optional(self.protocol_version).map(|version| version == "HTTP/2.0"),
+            url_path: decode(field(self.path)?),
+            query: optional(self.query)
+                .filter(|query| !query.is_empty())
+                .map(str::to_string),
+            referer: optional(self.referer).map(decode),
+            user_agent: optional(self.user_agent).map(decode),
+            cache_state,
+            response_status: field(self.status)?.parse().context("invalid status")?,
+            response_bytes: Some(field(self.bytes)?.parse().context("invalid byte count")?),
+            response_duration,
+            request_start_time: date.and_time(time).and_utc().into(),
+            language: None,
+            request_header_bytes: None,
+            request_body_bytes: None,
+            cache_age: None,
+            cache_ttl: None,
+            request_method: optional(self.method).and_then(request_method),
+            request_protocol: optional(self.protocol_version).and_then(request_protocol),
+            host: optional(self.host).and_then(request_host),
+            service_id: None,
+            raw_path: None,
+            tags: Vec::new(),
+            source: None,
+        })
+    }
+}
+
+/// The entries in a log file.
+struct Entries<R> {
+    input: R,
+    columns: Option<Columns>,
+    done: bool,
+}
+
+impl<R: BufRead> Entries<R> {
+    fn next_entry(&mut self) -> Option<Result<LogEntry, EntryError>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.input.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(e) => {
+                    let e = anyhow::Error::new(e).context("could not read log");
+                    return Some(Err(EntryError::Ended(e)));
+                }
+            }
+            let complete = line.ends_with('\n');
+            let trimmed = line.trim_end_matches(['\r', '\n']);
+            if let Some(header) = trimmed.strip_prefix("#Fields:") {
+                match Columns::new(header) {
+                    Ok(columns) => self.columns = Some(columns),
+                    Err(e) => return Some(Err(EntryError::Invalid(e.context("invalid #Fields")))),
+                }
+                continue;
+            }
+            if trimmed.starts_with('#') || trimmed.is_empty() {
+                continue;
+            }
+            let Some(columns) = &self.columns else {
+                let e = anyhow!("entry before the #Fields header");
+                return Some(Err(EntryError::Invalid(e)));
+            };
+            // A last line that's short of fields was cut off: where a truncated file ends.
+            let count = trimmed.split('\t').count();
+            if !complete && count < columns.count {
+                let e = anyhow!("entry cut off after {count} of {} fields", columns.count);
+                return Some(Err(EntryError::Ended(e)));
+            }
+            return Some(columns.entry(trimmed).map_err(EntryError::Invalid));
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Entries<R> {
+    type Item = Result<LogEntry, EntryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_entry();
+        // Invalid entries can be skipped; there's nothing after the end.
+        self.done |= matches!(next, None | Some(Err(EntryError::Ended(_))));
+        next
+    }
+}
+
+/// Undo CloudFront's URL-encoding of a field; invalid escapes are left as they are.
+fn decode(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CloudFront;
+    use crate::format::{EntryError, LogFormat};
+
+    const LOG: &str = "#Version: 1.0\n\
+        #Fields: date time x-edge-location sc-bytes c-ip cs-method cs(Host) cs-uri-stem \
+        sc-status cs(Referer) cs(User-Agent) cs-uri-query cs(Cookie) x-edge-result-type \
+        x-edge-request-id x-host-header cs-protocol cs-bytes time-taken x-forwarded-for \
+        ssl-protocol ssl-cipher x-edge-response-result-type cs-protocol-version\n\
+        2024-06-01\t12:30:00\tLAX1\t392\t2001:db8::1\tGET\td111.cloudfront.net\t/writing/a/\t200\t\
+        https://news.ycombinator.com/\tMozilla/5.0%20(X11;%20Linux)\tutm=feed\t-\tHit\tabc==\t\
+        example.com\thttps\t23\t0.012\t-\tTLSv1.3\tTLS_AES_128_GCM_SHA256\tHit\tHTTP/2.0\n\
+        2024-06-01\t12:30:01\tLAX1\t0\t192.0.2.1\tGET\td111.cloudfront.net\t/\t304\t-\t-\t-\t-\t\
+        RefreshHit\tdef==\texample.com\thttps\t23\t0.001\t-\tTLSv1.3\tTLS_AES_128_GCM_SHA256\t\
+        RefreshHit\tHTTP/1.1\n";
+
+    #[test]
+    fn parses_cloudfront_logs() {
+        let entries: Vec<_> = CloudFront
+            .parse(Box::new(LOG.as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        let entry = &entries[0];
+        assert_eq!(entry.url_path, "/writing/a/");
+        assert_eq!(
+            entry.user_agent.as_deref(),
+            Some("Mozilla/5.0 (X11; Linux)")
+        );
+        assert_eq!(
+            entry.referer.as_deref(),
+            Some("https://news.ycombinator.com/")
+        );
+        assert_eq!(entry.cache_state.as_deref(), Some("HIT"));
+        assert_eq!(entry.response_duration, Some(Duration::from_millis(12)));
+        assert_eq!(
+            entry.request_start_time.time.to_rfc3339(),
+            "2024-06-01T12:30:00+00:00"
+        );
+        assert!(entry.ipv6 && entry.http2 == Some(true));
+        assert_eq!(entry.request_method.as_deref(), Some("GET"));
+        assert_eq!(entry.request_protocol.as_deref(), Some("HTTP/2"));
+        assert_eq!(entry.host.as_deref(), Some("example.com"));
+        assert_eq!(entries[1].referer.as_deref(), Some(""));
+        assert_eq!(entries[1].cache_state.as_deref(), Some("HIT-REFRESH"));
+        assert_eq!(entries[1].response_status, 304);
+
+        // Cut partway through the last line.
+        let cut = &LOG[..LOG.len() - 40];
+        let mut entries = CloudFront.parse(Box::new(cut.as_bytes()));
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(entries.next(), Some(Err(EntryError::Ended(_)))));
+    }
+}
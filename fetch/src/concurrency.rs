@@ -0,0 +1,273 @@
+//! Adaptive concurrency for fetches: start with a few downloads in flight, and find how many
+//! the store (and the network, and the file descriptor limit) can take.
+//!
+//! This is additive-increase, multiplicative-decrease, as in TCP. Each round of downloads
+//! (as many as the limit) is timed; if it moved at least about as many bytes per second as the
+//! best round so far, the limit goes up by one; if it was slower, more in flight isn't helping,
+//! and the limit goes down by one. An error halves the limit, at most once per round, since
+//! the other downloads in flight at the time are likely to fail too.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How close to the best throughput a round has to be to keep growing.
+const KEEP_UP: f64 = 0.9;
+
+/// A limit on downloads in flight, adjusted as they complete.
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    cap: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    /// Permits to forget as they're returned, having lowered the limit while they were out.
+    debt: usize,
+    round: Round,
+    /// Bytes per second of the best round so far.
+    best_rate: f64,
+}
+
+#[derive(Clone, Copy)]
+struct Round {
+    started: Instant,
+    fetched: usize,
+    bytes: u64,
+    failed: bool,
+}
+
+impl Round {
+    fn new() -> Self {
+        Round {
+            started: Instant::now(),
+            fetched: 0,
+            bytes: 0,
+            failed: false,
+        }
+    }
+}
+
+/// Permission to run one download; holds a place under the limit until dropped.
+pub(crate) struct Slot {
+    permit: Option<OwnedSemaphorePermit>,
+    concurrency: Arc<Concurrency>,
+}
+
+impl Concurrency {
+    /// Start with `initial` downloads in flight, and never allow more than `cap`.
+    pub fn new(initial: usize, cap: usize) -> Self {
+        let cap = cap.max(1);
+        let limit = initial.clamp(1, cap);
+        Concurrency {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            cap,
+            state: Mutex::new(State {
+                limit,
+                debt: 0,
+                round: Round::new(),
+                best_rate: 0.0,
+            }),
+        }
+    }
+
+    /// The current limit.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Wait for a place under the limit.
+    pub(crate) async fn acquire(self: &Arc<Self>) -> Slot {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("concurrency semaphore is never closed");
+        Slot {
+            permit: Some(permit),
+            concurrency: Arc::clone(self),
+        }
+    }
+
+    /// Record a failed download.
+    pub(crate) fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.round.failed {
+            return;
+        }
+        let limit = (state.limit / 2).max(1);
+        tracing::debug!("fetch failed; lowering concurrency to {limit}");
+        self.resize(&mut state, limit);
+        state.round = Round {
+            failed: true,
+            ..Round::new()
+        };
+    }
+
+    /// Record a completed download of `bytes`.
+    fn fetched(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.round.fetched += 1;
+        state.round.bytes += bytes;
+        if state.round.fetched < state.limit {
+            return;
+        }
+        let elapsed = state
+            .round
+            .started
+            .elapsed()
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        let rate = state.round.bytes as f64 / elapsed;
+        let limit = if rate >= state.best_rate * KEEP_UP {
+            state.best_rate = state.best_rate.max(rate);
+            (state.limit + 1).min(self.cap)
+        } else {
+            // Start over from here, so the limit can grow again if conditions change.
+            state.best_rate = rate;
+            (state.limit - 1).max(1)
+        };
+        if limit != state.limit {
+            tracing::debug!("fetched {rate:.0} bytes/s; concurrency now {limit}");
+            self.resize(&mut state, limit);
+        }
+        state.round = Round::new();
+    }
+
+    fn resize(&self, state: &mut State, limit: usize) {
+        if limit > state.limit {
+            let mut add = limit - state.limit;
+            let paid = add.min(state.debt);
+            state.debt -= paid;
+            add -= paid;
+            self.semaphore.add_permits(add);
+        } else {
+            let remove = state.limit - limit;
+            // Permits that are out now are forgotten when they come back.
+            state.debt += remove - self.semaphore.forget_permits(remove);
+        }
+        state.limit = limit;
+    }
+}
+
+/// A limit on the total size of objects in flight, by their sizes in the listing.
+///
+/// Counted in KiB, so budgets up to 4 TiB fit in a semaphore's permits.
+pub(crate) struct ByteBudget {
+    semaphore: Arc<Semaphore>,
+    kib: u32,
+}
+
+impl ByteBudget {
+    pub(crate) fn new(bytes: u64) -> Self {
+        let kib = (bytes / 1024).clamp(1, u32::MAX.into()) as u32;
+        ByteBudget {
+            semaphore: Arc::new(Semaphore::new(kib as usize)),
+            kib,
+        }
+    }
+
+    /// Wait for room for an object of `size` bytes; the room is held until the permit is
+    /// dropped.
+    ///
+    /// An object larger than the whole budget waits for all of it, so it's fetched alone.
+    /// One of unknown size counts as 1 KiB.
+    pub(crate) async fn acquire(&self, size: Option<u64>) -> OwnedSemaphorePermit {
+        let kib = size.unwrap_or(0).div_ceil(1024).clamp(1, self.kib.into()) as u32;
+        Arc::clone(&self.semaphore)
+            .acquire_many_owned(kib)
+            .await
+            .expect("byte budget semaphore is never closed")
+    }
+}
+
+impl Slot {
+    /// Release the slot after a complete download of `bytes`.
+    pub(crate) fn fetched(self, bytes: u64) {
+        self.concurrency.fetched(bytes);
+    }
+
+    /// Release the slot after a failed download.
+    pub(crate) fn failed(self) {
+        self.concurrency.failed();
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        let mut state = self.concurrency.state.lock().unwrap();
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{ByteBudget, Concurrency, Slot};
+
+    #[test]
+    fn grows_and_backs_off() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let concurrency = Arc::new(Concurrency::new(2, 4));
+        rt.block_on(async {
+            // Each round takes about as long, so more in flight moves more bytes per second.
+            for _ in 0..2 {
+                let slots = take_slots(&concurrency, concurrency.limit()).await;
+                std::thread::sleep(Duration::from_millis(10));
+                for slot in slots {
+                    slot.fetched(1 << 20);
+                }
+            }
+            assert_eq!(concurrency.limit(), 4);
+
+            // An error halves it, once for the round, even while slots are out.
+            let mut slots = take_slots(&concurrency, 4).await;
+            slots.pop().unwrap().failed();
+            slots.pop().unwrap().failed();
+            assert_eq!(concurrency.limit(), 2);
+            drop(slots);
+            // The permits that were out are gone, too.
+            let _slots = take_slots(&concurrency, 2).await;
+            assert_eq!(concurrency.semaphore.available_permits(), 0);
+        });
+    }
+
+    #[test]
+    fn budgets_bytes() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let budget = ByteBudget::new(10 << 10);
+        rt.block_on(async {
+            let a = budget.acquire(Some(6 << 10)).await;
+            let b = budget.acquire(Some(3000)).await;
+            assert_eq!(budget.semaphore.available_permits(), 1);
+            let c = budget.acquire(None).await;
+            assert_eq!(budget.semaphore.available_permits(), 0);
+            drop((a, b, c));
+            // Larger than the whole budget: takes all of it.
+            let d = budget.acquire(Some(1 << 30)).await;
+            assert_eq!(d.num_permits(), 10);
+        });
+    }
+
+    async fn take_slots(concurrency: &Arc<Concurrency>, n: usize) -> Vec<Slot> {
+        let mut slots = Vec::new();
+        for _ in 0..n {
+            slots.push(concurrency.acquire().await);
+        }
+        slots
+    }
+}
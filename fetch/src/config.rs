@@ -0,0 +1,346 @@
+//! Configuration file for the cruncher.
+//!
+//! Settings that are too structured for command-line flags live here, in TOML.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    backup::Backup,
+    delimited::FIELDS,
+    dimensions::{Dimensions, Limits},
+    mapped,
+    notify::Webhook,
+    quota::Quotas,
+    retry::Retry,
+    tagging::TagRule,
+};
+
+/// Contents of the configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Service-level objectives, evaluated after each run.
+    #[serde(default)]
+    pub slo: Vec<Slo>,
+    /// The site's own hostnames, e.g. ["example.com", "www.example.com"]: referers from
+    /// these are internal navigation. List every host the site is served from.
+    #[serde(default)]
+    pub site_hosts: Vec<String>,
+    /// Chat webhooks to send run summaries and alerts to.
+    #[serde(default)]
+    pub notify: Vec<Webhook>,
+    /// Rules tagging requests as they're stored, from `[[tag]]` sections.
+    #[serde(default, rename = "tag")]
+    pub tags: Vec<TagRule>,
+    /// Named filters that reports can restrict themselves to, with `--segment`.
+    #[serde(default, rename = "segment")]
+    pub segments: Vec<Segment>,
+    /// Caps and normalization rules for the paths, referers, user agents, and hosts tables.
+    #[serde(default)]
+    pub dimensions: Dimensions,
+    /// Retries for transient storage errors.
+    #[serde(default)]
+    pub retry: Retry,
+    /// Waiting on other processes that hold the database, e.g. datasette.
+    #[serde(default)]
+    pub sqlite: Sqlite,
+    /// Periodic backups of the database to object storage.
+    pub backup: Option<Backup>,
+    /// Columns of the requests table computed from other fields, from `[[column]]` sections.
+    #[serde(default, rename = "column")]
+    pub columns: Vec<ComputedColumn>,
+    /// Which key of Fastly's JSON, or column of CSV or TSV logs, each field is in, where it
+    /// isn't the usual one.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Requests stored per hour from each client IP, AS, or country; the rest are counted.
+    #[serde(default)]
+    pub quotas: Quotas,
+    /// How long to keep requests, after which only the rollups have them.
+    pub retention: Option<Retention>,
+    /// The Fastly service each source's logs are from, by its label (e.g. "gs://bucket"),
+    /// for requests whose log format doesn't include it.
+    #[serde(default)]
+    pub service_ids: BTreeMap<String, String>,
+    /// Lookups a run ends with, beyond computed columns and packs.
+    #[serde(default)]
+    pub enrichment: Enrichment,
+}
+
+impl Config {
+    /// Read the configuration from a TOML file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("could not parse config file {}", path.display()))
+    }
+
+    /// Everything wrong with the settings that parsed, for reporting all at once rather than
+    /// as each comes up.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut names = HashSet::new();
+        for slo in &self.slo {
+            if !names.insert(&slo.name) {
+                problems.push(format!("SLO {:?} is defined twice", slo.name));
+            }
+            for (target, value) in [("latency", slo.latency_target), ("error", slo.error_target)] {
+                if value <= 0.0 || value > 1.0 || value.is_nan() {
+                    problems.push(format!(
+                        "SLO {:?}: {target}_target must be a fraction in (0, 1], not {value}",
+                        slo.name
+                    ));
+                }
+            }
+            if slo.window_days == 0 {
+                problems.push(format!(
+                    "SLO {:?}: window_days must be at least 1",
+                    slo.name
+                ));
+            }
+        }
+        let mut names = HashSet::new();
+        for segment in &self.segments {
+            if !valid_segment_name(&segment.name) {
+                problems.push(format!("invalid segment name {:?}", segment.name));
+            } else if !names.insert(&segment.name) {
+                problems.push(format!("segment {:?} is defined twice", segment.name));
+            }
+        }
+        let mut names = HashSet::new();
+        for column in &self.columns {
+            if !valid_column_name(&column.name) {
+                problems.push(format!("invalid computed column name {:?}", column.name));
+            } else if !names.insert(&column.name) {
+                problems.push(format!(
+                    "computed column {:?} is defined twice",
+                    column.name
+                ));
+            }
+        }
+        for field in self.fields.keys() {
+            if !FIELDS.contains(&field.as_str()) && !mapped::is_field(field) {
+                problems.push(format!(
+                    "[fields]: {field:?} isn't a field that can be mapped; these are: {} \
+                    (and, for Fastly's JSON, ipv6, language, request_header_bytes, \
+                    request_body_bytes, cache_age, and cache_ttl)",
+                    FIELDS.join(", ")
+                ));
+            }
+        }
+        if let Some(retention) = &self.retention {
+            if retention.raw_days == 0 {
+                problems.push("retention: raw_days must be at least 1".to_string());
+            }
+            for slo in self
+                .slo
+                .iter()
+                .filter(|slo| slo.window_days > retention.raw_days)
+            {
+                problems.push(format!(
+                    "SLO {:?}: window_days is longer than the {} days requests are kept",
+                    slo.name, retention.raw_days
+                ));
+            }
+        }
+        if self.site_hosts.iter().any(|host| host.trim().is_empty()) {
+            problems.push("site_hosts has an empty host".to_string());
+        }
+        for (section, retry) in [("retry", &self.retry), ("sqlite.retry", &self.sqlite.retry)] {
+            problems.extend(
+                retry
+                    .problems()
+                    .into_iter()
+                    .map(|problem| format!("[{section}]: {problem}")),
+            );
+        }
+        problems.extend(
+            self.quotas
+                .problems()
+                .into_iter()
+                .map(|problem| format!("[quotas]: {problem}")),
+        );
+        if let Err(e) = Limits::new(&self.dimensions) {
+            problems.push(format!("[dimensions]: {e:#}"));
+        }
+        problems
+    }
+}
+
+/// Whether a segment name can be passed to `--segment`.
+pub(crate) fn valid_segment_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether a computed column name is a plain SQL identifier.
+pub(crate) fn valid_column_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A latency and error-rate objective for a set of paths.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Slo {
+    /// Name of the objective, as it appears in the slo_results table.
+    pub name: String,
+    /// SQL LIKE pattern of the paths this objective covers, e.g. "/writing/%".
+    pub path: String,
+    /// Requests slower than this count against the latency objective.
+    pub latency_ms: u64,
+    /// Fraction of requests that should be faster than `latency_ms`.
+    pub latency_target: f64,
+    /// Fraction of requests that should not be server errors (5xx).
+    pub error_target: f64,
+    /// How far back to look, in days.
+    #[serde(default = "default_slo_window_days")]
+    pub window_days: u32,
+}
+
+fn default_slo_window_days() -> u32 {
+    28
+}
+
+/// How long requests are kept, from the `[retention]` section:
+///
+/// ```toml
+/// [retention]
+/// raw_days = 90
+/// ```
+///
+/// After each run, requests from before the last `raw_days` days (by date, in UTC) are deleted.
+/// The daily rollups (totals, paths, statuses, countries, referers) keep them, for trends over
+/// any span; per-request reports only reach back as far as the requests kept.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    /// Days of requests to keep, counting today.
+    pub raw_days: u32,
+}
+
+/// A named subset of requests, e.g. human traffic, for reports to restrict themselves to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Segment {
+    /// Name to pass to `--segment`: letters, digits, `-`, and `_`.
+    pub name: String,
+    /// SQL expression over the columns of the reqs view in reports/joins.sql,
+    /// e.g. "user_agent NOT LIKE '%bot%' AND NOT same_site".
+    pub filter: String,
+}
+
+/// A column of the requests table computed from each request's fields as it's stored, and
+/// indexed: for classifications that reports filter on often, e.g.
+///
+/// ```toml
+/// [[column]]
+/// name = "is_feed"
+/// expr = "url_path LIKE '/feed%'"
+/// ```
+///
+/// Reports can get at them with `JOIN requests USING (id)`. Changing the expression recomputes
+/// the column for every request; removing the section drops the column.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComputedColumn {
+    /// Name of the column: a letter or `_`, then letters, digits, and `_`.
+    pub name: String,
+    /// SQL expression over the columns of the computed_inputs view in cruncher.rs:
+    /// the request's fields, with paths, referers, and user agents as text.
+    pub expr: String,
+}
+
+/// How to wait for the database when another process holds it, from the `[sqlite]` section.
+///
+/// SQLite itself waits up to `busy_timeout_ms` for a lock; if the database is still busy after
+/// that, the whole transaction is retried per `retry`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sqlite {
+    pub busy_timeout_ms: u64,
+    pub retry: Retry,
+}
+
+impl Default for Sqlite {
+    fn default() -> Self {
+        Sqlite {
+            busy_timeout_ms: 5_000,
+            retry: Retry::default(),
+        }
+    }
+}
+
+/// Lookups at the end of each run, from the `[enrichment]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Enrichment {
+    /// Name the ASes requests come from, from PeeringDB (or Spamhaus's list, where it has them).
+    pub peeringdb: bool,
+}
+
+impl Default for Enrichment {
+    fn default() -> Self {
+        Enrichment { peeringdb: true }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn lists_every_problem() {
+        let config: Config = toml::from_str(
+            r#"
+            [[slo]]
+            name = "articles"
+            path = "/writing/%"
+            latency_ms = 200
+            latency_target = 99
+            error_target = 0.999
+
+            [[segment]]
+            name = "humans only"
+            filter = "TRUE"
+
+            [retry]
+            max_attempts = 0
+            "#,
+        )
+        .unwrap();
+        let problems = config.problems();
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].contains("latency_target"));
+        assert!(Config::default().problems().is_empty());
+    }
+
+    #[test]
+    fn parses_slos() {
+        let config: Config = toml::from_str(
+            r#"
+            [[slo]]
+            name = "articles"
+            path = "/writing/%"
+            latency_ms = 200
+            latency_target = 0.99
+            error_target = 0.999
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.slo.len(), 1);
+        assert_eq!(config.slo[0].window_days, 28);
+    }
+}
@@ -1,51 +1,1225 @@
-use crate::record::LogEntry;
+use crate::{
+    config::{valid_column_name, valid_segment_name, ComputedColumn, Segment, Slo, Sqlite},
+    dimensions::Limits,
+    packs::Pack,
+    preview::{self, Preview},
+    quota::{Quotas, Usage},
+    record::LogEntry,
+    retry::Retry,
+    tagging::{Request, TagRule},
+};
 use anyhow::{anyhow, Context};
-use rusqlite::{named_params, Connection};
+use backon::BlockingRetryable;
+use chrono::NaiveDate;
+use rusqlite::{
+    named_params, Connection, ErrorCode, OptionalExtension, Transaction, TransactionBehavior,
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     path::Path,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio::task::JoinSet;
 
+/// What one batch of reclassifying did.
+pub struct ReclassifiedBatch {
+    /// The last request in the batch.
+    pub last: i64,
+    pub requests: usize,
+    pub added: usize,
+    pub removed: usize,
+    /// Requests with tags left as they were; see Reclassified.
+    pub kept: usize,
+}
+
+/// A request as rules see it, read back from the database.
+struct Stored {
+    id: i64,
+    path: String,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    asn: Option<u32>,
+    status: usize,
+}
+
 /// Consumer of logs.
 pub struct Cruncher {
     conn: Mutex<Connection>,
+    limits: Limits,
+    quotas: Quotas,
+    /// Keep only the rollups, not the requests themselves.
+    aggregate_only: bool,
+    /// Retries for when another process holds the database past the busy timeout.
+    busy_retry: Retry,
 }
 
 const SCHEMA: &str = include_str!("schema.sql");
 
+/// The schema version (user_version) of a database with every migration applied.
+pub(crate) const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Changes to existing tables, applied in order after SCHEMA.
+///
+/// The database's user_version records how many of these have been applied.
+/// Only append to this list; new tables can go in SCHEMA directly.
+const MIGRATIONS: &[&str] = &[
+    // 1: content hashes, for deduplicating objects.
+    r#"
+    ALTER TABLE processed_objects ADD COLUMN content_hash TEXT NULL;
+    CREATE INDEX IF NOT EXISTS processed_objects_by_hash ON processed_objects(content_hash);
+    "#,
+    // 2: backfill the per-path rollup from existing requests.
+    r#"
+    INSERT INTO daily_paths (date, url_path, requests, response_bytes)
+    SELECT date(request_start_time), url_path, COUNT(*), SUM(response_bytes)
+    FROM requests
+    GROUP BY 1, 2;
+    "#,
+    // 3: preferred language, from Accept-Language.
+    r#"
+    ALTER TABLE requests ADD COLUMN language TEXT NULL;
+    "#,
+    // 4: referer hosts, for telling internal navigation from external referrals.
+    // New referers get this from record.rs; this is a rougher parse for the old ones.
+    r#"
+    ALTER TABLE referers ADD COLUMN host TEXT NULL;
+    WITH
+        rest AS (
+            SELECT id, substr(referer, instr(referer, '://') + 3) AS rest
+            FROM referers WHERE instr(referer, '://') > 0
+        )
+    ,   authority AS (
+            SELECT id, substr(rest, 1, instr(rest || '/', '/') - 1) AS authority FROM rest
+        )
+    UPDATE referers SET host = lower(
+        CASE WHEN instr(authority, ':') > 0
+            THEN substr(authority, 1, instr(authority, ':') - 1)
+            ELSE authority
+        END
+    )
+    FROM authority WHERE referers.id = authority.id;
+    "#,
+    // 5: which source each request was fetched from, in runs over several.
+    r#"
+    ALTER TABLE requests ADD COLUMN source INTEGER NULL REFERENCES sources(id);
+    "#,
+    // 6: request sizes, for counting transfer in both directions.
+    r#"
+    ALTER TABLE requests ADD COLUMN request_header_bytes INTEGER NULL;
+    ALTER TABLE requests ADD COLUMN request_body_bytes INTEGER NULL;
+    "#,
+    // 7: cached object age and TTL, for tuning Cache-Control.
+    r#"
+    ALTER TABLE requests ADD COLUMN cache_age REAL NULL;
+    ALTER TABLE requests ADD COLUMN cache_ttl REAL NULL;
+    "#,
+    // 8: backfill the daily rollups from existing requests.
+    r#"
+    INSERT INTO daily_totals (date, requests, response_bytes)
+    SELECT date(request_start_time), COUNT(*), COALESCE(SUM(response_bytes), 0)
+    FROM requests
+    GROUP BY 1;
+    INSERT INTO daily_statuses (date, status, requests)
+    SELECT date(request_start_time), CAST(response_status AS INTEGER), COUNT(*)
+    FROM requests
+    GROUP BY 1, 2;
+    INSERT INTO daily_countries (date, country, requests)
+    SELECT date(request_start_time), COALESCE(country_code, ''), COUNT(*)
+    FROM requests
+    GROUP BY 1, 2;
+    INSERT INTO daily_referers (date, referer, requests)
+    SELECT date(request_start_time), referer, COUNT(*)
+    FROM requests
+    WHERE referer IS NOT NULL
+    GROUP BY 1, 2;
+    "#,
+    // 9: clients of days whose requests were pruned, and an index to prune by.
+    r#"
+    ALTER TABLE daily_totals ADD COLUMN clients INTEGER NULL;
+    CREATE INDEX IF NOT EXISTS requests_by_start_time ON requests(request_start_time);
+    "#,
+    // 10: keys to tell requests stored twice apart; requests from before have none.
+    r#"
+    ALTER TABLE requests ADD COLUMN dedup_key BLOB NULL;
+    "#,
+    // 11: request methods and protocols, for telling GETs from POSTs.
+    r#"
+    ALTER TABLE requests ADD COLUMN request_method TEXT NULL;
+    ALTER TABLE requests ADD COLUMN request_protocol TEXT NULL;
+    "#,
+    // 12: backfill the bandwidth rollup from existing requests.
+    r#"
+    INSERT INTO minute_bandwidth (minute, requests, response_bytes)
+    SELECT strftime('%Y-%m-%d %H:%M:00', request_start_time), COUNT(*),
+        COALESCE(SUM(response_bytes), 0)
+    FROM requests
+    GROUP BY 1;
+    "#,
+    // 13: which of several domains each request was for.
+    r#"
+    ALTER TABLE requests ADD COLUMN host INTEGER NULL REFERENCES hosts(id);
+    "#,
+    // 14: whether each request's AS was on a droplist when it was stored. Requests from
+    // before are left NULL, as their AS's membership then isn't known.
+    r#"
+    ALTER TABLE requests ADD COLUMN droplisted INTEGER NULL;
+    "#,
+    // 15: which Fastly service each request was for, in databases that hold several.
+    r#"
+    ALTER TABLE requests ADD COLUMN service INTEGER NULL REFERENCES services(id);
+    "#,
+    // 16: query strings, split from paths, where they're kept.
+    r#"
+    ALTER TABLE requests ADD COLUMN query TEXT NULL;
+    "#,
+    // 17: how each request's start time was written, for auditing a change of log format.
+    r#"
+    ALTER TABLE requests ADD COLUMN time_format TEXT NULL;
+    "#,
+    // 18: paths as logged, where normalizing them changed them.
+    r#"
+    ALTER TABLE requests ADD COLUMN raw_path TEXT NULL;
+    "#,
+    // 19: path snapshots only where a run changed a path's totals; drop the rest of the old ones.
+    r#"
+    CREATE INDEX IF NOT EXISTS path_snapshots_by_path ON path_snapshots(url_path, run_id);
+    DELETE FROM path_snapshots AS snapshot
+    WHERE (requests, response_bytes) IS (
+        SELECT requests, response_bytes FROM path_snapshots AS before
+        WHERE before.url_path = snapshot.url_path AND before.run_id < snapshot.run_id
+        ORDER BY before.run_id DESC
+        LIMIT 1
+    );
+    "#,
+    // 20: objects by source and name, so sources with the same names don't shadow each other.
+    // Objects from before have no source.
+    r#"
+    CREATE TABLE processed_objects_by_source (
+      source TEXT NOT NULL -- as in the sources table; '' if the run had no label for it
+    , name TEXT NOT NULL
+    , status TEXT NOT NULL -- "ok", "error", "skipped"
+    , detail TEXT NULL
+    , processed_at TEXT NOT NULL
+    , content_hash TEXT NULL
+
+    , PRIMARY KEY (source, name)
+    ) STRICT;
+    INSERT INTO processed_objects_by_source
+        (source, name, status, detail, processed_at, content_hash)
+    SELECT '', name, status, detail, processed_at, content_hash FROM processed_objects;
+    DROP TABLE processed_objects;
+    ALTER TABLE processed_objects_by_source RENAME TO processed_objects;
+    CREATE INDEX IF NOT EXISTS processed_objects_by_hash ON processed_objects(content_hash);
+    "#,
+];
+
+/// What computed column expressions are evaluated over: each request's fields, by the names
+/// they have in LogEntry, with the other tables joined in.
+pub(crate) const COMPUTED_INPUTS: &str = r#"
+CREATE TEMP VIEW IF NOT EXISTS computed_inputs AS
+SELECT
+  requests.id AS id
+, COALESCE(client_ips.ipv4, client_ips.ipv6) AS client_ip
+, requests.asn AS asn
+, requests.country_code AS country_code
+, requests.ipv6 AS ipv6
+, requests.http2 AS http2
+, requests.cache_state AS cache_state
+, CAST(requests.response_status AS INTEGER) AS response_status
+, requests.response_bytes AS response_bytes
+, CAST(requests.response_duration AS REAL) AS response_duration
+, requests.request_start_time AS request_start_time
+, paths.path AS url_path
+, requests.query AS query
+, requests.raw_path AS raw_path
+, referers.referer AS referer
+, referers.host AS referer_host
+, user_agents.user_agent AS user_agent
+, requests.language AS language
+, requests.request_header_bytes AS request_header_bytes
+, requests.request_body_bytes AS request_body_bytes
+, requests.cache_age AS cache_age
+, requests.cache_ttl AS cache_ttl
+, requests.request_method AS request_method
+, requests.request_protocol AS request_protocol
+, hosts.host AS host
+, requests.droplisted AS droplisted
+, requests.time_format AS time_format
+, services.service_id AS service_id
+, sources.name AS source
+FROM requests
+    LEFT JOIN client_ips ON requests.client_ip = client_ips.id
+    LEFT JOIN paths ON requests.url_path = paths.id
+    LEFT JOIN referers ON requests.referer = referers.id
+    LEFT JOIN user_agents ON requests.user_agent = user_agents.id
+    LEFT JOIN sources ON requests.source = sources.id
+    LEFT JOIN hosts ON requests.host = hosts.id
+    LEFT JOIN services ON requests.service = services.id;
+"#;
+
+/// An object cleanup deleted, that can be restored.
+pub struct DeletedObject {
+    /// Label of the source it was deleted from, if the run had one.
+    pub source: Option<String>,
+    pub name: String,
+    pub generation: String,
+}
+
+/// A transaction that entries are stored into in batches, e.g. as an object is parsed.
+///
+/// Rolls back if dropped without committing.
+pub struct BatchTransaction<'a> {
+    cruncher: &'a Cruncher,
+    done: bool,
+}
+
+impl BatchTransaction<'_> {
+    /// Store a batch of entries.
+    ///
+    /// The transaction holds the write lock from the start, so this doesn't wait on other writers.
+    pub fn store(&self, data: &[LogEntry]) -> anyhow::Result<()> {
+        let conn = self.cruncher.conn.lock().unwrap();
+        self.cruncher.store_entries(&conn, data)
+    }
+
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        // Committing waits for readers to finish; if they're still busy, the transaction is
+        // still open, and the commit can be retried.
+        let result = self.cruncher.retry_busy(|| {
+            let conn = self.cruncher.conn.lock().unwrap();
+            conn.execute_batch("COMMIT")
+                .context("could not commit transaction")
+        });
+        // If the commit failed, the transaction is still open; let drop roll it back.
+        self.done = result.is_ok();
+        result
+    }
+
+    /// Record the outcome of processing an object from `source`, along with the entries stored
+    /// from it.
+    pub fn record_object(
+        &self,
+        source: Option<&str>,
+        name: &str,
+        status: &str,
+        detail: Option<&str>,
+        content_hash: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let conn = self.cruncher.conn.lock().unwrap();
+        record_object(&conn, source, name, status, detail, content_hash)
+    }
+
+    /// Record where in a journal the entries stored were read up to.
+    pub fn record_journal_cursor(&self, journal: &str, cursor: &str) -> anyhow::Result<()> {
+        let conn = self.cruncher.conn.lock().unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO journal_cursors (journal, cursor) VALUES (?1, ?2)
+            ON CONFLICT (journal) DO UPDATE SET cursor = ?2;
+            "#,
+            [journal, cursor],
+        )
+        .context("could not record journal cursor")?;
+        Ok(())
+    }
+
+    /// Whether the object from `source` has been stored already, in full or in part.
+    pub fn stored(&self, source: Option<&str>, name: &str) -> anyhow::Result<bool> {
+        let conn = self.cruncher.conn.lock().unwrap();
+        let stored = conn
+            .prepare_cached(
+                r#"
+            SELECT EXISTS(
+                SELECT 1 FROM processed_objects
+                WHERE source = :source AND name = :name AND status IN ('ok', 'partial')
+            );
+            "#,
+            )
+            .context("invalid query for stored objects")?
+            .query_row(
+                named_params! {":source": source.unwrap_or_default(), ":name": name},
+                |row| row.get(0),
+            )
+            .with_context(|| format!("could not check status of object {name}"))?;
+        Ok(stored)
+    }
+}
+
+/// Record the outcome of processing an object; the latest outcome wins.
+fn record_object(
+    conn: &Connection,
+    source: Option<&str>,
+    name: &str,
+    status: &str,
+    detail: Option<&str>,
+    content_hash: Option<&str>,
+) -> anyhow::Result<()> {
+    conn.prepare_cached(
+        r#"
+    INSERT INTO processed_objects (source, name, status, detail, processed_at, content_hash)
+    VALUES (:source, :name, :status, :detail, datetime('now'), :content_hash)
+    ON CONFLICT (source, name) DO
+    UPDATE SET
+        status = :status
+    ,   detail = :detail
+    ,   processed_at = datetime('now')
+    ,   content_hash = :content_hash;
+    "#,
+    )
+    .context("invalid query to record object")?
+    .execute(named_params! {
+        ":source": source.unwrap_or_default(),
+        ":name": name,
+        ":status": status,
+        ":detail": detail,
+        ":content_hash": content_hash,
+    })
+    .with_context(|| format!("could not record status of object {name}"))?;
+    Ok(())
+}
+
+impl Drop for BatchTransaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Err(e) = self.cruncher.conn.lock().unwrap().execute_batch("ROLLBACK") {
+                tracing::error!("could not roll back transaction: {}", e);
+            }
+        }
+    }
+}
+
 impl Cruncher {
     /// Create a new Cruncher, which collates log records into a database.
-    pub fn new(db: &Path) -> anyhow::Result<Self> {
+    ///
+    /// `sqlite` says how long to wait for other processes that hold the database.
+    pub fn open(db: &Path, sqlite: &Sqlite) -> anyhow::Result<Self> {
         let mut conn = Connection::open(db).context("could not open DB")?;
+        conn.busy_timeout(Duration::from_millis(sqlite.busy_timeout_ms))
+            .context("could not set busy timeout")?;
         {
-            let tx = conn.transaction().context("could not initialize DB")?;
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .context("could not initialize DB")?;
             tx.execute_batch(SCHEMA)
                 .context("could not initialize DB schema")?;
+            let version: usize = tx
+                .query_row("PRAGMA user_version", [], |row| row.get(0))
+                .context("could not get schema version")?;
+            for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                tx.execute_batch(migration)
+                    .with_context(|| format!("could not apply schema migration {}", i + 1))?;
+            }
+            tx.pragma_update(None, "user_version", MIGRATIONS.len())
+                .context("could not update schema version")?;
             tx.commit()?;
         }
 
         Ok(Self {
             conn: Mutex::new(conn),
+            limits: Limits::default(),
+            quotas: Quotas::default(),
+            aggregate_only: false,
+            busy_retry: sqlite.retry.clone(),
+        })
+    }
+
+    /// Use write-ahead logging, so readers (and replication tools like Litestream) don't block
+    /// writes or get blocked by them. This sticks with the database file.
+    pub fn use_wal(&self) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mode: String = self.retry_busy(|| {
+            conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+                .context("could not switch to WAL mode")
+        })?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            anyhow::bail!("could not switch to WAL mode: still in {mode} mode");
+        }
+        // Durable as of the last checkpoint, rather than the last commit; and much faster.
+        conn.pragma_update(None, "synchronous", "NORMAL")
+            .context("could not set synchronous mode")?;
+        Ok(())
+    }
+
+    /// Copy what's in the write-ahead log back into the database, as far as readers allow,
+    /// without waiting for them.
+    pub fn checkpoint(&self) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let (busy, log, checkpointed): (bool, i64, i64) = conn
+            .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .context("could not checkpoint")?;
+        tracing::debug!("checkpointed {checkpointed} of {log} WAL pages (busy: {busy})");
+        Ok(())
+    }
+
+    /// Copy the database to a new file at `path`, consistently, with SQLite's backup API.
+    pub fn snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("could not remove old snapshot {}", path.display()))?;
+        }
+        let conn = self.conn.lock().unwrap();
+        let mut dest = Connection::open(path)
+            .with_context(|| format!("could not create snapshot {}", path.display()))?;
+        let backup =
+            rusqlite::backup::Backup::new(&conn, &mut dest).context("could not start snapshot")?;
+        // Pause between steps, to let other processes write.
+        backup
+            .run_to_completion(1024, Duration::from_millis(10), None)
+            .context("could not copy database to snapshot")?;
+        Ok(())
+    }
+
+    /// Whether it's been at least `interval` since the last backup.
+    pub fn backup_due(&self, interval: Duration) -> anyhow::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM backups WHERE finished_at > datetime('now', ?))",
+            [format!("-{} seconds", interval.as_secs())],
+            |row| row.get(0),
+        )
+        .context("could not check for recent backups")
+    }
+
+    /// Record a completed backup.
+    pub fn record_backup(&self, object: &str, bytes: usize) -> anyhow::Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "INSERT INTO backups (finished_at, object, bytes) VALUES (datetime('now'), ?, ?)",
+                (object, bytes),
+            )
+            .context("could not record backup")?;
+            Ok(())
+        })
+    }
+
+    /// Normalize and cap dimension values as they're stored.
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Cruncher { limits, ..self }
+    }
+
+    /// Store only so many requests per hour from each client IP, AS, or country.
+    pub fn with_quotas(self, quotas: Quotas) -> Self {
+        Cruncher { quotas, ..self }
+    }
+
+    /// Store no requests, only count them in the daily rollups: for a far smaller database,
+    /// and faster crunches, where only the stats matter.
+    pub fn with_aggregate_only(self, aggregate_only: bool) -> Self {
+        Cruncher {
+            aggregate_only,
+            ..self
+        }
+    }
+
+    /// Store an entry, if it's within its quotas, with its dimensions capped.
+    fn store_entry(
+        &self,
+        conn: &Connection,
+        usage: &mut Usage,
+        entry: &LogEntry,
+    ) -> rusqlite::Result<()> {
+        if self.aggregate_only {
+            return self
+                .limits
+                .apply(conn, entry)
+                .and_then(|entry| entry.store_aggregates(conn));
+        }
+        if !self.quotas.admit(conn, usage, entry)? {
+            return Ok(());
+        }
+        self.limits
+            .apply(conn, entry)
+            .and_then(|entry| entry.store(conn))
+    }
+
+    /// Delete the requests from before `before`, keeping what the rollups have of them. The
+    /// rollups are kept up as requests are stored, so only the day's count of clients needs
+    /// saving first. Returns how many requests were deleted.
+    pub fn prune_requests(&self, before: NaiveDate) -> anyhow::Result<usize> {
+        let before = before.to_string();
+        self.write(|tx| {
+            // Requests that come in late, for a day already pruned, add their own clients.
+            tx.execute(
+                r#"
+                UPDATE daily_totals
+                SET clients = COALESCE(daily_totals.clients, 0) + pruned.clients
+                FROM (
+                    SELECT date(request_start_time) AS date, COUNT(DISTINCT client_ip) AS clients
+                    FROM requests
+                    WHERE request_start_time < :before
+                    GROUP BY 1
+                ) AS pruned
+                WHERE daily_totals.date = pruned.date;
+                "#,
+                named_params! {":before": before},
+            )
+            .context("could not save clients of pruned days")?;
+            tx.execute(
+                r#"
+                DELETE FROM request_tags
+                WHERE request IN (SELECT id FROM requests WHERE request_start_time < :before);
+                "#,
+                named_params! {":before": before},
+            )
+            .context("could not prune request tags")?;
+            // Hours are "%Y-%m-%dT%H", so those of days before come before.
+            tx.execute(
+                "DELETE FROM quota_usage WHERE hour < :before",
+                named_params! {":before": before},
+            )
+            .context("could not prune quota usage")?;
+            tx.execute(
+                "DELETE FROM requests WHERE request_start_time < :before",
+                named_params! {":before": before},
+            )
+            .context("could not prune requests")
+        })
+    }
+
+    /// How many requests are stored.
+    pub fn count_requests(&self) -> anyhow::Result<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))
+            .context("could not count requests")
+    }
+
+    /// Apply the tagging rules to up to `batch` requests after request `after`, in one
+    /// transaction; see tagging::reclassify. Returns None once there are no more requests.
+    pub fn reclassify_batch(
+        &self,
+        rules: &[TagRule],
+        after: i64,
+        batch: usize,
+    ) -> anyhow::Result<Option<ReclassifiedBatch>> {
+        let names: BTreeSet<&str> = rules.iter().map(|rule| rule.tag.as_str()).collect();
+        self.write(|tx| {
+            let requests: Vec<Stored> = tx
+                .prepare_cached(
+                    r#"
+                    SELECT requests.id, paths.path, user_agents.user_agent, referers.referer,
+                        requests.asn, CAST(requests.response_status AS INTEGER)
+                    FROM requests
+                        JOIN paths ON requests.url_path = paths.id
+                        LEFT JOIN user_agents ON requests.user_agent = user_agents.id
+                        LEFT JOIN referers ON requests.referer = referers.id
+                    WHERE requests.id > ?1
+                    ORDER BY requests.id
+                    LIMIT ?2
+                    "#,
+                )?
+                .query_map((after, batch), |row| {
+                    Ok(Stored {
+                        id: row.get(0)?,
+                        path: row.get(1)?,
+                        user_agent: row.get(2)?,
+                        referer: row.get(3)?,
+                        asn: row.get(4)?,
+                        status: row.get::<_, Option<usize>>(5)?.unwrap_or_default(),
+                    })
+                })?
+                .collect::<Result<_, _>>()
+                .context("could not read requests")?;
+            let (Some(first), Some(last)) = (requests.first(), requests.last()) else {
+                return Ok(None);
+            };
+            let mut tagged: HashSet<(i64, String)> = tx
+                .prepare_cached(
+                    r#"
+                    SELECT request_tags.request, tags.tag
+                    FROM request_tags JOIN tags ON request_tags.tag = tags.id
+                    WHERE request_tags.request BETWEEN ?1 AND ?2
+                    "#,
+                )?
+                .query_map((first.id, last.id), |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()
+                .context("could not read request tags")?;
+            tagged.retain(|(_, tag)| names.contains(tag.as_str()));
+            let mut done = ReclassifiedBatch {
+                last: last.id,
+                requests: requests.len(),
+                added: 0,
+                removed: 0,
+                kept: 0,
+            };
+            for stored in &requests {
+                let id = stored.id;
+                let request = Request {
+                    path: &stored.path,
+                    user_agent: stored.user_agent.as_deref(),
+                    referer: stored.referer.as_deref(),
+                    asn: stored.asn,
+                    status: stored.status,
+                };
+                // Rules looking at values that were rewritten or capped when stored saw other
+                // values then; whether they'd match now says nothing about whether they did.
+                let changed = self.limits.changed(&request);
+                let mut kept = false;
+                for name in &names {
+                    let (unsure, sure): (Vec<&TagRule>, Vec<&TagRule>) = rules
+                        .iter()
+                        .filter(|rule| rule.tag == *name)
+                        .partition(|rule| rule.looks_at(&changed));
+                    let matches = sure.iter().any(|rule| rule.matches_request(&request));
+                    let has = tagged.contains(&(id, name.to_string()));
+                    if !matches && !unsure.is_empty() {
+                        kept = true;
+                    } else if matches && !has {
+                        tx.prepare_cached(
+                            "INSERT INTO tags (tag) VALUES (?) ON CONFLICT DO NOTHING",
+                        )?
+                        .execute([name])?;
+                        tx.prepare_cached(
+                            r#"
+                            INSERT INTO request_tags (request, tag)
+                            VALUES (?1, (SELECT id FROM tags WHERE tag = ?2))
+                            "#,
+                        )?
+                        .execute((id, name))
+                        .context("could not tag request")?;
+                        done.added += 1;
+                    } else if has && !matches {
+                        tx.prepare_cached(
+                            r#"
+                            DELETE FROM request_tags
+                            WHERE request = ?1 AND tag = (SELECT id FROM tags WHERE tag = ?2)
+                            "#,
+                        )?
+                        .execute((id, name))
+                        .context("could not untag request")?;
+                        done.removed += 1;
+                    }
+                }
+                done.kept += usize::from(kept);
+            }
+            Ok(Some(done))
+        })
+    }
+
+    /// Run `f` until it succeeds or fails with something other than the database being busy
+    /// (or locked, by another connection), backing off between attempts.
+    fn retry_busy<T>(&self, f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        f.retry(&self.busy_retry.backoff())
+            .when(is_busy)
+            .notify(|err, delay| {
+                tracing::warn!("database is busy, retrying in {:?}: {:#}", delay, err)
+            })
+            .call()
+    }
+
+    /// Run `f` in a write transaction, and commit it, retrying the whole thing if the database
+    /// is busy.
+    ///
+    /// The transaction takes the write lock from the start, rather than on its first write,
+    /// so that it waits (up to the busy timeout) for other writers rather than failing outright.
+    fn write<T>(&self, mut f: impl FnMut(&Transaction) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        self.retry_busy(|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .context("could not begin transaction")?;
+            let result = f(&tx)?;
+            tx.commit().context("could not commit transaction")?;
+            Ok(result)
         })
     }
 
     /// Add the entries to the database.
     pub fn crunch(&self, data: &[LogEntry]) -> anyhow::Result<()> {
-        let mut conn = self.conn.lock().unwrap();
-        let tx = conn.transaction().context("could not begin transaction")?;
+        self.write(|tx| self.store_entries(tx, data))
+    }
+
+    fn store_entries(&self, conn: &Connection, data: &[LogEntry]) -> anyhow::Result<()> {
+        let mut usage = Usage::default();
         for (i, entry) in data.iter().enumerate() {
-            entry.store(&tx).with_context(|| format!("in entry {i}"))?;
+            self.store_entry(conn, &mut usage, entry)
+                .with_context(|| format!("in entry {i}"))?;
+        }
+        usage.save(conn).context("could not count quota usage")
+    }
+
+    /// Where the last run left off in a journal, if it's been read before.
+    pub fn journal_cursor(&self, journal: &str) -> anyhow::Result<Option<String>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT cursor FROM journal_cursors WHERE journal = ?",
+                [journal],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("could not get journal cursor")
+    }
+
+    /// Replace the list of the site's own hostnames.
+    ///
+    /// An empty list leaves the stored hosts alone, so runs without a config file
+    /// don't forget them.
+    pub fn set_site_hosts(&self, hosts: &[String]) -> anyhow::Result<()> {
+        if hosts.is_empty() {
+            return Ok(());
+        }
+        self.write(|tx| {
+            tx.execute("DELETE FROM site_hosts", [])?;
+            for host in hosts {
+                tx.execute(
+                    "INSERT INTO site_hosts (host) VALUES (lower(?)) ON CONFLICT DO NOTHING",
+                    [host],
+                )?;
+            }
+            Ok(())
+        })
+        .context("could not update site hosts")
+    }
+
+    /// Replace the segment definitions, as with set_site_hosts.
+    pub fn set_segments(&self, segments: &[Segment]) -> anyhow::Result<()> {
+        if segments.is_empty() {
+            return Ok(());
+        }
+        for segment in segments {
+            if !valid_segment_name(&segment.name) {
+                return Err(anyhow!("invalid segment name {:?}", segment.name));
+            }
+        }
+        self.write(|tx| {
+            tx.execute("DELETE FROM segments", [])?;
+            for segment in segments {
+                tx.execute(
+                    "INSERT INTO segments (name, filter) VALUES (?, ?)",
+                    [&segment.name, &segment.filter],
+                )
+                .with_context(|| format!("could not store segment {}", segment.name))?;
+            }
+            Ok(())
+        })
+        .context("could not update segments")
+    }
+
+    /// Keep requests unique by their dedup keys, or stop.
+    ///
+    /// The unique index is the setting: once it's made, every run keeps requests unique,
+    /// until it's dropped by asking to stop. Requests stored before keys were have none, so
+    /// they can be stored again.
+    pub fn set_unique_requests(&self, unique: bool) -> anyhow::Result<()> {
+        self.write(|tx| {
+            if unique {
+                tx.execute_batch(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS requests_by_dedup_key \
+                    ON requests(dedup_key)",
+                )
+                .context("could not make requests unique; is a request stored twice already?")
+            } else {
+                tx.execute_batch("DROP INDEX IF EXISTS requests_by_dedup_key")
+                    .context("could not drop unique index on requests")
+            }
+        })
+    }
+
+    /// Add, change, and drop computed columns to match the config.
+    ///
+    /// New and changed columns are filled in by the next compute_columns.
+    pub fn set_computed_columns(&self, columns: &[ComputedColumn]) -> anyhow::Result<()> {
+        for column in columns {
+            if !valid_column_name(&column.name) {
+                return Err(anyhow!("invalid computed column name {:?}", column.name));
+            }
+        }
+        self.write(|tx| {
+            tx.execute_batch(COMPUTED_INPUTS)
+                .context("could not create computed_inputs view")?;
+            let existing: HashMap<String, String> = tx
+                .prepare("SELECT name, expr FROM computed_columns")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let request_columns: HashSet<String> = tx
+                .prepare("SELECT name FROM pragma_table_info('requests')")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            for column in columns {
+                let name = &column.name;
+                tx.prepare(&format!(
+                    "SELECT ({}) FROM computed_inputs LIMIT 0",
+                    column.expr
+                ))
+                .with_context(|| format!("invalid expression for computed column {name}"))?;
+                match existing.get(name) {
+                    Some(expr) if *expr == column.expr => (),
+                    Some(_) => {
+                        tracing::info!("expression for {name} changed; recomputing it");
+                        tx.execute(
+                            "UPDATE computed_columns SET expr = ?, through = 0 WHERE name = ?",
+                            [&column.expr, name],
+                        )?;
+                    }
+                    None if request_columns.contains(name) => {
+                        return Err(anyhow!(
+                            "computed column {name} would replace a column of requests"
+                        ));
+                    }
+                    None => {
+                        tx.execute_batch(&format!(
+                            r#"
+                            ALTER TABLE requests ADD COLUMN "{name}" ANY;
+                            CREATE INDEX "requests_by_{name}" ON requests("{name}");
+                            "#
+                        ))
+                        .with_context(|| format!("could not add computed column {name}"))?;
+                        tx.execute(
+                            "INSERT INTO computed_columns (name, expr, through) VALUES (?, ?, 0)",
+                            [name, &column.expr],
+                        )?;
+                    }
+                }
+            }
+            for name in existing.keys() {
+                if columns.iter().any(|column| column.name == *name) {
+                    continue;
+                }
+                tracing::info!("computed column {name} is no longer configured; dropping it");
+                tx.execute_batch(&format!(
+                    r#"
+                    DROP INDEX IF EXISTS "requests_by_{name}";
+                    ALTER TABLE requests DROP COLUMN "{name}";
+                    "#
+                ))
+                .with_context(|| format!("could not drop computed column {name}"))?;
+                tx.execute("DELETE FROM computed_columns WHERE name = ?", [name])?;
+            }
+            Ok(())
+        })
+        .context("could not update computed columns")
+    }
+
+    /// Fill in computed columns for requests stored since they were last computed.
+    pub fn compute_columns(&self) -> anyhow::Result<()> {
+        self.write(|tx| {
+            tx.execute_batch(COMPUTED_INPUTS)
+                .context("could not create computed_inputs view")?;
+            let columns: Vec<(String, String, i64)> = tx
+                .prepare("SELECT name, expr, through FROM computed_columns")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<Result<_, _>>()?;
+            let last: i64 =
+                tx.query_row("SELECT COALESCE(MAX(id), 0) FROM requests", [], |row| {
+                    row.get(0)
+                })?;
+            for (name, expr, through) in columns {
+                let updated = tx
+                    .execute(
+                        &format!(
+                            r#"
+                            UPDATE requests SET "{name}" = computed.value
+                            FROM (
+                                SELECT id, ({expr}) AS value FROM computed_inputs
+                                WHERE id > ?1 AND id <= ?2
+                            ) AS computed
+                            WHERE requests.id = computed.id
+                            "#
+                        ),
+                        [through, last],
+                    )
+                    .with_context(|| format!("could not compute column {name}"))?;
+                tx.execute(
+                    "UPDATE computed_columns SET through = ? WHERE name = ?",
+                    rusqlite::params![last, name],
+                )?;
+                tracing::debug!("computed {name} for {updated} requests");
+            }
+            Ok(())
+        })
+    }
+
+    /// Start a transaction to store entries into, a batch at a time.
+    pub fn begin(&self) -> anyhow::Result<BatchTransaction<'_>> {
+        self.retry_busy(|| {
+            self.conn
+                .lock()
+                .unwrap()
+                .execute_batch("BEGIN IMMEDIATE")
+                .context("could not begin transaction")
+        })?;
+        Ok(BatchTransaction {
+            cruncher: self,
+            done: false,
+        })
+    }
+
+    /// Create the views from each pack.
+    pub fn install_packs(&self, packs: &[Pack]) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        for pack in packs {
+            if let Some(views) = &pack.views {
+                conn.execute_batch(views)
+                    .with_context(|| format!("could not create views from pack {}", &pack.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// What the computed columns, the packs' enrichments, and naming ASes would do, tried on
+    /// `sample` requests each and rolled back; see the preview module.
+    pub fn preview_enrichments(
+        &self,
+        columns: &[ComputedColumn],
+        packs: &[Pack],
+        name_asns: bool,
+        sample: usize,
+    ) -> anyhow::Result<Vec<Preview>> {
+        preview::preview(
+            &self.conn.lock().unwrap(),
+            columns,
+            packs,
+            name_asns,
+            sample,
+        )
+    }
+
+    /// Run the enrichments from each pack.
+    pub fn enrich(&self, packs: &[Pack]) -> anyhow::Result<()> {
+        for pack in packs {
+            if let Some(enrich) = &pack.enrich {
+                self.write(|tx| {
+                    tx.execute_batch(enrich).with_context(|| {
+                        format!("could not run enrichment from pack {}", &pack.name)
+                    })
+                })?;
+            }
         }
-        tx.commit().context("could not commit transaction")?;
         Ok(())
     }
 
-    /// Fill AS numbers in the database.
+    /// Record the start of a run, returning its ID.
+    pub fn start_run(&self) -> anyhow::Result<i64> {
+        self.write(|tx| {
+            tx.execute(
+                "INSERT INTO runs (started_at) VALUES (datetime('now'));",
+                [],
+            )
+            .context("could not record start of run")?;
+            Ok(tx.last_insert_rowid())
+        })
+    }
+
+    /// Record the end of a run, and snapshot the rollups as of its completion.
+    pub fn finish_run(
+        &self,
+        run_id: i64,
+        ok: usize,
+        errors: usize,
+        skipped: usize,
+    ) -> anyhow::Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                r#"
+                UPDATE runs
+                SET finished_at = datetime('now'), ok = :ok, errors = :errors, skipped = :skipped
+                WHERE id = :run_id;
+                "#,
+                named_params! {
+                    ":run_id": run_id,
+                    ":ok": ok,
+                    ":errors": errors,
+                    ":skipped": skipped,
+                },
+            )
+            .context("could not record end of run")?;
+            // Only paths whose totals changed since their last snapshot get a new one.
+            tx.execute(
+                r#"
+                INSERT INTO path_snapshots (run_id, url_path, requests, response_bytes)
+                SELECT :run_id, url_path, requests, response_bytes
+                FROM (
+                    SELECT url_path, SUM(requests) AS requests,
+                        SUM(response_bytes) AS response_bytes
+                    FROM daily_paths
+                    GROUP BY url_path
+                ) AS totals
+                WHERE (requests, response_bytes) IS NOT (
+                    SELECT requests, response_bytes FROM path_snapshots
+                    WHERE path_snapshots.url_path = totals.url_path
+                    ORDER BY run_id DESC
+                    LIMIT 1
+                );
+                "#,
+                named_params! {":run_id": run_id},
+            )
+            .context("could not snapshot path rollup")?;
+            Ok(())
+        })
+        .context("could not commit end of run")
+    }
+
+    /// Evaluate the service-level objectives over their windows, as of now.
+    pub fn evaluate_slos(&self, run_id: i64, slos: &[Slo]) -> anyhow::Result<()> {
+        self.write(|conn| {
+            for slo in slos {
+                conn.prepare_cached(
+                    r#"
+                INSERT INTO slo_results (
+                  run_id, name, window_start, window_end
+                , requests, fast_requests, good_requests
+                , latency_target, error_target
+                )
+                SELECT
+                  :run_id, :name, datetime('now', :window), datetime('now')
+                , COUNT(*)
+                , COALESCE(SUM(CAST(response_duration AS REAL) * 1000 <= :latency_ms), 0)
+                , COALESCE(SUM(CAST(response_status AS INTEGER) < 500), 0)
+                , :latency_target, :error_target
+                FROM requests
+                    JOIN paths ON requests.url_path = paths.id
+                WHERE paths.path LIKE :path
+                  AND requests.request_start_time >= datetime('now', :window);
+                "#,
+                )
+                .context("invalid query for SLO evaluation")?
+                .execute(named_params! {
+                    ":run_id": run_id,
+                    ":name": &slo.name,
+                    ":window": format!("-{} days", slo.window_days),
+                    ":latency_ms": slo.latency_ms,
+                    ":path": &slo.path,
+                    ":latency_target": slo.latency_target,
+                    ":error_target": slo.error_target,
+                })
+                .with_context(|| format!("could not evaluate SLO {}", &slo.name))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Record the outcome of processing an object.
+    ///
+    /// Objects may be seen more than once (e.g. if they were skipped or failed);
+    /// the latest outcome wins.
+    pub fn record_object(
+        &self,
+        source: Option<&str>,
+        name: &str,
+        status: &str,
+        detail: Option<&str>,
+        content_hash: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.write(|conn| record_object(conn, source, name, status, detail, content_hash))
+    }
+
+    /// Record that cleanup deleted an object from `source`, and the generation it can be
+    /// restored from.
+    pub fn record_deletion(
+        &self,
+        run_id: i64,
+        source: Option<&str>,
+        name: &str,
+        generation: &str,
+    ) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.prepare_cached(
+                r#"
+            INSERT INTO deleted_objects (run_id, source, name, generation, deleted_at)
+            VALUES (:run_id, :source, :name, :generation, datetime('now'));
+            "#,
+            )
+            .context("invalid query to record deletion")?
+            .execute(named_params! {
+                ":run_id": run_id,
+                ":source": source,
+                ":name": name,
+                ":generation": generation,
+            })
+            .with_context(|| format!("could not record deletion of object {name}"))?;
+            Ok(())
+        })
+    }
+
+    /// The objects a run deleted that haven't been restored yet.
+    pub fn deleted_objects(&self, run_id: i64) -> anyhow::Result<Vec<DeletedObject>> {
+        let conn = self.conn.lock().unwrap();
+        let deleted: Result<Vec<_>, _> = conn
+            .prepare(
+                r#"
+            SELECT source, name, generation FROM deleted_objects
+            WHERE run_id = ? AND restored_at IS NULL
+            ORDER BY source, name;
+            "#,
+            )
+            .context("invalid query for deleted objects")?
+            .query_map([run_id], |row| {
+                Ok(DeletedObject {
+                    source: row.get(0)?,
+                    name: row.get(1)?,
+                    generation: row.get(2)?,
+                })
+            })
+            .context("failed query for deleted objects")?
+            .collect();
+        deleted.with_context(|| format!("could not list objects deleted in run {run_id}"))
+    }
+
+    /// Record that a deleted object was put back.
+    pub fn record_restored(&self, run_id: i64, deleted: &DeletedObject) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                r#"
+                UPDATE deleted_objects SET restored_at = datetime('now')
+                WHERE run_id = :run_id AND source IS :source AND name = :name;
+                "#,
+                named_params! {
+                    ":run_id": run_id,
+                    ":source": deleted.source,
+                    ":name": deleted.name,
+                },
+            )
+            .with_context(|| format!("could not record restoring object {}", deleted.name))?;
+            Ok(())
+        })
+    }
+
+    /// Find another object with the same contents that has already been crunched, from any
+    /// source.
+    pub fn find_duplicate(
+        &self,
+        source: Option<&str>,
+        name: &str,
+        content_hash: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let original = conn
+            .prepare_cached(
+                r#"
+            SELECT name FROM processed_objects
+            WHERE content_hash = :content_hash
+              AND (source, name) != (:source, :name)
+              AND status IN ('ok', 'partial')
+            LIMIT 1;
+            "#,
+            )
+            .context("invalid query for duplicate objects")?
+            .query_row(
+                named_params! {
+                    ":source": source.unwrap_or_default(),
+                    ":name": name,
+                    ":content_hash": content_hash,
+                },
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(|| format!("could not check for duplicates of object {name}"))?;
+        Ok(original)
+    }
+
+    /// Fill in the names of ASes in the database, from PeeringDB.
     pub async fn asn_catchup(&self) -> anyhow::Result<()> {
         let asns: Vec<u32> = {
             let conn = self.conn.lock().unwrap();
+            // The snapshot's names first, so PeeringDB is only asked about the rest.
+            #[cfg(feature = "asn-seed")]
+            crate::seed::seed_asns(&conn)?;
             let asns: Result<Vec<u32>, _> = conn
                 .prepare("SELECT asn FROM autonomous_systems WHERE name IS NULL")
                 .context("incorrect query for unnamed ASNs")?
@@ -60,7 +1234,6 @@ impl Cruncher {
             let client = client.clone();
             asn_queries.spawn(async move { (asn, Self::peeringdb_asn_query(client, asn).await) });
         }
-        let mut unknown_asns: Vec<u32> = Default::default();
         while let Some(res) = asn_queries.join_next().await {
             let conn = self.conn.lock().unwrap();
             let (asn, result) = res.unwrap();
@@ -68,7 +1241,6 @@ impl Cruncher {
                 Ok(v) => v,
                 Err(err) => {
                     tracing::warn!("could not get results for ASN {asn} from PeeringDB: {err}");
-                    unknown_asns.push(asn);
                     continue;
                 }
             };
@@ -98,42 +1270,75 @@ impl Cruncher {
                 )
             })?;
         }
-        if unknown_asns.is_empty() {
+        Ok(())
+    }
+
+    /// Bring the Spamhaus DROP list up to date, if it's more than an hour old (Spamhaus asks
+    /// for no more), so requests stored after are flagged by it.
+    pub async fn sync_droplist(&self) -> anyhow::Result<()> {
+        let fresh: bool = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM droplists
+                    WHERE name = 'spamhaus' AND synced > datetime('now', '-1 hour'))",
+                [],
+                |row| row.get(0),
+            )
+            .context("could not check when the droplist was synced")?;
+        if fresh {
             return Ok(());
         }
-
-        // Compare all the remaining ones against Spamhaus.
-        let drop_list = Self::spamhaus_droplist(&client)
+        let listed = Self::spamhaus_droplist(&reqwest::Client::new())
             .await
-            .map_err(|err| anyhow!("could not get DROP list from Spamhaus: {err}"))?;
-        let conn = self.conn.lock().unwrap();
-        for asn in unknown_asns.iter() {
-            if let Some(name) = drop_list.get(asn) {
-                let exec = conn
-                    .prepare(
-                        r#"
-                INSERT INTO autonomous_systems (asn, name, droplist) VALUES (:asn, :name, :droplist)
-                ON CONFLICT (asn) DO
-                UPDATE SET name = :name , droplist = :droplist WHERE asn = :asn;
-                "#,
-                    )
-                    .and_then(|mut c| {
-                        c.execute(
-                            named_params! {":asn": asn, ":name": name, ":droplist": "spamhaus"},
-                        )
-                    })
-                    .map_err(|err| anyhow!("failed to insert of ASN entry with droplist: {err}"));
-                if let Err(err) = exec {
-                    tracing::error!("error: {err}");
-                }
-            }
-        }
+            .context("could not get DROP list from Spamhaus")?;
+        self.apply_droplist("spamhaus", &listed)
+    }
 
-        Ok(())
+    /// Mark the ASes on the droplist, and only them, as listed on it; the names it has are
+    /// used for ASes without one.
+    pub(crate) fn apply_droplist(
+        &self,
+        droplist: &str,
+        listed: &HashMap<u32, String>,
+    ) -> anyhow::Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "UPDATE autonomous_systems SET droplist = NULL WHERE droplist = ?1",
+                [droplist],
+            )
+            .context("could not clear droplist")?;
+            let mut insert = tx
+                .prepare(
+                    r#"
+                    INSERT INTO autonomous_systems (asn, name, droplist)
+                    VALUES (:asn, :name, :droplist)
+                    ON CONFLICT (asn) DO
+                    UPDATE SET name = COALESCE(name, :name), droplist = :droplist;
+                    "#,
+                )
+                .context("invalid query to list ASN")?;
+            for (asn, name) in listed {
+                insert
+                    .execute(named_params! {":asn": asn, ":name": name, ":droplist": droplist})
+                    .with_context(|| format!("could not list ASN {asn}"))?;
+            }
+            tx.execute(
+                "INSERT INTO droplists (name, synced) VALUES (?1, datetime('now'))
+                ON CONFLICT (name) DO UPDATE SET synced = excluded.synced",
+                [droplist],
+            )
+            .context("could not record droplist sync")?;
+            Ok(())
+        })
     }
 
     /// Queries PeeringDB for the name of an ASN.
-    async fn peeringdb_asn_query(client: Arc<reqwest::Client>, asn: u32) -> anyhow::Result<String> {
+    pub(crate) async fn peeringdb_asn_query(
+        client: Arc<reqwest::Client>,
+        asn: u32,
+    ) -> anyhow::Result<String> {
         // Response from PeeringDB's "list as-set by asn" API:
         // https://www.peeringdb.com/apidocs/
         #[derive(serde::Deserialize)]
@@ -170,7 +1375,9 @@ impl Cruncher {
     }
 
     /// Queries Spamhaus for the ASNs in the "don't route or peer" list.
-    async fn spamhaus_droplist(client: &reqwest::Client) -> anyhow::Result<HashMap<u32, String>> {
+    pub(crate) async fn spamhaus_droplist(
+        client: &reqwest::Client,
+    ) -> anyhow::Result<HashMap<u32, String>> {
         const DROPLIST_URL: &str = "https://www.spamhaus.org/drop/asndrop.json";
 
         // Response from PeeringDB's "list as-set by asn" API:
@@ -216,3 +1423,414 @@ impl Cruncher {
             })
     }
 }
+
+/// Whether the error is from another connection holding the database.
+fn is_busy(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<rusqlite::Error>()
+            .and_then(rusqlite::Error::sqlite_error_code)
+            .is_some_and(|code| matches!(code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Cruncher, MIGRATIONS};
+    use crate::{
+        config::{ComputedColumn, Sqlite},
+        dimensions::{Dimensions, Limits, OVERFLOW},
+        quota::Quotas,
+        testdata::entry,
+    };
+
+    #[test]
+    fn reopens_migrated_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cruncher.db");
+        for _ in 0..2 {
+            let cruncher = Cruncher::open(&path, &Sqlite::default()).unwrap();
+            let version: usize = cruncher
+                .conn
+                .lock()
+                .unwrap()
+                .query_row("PRAGMA user_version", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(version, MIGRATIONS.len());
+        }
+    }
+
+    #[test]
+    fn records_objects_by_source() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        cruncher
+            .record_object(Some("gs://a"), "log.gz", "ok", None, Some("hash"))
+            .unwrap();
+        cruncher
+            .record_object(Some("gs://b"), "log.gz", "error", None, None)
+            .unwrap();
+        let tx = cruncher.begin().unwrap();
+        assert!(tx.stored(Some("gs://a"), "log.gz").unwrap());
+        assert!(!tx.stored(Some("gs://b"), "log.gz").unwrap());
+        drop(tx);
+        // The same contents from another source are a duplicate, though.
+        assert_eq!(
+            cruncher
+                .find_duplicate(Some("gs://b"), "log.gz", "hash")
+                .unwrap(),
+            Some("log.gz".to_string())
+        );
+        assert_eq!(
+            cruncher
+                .find_duplicate(Some("gs://a"), "log.gz", "hash")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn caps_dimensions() {
+        let dimensions: Dimensions = toml::from_str(
+            r#"
+            [paths]
+            max_values = 2
+            normalize = [{ pattern = "^/api/item/[0-9]+$", replace = "/api/item/:id" }]
+
+            [hosts]
+            normalize = [
+                { pattern = "^[a-z0-9-]+\\.preview\\.example\\.com$", replace = "preview" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default())
+            .unwrap()
+            .with_limits(Limits::new(&dimensions).unwrap());
+        let entries: Vec<_> = ["/api/item/1", "/api/item/2", "/", "/about/", "/"]
+            .into_iter()
+            .map(|path| {
+                let mut entry = entry();
+                entry.url_path = path.to_string();
+                entry.host = Some(format!("pr-{}.preview.example.com", path.len()));
+                entry
+            })
+            .collect();
+        cruncher.crunch(&entries).unwrap();
+        let conn = cruncher.conn.lock().unwrap();
+        let paths: Vec<String> = conn
+            .prepare("SELECT path FROM paths ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(paths, vec!["/api/item/:id", "/", OVERFLOW]);
+        let overflowed: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM requests JOIN paths ON url_path = paths.id WHERE path = ?",
+                [OVERFLOW],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(overflowed, 1);
+        let hosts: Vec<String> = conn
+            .prepare("SELECT host FROM hosts")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(hosts, ["preview"]);
+    }
+
+    #[test]
+    fn stores_requests_once_by_dedup_key() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        cruncher.set_unique_requests(true).unwrap();
+        let mut other = entry();
+        other.response_status = 304;
+        cruncher.crunch(&[entry(), entry()]).unwrap();
+        cruncher.crunch(&[entry(), other]).unwrap();
+        let conn = cruncher.conn.lock().unwrap();
+        let count = |query: &str| -> usize { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM requests"), 2);
+        assert_eq!(count("SELECT SUM(requests) FROM daily_totals"), 2);
+        drop(conn);
+
+        // Without the index, it's stored again.
+        cruncher.set_unique_requests(false).unwrap();
+        cruncher.crunch(&[entry()]).unwrap();
+        assert!(cruncher.set_unique_requests(true).is_err());
+    }
+
+    #[test]
+    fn flags_droplisted_requests() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        // Before the droplist is synced, it's not known.
+        cruncher.crunch(&[entry()]).unwrap();
+        let listed = HashMap::from([(64496, "EXAMPLE".to_string())]);
+        cruncher.apply_droplist("spamhaus", &listed).unwrap();
+        let mut unknown = entry();
+        unknown.asn = None;
+        cruncher.crunch(&[entry(), unknown]).unwrap();
+        // Delisted.
+        cruncher
+            .apply_droplist("spamhaus", &HashMap::new())
+            .unwrap();
+        cruncher.crunch(&[entry()]).unwrap();
+        let conn = cruncher.conn.lock().unwrap();
+        let flags: Vec<Option<bool>> = conn
+            .prepare("SELECT droplisted FROM requests ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        // Each keeps the flag it was stored with.
+        assert_eq!(flags, [None, Some(true), None, Some(false)]);
+        let droplist: Option<String> = conn
+            .query_row(
+                "SELECT droplist FROM autonomous_systems WHERE asn = 64496",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(droplist, None);
+    }
+
+    #[test]
+    fn applies_quotas() {
+        let quotas: Quotas = toml::from_str("per_ip = 2\nper_asn = 3").unwrap();
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default())
+            .unwrap()
+            .with_quotas(quotas);
+        let entries: Vec<_> = [
+            "192.0.2.1",
+            "192.0.2.1",
+            "192.0.2.1",
+            "192.0.2.2",
+            "192.0.2.3",
+        ]
+        .into_iter()
+        .map(|ip| {
+            let mut entry = entry();
+            entry.client_ip = ip.parse().unwrap();
+            entry
+        })
+        .collect();
+        // Usage carries over from one batch to the next.
+        cruncher.crunch(&entries[..2]).unwrap();
+        cruncher.crunch(&entries[2..]).unwrap();
+        let conn = cruncher.conn.lock().unwrap();
+        let stored: usize = conn
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))
+            .unwrap();
+        // Two from the first IP, then one more from the AS.
+        assert_eq!(stored, 3);
+        let overflow: Vec<(String, String, usize)> = conn
+            .prepare(
+                "SELECT kind, key, overflow_requests FROM quota_usage \
+                WHERE overflow_requests > 0 ORDER BY kind, key",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let asn = entries[0].asn.unwrap().to_string();
+        assert_eq!(
+            overflow,
+            vec![
+                ("asn".to_string(), asn, 1),
+                ("ip".to_string(), "192.0.2.1".to_string(), 1)
+            ]
+        );
+        drop(conn);
+
+        // Hours go with the requests they counted.
+        let after = entries[0].request_start_time.time.date_naive() + chrono::Days::new(1);
+        cruncher.prune_requests(after).unwrap();
+        let hours: usize = cruncher
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM quota_usage", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(hours, 0);
+    }
+
+    #[test]
+    fn snapshots_changed_paths() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        let mut entries = [entry(), entry()];
+        entries[1].url_path = "/other".to_string();
+        let snapshots = |cruncher: &Cruncher| -> Vec<(i64, usize)> {
+            cruncher
+                .conn
+                .lock()
+                .unwrap()
+                .prepare("SELECT run_id, requests FROM path_snapshots ORDER BY run_id, requests")
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let mut runs = Vec::new();
+        for entries in [&entries[..], &[], &entries[..1]] {
+            let run = cruncher.start_run().unwrap();
+            cruncher.crunch(entries).unwrap();
+            cruncher.finish_run(run, 1, 0, 0).unwrap();
+            runs.push(run);
+        }
+        // Nothing for the run that changed nothing, and only the path that changed after.
+        assert_eq!(
+            snapshots(&cruncher),
+            [(runs[0], 1), (runs[0], 1), (runs[2], 2)]
+        );
+    }
+
+    #[test]
+    fn prunes_requests_into_rollups() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        let mut entries = vec![entry(), entry(), entry()];
+        entries[1].client_ip = "192.0.2.2".parse().unwrap();
+        entries[2].request_start_time.time += chrono::TimeDelta::days(1);
+        cruncher.crunch(&entries).unwrap();
+        let day = entries[2].request_start_time.time.date_naive();
+        assert_eq!(cruncher.prune_requests(day).unwrap(), 2);
+        assert_eq!(cruncher.prune_requests(day).unwrap(), 0);
+        let conn = cruncher.conn.lock().unwrap();
+        let count = |query: &str| -> usize { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM requests"), 1);
+        assert_eq!(count("SELECT SUM(requests) FROM daily_totals"), 3);
+        assert_eq!(count("SELECT SUM(clients) FROM daily_totals"), 2);
+    }
+
+    #[test]
+    fn aggregates_without_requests() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default())
+            .unwrap()
+            .with_aggregate_only(true);
+        let mut entries = vec![entry(), entry(), entry()];
+        entries[2].response_status = 404;
+        entries[2].country_code = None;
+        cruncher.crunch(&entries).unwrap();
+        let conn = cruncher.conn.lock().unwrap();
+        let count = |query: &str| -> usize { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM requests"), 0);
+        assert_eq!(count("SELECT SUM(requests) FROM daily_totals"), 3);
+        assert_eq!(count("SELECT SUM(requests) FROM daily_paths"), 3);
+        assert_eq!(
+            count("SELECT requests FROM daily_statuses WHERE status = 404"),
+            1
+        );
+        assert_eq!(
+            count("SELECT requests FROM daily_countries WHERE country = ''"),
+            1
+        );
+        assert_eq!(count("SELECT SUM(requests) FROM daily_referers"), 3);
+        assert_eq!(
+            count(
+                "SELECT response_bytes FROM minute_bandwidth WHERE minute = '2024-07-01 00:00:00'"
+            ),
+            3 * 1024
+        );
+    }
+
+    #[test]
+    fn computes_columns() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        let column = |expr: &str| ComputedColumn {
+            name: "is_feed".to_string(),
+            expr: expr.to_string(),
+        };
+        let is_feed = |cruncher: &Cruncher| -> Vec<i64> {
+            cruncher
+                .conn
+                .lock()
+                .unwrap()
+                .prepare("SELECT is_feed FROM requests ORDER BY id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let entries: Vec<_> = ["/feed.xml", "/"]
+            .into_iter()
+            .map(|path| {
+                let mut entry = entry();
+                entry.url_path = path.to_string();
+                entry
+            })
+            .collect();
+        cruncher.crunch(&entries).unwrap();
+
+        cruncher
+            .set_computed_columns(&[column("url_path LIKE '/feed%'")])
+            .unwrap();
+        cruncher.compute_columns().unwrap();
+        assert_eq!(is_feed(&cruncher), vec![1, 0]);
+        // Only new requests are computed...
+        cruncher.crunch(&entries[..1]).unwrap();
+        cruncher.compute_columns().unwrap();
+        assert_eq!(is_feed(&cruncher), vec![1, 0, 1]);
+        // ...unless the expression changes.
+        cruncher
+            .set_computed_columns(&[column("response_bytes > 1e6")])
+            .unwrap();
+        cruncher.compute_columns().unwrap();
+        assert_eq!(is_feed(&cruncher), vec![0, 0, 0]);
+
+        assert!(cruncher
+            .set_computed_columns(&[column("no_such_field")])
+            .is_err());
+        cruncher.set_computed_columns(&[]).unwrap();
+        let columns: usize = cruncher
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('requests') WHERE name = 'is_feed'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(columns, 0);
+    }
+
+    #[test]
+    fn retries_when_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("busy.db");
+        // Don't wait in SQLite, so the retries have to do the waiting.
+        let sqlite: Sqlite = toml::from_str(
+            r#"
+            busy_timeout_ms = 0
+            retry = { max_attempts = 20, min_delay_ms = 20, max_delay_ms = 50, jitter = false }
+            "#,
+        )
+        .unwrap();
+        let cruncher = Cruncher::open(&path, &sqlite).unwrap();
+        // Another process, holding the write lock for a bit.
+        let other = rusqlite::Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            other.execute_batch("COMMIT").unwrap();
+        });
+        cruncher.crunch(&[entry()]).unwrap();
+        holder.join().unwrap();
+        let count: usize = cruncher
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        drop(cruncher);
+    }
+}
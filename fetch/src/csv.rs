@@ -0,0 +1,131 @@
+//! Comma-separated values, per RFC 4180, read with the csv crate: fields may be quoted, with
+//! `""` for a quote, and quoted fields may span lines. Other delimiters (tabs) work the same way.
+
+use std::io::{self, BufRead, Read};
+
+use anyhow::anyhow;
+
+/// The records in a CSV file, each a list of its fields. Blank lines are skipped.
+pub(crate) struct Records<R> {
+    reader: csv::Reader<Tail<R>>,
+    record: csv::StringRecord,
+}
+
+impl<R: BufRead> Records<R> {
+    pub(crate) fn new(input: R) -> Self {
+        Self::with_delimiter_of(Tail::new(input, b','))
+    }
+
+    /// Separate fields with `delimiter`, rather than commas.
+    pub(crate) fn with_delimiter(self, delimiter: u8) -> Self {
+        let input = self.reader.into_inner().input;
+        Self::with_delimiter_of(Tail::new(input, delimiter))
+    }
+
+    fn with_delimiter_of(input: Tail<R>) -> Self {
+        Records {
+            reader: csv::ReaderBuilder::new()
+                .delimiter(input.delimiter)
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(input),
+            record: csv::StringRecord::new(),
+        }
+    }
+
+    /// Whether the last record read ended in a newline; one that didn't may have been cut off.
+    pub(crate) fn terminated(&self) -> bool {
+        // The input is read a buffer at a time, so only the last record can tell.
+        let input = self.reader.get_ref();
+        !input.ended || input.last.is_none_or(|b| b == b'\n')
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = anyhow::Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_record(&mut self.record) {
+            Err(e) => Some(Err(anyhow::Error::new(e).context("could not read CSV"))),
+            Ok(false) => None,
+            // The csv crate ends a quoted field left open where the input does.
+            Ok(true)
+                if self.reader.get_ref().ended && self.reader.get_ref().quote == Quote::Open =>
+            {
+                Some(Err(anyhow!("unterminated quoted field")))
+            }
+            Ok(true) => Some(Ok(self.record.iter().map(str::to_string).collect())),
+        }
+    }
+}
+
+/// Where the input is, as to quoted fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    /// At the start of a field, where a quote opens one.
+    Start,
+    /// In an unquoted field.
+    None,
+    Open,
+    /// Just after a quote in a quoted field: its end, or the first of `""`.
+    Closing,
+}
+
+/// Input, noting how it ends, to tell a record that was cut off.
+struct Tail<R> {
+    input: R,
+    delimiter: u8,
+    /// Whether all of it's been read.
+    ended: bool,
+    last: Option<u8>,
+    quote: Quote,
+}
+
+impl<R> Tail<R> {
+    fn new(input: R, delimiter: u8) -> Self {
+        Tail {
+            input,
+            delimiter,
+            ended: false,
+            last: None,
+            quote: Quote::Start,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Tail<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.ended |= n == 0 && !buf.is_empty();
+        for &b in &buf[..n] {
+            self.quote = match (self.quote, b) {
+                (Quote::Start, b'"') | (Quote::Closing, b'"') => Quote::Open,
+                (Quote::Open, b'"') => Quote::Closing,
+                (Quote::Open, _) => Quote::Open,
+                (_, b'\r' | b'\n') => Quote::Start,
+                (_, b) if b == self.delimiter => Quote::Start,
+                _ => Quote::None,
+            };
+        }
+        self.last = buf[..n].last().copied().or(self.last);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Records;
+
+    #[test]
+    fn parses_quoted_fields() {
+        let input = "a,\"b, \"\"c\"\"\",\r\n\n\"multi\nline\",\"\"\n";
+        let records: Vec<_> = Records::new(input.as_bytes())
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![vec!["a", "b, \"c\"", ""], vec!["multi\nline", ""],]
+        );
+        assert!(Records::new("\"open".as_bytes()).next().unwrap().is_err());
+    }
+}
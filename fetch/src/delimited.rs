@@ -0,0 +1,356 @@
+//! Logs as delimited text, CSV or TSV, with a header row naming the columns: as exported from
+//! BigQuery or a spreadsheet.
+//!
+//! Each field is read from the column of the same name (`client_ip`, `url_path`, ...), unless
+//! the `[fields]` section of the config names another:
+//!
+//! ```toml
+//! [fields]
+//! client_ip = "remote_addr"
+//! request_start_time = "timestamp"
+//! ```
+//!
+//! client_ip, request_start_time, url_path, and response_status are needed; the other fields
+//! are left NULL without a column. Times can be RFC 3339, BigQuery's
+//! `2024-06-01 12:30:00.123 UTC`, or Unix seconds; response_duration is in seconds.
+
+use std::{collections::BTreeMap, io::BufRead, net::IpAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use crate::{
+    csv::Records,
+    format::{EntryError, LogFormat},
+    record::{request_host, request_method, request_protocol, LogEntry},
+};
+
+/// The fields that can be read from columns.
+pub(crate) const FIELDS: &[&str] = &[
+    "client_ip",
+    "request_start_time",
+    "url_path",
+    "response_status",
+    "asn",
+    "country_code",
+    "requests",
+    "http2",
+    "referer",
+    "user_agent",
+    "cache_state",
+    "response_bytes",
+    "response_duration",
+    "request_method",
+    "request_protocol",
+    "host",
+    "service_id",
+    "query",
+];
+
+/// Delimited text, with columns mapped to fields by name.
+pub(crate) struct Delimited {
+    delimiter: u8,
+    /// Which column each field is in, where it isn't the field's own name.
+    fields: Arc<BTreeMap<String, String>>,
+}
+
+impl Delimited {
+    pub(crate) fn new(delimiter: u8, fields: &BTreeMap<String, String>) -> Self {
+        Delimited {
+            delimiter,
+            fields: Arc::new(fields.clone()),
+        }
+    }
+}
+
+impl LogFormat for Delimited {
+    fn parse<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Box<dyn Iterator<Item = Result<LogEntry, EntryError>> + 'a> {
+        Box::new(Entries {
+            records: Records::new(input).with_delimiter(self.delimiter),
+            fields: Arc::clone(&self.fields),
+            columns: None,
+            done: false,
+        })
+    }
+
+    /// Text whose header row has the delimiter in it (as far as `start` goes).
+    fn accepts_plain(&self, start: &[u8]) -> bool {
+        let header = start.split(|&b| b == b'\n').next().unwrap_or_default();
+        !start.contains(&0) && header.contains(&self.delimiter)
+    }
+}
+
+/// Where the fields are, per the header row.
+struct Columns {
+    /// How many columns there are.
+    count: usize,
+    client_ip: usize,
+    request_start_time: usize,
+    url_path: usize,
+    response_status: usize,
+    asn: Option<usize>,
+    country_code: Option<usize>,
+    requests: Option<usize>,
+    http2: Option<usize>,
+    referer: Option<usize>,
+    user_agent: Option<usize>,
+    cache_state: Option<usize>,
+    response_bytes: Option<usize>,
+    response_duration: Option<usize>,
+    request_method: Option<usize>,
+    request_protocol: Option<usize>,
+    host: Option<usize>,
+    service_id: Option<usize>,
+    query: Option<usize>,
+}
+
+impl Columns {
+    fn new(header: &[String], fields: &BTreeMap<String, String>) -> anyhow::Result<Self> {
+        let find = |field: &str| {
+            let column = fields.get(field).map_or(field, String::as_str);
+            // Spreadsheets may start the file with a byte-order mark.
+            header
+                .iter()
+                .position(|name| name.trim_start_matches('\u{feff}').trim() == column)
+        };
+        let require = |field: &str| {
+            find(field).ok_or_else(|| {
+                let column = fields.get(field).map_or(field, String::as_str);
+                anyhow!("no {column} column, for {field}")
+            })
+        };
+        Ok(Columns {
+            count: header.len(),
+            client_ip: require("client_ip")?,
+            request_start_time: require("request_start_time")?,
+            url_path: require("url_path")?,
+            response_status: require("response_status")?,
+            asn: find("asn"),
+            country_code: find("country_code"),
+            requests: find("requests"),
+            http2: find("http2"),
+            referer: find("referer"),
+            user_agent: find("user_agent"),
+            cache_state: find("cache_state"),
+            response_bytes: find("response_bytes"),
+            response_duration: find("response_duration"),
+            request_method: find("request_method"),
+            request_protocol: find("request_protocol"),
+            host: find("host"),
+            service_id: find("service_id"),
+            query: find("query"),
+        })
+    }
+
+    fn entry(&self, record: &[String]) -> anyhow::Result<LogEntry> {
+        if record.len() != self.count {
+            return Err(anyhow!(
+                "{} fields, where the header has {}",
+                record.len(),
+                self.count
+            ));
+        }
+        let field = |i: usize| record[i].trim();
+        // Optional fields are None without a column.
+        let optional = |i: Option<usize>| i.map(field);
+        let number = |i: Option<usize>, name: &str| -> anyhow::Result<Option<usize>> {
+            match optional(i) {
+                None | Some("") => Ok(None),
+                Some(n) => n
+                    .parse()
+                    .map(Some)
+                    .with_context(|| format!("invalid {name}")),
+            }
+        };
+
+        let client_ip: IpAddr = field(self.client_ip).parse().context("invalid client_ip")?;
+        let response_duration = match optional(self.response_duration) {
+            None | Some("") => None,
+            Some(seconds) => Some(
+                seconds
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .context("invalid response_duration")?,
+            ),
+        };
+        let asn = match optional(self.asn) {
+            None | Some("") => None,
+            Some(asn) => Some(
+                asn.trim_start_matches("AS")
+                    .parse()
+                    .context("invalid asn")?,
+            ),
+        };
+        Ok(LogEntry {
+            client_ip,
+            asn,
+            country_code: optional(self.country_code)
+                .filter(|country| !country.is_empty())
+                .map(str::to_string),
+            requests: number(self.requests, "requests")?.unwrap_or(1),
+            ipv6: client_ip.is_ipv6(),
+            http2: optional(self.http2)
+                .map(|http2| matches!(http2.to_ascii_lowercase().as_str(), "true" | "1" | "yes")),
+            url_path: field(self.url_path).to_string(),
+            query: optional(self.query)
+                .filter(|query| !query.is_empty())
+                .map(str::to_string),
+            referer: optional(self.referer).map(str::to_string),
+            user_agent: optional(self.user_agent).map(str::to_string),
+            cache_state: optional(self.cache_state).map(str::to_string),
+            response_status: field(self.response_status)
+                .parse()
+                .context("invalid response_status")?,
+            response_bytes: number(self.response_bytes, "response_bytes")?,
+            response_duration,
+            request_start_time: parse_time(field(self.request_start_time))
+                .context("invalid request_start_time")?
+                .into(),
+            language: None,
+            request_header_bytes: None,
+            request_body_bytes: None,
+            cache_age: None,
+            cache_ttl: None,
+            request_method: optional(self.request_method).and_then(request_method),
+            request_protocol: optional(self.request_protocol).and_then(request_protocol),
+            host: optional(self.host).and_then(request_host),
+            service_id: optional(self.service_id)
+                .filter(|service_id| !service_id.is_empty())
+                .map(str::to_string),
+            raw_path: None,
+            tags: Vec::new(),
+            source: None,
+        })
+    }
+}
+
+/// A time as RFC 3339, as BigQuery exports it, or as Unix seconds.
+fn parse_time(time: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.into());
+    }
+    let bigquery = time.strip_suffix(" UTC").unwrap_or(time);
+    if let Ok(time) = NaiveDateTime::parse_from_str(bigquery, "%Y-%m-%d %H:%M:%S%.f") {
+        return Ok(time.and_utc());
+    }
+    let seconds: i64 = time
+        .parse()
+        .with_context(|| format!("unknown format {time:?}"))?;
+    DateTime::from_timestamp(seconds, 0).context("timestamp out of range")
+}
+
+/// The entries in a log file.
+struct Entries<R> {
+    records: Records<R>,
+    fields: Arc<BTreeMap<String, String>>,
+    columns: Option<Columns>,
+    done: bool,
+}
+
+impl<R: BufRead> Entries<R> {
+    fn next_entry(&mut self) -> Option<Result<LogEntry, EntryError>> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            // A read error, or a quoted field left open at the end: where a truncated file ends.
+            Err(e) => return Some(Err(EntryError::Ended(e))),
+        };
+        let Some(columns) = &self.columns else {
+            match Columns::new(&record, &self.fields) {
+                Ok(columns) => self.columns = Some(columns),
+                Err(e) => {
+                    // Without the columns, no entry can be read.
+                    self.done = true;
+                    return Some(Err(EntryError::Invalid(e.context("invalid header"))));
+                }
+            }
+            return self.next_entry();
+        };
+        // A last record that's short of fields was cut off.
+        if !self.records.terminated() && record.len() < columns.count {
+            let e = anyhow!(
+                "entry cut off after {} of {} fields",
+                record.len(),
+                columns.count
+            );
+            return Some(Err(EntryError::Ended(e)));
+        }
+        Some(columns.entry(&record).map_err(EntryError::Invalid))
+    }
+}
+
+impl<R: BufRead> Iterator for Entries<R> {
+    type Item = Result<LogEntry, EntryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_entry();
+        // Invalid entries can be skipped; there's nothing after the end.
+        self.done |= matches!(next, None | Some(Err(EntryError::Ended(_))));
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::Delimited;
+    use crate::format::{EntryError, LogFormat};
+
+    #[test]
+    fn maps_columns_by_header() {
+        let csv = "\u{feff}timestamp,remote_addr,url_path,response_status,\
+            user_agent,response_duration\n\
+            2024-06-01 12:30:00.5 UTC,192.0.2.1,/writing/a/,200,\
+            \"Mozilla/5.0 (X11, Linux)\",0.012\n\
+            2024-06-01T12:30:01Z,2001:db8::1,/,404,,\n";
+        let fields = BTreeMap::from([
+            ("client_ip".to_string(), "remote_addr".to_string()),
+            ("request_start_time".to_string(), "timestamp".to_string()),
+        ]);
+        let format = Delimited::new(b',', &fields);
+        let entries: Vec<_> = format
+            .parse(Box::new(csv.as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].user_agent.as_deref(),
+            Some("Mozilla/5.0 (X11, Linux)")
+        );
+        assert_eq!(
+            entries[0].response_duration,
+            Some(Duration::from_millis(12))
+        );
+        assert_eq!(
+            entries[0].request_start_time.time.to_rfc3339(),
+            "2024-06-01T12:30:00.500+00:00"
+        );
+        assert!(entries[1].ipv6);
+        assert_eq!(entries[1].response_status, 404);
+
+        // Cut partway through the last line.
+        let cut = &csv[..csv.len() - 8];
+        let mut entries = format.parse(Box::new(cut.as_bytes()));
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(entries.next(), Some(Err(EntryError::Ended(_)))));
+
+        // Without the mapping, there's no client_ip column.
+        let mut entries = Delimited::new(b',', &BTreeMap::new()).parse(Box::new(csv.as_bytes()));
+        assert!(matches!(entries.next(), Some(Err(EntryError::Invalid(_)))));
+
+        let tsv = "client_ip\trequest_start_time\turl_path\tresponse_status\n\
+            192.0.2.1\t1717245000\t/\t200\n";
+        let entries: Vec<_> = Delimited::new(b'\t', &BTreeMap::new())
+            .parse(Box::new(tsv.as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries[0].url_path, "/");
+    }
+}
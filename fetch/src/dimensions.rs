@@ -0,0 +1,419 @@
+//! Bounds on the dimension tables: paths, referers, user agents, and hosts.
+//!
+//! Values with IDs or tokens in them make a new row for nearly every request. Rewrite rules
+//! collapse them into patterns, e.g. `/api/item/12345` into `/api/item/:id`; and once a table
+//! is at its cap, values it hasn't seen before are stored as OVERFLOW.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use anyhow::Context;
+use regex_lite::Regex;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Deserialize;
+
+use crate::{record::LogEntry, tagging::Request};
+
+/// What values past a dimension's cap are stored as.
+pub const OVERFLOW: &str = "(other)";
+
+/// Limits on each dimension, from the `[dimensions]` section of the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dimensions {
+    #[serde(default)]
+    pub paths: Dimension,
+    #[serde(default)]
+    pub referers: Dimension,
+    #[serde(default)]
+    pub user_agents: Dimension,
+    #[serde(default)]
+    pub hosts: Dimension,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dimension {
+    /// How many distinct values to keep before bucketing new ones into OVERFLOW.
+    pub max_values: Option<u64>,
+    /// Rewrites to apply to each value; the first rule that matches applies.
+    #[serde(default)]
+    pub normalize: Vec<Rewrite>,
+}
+
+/// A regex, and its replacement; the replacement can refer to groups as `$1` or `${name}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rewrite {
+    pub pattern: String,
+    pub replace: String,
+}
+
+/// Dimensions, ready to apply.
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    paths: Limit,
+    referers: Limit,
+    user_agents: Limit,
+    hosts: Limit,
+}
+
+#[derive(Debug, Default)]
+struct Limit {
+    max_values: Option<u64>,
+    rewrites: Vec<(Regex, String)>,
+    /// What each rewrite can write in a value; see replacement_pattern.
+    outputs: Vec<Regex>,
+}
+
+/// Which of a stored request's values may not be as they were before it was stored: rewritten,
+/// or stored as OVERFLOW.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Changed {
+    pub path: bool,
+    pub user_agent: bool,
+    pub referer: bool,
+}
+
+impl Limit {
+    fn new(dimension: &Dimension) -> anyhow::Result<Self> {
+        let rewrites = dimension
+            .normalize
+            .iter()
+            .map(|rewrite| {
+                Regex::new(&rewrite.pattern)
+                    .with_context(|| format!("invalid pattern {}", rewrite.pattern))
+                    .map(|re| (re, rewrite.replace.clone()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let outputs = dimension
+            .normalize
+            .iter()
+            .map(|rewrite| replacement_pattern(&rewrite.replace))
+            .collect();
+        Ok(Limit {
+            max_values: dimension.max_values,
+            rewrites,
+            outputs,
+        })
+    }
+
+    /// Whether a stored value may not be the value as it came in. Rewritten values can't be
+    /// told from others for sure, so this takes any value a rewrite could have written as
+    /// rewritten.
+    fn may_have_changed(&self, stored: &str) -> bool {
+        stored == OVERFLOW || self.outputs.iter().any(|re| re.is_match(stored))
+    }
+
+    /// The value to store in place of `value`, if not `value` itself.
+    ///
+    /// `table` and `column` are trusted: they name the dimension's table.
+    fn apply(
+        &self,
+        conn: &Connection,
+        table: &str,
+        column: &str,
+        value: &str,
+    ) -> rusqlite::Result<Option<String>> {
+        let rewritten = self
+            .rewrites
+            .iter()
+            .find(|(re, _)| re.is_match(value))
+            .map(|(re, replace)| re.replace(value, replace.as_str()).into_owned());
+        let Some(max_values) = self.max_values else {
+            return Ok(rewritten);
+        };
+        let value = rewritten.as_deref().unwrap_or(value);
+        // Rows aren't deleted from dimension tables, so the largest ID is the row count.
+        let count: Option<u64> = conn
+            .prepare_cached(&format!("SELECT max(id) FROM {table}"))?
+            .query_row([], |row| row.get(0))?;
+        if count.unwrap_or(0) < max_values {
+            return Ok(rewritten);
+        }
+        let known = conn
+            .prepare_cached(&format!("SELECT 1 FROM {table} WHERE {column} = ?"))?
+            .query_row([value], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if known {
+            Ok(rewritten)
+        } else {
+            Ok(Some(OVERFLOW.to_string()))
+        }
+    }
+}
+
+impl Limits {
+    pub fn new(dimensions: &Dimensions) -> anyhow::Result<Self> {
+        Ok(Limits {
+            paths: Limit::new(&dimensions.paths).context("in paths")?,
+            referers: Limit::new(&dimensions.referers).context("in referers")?,
+            user_agents: Limit::new(&dimensions.user_agents).context("in user_agents")?,
+            hosts: Limit::new(&dimensions.hosts).context("in hosts")?,
+        })
+    }
+
+    /// Which of a stored request's values may have been rewritten or capped when it was stored.
+    pub(crate) fn changed(&self, request: &Request) -> Changed {
+        Changed {
+            path: self.paths.may_have_changed(request.path),
+            user_agent: request
+                .user_agent
+                .is_some_and(|user_agent| self.user_agents.may_have_changed(user_agent)),
+            referer: request
+                .referer
+                .is_some_and(|referer| self.referers.may_have_changed(referer)),
+        }
+    }
+
+    /// The entry with its dimensions normalized and capped; copied only if that changes it.
+    pub fn apply<'a>(
+        &self,
+        conn: &Connection,
+        entry: &'a LogEntry,
+    ) -> rusqlite::Result<Cow<'a, LogEntry>> {
+        let path = self.paths.apply(conn, "paths", "path", &entry.url_path)?;
+        let referer = match &entry.referer {
+            Some(referer) => self.referers.apply(conn, "referers", "referer", referer)?,
+            None => None,
+        };
+        let user_agent = match &entry.user_agent {
+            Some(user_agent) => {
+                self.user_agents
+                    .apply(conn, "user_agents", "user_agent", user_agent)?
+            }
+            None => None,
+        };
+        let host = match &entry.host {
+            Some(host) => self.hosts.apply(conn, "hosts", "host", host)?,
+            None => None,
+        };
+        if path.is_none() && referer.is_none() && user_agent.is_none() && host.is_none() {
+            return Ok(Cow::Borrowed(entry));
+        }
+        let mut entry = entry.clone();
+        if let Some(path) = path {
+            entry.url_path = path;
+        }
+        if let Some(referer) = referer {
+            entry.referer = Some(referer);
+        }
+        if let Some(user_agent) = user_agent {
+            entry.user_agent = Some(user_agent);
+        }
+        if let Some(host) = host {
+            entry.host = Some(host);
+        }
+        Ok(Cow::Owned(entry))
+    }
+}
+
+/// A regex for what a rewrite's `replace` writes in place of a match: its text, with anything
+/// at all for each group it refers to (`$1`, `$name`, `${name}`; `$$` is a `$`).
+fn replacement_pattern(replace: &str) -> Regex {
+    let mut pattern = String::new();
+    let mut rest = replace;
+    while let Some(i) = rest.find('$') {
+        pattern.push_str(&regex_lite::escape(&rest[..i]));
+        rest = &rest[i + 1..];
+        let braced = rest
+            .strip_prefix('{')
+            .and_then(|group| group.find('}').map(|end| &group[end + 1..]));
+        let named = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if let Some(after) = braced {
+            pattern.push_str("(?s:.*)");
+            rest = after;
+        } else if named > 0 {
+            pattern.push_str("(?s:.*)");
+            rest = &rest[named..];
+        } else {
+            // `$$`, or a `$` that doesn't refer to a group, is written as it is.
+            pattern.push_str(r"\$");
+            rest = rest.strip_prefix('$').unwrap_or(rest);
+        }
+    }
+    pattern.push_str(&regex_lite::escape(rest));
+    Regex::new(&pattern).expect("replacement pattern should be valid")
+}
+
+/// A suggested rewrite for a cluster of similar paths.
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub rewrite: Rewrite,
+    /// How many distinct paths it collapses.
+    pub paths: usize,
+    pub example: String,
+}
+
+/// Segment shapes that are almost always IDs, and what to call them.
+const SEGMENT_KINDS: &[(&str, &str)] = &[
+    ("[0-9]+", ":id"),
+    (
+        "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+        ":uuid",
+    ),
+    ("[0-9a-fA-F]{16,}", ":hash"),
+];
+
+/// A path segment in a template: the segment itself, or a placeholder and its pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Segment {
+    Fixed(String),
+    Variable {
+        name: &'static str,
+        pattern: &'static str,
+    },
+}
+
+/// Suggest rewrites for paths that vary only in an ID-like segment, for `[dimensions.paths]`.
+///
+/// Segments shaped like numbers, UUIDs, or hashes are placeholders wherever they appear.
+/// Other segments become a placeholder if at least `min_distinct` paths differ only there.
+/// Only templates covering at least `min_distinct` paths are suggested, most paths first.
+pub fn suggest_rewrites<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    min_distinct: usize,
+) -> Vec<Suggestion> {
+    let kinds: Vec<(Regex, &str, &str)> = SEGMENT_KINDS
+        .iter()
+        .map(|(pattern, name)| {
+            let re = Regex::new(&format!("^{pattern}$")).expect("invalid segment pattern");
+            (re, *pattern, *name)
+        })
+        .collect();
+    let mut templates: Vec<(Vec<Segment>, &str)> = paths
+        .into_iter()
+        .filter(|path| *path != OVERFLOW)
+        .map(|path| {
+            let segments = path
+                .split('/')
+                .map(|segment| {
+                    kinds
+                        .iter()
+                        .find(|(re, _, _)| re.is_match(segment))
+                        .map(|(_, pattern, name)| Segment::Variable { name, pattern })
+                        .unwrap_or_else(|| Segment::Fixed(segment.to_string()))
+                })
+                .collect();
+            (segments, path)
+        })
+        .collect();
+
+    // Fan-out: a position where many paths differ, and nothing else does.
+    let max_len = templates.iter().map(|(t, _)| t.len()).max().unwrap_or(0);
+    for position in 1..max_len {
+        let mut siblings: BTreeMap<Vec<Segment>, BTreeSet<String>> = BTreeMap::new();
+        for (template, _) in &templates {
+            if let Some(Segment::Fixed(segment)) = template.get(position) {
+                if segment.is_empty() {
+                    continue;
+                }
+                let mut key = template.clone();
+                key[position] = Segment::Fixed(String::new());
+                siblings.entry(key).or_default().insert(segment.clone());
+            }
+        }
+        for (template, _) in &mut templates {
+            let Some(Segment::Fixed(segment)) = template.get(position) else {
+                continue;
+            };
+            if segment.is_empty() {
+                continue;
+            }
+            let mut key = template.clone();
+            key[position] = Segment::Fixed(String::new());
+            if siblings[&key].len() >= min_distinct {
+                template[position] = Segment::Variable {
+                    name: ":param",
+                    pattern: "[^/]+",
+                };
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<Vec<Segment>, (usize, &str)> = BTreeMap::new();
+    for (template, path) in templates {
+        clusters.entry(template).or_insert((0, path)).0 += 1;
+    }
+    let mut suggestions: Vec<Suggestion> = clusters
+        .into_iter()
+        .filter(|(template, (paths, _))| {
+            *paths >= min_distinct
+                && template
+                    .iter()
+                    .any(|s| matches!(s, Segment::Variable { .. }))
+        })
+        .map(|(template, (paths, example))| {
+            let mut pattern = Vec::new();
+            let mut replace = Vec::new();
+            for segment in &template {
+                match segment {
+                    Segment::Fixed(s) => {
+                        pattern.push(regex_lite::escape(s));
+                        replace.push(s.replace('$', "$$"));
+                    }
+                    Segment::Variable { name, pattern: p } => {
+                        pattern.push(p.to_string());
+                        replace.push(name.to_string());
+                    }
+                }
+            }
+            Suggestion {
+                rewrite: Rewrite {
+                    pattern: format!("^{}$", pattern.join("/")),
+                    replace: replace.join("/"),
+                },
+                paths,
+                example: example.to_string(),
+            }
+        })
+        .collect();
+    suggestions.sort_by_key(|s| std::cmp::Reverse(s.paths));
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replacement_pattern, suggest_rewrites};
+
+    #[test]
+    fn matches_what_rewrites_write() {
+        let re = replacement_pattern("/api/item/:id");
+        assert!(re.is_match("/v2/api/item/:id"));
+        assert!(!re.is_match("/api/item/12"));
+        let re = replacement_pattern("${section}/:post$$");
+        assert!(re.is_match("/blog/:post$"));
+        assert!(!re.is_match("/blog/:post"));
+        assert!(replacement_pattern("$1").is_match("anything"));
+    }
+
+    #[test]
+    fn suggests_rewrites_for_id_segments() {
+        let mut paths: Vec<String> = (0..5).map(|i| format!("/api/item/{i}")).collect();
+        paths.extend((0..5).map(|i| format!("/users/user{i}/profile")));
+        paths.extend(["/".to_string(), "/about/".to_string()]);
+        let suggestions = suggest_rewrites(paths.iter().map(String::as_str), 3);
+        let rewrites: Vec<_> = suggestions
+            .iter()
+            .map(|s| {
+                (
+                    s.rewrite.pattern.as_str(),
+                    s.rewrite.replace.as_str(),
+                    s.paths,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rewrites,
+            vec![
+                ("^/api/item/[0-9]+$", "/api/item/:id", 5),
+                ("^/users/[^/]+/profile$", "/users/:param/profile", 5),
+            ]
+        );
+    }
+}
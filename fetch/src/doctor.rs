@@ -0,0 +1,156 @@
+//! A checklist of what a run depends on, to go through before starting a long one: the
+//! stores' credentials, the APIs that name autonomous systems, the database, and the FD limit.
+//!
+//! Checks only look; they don't change the database or the stores.
+
+use std::{fmt, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context};
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+
+use crate::cruncher::{Cruncher, SCHEMA_VERSION};
+
+/// How long to wait on PeeringDB and Spamhaus.
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// File descriptors kept back from fetches, for the database, logs, and such; as crunch_gcs
+/// does in setting its concurrency.
+const FD_HEADROOM: u64 = 100;
+
+/// An AS to look up in PeeringDB: Cloudflare's, which isn't going anywhere.
+const KNOWN_ASN: u32 = 13335;
+
+/// One item on the checklist.
+pub struct Check {
+    /// What was checked.
+    pub name: String,
+    /// What was found, or what's wrong.
+    pub result: anyhow::Result<String>,
+}
+
+impl Check {
+    pub(crate) fn new(name: impl Into<String>, result: anyhow::Result<String>) -> Self {
+        Check {
+            name: name.into(),
+            result,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(found) => write!(f, "[ ok ] {}: {}", self.name, found),
+            Err(e) => write!(f, "[FAIL] {}: {:#}", self.name, e),
+        }
+    }
+}
+
+/// Whether PeeringDB answers for a well-known AS.
+pub(crate) async fn check_peeringdb() -> anyhow::Result<String> {
+    let client = reqwest::Client::builder()
+        .timeout(API_TIMEOUT)
+        .build()
+        .context("could not set up HTTP client")?;
+    let name = Cruncher::peeringdb_asn_query(Arc::new(client), KNOWN_ASN).await?;
+    Ok(format!("AS{KNOWN_ASN} is {name}"))
+}
+
+/// Whether Spamhaus's DROP list can be downloaded.
+pub(crate) async fn check_spamhaus() -> anyhow::Result<String> {
+    let client = reqwest::Client::builder()
+        .timeout(API_TIMEOUT)
+        .build()
+        .context("could not set up HTTP client")?;
+    let droplist = Cruncher::spamhaus_droplist(&client).await?;
+    Ok(format!("{} ASes on the DROP list", droplist.len()))
+}
+
+/// Whether the database can be written, and whether this build knows its schema.
+pub(crate) fn check_database(path: &Path) -> anyhow::Result<String> {
+    if !path.exists() {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let probe = dir.join(format!(".log-cruncher-doctor-{}", std::process::id()));
+        std::fs::write(&probe, b"")
+            .and_then(|()| std::fs::remove_file(&probe))
+            .with_context(|| format!("could not write to {}", dir.display()))?;
+        return Ok(format!(
+            "doesn't exist yet; it can be created in {}",
+            dir.display()
+        ));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        .context("could not open DB")?;
+    // SQLite falls back to read-only, rather than failing, if it can't write the file.
+    if conn
+        .is_readonly(DatabaseName::Main)
+        .context("could not open DB")?
+    {
+        bail!("{} is read-only", path.display());
+    }
+    // Take the write lock, and give it back without writing anything.
+    conn.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+        .context("could not lock DB for writing; is another process holding it?")?;
+    let version: usize = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .context("could not get schema version")?;
+    match version.cmp(&SCHEMA_VERSION) {
+        std::cmp::Ordering::Less => Ok(format!(
+            "writable, at schema version {version}; the next run migrates it to {SCHEMA_VERSION}"
+        )),
+        std::cmp::Ordering::Equal => Ok(format!("writable, at schema version {version}")),
+        std::cmp::Ordering::Greater => bail!(
+            "schema version {version} is newer than this build knows ({SCHEMA_VERSION}); \
+            upgrade before crunching into it"
+        ),
+    }
+}
+
+/// Whether the FD limit leaves room for `concurrency` fetches at once.
+pub(crate) fn check_fd_limit(concurrency: usize) -> anyhow::Result<String> {
+    let (soft, hard) = nix::sys::resource::getrlimit(nix::sys::resource::Resource::RLIMIT_NOFILE)
+        .context("could not query FD limit")?;
+    let room = soft.saturating_sub(FD_HEADROOM);
+    if room < concurrency as u64 {
+        bail!(
+            "the soft limit of {soft} only leaves room for {room} of {concurrency} fetches at \
+            once; raise it with `ulimit -n` (up to the hard limit, {hard})"
+        );
+    }
+    Ok(format!(
+        "the soft limit of {soft} (hard {hard}) leaves room for {concurrency} fetches at once"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_database;
+    use crate::{cruncher::Cruncher, Sqlite};
+
+    #[test]
+    fn checks_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("doctor.db");
+        let found = check_database(&path).unwrap();
+        assert!(found.starts_with("doesn't exist yet"), "{found}");
+        // Checking doesn't create it.
+        assert!(!path.exists());
+
+        drop(Cruncher::open(&path, &Sqlite::default()).unwrap());
+        let found = check_database(&path).unwrap();
+        assert!(found.starts_with("writable, at schema version"), "{found}");
+
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", 1000)
+            .unwrap();
+        assert!(check_database(&path).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Query plans for report queries, for tuning large databases: which tables a query reads in
+//! full, which indexes it uses, and indexes that might spare it the full reads.
+//!
+//! Suggestions are a guess, from the columns a query (and the views it reads) filters, joins,
+//! groups, or sorts on; check a suggested index with another `--explain` before keeping it.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Context;
+use regex_lite::Regex;
+use rusqlite::Connection;
+
+/// Tables with fewer rows than this are cheap enough to read in full.
+const MIN_ROWS: usize = 10_000;
+
+/// The keywords that start the clauses whose columns an index could help with.
+const INDEXED_CLAUSES: &[&str] = &["where", "on", "by", "having"];
+
+/// The keywords that end them.
+const OTHER_CLAUSES: &[&str] = &[
+    "select",
+    "from",
+    "join",
+    "limit",
+    "union",
+    "except",
+    "intersect",
+    "window",
+    "as",
+];
+
+/// How SQLite plans to run a query.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    /// Steps of the plan, as `EXPLAIN QUERY PLAN` has them: with the id of the step each is
+    /// under, or 0.
+    pub steps: Vec<(i64, i64, String)>,
+    /// Tables read in full.
+    pub scans: Vec<String>,
+    /// Indexes used, including those SQLite builds for the query alone ("automatic").
+    pub indexes: Vec<String>,
+    /// What SQLite sorts in temporary B-trees: GROUP BY, ORDER BY, DISTINCT.
+    pub sorts: Vec<String>,
+    /// Indexes that might help, as SQL.
+    pub suggestions: Vec<String>,
+}
+
+/// Plan `sql`, a single SELECT statement, without running it.
+pub fn explain(conn: &Connection, sql: &str) -> anyhow::Result<Plan> {
+    let sql = sql.trim().trim_end_matches(';');
+    let steps: Vec<(i64, i64, String)> = conn
+        .prepare(&format!("EXPLAIN QUERY PLAN {sql}"))
+        .context("invalid query")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(3)?)))?
+        .collect::<Result<_, _>>()
+        .context("could not plan query")?;
+    let schema = Schema::load(conn)?;
+    let text = schema.with_views(sql);
+    let clause_words = clause_words(&text);
+
+    let mut plan = Plan {
+        steps,
+        ..Plan::default()
+    };
+    let mut suggestions = BTreeSet::new();
+    for (_, _, detail) in &plan.steps {
+        if let Some(rest) = detail.strip_prefix("SCAN ") {
+            let name = rest.split(' ').next().unwrap_or_default();
+            if let Some((_, index)) = rest.split_once(" INDEX ") {
+                plan.indexes.push(index.to_string());
+                continue;
+            }
+            let Some(table) = schema.resolve(name, &text) else {
+                continue;
+            };
+            plan.scans.push(table.to_string());
+            if schema.rows(conn, table)? < MIN_ROWS {
+                continue;
+            }
+            for column in &schema.columns[table] {
+                if clause_words.contains(&column.to_ascii_lowercase())
+                    && !schema
+                        .indexed
+                        .contains(&(table.to_string(), column.clone()))
+                {
+                    suggestions.insert(index_sql(table, column));
+                }
+            }
+        } else if let Some(rest) = detail.strip_prefix("SEARCH ") {
+            let Some((name, using)) = rest.split_once(" USING ") else {
+                continue;
+            };
+            let (index, constraints) = using.split_once(" (").unwrap_or((using, ""));
+            let index = index
+                .strip_prefix("COVERING ")
+                .unwrap_or(index)
+                .trim_start_matches("INDEX ");
+            if !plan.indexes.iter().any(|i| i == index) {
+                plan.indexes.push(index.to_string());
+            }
+            // An index SQLite builds for each run of the query could be built once instead.
+            if index.starts_with("AUTOMATIC") {
+                let Some(table) = schema.resolve(name, &text) else {
+                    continue;
+                };
+                for constraint in constraints.trim_end_matches(')').split(" AND ") {
+                    let column = constraint
+                        .split(['=', '<', '>', ' '])
+                        .next()
+                        .unwrap_or_default();
+                    if schema.columns[table].iter().any(|c| c == column) {
+                        suggestions.insert(index_sql(table, column));
+                    }
+                }
+            }
+        } else if let Some((_, sort)) = detail.split_once("TEMP B-TREE FOR ") {
+            plan.sorts.push(sort.to_string());
+        }
+    }
+    plan.suggestions = suggestions.into_iter().collect();
+    Ok(plan)
+}
+
+fn index_sql(table: &str, column: &str) -> String {
+    format!(r#"CREATE INDEX "{table}_by_{column}" ON "{table}"("{column}");"#)
+}
+
+impl Plan {
+    /// Render the plan as a tree, like the sqlite3 shell does, and what it comes to.
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("QUERY PLAN\n");
+        self.render_steps(0, "", &mut out);
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "none".to_string()
+            } else {
+                items.join(", ")
+            }
+        };
+        out.push_str(&format!("\nfull scans: {}\n", list(&self.scans)));
+        out.push_str(&format!("indexes used: {}\n", list(&self.indexes)));
+        out.push_str(&format!("temporary b-trees: {}\n", list(&self.sorts)));
+        if self.suggestions.is_empty() {
+            out.push_str("no indexes to suggest\n");
+        } else {
+            out.push_str("indexes that might help:\n");
+            for suggestion in &self.suggestions {
+                out.push_str(&format!("  {suggestion}\n"));
+            }
+        }
+        out
+    }
+
+    fn render_steps(&self, parent: i64, indent: &str, out: &mut String) {
+        let children: Vec<_> = self.steps.iter().filter(|(_, p, _)| *p == parent).collect();
+        for (i, (id, _, detail)) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            out.push_str(&format!(
+                "{indent}{}{detail}\n",
+                if last { "`--" } else { "|--" }
+            ));
+            let indent = format!("{indent}{}", if last { "   " } else { "|  " });
+            self.render_steps(*id, &indent, out);
+        }
+    }
+}
+
+/// What's in the database, as far as planning goes.
+struct Schema {
+    /// Columns of each table.
+    columns: BTreeMap<String, Vec<String>>,
+    /// SQL of each view.
+    views: BTreeMap<String, String>,
+    /// Columns that lead an index, by table.
+    indexed: BTreeSet<(String, String)>,
+}
+
+impl Schema {
+    fn load(conn: &Connection) -> anyhow::Result<Self> {
+        let mut columns: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut indexed = BTreeSet::new();
+        let mut stmt = conn.prepare(
+            "SELECT m.name, p.name, p.pk FROM sqlite_schema AS m, pragma_table_info(m.name) AS p
+            WHERE m.type = 'table'",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (table, column, pk) = row.context("could not read table columns")?;
+            // Primary keys are indexes of their own.
+            if pk == 1 {
+                indexed.insert((table.clone(), column.clone()));
+            }
+            columns.entry(table).or_default().push(column);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT m.name, i.name FROM sqlite_schema AS m, pragma_index_list(m.name) AS l,
+                pragma_index_info(l.name) AS i
+            WHERE m.type = 'table' AND i.seqno = 0",
+        )?;
+        for row in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            indexed.insert(row.context("could not read indexes")?);
+        }
+        let views = conn
+            .prepare(
+                "SELECT name, sql FROM sqlite_schema WHERE type = 'view'
+                UNION ALL SELECT name, sql FROM sqlite_temp_schema WHERE type = 'view'",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()
+            .context("could not read views")?;
+        Ok(Schema {
+            columns,
+            views,
+            indexed,
+        })
+    }
+
+    /// The query, and the SQL of every view it reads, directly or through other views.
+    fn with_views(&self, sql: &str) -> String {
+        let mut text = sql.to_string();
+        let mut seen = BTreeSet::new();
+        loop {
+            let words = words(&text);
+            let more: Vec<_> = self
+                .views
+                .iter()
+                .filter(|(name, _)| words.contains(&name.to_ascii_lowercase()))
+                .filter(|(name, _)| seen.insert(name.to_string()))
+                .collect();
+            if more.is_empty() {
+                return text;
+            }
+            for (_, view) in more {
+                text.push('\n');
+                text.push_str(view);
+            }
+        }
+    }
+
+    /// The table `name` in a plan is: itself, or the table it's an alias for in `text`.
+    fn resolve<'a>(&'a self, name: &str, text: &str) -> Option<&'a str> {
+        if let Some((table, _)) = self.columns.get_key_value(name) {
+            return Some(table);
+        }
+        let words: Vec<String> = text.split_whitespace().map(|w| w.to_string()).collect();
+        words.iter().enumerate().find_map(|(i, word)| {
+            let alias = match words.get(i + 1) {
+                Some(next) if next.eq_ignore_ascii_case("as") => words.get(i + 2)?,
+                next => next?,
+            };
+            let alias = alias.trim_end_matches([',', ')']);
+            (alias == name)
+                .then(|| self.columns.get_key_value(word.as_str()))
+                .flatten()
+                .map(|(table, _)| table.as_str())
+        })
+    }
+
+    /// How many rows `table` has, up to MIN_ROWS.
+    fn rows(&self, conn: &Connection, table: &str) -> anyhow::Result<usize> {
+        conn.query_row(
+            &format!(r#"SELECT COUNT(*) FROM (SELECT 1 FROM "{table}" LIMIT {MIN_ROWS})"#),
+            [],
+            |row| row.get(0),
+        )
+        .with_context(|| format!("could not count rows of {table}"))
+    }
+}
+
+/// The identifiers in `text`, lowercased.
+fn words(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// The identifiers in the WHERE, ON, GROUP BY, ORDER BY, and HAVING clauses of `text`, and
+/// the columns they're aliases for, as views rename columns.
+fn clause_words(text: &str) -> BTreeSet<String> {
+    let mut words = clause_identifiers(text);
+    let alias = Regex::new(r"(?i)([a-z_][a-z0-9_]*)\s+as\s+([a-z_][a-z0-9_]*)").unwrap();
+    for captures in alias.captures_iter(text) {
+        if words.contains(&captures[2].to_ascii_lowercase()) {
+            words.insert(captures[1].to_ascii_lowercase());
+        }
+    }
+    words
+}
+
+fn clause_identifiers(text: &str) -> BTreeSet<String> {
+    let mut words = BTreeSet::new();
+    let mut in_clause = false;
+    for word in text
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+    {
+        if INDEXED_CLAUSES.contains(&word.as_str()) {
+            in_clause = true;
+        } else if OTHER_CLAUSES.contains(&word.as_str()) {
+            in_clause = false;
+        } else if in_clause {
+            words.insert(word);
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{explain, MIN_ROWS};
+
+    #[test]
+    fn suggests_indexes_for_full_scans() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE hits (id INTEGER PRIMARY KEY, status INTEGER, path TEXT);
+            INSERT INTO hits (status, path)
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {MIN_ROWS})
+            SELECT i % 5 * 100, '/' || i FROM n;
+            CREATE VIEW errors AS SELECT * FROM hits AS h WHERE h.status >= 500;"
+        ))
+        .unwrap();
+        let query = "SELECT path, COUNT(*) FROM errors GROUP BY path;";
+        let plan = explain(&conn, query).unwrap();
+        assert_eq!(plan.scans, vec!["hits"]);
+        assert_eq!(plan.sorts, vec!["GROUP BY"]);
+        assert_eq!(
+            plan.suggestions,
+            vec![
+                r#"CREATE INDEX "hits_by_path" ON "hits"("path");"#,
+                r#"CREATE INDEX "hits_by_status" ON "hits"("status");"#,
+            ]
+        );
+        assert!(plan.to_text().contains("`--"));
+
+        conn.execute_batch(&plan.suggestions[1]).unwrap();
+        let plan = explain(&conn, query).unwrap();
+        assert!(plan.scans.is_empty());
+        assert_eq!(plan.indexes, vec!["hits_by_status"]);
+    }
+}
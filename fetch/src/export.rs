@@ -0,0 +1,92 @@
+//! Exports of the database for other uses.
+
+use std::path::Path;
+
+use anyhow::Context;
+use rusqlite::Connection;
+
+const PUBLIC: &str = include_str!("public.sql");
+
+/// Write a database of daily rollups to `out`, for publishing alongside a site: no addresses,
+/// user agents, full referers, or individual requests.
+///
+/// Paths, countries, and referer hosts seen fewer than `min_requests` times in a day are left
+/// out, so that rarely-visited (maybe private) paths and one-off referers don't show up.
+/// If `out` exists, it's replaced once the new export is complete.
+pub fn export_public_db(db: &Path, out: &Path, min_requests: u64) -> anyhow::Result<()> {
+    let conn = Connection::open(db).context("could not open DB")?;
+    let partial = out.with_file_name(format!(
+        "{}.partial",
+        out.file_name()
+            .context("no file name for export")?
+            .to_string_lossy()
+    ));
+    if partial.exists() {
+        std::fs::remove_file(&partial)
+            .with_context(|| format!("could not remove old {}", partial.display()))?;
+    }
+    let partial_name = partial
+        .to_str()
+        .with_context(|| format!("non-UTF-8 path {}", partial.display()))?;
+    conn.execute("ATTACH DATABASE ? AS public", [partial_name])
+        .context("could not create export")?;
+    conn.execute(
+        "CREATE TEMP TABLE export_params AS SELECT ? AS min_requests",
+        [min_requests],
+    )
+    .context("could not set export parameters")?;
+    conn.execute_batch(PUBLIC)
+        .context("could not write rollups to export")?;
+    conn.execute_batch("DETACH DATABASE public")
+        .context("could not finish export")?;
+    std::fs::rename(&partial, out)
+        .with_context(|| format!("could not move export into place at {}", out.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::export_public_db;
+    use crate::{config::Sqlite, cruncher::Cruncher, testdata::entry};
+
+    #[test]
+    fn exports_rollups_without_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("logs.db");
+        let cruncher = Cruncher::open(&db, &Sqlite::default()).unwrap();
+        let mut entries = vec![entry(), entry(), entry()];
+        entries[2].url_path = "/secret-draft/".to_string();
+        cruncher.crunch(&entries).unwrap();
+        drop(cruncher);
+
+        let out = dir.path().join("public.db");
+        export_public_db(&db, &out, 2).unwrap();
+        let public = rusqlite::Connection::open(&out).unwrap();
+        let (requests, clients): (usize, usize) = public
+            .query_row(
+                "SELECT SUM(requests), SUM(clients) FROM daily_totals",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((requests, clients), (3, 1));
+        // The path seen only once is left out.
+        let paths: Vec<String> = public
+            .prepare("SELECT path FROM daily_paths")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(paths, vec![entry().url_path]);
+        let columns: usize = public
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_schema, pragma_table_info(sqlite_schema.name)
+                WHERE sqlite_schema.type = 'table' AND pragma_table_info.name LIKE '%ip%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(columns, 0);
+    }
+}
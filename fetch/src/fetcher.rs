@@ -1,47 +1,472 @@
 //! Fetcher for log entries from backing storage.
 //!
 
-use crate::{record::LogEntry, LogSet};
-use std::sync::Arc;
+use crate::{
+    concurrency::{ByteBudget, Concurrency, Slot},
+    decompress,
+    filter::{Compression, EmptyObjects, ObjectFilter, CONTENTS_CHECK_LEN, PLAIN_CHECK_LEN},
+    format::{FastlyJson, LogFormat},
+    listing::ListingCache,
+    parse_object,
+    record::LogEntry,
+    retry::{Retry, RetryingSource},
+    source::{ByteStream, LogSource, ObjectInfo, ObjectStream, OpendalSource, UrlListSource},
+    Batch, LogSet, MaxInvalid,
+};
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use anyhow::Context;
-use opendal::{layers::TracingLayer, Operator};
-use tokio::sync::mpsc::Sender;
+use anyhow::{anyhow, bail, Context};
+use bytes::{Bytes, BytesMut};
+use md5::{Digest, Md5};
+use tokio::sync::{
+    mpsc::{Sender, UnboundedReceiver},
+    OwnedSemaphorePermit,
+};
 use tokio_stream::StreamExt;
 
 /// Fetches log chunks from a backing store.
 pub struct Fetcher {
-    operator: opendal::Operator,
+    source: Box<dyn LogSource>,
     cleanup: bool,
+    filter: ObjectFilter,
+    empty_objects: EmptyObjects,
+    salvage_truncated: bool,
+    skip_invalid: bool,
+    max_invalid: MaxInvalid,
+    max_objects: Option<usize>,
+    max_bytes: Option<u64>,
+    archive: Option<Archive>,
+    dead_letter: Option<String>,
+    verify: bool,
+    oldest_first: bool,
+    concurrency: Option<Arc<Concurrency>>,
+    inflight_bytes: Option<ByteBudget>,
+    listing_cache: Option<ListingCache>,
+    transcoding: bool,
+    ranges: Option<Ranges>,
+    format: Arc<dyn LogFormat>,
+    latch: Option<DeletionLatch>,
 }
 
-impl<T> LogSet<T> {
+/// Once anything in a run goes wrong, stops every deletion for the rest of it: cleanup,
+/// archiving, dead letters, and empty objects. Shared between the run's fetchers.
+#[derive(Clone, Default)]
+pub struct DeletionLatch(Arc<AtomicBool>);
+
+impl DeletionLatch {
+    fn trip(&self, err: &anyhow::Error) {
+        if !self.0.swap(true, Ordering::SeqCst) {
+            tracing::warn!("deleting nothing more in this run, after an error: {err:#}");
+        }
+    }
+
+    fn tripped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// How to download large objects in parts.
+struct Ranges {
+    chunk_size: u64,
+    parallelism: usize,
+}
+
+/// Where to move objects once they're processed, rather than only deleting them.
+pub struct Archive {
+    /// Prepended to each object's name.
+    prefix: String,
+    /// A separate store to move them to; if None, they're copied within the source.
+    store: Option<OpendalSource>,
+    /// Recompress them with zstd at this level.
+    zstd_level: Option<i32>,
+}
+
+impl Archive {
+    pub fn new(prefix: String, store: Option<OpendalSource>) -> anyhow::Result<Self> {
+        if store.is_none() && prefix.is_empty() {
+            return Err(anyhow!(
+                "archiving within the source needs a prefix to archive to"
+            ));
+        }
+        Ok(Archive {
+            prefix,
+            store,
+            zstd_level: None,
+        })
+    }
+
+    /// Recompress archived objects with zstd at `level` (1 to 22; 19 is a good trade for
+    /// logs kept a long time), and archive them as `.zst`.
+    pub fn with_zstd(self, level: Option<i32>) -> Self {
+        Archive {
+            zstd_level: level,
+            ..self
+        }
+    }
+
+    /// Whether this object is one we archived, rather than one to process.
+    fn contains(&self, name: &str) -> bool {
+        self.store.is_none() && name.starts_with(&self.prefix)
+    }
+}
+
+/// Recompress an object with zstd as it's read. Gzip, zstd, and bzip2 are undone first, so
+/// the archive has the one layer of compression.
+fn recompress(input: impl Read, output: impl Write, level: i32) -> anyhow::Result<()> {
+    let mut input = decompress(input).context("could not read object")?;
+    let mut encoder =
+        zstd::stream::write::Encoder::new(output, level).context("could not start zstd")?;
+    io::copy(&mut input, &mut encoder).context("could not recompress object")?;
+    encoder
+        .finish()
+        .and_then(|mut output| output.flush())
+        .context("could not finish recompressing object")
+}
+
+/// The name of an object once it's recompressed with zstd.
+fn zstd_name(name: &str) -> String {
+    format!("{}.zst", name.strip_suffix(".gz").unwrap_or(name))
+}
+
+/// An object fetched from the backing store.
+pub enum Object {
+    /// A set of log entries.
+    Logs(LogSet),
+    /// An object that isn't a log file, and why we think so.
+    Skipped {
+        /// The source it was fetched from, in runs over several.
+        source: Option<String>,
+        name: String,
+        reason: String,
+    },
+}
+
+impl Object {
+    /// Pass a log set's entries through `stage`; see LogSet::map_entries.
+    pub fn map_entries(
+        self,
+        stage: impl FnMut(Vec<LogEntry>) -> anyhow::Result<Vec<LogEntry>> + Send + Sync + 'static,
+    ) -> Self {
+        match self {
+            Object::Logs(log_set) => Object::Logs(log_set.map_entries(stage)),
+            skipped => skipped,
+        }
+    }
+}
+
+impl LogSet {
     /// Mark this set of logs as processed, successfully or unsuccessfully.
     ///
-    /// Returns the original error and/or an error in cleanup.
-    pub async fn complete(self, status: anyhow::Result<()>) -> anyhow::Result<()> {
+    /// Returns the original error and/or an error in cleanup; or, if the object was deleted
+    /// from a store that keeps deleted objects, the generation to restore it from.
+    pub async fn complete(self, status: anyhow::Result<()>) -> anyhow::Result<Option<String>> {
         if status.is_ok() {
             // Clean up the object from storage.
-            return self
-                .source
+            let deleted = self
+                .fetcher
                 .delete_object(&self.name)
                 .await
                 .context("failed to delete object: ");
+            if let Err(e) = &deleted {
+                self.fetcher.failed(e);
+            }
+            return deleted;
         }
-        // Don't clean it up.
-        status.with_context(|| format!("in handling object {}: ", &self.name))
+        // Don't clean it up, but maybe move it out of the way.
+        let err = status.unwrap_err();
+        self.fetcher.failed(&err);
+        if let Err(e) = self.fetcher.dead_letter(&self.name, &err).await {
+            tracing::error!("could not move {} to dead letters: {:#}", &self.name, e);
+        }
+        Err(err).with_context(|| format!("in handling object {}: ", &self.name))
     }
 }
 
 impl Fetcher {
-    /// Create a new fetcher from GCS buckets.
+    /// Create a new fetcher from an arbitrary source.
     ///
     /// Cleanup indicates whether successfully logged objects should be deleted from storage.
-    pub fn new_gcs(bucket: &str, cleanup: bool) -> anyhow::Result<Self> {
-        let mut builder = opendal::services::Gcs::default();
-        builder.bucket(bucket);
-        let operator = Operator::new(builder)?.layer(TracingLayer).finish();
-        Ok(Fetcher { operator, cleanup })
+    /// Objects rejected by the filter are neither parsed nor deleted.
+    pub fn new(source: impl LogSource + 'static, cleanup: bool, filter: ObjectFilter) -> Self {
+        Fetcher {
+            source: Box::new(source),
+            cleanup,
+            filter,
+            empty_objects: EmptyObjects::default(),
+            salvage_truncated: false,
+            skip_invalid: false,
+            max_invalid: MaxInvalid::default(),
+            max_objects: None,
+            max_bytes: None,
+            archive: None,
+            dead_letter: None,
+            verify: false,
+            oldest_first: false,
+            concurrency: None,
+            inflight_bytes: None,
+            listing_cache: None,
+            transcoding: false,
+            ranges: None,
+            format: Arc::new(FastlyJson),
+            latch: None,
+        }
+    }
+
+    /// Create a new fetcher from GCS buckets.
+    ///
+    /// Credentials come from the service account key at `credentials`, if given;
+    /// otherwise from the defaults.
+    pub fn new_gcs(
+        bucket: &str,
+        credentials: Option<&Path>,
+        scope: Option<&str>,
+        endpoint: Option<&str>,
+        cleanup: bool,
+        filter: ObjectFilter,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            OpendalSource::gcs(bucket, credentials, scope, endpoint)?,
+            cleanup,
+            filter,
+        ))
+    }
+
+    /// Create a new fetcher from S3 buckets, on AWS or an S3-compatible store.
+    ///
+    /// See [OpendalSource::s3] for how credentials, region, and addressing are chosen.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_s3(
+        bucket: &str,
+        region: Option<&str>,
+        endpoint: Option<&str>,
+        virtual_host_style: bool,
+        access_key_id: Option<&str>,
+        secret_key_file: Option<&Path>,
+        cleanup: bool,
+        filter: ObjectFilter,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            OpendalSource::s3(
+                bucket,
+                region,
+                endpoint,
+                virtual_host_style,
+                access_key_id,
+                secret_key_file,
+            )?,
+            cleanup,
+            filter,
+        ))
+    }
+
+    /// Create a new fetcher from a directory on an SFTP server.
+    ///
+    /// Authentication goes through OpenSSH: the key, if given, otherwise the agent and
+    /// the user's SSH configuration.
+    pub fn new_sftp(
+        host: &str,
+        user: Option<&str>,
+        root: &str,
+        key: Option<&Path>,
+        known_hosts: Option<&str>,
+        cleanup: bool,
+        filter: ObjectFilter,
+    ) -> anyhow::Result<Self> {
+        let source = OpendalSource::sftp(host, user, root, key, known_hosts)?;
+        Ok(Self::new(source, cleanup, filter))
+    }
+
+    /// Create a new fetcher from a local directory, including its subdirectories.
+    pub fn new_fs(dir: &Path, cleanup: bool, filter: ObjectFilter) -> anyhow::Result<Self> {
+        Ok(Self::new(OpendalSource::fs(dir)?, cleanup, filter))
+    }
+
+    /// Create a new fetcher from the URLs listed in a manifest file.
+    pub fn new_urls(manifest: &Path, cleanup: bool, filter: ObjectFilter) -> anyhow::Result<Self> {
+        Ok(Self::new(UrlListSource::load(manifest)?, cleanup, filter))
+    }
+
+    /// Set the handling of empty objects.
+    pub fn with_empty_objects(self, empty_objects: EmptyObjects) -> Self {
+        Fetcher {
+            empty_objects,
+            ..self
+        }
+    }
+
+    /// Set whether to keep the complete entries from truncated objects.
+    pub fn with_salvage_truncated(self, salvage_truncated: bool) -> Self {
+        Fetcher {
+            salvage_truncated,
+            ..self
+        }
+    }
+
+    /// Set whether to skip invalid entries, rather than failing their objects; and how many
+    /// an object may have before it fails anyway.
+    pub fn with_skip_invalid(self, skip_invalid: bool, max_invalid: MaxInvalid) -> Self {
+        Fetcher {
+            skip_invalid,
+            max_invalid,
+            ..self
+        }
+    }
+
+    /// Limit how much a run fetches: at most `max_objects` objects, and stop starting new
+    /// fetches once `max_bytes` have been listed for fetching.
+    ///
+    /// With cleanup, the objects left over are picked up by the next run.
+    pub fn with_budget(self, max_objects: Option<usize>, max_bytes: Option<u64>) -> Self {
+        Fetcher {
+            max_objects,
+            max_bytes,
+            ..self
+        }
+    }
+
+    /// With cleanup, move processed objects to the archive before deleting them.
+    pub fn with_archive(self, archive: Archive) -> Self {
+        Fetcher {
+            archive: Some(archive),
+            ..self
+        }
+    }
+
+    /// With cleanup, move objects that fail to process under this prefix, with the error
+    /// alongside in `<name>.error`, so that later runs don't keep retrying them.
+    pub fn with_dead_letter(self, prefix: String) -> Self {
+        Fetcher {
+            dead_letter: Some(prefix),
+            ..self
+        }
+    }
+
+    /// Check each object's size and MD5 against the listing, and fail objects that don't match,
+    /// rather than crunching (and cleaning up) a short or corrupted download.
+    pub fn with_verify(self, verify: bool) -> Self {
+        Fetcher { verify, ..self }
+    }
+
+    /// Take uncompressed objects to be ones the store decompresses as it sends them, as GCS
+    /// does for objects stored with `Content-Encoding: gzip` (decompressive transcoding).
+    ///
+    /// The listing's sizes and MD5s are of the stored (compressed) contents, so transcoded
+    /// objects can't be verified against them; and GCS sends them whole, rather than the range
+    /// asked for, so reads that break aren't resumed.
+    pub fn with_transcoding(self, transcoding: bool) -> Self {
+        Fetcher {
+            transcoding,
+            ..self
+        }
+    }
+
+    /// Collect the whole listing before fetching anything, and fetch the oldest objects first:
+    /// by the timestamps in their names, or failing that, their modification times.
+    ///
+    /// Objects are still fetched concurrently, so they finish roughly, not strictly, in order;
+    /// but a run that stops early (e.g. at its budget) leaves only newer objects behind.
+    pub fn with_oldest_first(self, oldest_first: bool) -> Self {
+        Fetcher {
+            oldest_first,
+            ..self
+        }
+    }
+
+    /// Adjust how many objects are downloaded at once, by how the downloads are going;
+    /// otherwise, it's only bounded by the buffer passed to `fetch`.
+    pub fn with_concurrency(self, concurrency: Concurrency) -> Self {
+        Fetcher {
+            concurrency: Some(Arc::new(concurrency)),
+            ..self
+        }
+    }
+
+    /// Limit the total size of objects being downloaded and parsed at once, by their sizes in
+    /// the listing, rather than only their number.
+    pub fn with_max_inflight_bytes(self, max_inflight_bytes: Option<u64>) -> Self {
+        Fetcher {
+            inflight_bytes: max_inflight_bytes.map(ByteBudget::new),
+            ..self
+        }
+    }
+
+    /// Download objects larger than `chunk_size` in parts of that size, `parallelism` at a
+    /// time, for links faster than a single read goes. The parts are passed on in order, so
+    /// up to `parallelism` of them are held in memory per object.
+    ///
+    /// Objects are taken to be the size they're listed as; and ranges aren't used with
+    /// transcoding, as GCS sends transcoded objects whole.
+    pub fn with_parallel_ranges(self, chunk_size: Option<u64>, parallelism: usize) -> Self {
+        Fetcher {
+            ranges: chunk_size.map(|chunk_size| Ranges {
+                chunk_size: chunk_size.max(1),
+                parallelism: parallelism.max(1),
+            }),
+            ..self
+        }
+    }
+
+    /// Stop deleting objects once `latch` is tripped, and trip it on any error: for runs that
+    /// would rather leave objects (even crunched ones) in place than risk losing any.
+    pub fn with_deletion_latch(self, latch: DeletionLatch) -> Self {
+        Fetcher {
+            latch: Some(latch),
+            ..self
+        }
+    }
+
+    /// Note an error in the run, for the deletion latch.
+    fn failed(&self, err: &anyhow::Error) {
+        if let Some(latch) = &self.latch {
+            latch.trip(err);
+        }
+    }
+
+    /// Whether deletions have been stopped by an error in the run.
+    fn keeping_everything(&self) -> bool {
+        self.latch.as_ref().is_some_and(DeletionLatch::tripped)
+    }
+
+    /// Parse objects in `format`, rather than as Fastly's JSON.
+    pub(crate) fn with_format(self, format: Arc<dyn LogFormat>) -> Self {
+        Fetcher { format, ..self }
+    }
+
+    /// Keep the listing in the file at `path`, and only list objects newer than it has, when
+    /// the store supports that and object names sort by their timestamps; see the listing
+    /// module.
+    pub fn with_listing_cache(self, path: PathBuf) -> Self {
+        Fetcher {
+            listing_cache: Some(ListingCache::new(path)),
+            ..self
+        }
+    }
+
+    /// Save the listing cache, if any, once the run's objects are processed (and cleaned up).
+    pub fn save_listing_cache(&self) -> anyhow::Result<()> {
+        match &self.listing_cache {
+            Some(cache) => cache.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Retry transient failures to list, read, or delete objects.
+    ///
+    /// Set after `with_transcoding`: reads that break are only resumed without it.
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Fetcher {
+            source: Box::new(
+                RetryingSource::new(self.source, retry).with_resume(!self.transcoding),
+            ),
+            ..self
+        }
     }
 
     /// Start the fetch process, returning a stream of logs.
@@ -49,13 +474,17 @@ impl Fetcher {
     pub async fn fetch(
         self: &Arc<Self>,
         buffer: usize,
-    ) -> tokio::sync::mpsc::Receiver<anyhow::Result<LogSet<LogEntry>>> {
+    ) -> tokio::sync::mpsc::Receiver<anyhow::Result<Object>> {
         let (tx, rx) = tokio::sync::mpsc::channel(buffer);
         tokio::spawn({
             let fetcher = Arc::clone(self);
             let tx_ch = tx.clone();
             async move {
+                let latch = fetcher.latch.clone();
                 if let Err(e) = fetcher.fetch_loop(tx_ch).await {
+                    if let Some(latch) = latch {
+                        latch.trip(&e);
+                    }
                     // Ignore a send error; likely hung up
                     let _ = tx.send(Err(e)).await;
                 }
@@ -64,81 +493,1139 @@ impl Fetcher {
         rx
     }
 
-    async fn fetch_loop(
-        self: Arc<Self>,
-        tx: Sender<anyhow::Result<LogSet<LogEntry>>>,
-    ) -> anyhow::Result<()> {
+    /// Check that the source can be listed and read, and deleted from if cleaning up,
+    /// before starting to fetch.
+    ///
+    /// The delete check deletes an object that doesn't exist: stores report a missing object
+    /// as success (or not found) only if the credentials could have deleted it.
+    pub async fn preflight(&self) -> anyhow::Result<()> {
         let mut lister = self
-            .operator
-            .lister("")
+            .source
+            .list(self.filter.prefix(), false)
             .await
-            .context("could not list entries from storage")?;
+            .context(
+                "preflight: could not list objects; do the credentials have permission to list?",
+            )?;
+        let first = lister.next().await.transpose().context(
+            "preflight: could not list objects; do the credentials have permission to list?",
+        )?;
+        if let Some(object) = first {
+            let mut stream = self
+                .source
+                .read_stream(&object.name)
+                .await
+                .with_context(|| {
+                    format!(
+                        "preflight: could not read {}; do the credentials have permission to read?",
+                        object.name
+                    )
+                })?;
+            stream.next().await.transpose().with_context(|| {
+                format!(
+                    "preflight: could not read {}; do the credentials have permission to read?",
+                    object.name
+                )
+            })?;
+        }
+        if self.cleanup {
+            self.source.delete(PREFLIGHT_OBJECT).await.context(
+                "preflight: could not delete a test object; do the credentials have permission \
+                to delete? Cleanup needs it",
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn fetch_loop(self: Arc<Self>, tx: Sender<anyhow::Result<Object>>) -> anyhow::Result<()> {
+        // The byte budgets need sizes from the listing, too.
+        // As does verification, and sorting by modification time.
+        let sizes = self.empty_objects != EmptyObjects::Fetch
+            || self.max_bytes.is_some()
+            || self.inflight_bytes.is_some()
+            || self.verify
+            || self.oldest_first
+            || self.ranges.is_some();
+        let mut lister = self.list(sizes).await?;
+        if self.oldest_first {
+            let mut listing: Vec<_> = lister.collect().await;
+            tracing::info!("listed {} objects; fetching oldest first", listing.len());
+            // Listing errors first, then objects by time; any with no time at all go last.
+            listing.sort_by_cached_key(|entry| {
+                entry.as_ref().ok().map(|object| {
+                    let time = self
+                        .filter
+                        .name_time(&object.name)
+                        .map(|time| time.and_utc())
+                        .or(object.modified);
+                    (time.is_none(), time, object.name.clone())
+                })
+            });
+            lister = Box::pin(tokio_stream::iter(listing));
+        }
+        let mut objects = 0;
+        let mut bytes = 0;
+        let mut listed = true;
         while let Some(entry) = lister.next().await {
-            match entry.context("in listing bucket entries: ") {
-                Err(e) => tx
-                    .send(Err(e))
-                    .await
-                    .context("could not propagate error from fetch loop: ")?,
+            if self.max_objects.is_some_and(|max| objects >= max)
+                || self.max_bytes.is_some_and(|max| bytes >= max)
+            {
+                tracing::info!(
+                    "run budget reached after {objects} objects, {bytes} bytes; \
+                    leaving the rest for the next run"
+                );
+                if self.listing_cache.is_some() {
+                    // The cache needs the whole listing.
+                    while let Some(entry) = lister.next().await {
+                        listed &= entry.is_ok();
+                    }
+                }
+                break;
+            }
+            match entry {
+                Err(e) => {
+                    listed = false;
+                    self.failed(&e);
+                    tx.send(Err(e))
+                        .await
+                        .context("could not propagate error from fetch loop: ")?
+                }
+                Ok(v) if !self.filter.selects(&v.name) => {
+                    tracing::debug!("not selected: {}", &v.name);
+                }
+                Ok(v) if self.archive.as_ref().is_some_and(|a| a.contains(&v.name)) => {
+                    tracing::debug!("already archived: {}", &v.name);
+                }
+                Ok(v)
+                    if self
+                        .dead_letter
+                        .as_ref()
+                        .is_some_and(|p| v.name.starts_with(p)) =>
+                {
+                    tracing::debug!("dead letter: {}", &v.name);
+                }
                 Ok(v) => {
-                    // We spawn an executor for every source,
-                    // but we only start the fetch once we have a permit from
-                    // the Sender. We might have a lot of Futures, but only a few active.
-                    //
-                    // What I'd _like_ to do is have the permit claimed in the spawner,
-                    // and passed in to the worker task -- so the concurrency limits the number
-                    // of tasks as well. But the permit closes over the lifetime of the Sender,
-                    // which requires some sort of async spawn_scoped.
-                    // There's some efforts to that end --
-                    // from https://without.boats/blog/the-scoped-task-trilemma/,
-                    // https://docs.rs/async_nursery/latest/async_nursery/
-                    // looks viable?
-                    // -- but I'm not going to try it yet.
-                    // TODO: Try out async_nursery?
-                    let tx = tx.clone();
+                    objects += 1;
+                    bytes += v.size.unwrap_or(0);
+                    // Claim the permit here, before spawning, so the channel's capacity
+                    // bounds the number of tasks -- not just the number of active ones.
+                    // An owned permit holds its own Sender, so it can move into the task.
+                    // This also stops listing while the receiver is behind.
+                    let room = match &self.inflight_bytes {
+                        Some(budget) => Some(budget.acquire(v.size).await),
+                        None => None,
+                    };
+                    let slot = match &self.concurrency {
+                        Some(concurrency) => Some(concurrency.acquire().await),
+                        None => None,
+                    };
+                    let permit = tx
+                        .clone()
+                        .reserve_owned()
+                        .await
+                        .context("could not prepare to send from fetch loop: ")?;
                     let fetcher = Arc::clone(&self);
                     tokio::spawn(async move {
-                        if let Ok(permit) = tx
-                            .reserve()
-                            .await
-                            .context("could not prepare to send from fetch loop: ")
-                        {
-                            permit.send(fetcher.fetch_one(v.path()).await);
+                        let result = Arc::clone(&fetcher).fetch_entry(v, slot, room).await;
+                        if let Err(e) = &result {
+                            fetcher.failed(e);
+                            if let Some(concurrency) = &fetcher.concurrency {
+                                concurrency.failed();
+                            }
                         }
+                        permit.send(result);
                     });
                 }
             }
         }
+        if let (true, Some(cache)) = (listed, &self.listing_cache) {
+            cache.finish(|name| self.filter.name_time(name));
+        }
         Ok(())
     }
 
-    async fn fetch_one(self: Arc<Self>, path: &str) -> anyhow::Result<LogSet<LogEntry>> {
+    /// List the objects under the filter's prefix: all of them, or, with a listing cache,
+    /// those it has and those newer than it.
+    async fn list(self: &Arc<Self>, sizes: bool) -> anyhow::Result<ObjectStream> {
+        let prefix = self.filter.prefix();
+        let Some(cache) = &self.listing_cache else {
+            return self.source.list(prefix, sizes).await;
+        };
+        let lister = match cache.start(prefix) {
+            Some((marker, cached)) => match self.source.list_after(prefix, sizes, &marker).await? {
+                Some(after) => {
+                    tracing::info!(
+                        "{} objects from the listing cache; listing after {marker}",
+                        cached.len()
+                    );
+                    Box::pin(tokio_stream::iter(cached.into_iter().map(Ok)).chain(after))
+                }
+                None => self.source.list(prefix, sizes).await?,
+            },
+            None => self.source.list(prefix, sizes).await?,
+        };
+        let fetcher = Arc::clone(self);
+        Ok(Box::pin(lister.map(move |entry| {
+            if let (Ok(object), Some(cache)) = (&entry, &fetcher.listing_cache) {
+                // Archived objects and dead letters are passed over anyway.
+                if !fetcher.moved_aside(&object.name) {
+                    cache.record(object);
+                }
+            }
+            entry
+        })))
+    }
+
+    /// List the objects a run would fetch, with their sizes: those the filter selects and
+    /// doesn't skip by name, but for those archived or moved to dead letters.
+    pub async fn selected(&self) -> anyhow::Result<Vec<ObjectInfo>> {
+        let mut lister = self.source.list(self.filter.prefix(), true).await?;
+        let mut objects = Vec::new();
+        while let Some(object) = lister.next().await {
+            let object = object?;
+            if self.filter.selects(&object.name)
+                && self.filter.check_name(&object.name).is_none()
+                && !self.moved_aside(&object.name)
+            {
+                objects.push(object);
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Whether the object is one this fetcher archived, or moved to dead letters.
+    fn moved_aside(&self, name: &str) -> bool {
+        self.archive.as_ref().is_some_and(|a| a.contains(name))
+            || self
+                .dead_letter
+                .as_ref()
+                .is_some_and(|p| name.starts_with(p))
+    }
+
+    /// Note that an object has been removed from the store.
+    fn removed(&self, name: &str) {
+        if let Some(cache) = &self.listing_cache {
+            cache.remove(name);
+        }
+    }
+
+    /// Fetch a listed entry, checking its metadata first.
+    ///
+    /// The slot, if any, is held until the object has been downloaded;
+    /// its room in the byte budget, until it's been parsed.
+    async fn fetch_entry(
+        self: Arc<Self>,
+        entry: ObjectInfo,
+        slot: Option<Slot>,
+        room: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<Object> {
+        let path = entry.name.as_str();
+        if self.empty_objects != EmptyObjects::Fetch && entry.size == Some(0) {
+            let reason = match self.empty_objects {
+                EmptyObjects::Delete if self.keeping_everything() => {
+                    "empty object, kept after an error in the run"
+                }
+                EmptyObjects::Delete => {
+                    self.source.delete(path).await?;
+                    self.removed(path);
+                    "empty object, deleted"
+                }
+                _ => "empty object",
+            };
+            tracing::info!("skipping object {path}: {reason}");
+            return Ok(Object::Skipped {
+                source: None,
+                name: path.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+        self.fetch_one(&entry, slot, room).await
+    }
+
+    async fn fetch_one(
+        self: Arc<Self>,
+        entry: &ObjectInfo,
+        mut slot: Option<Slot>,
+        room: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<Object> {
+        let path = entry.name.as_str();
+        if let Some(reason) = self.filter.check_name(path) {
+            tracing::info!("skipping object {path}: {reason}");
+            return Ok(Object::Skipped {
+                source: None,
+                name: path.to_string(),
+                reason,
+            });
+        }
         tracing::info!("reading object: {path}");
-        let rd = self
-            .operator
-            .reader(path)
-            .await
-            .with_context(|| format!("failed to start read of object {}: ", path))?;
-        let data = rd
-            .read(0..)
-            .await
-            .with_context(|| format!("failed to read object contents {}: ", path))?;
-        let bytes = LogSet {
+        let mut stream = match (&self.ranges, entry.size) {
+            (Some(ranges), Some(size)) if size > ranges.chunk_size && !self.transcoding => {
+                Arc::clone(&self).read_ranges(path, size, ranges).await?
+            }
+            _ => self.source.read_stream(path).await?,
+        };
+        // Enough of the start of the object to tell what it is.
+        let mut head = BytesMut::new();
+        while head.len() < CONTENTS_CHECK_LEN
+            || Compression::detect(&head) == Compression::Plain
+                && head.len() < PLAIN_CHECK_LEN
+                && !head.contains(&b'\n')
+        {
+            match stream.next().await {
+                Some(chunk) => head.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+        // Already decompressed by the store, it's a stream of JSON objects.
+        let transcoded = self.transcoding && head.first() == Some(&b'{');
+        if let Some(reason) = self.filter.check_contents(&head, &*self.format) {
+            tracing::info!("skipping object {path}: {reason}");
+            return Ok(Object::Skipped {
+                source: None,
+                name: path.to_string(),
+                reason,
+            });
+        }
+        if transcoded {
+            tracing::debug!("object {path} was decompressed by the store");
+        }
+
+        // Read the rest of the object in the background, as the parser is ready for it...
+        // Unbounded, so the download isn't held up by the parser (or the cruncher behind it),
+        // and its slot goes back as soon as it's done. The byte budget, if any, bounds the
+        // memory that takes.
+        let (chunk_tx, chunk_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut check = (self.verify && !transcoded).then(|| Check::new(entry));
+        tokio::spawn(async move {
+            let mut next = Some(Ok(head.freeze()));
+            let mut bytes = 0;
+            while let Some(chunk) = next {
+                match &chunk {
+                    Ok(chunk) => {
+                        bytes += chunk.len() as u64;
+                        if let Some(check) = &mut check {
+                            check.update(chunk);
+                        }
+                    }
+                    Err(_) => {
+                        if let Some(slot) = slot.take() {
+                            slot.failed();
+                        }
+                    }
+                }
+                if chunk_tx.send(chunk).is_err() {
+                    // The parser stopped early.
+                    return;
+                }
+                next = stream.next().await;
+            }
+            let verified = check.map_or(Ok(()), Check::verify);
+            match (slot, &verified) {
+                (Some(slot), Ok(())) => slot.fetched(bytes),
+                (Some(slot), Err(_)) => slot.failed(),
+                (None, _) => (),
+            }
+            // The parser reads to the end of the object, so this fails it before it's done.
+            if let Err(e) = verified {
+                let _ = chunk_tx.send(Err(e));
+            }
+        });
+        // ...and parse it in batches, as the cruncher is ready for them.
+        let (batch_tx, batch_rx) = tokio::sync::mpsc::channel(1);
+        let (salvage, skip_invalid, max_invalid) =
+            (self.salvage_truncated, self.skip_invalid, self.max_invalid);
+        let format = self.format.clone();
+        let name = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            let input = ChannelReader::new(chunk_rx);
+            let result = parse_object(
+                input,
+                &*format,
+                salvage,
+                skip_invalid,
+                max_invalid,
+                |entries| {
+                    batch_tx
+                        .blocking_send(Ok(Batch::Entries(entries)))
+                        .map_err(|_| anyhow!("log set was dropped"))
+                },
+            );
+            if let Ok(parsed) = &result {
+                if parsed.truncated {
+                    tracing::warn!("log set {} is truncated; salvaged what was complete", &name);
+                }
+                if parsed.skipped.count > 0 {
+                    tracing::warn!(
+                        "skipped {} invalid entries in log set {}, e.g. {}",
+                        parsed.skipped.count,
+                        &name,
+                        parsed.skipped.samples.join("; ")
+                    );
+                }
+            }
+            let end = result
+                .map(|parsed| Batch::End {
+                    truncated: parsed.truncated,
+                    skipped: parsed.skipped.count,
+                    content_hash: parsed.content_hash,
+                })
+                .with_context(|| format!("in log set {name}"));
+            // Ignore a send error; the log set was dropped.
+            let _ = batch_tx.blocking_send(end);
+            drop(room);
+        });
+        Ok(Object::Logs(LogSet {
             name: path.to_string(),
-            data: data.to_vec(),
-            source: self,
+            source: None,
+            batches: Box::pin(tokio_stream::wrappers::ReceiverStream::new(batch_rx)),
+            fetcher: self,
+        }))
+    }
+
+    /// Read an object of `size` bytes in ranges, as a stream of them in order; or all at once,
+    /// if the store can't read ranges.
+    async fn read_ranges(
+        self: Arc<Self>,
+        path: &str,
+        size: u64,
+        ranges: &Ranges,
+    ) -> anyhow::Result<ByteStream> {
+        let chunk_size = ranges.chunk_size;
+        let Some(first) = self.source.read_range(path, 0..chunk_size).await? else {
+            return self.source.read_stream(path).await;
         };
-        tracing::info!("downloaded, now parsing: {path}");
-        bytes.try_into()
+        let name = path.to_string();
+        let rest = (1..size.div_ceil(chunk_size)).map(move |i| {
+            let (this, name) = (Arc::clone(&self), name.clone());
+            let range = i * chunk_size..((i + 1) * chunk_size).min(size);
+            async move {
+                let len = range.end - range.start;
+                let chunk = this
+                    .source
+                    .read_range(&name, range.clone())
+                    .await?
+                    .with_context(|| format!("could not read bytes {range:?} of {name}"))?;
+                if chunk.len() as u64 != len {
+                    bail!("object {name} is shorter than listed");
+                }
+                Ok(chunk)
+            }
+        });
+        let rest = futures::StreamExt::buffered(futures::stream::iter(rest), ranges.parallelism);
+        Ok(Box::pin(tokio_stream::once(Ok(first)).chain(rest)))
     }
 
-    async fn delete_object(&self, object: &str) -> anyhow::Result<()> {
-        if self.cleanup {
-            self.operator
-                .delete(object)
-                .await
-                .with_context(|| format!("could not delete object {}: ", object))
-        } else {
+    /// Recompress an object into the archive with zstd, streaming it through, so it's never
+    /// held in memory whole.
+    async fn archive_zstd(
+        &self,
+        object: &str,
+        store: &dyn LogSource,
+        to: &str,
+        level: i32,
+    ) -> anyhow::Result<()> {
+        let input = self.source.read_stream(object).await?;
+        let (chunks, output) = tokio::sync::mpsc::channel(RECOMPRESS_CHUNKS);
+        let handle = tokio::runtime::Handle::current();
+        let recompressing = tokio::task::spawn_blocking(move || {
+            let failed = chunks.clone();
+            let output = io::BufWriter::with_capacity(RECOMPRESS_CHUNK_LEN, ChunkWriter(chunks));
+            let result = recompress(StreamReader::new(input, handle), output, level);
+            if result.is_err() {
+                // So the write fails, rather than leaving part of an object behind.
+                let _ = failed.blocking_send(Err(anyhow!("could not recompress object")));
+            }
+            result
+        });
+        let output = Box::pin(tokio_stream::wrappers::ReceiverStream::new(output));
+        let (written, recompressed) = tokio::join!(store.write_stream(to, output), recompressing);
+        recompressed.context("could not recompress object")??;
+        written
+    }
+
+    /// Clean up an object, returning the generation it can be restored from if it was deleted
+    /// outright from a store that keeps them.
+    async fn delete_object(&self, object: &str) -> anyhow::Result<Option<String>> {
+        if !self.cleanup {
+            return Ok(None);
+        }
+        if self.keeping_everything() {
+            tracing::info!("leaving {object} in place, after an error in the run");
+            return Ok(None);
+        }
+        if let Some(archive) = &self.archive {
+            let to = format!("{}{}", archive.prefix, object);
+            match (&archive.store, archive.zstd_level) {
+                (store, Some(level)) => {
+                    let store = store.as_ref().map_or(&*self.source, |store| store as _);
+                    self.archive_zstd(object, store, &zstd_name(&to), level)
+                        .await?;
+                }
+                (Some(store), None) => store.write(&to, self.source.read(object).await?).await?,
+                (None, None) => self.source.copy(object, &to).await?,
+            }
+            self.source.delete(object).await?;
+            self.removed(object);
+            return Ok(None);
+        }
+        let generation = self
+            .source
+            .generation(object)
+            .await
+            .context("could not get generation to restore from")?;
+        self.source.delete(object).await?;
+        self.removed(object);
+        Ok(generation)
+    }
+
+    async fn dead_letter(&self, object: &str, err: &anyhow::Error) -> anyhow::Result<()> {
+        let Some(prefix) = self.dead_letter.as_ref().filter(|_| self.cleanup) else {
+            return Ok(());
+        };
+        if self.keeping_everything() {
+            return Ok(());
+        }
+        let to = format!("{prefix}{object}");
+        self.source.copy(object, &to).await?;
+        self.source
+            .write(&format!("{to}.error"), format!("{err:#}\n").into_bytes())
+            .await?;
+        self.source.delete(object).await?;
+        self.removed(object);
+        Ok(())
+    }
+}
+
+/// Compares an object's contents, as they're read, with its listing.
+struct Check {
+    name: String,
+    expected_size: Option<u64>,
+    expected_md5: Option<[u8; 16]>,
+    size: u64,
+    md5: Md5,
+}
+
+impl Check {
+    fn new(entry: &ObjectInfo) -> Self {
+        Check {
+            name: entry.name.clone(),
+            expected_size: entry.size,
+            expected_md5: entry.md5,
+            size: 0,
+            md5: Md5::new(),
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        if self.expected_md5.is_some() {
+            self.md5.update(chunk);
+        }
+    }
+
+    fn verify(self) -> anyhow::Result<()> {
+        if let Some(expected) = self.expected_size.filter(|&size| size != self.size) {
+            return Err(anyhow!(
+                "read {} bytes of {}, but it's listed as {expected} bytes",
+                self.size,
+                self.name
+            ));
+        }
+        if let Some(expected) = self.expected_md5 {
+            let md5: [u8; 16] = self.md5.finalize().into();
+            if md5 != expected {
+                return Err(anyhow!("MD5 of {} doesn't match its listing", self.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Name of the (nonexistent) object the preflight check deletes.
+const PREFLIGHT_OBJECT: &str = ".log-cruncher-preflight-check";
+
+/// How much of a recompressed object to pass on to be written at a time, and how many of
+/// those to have waiting at most.
+const RECOMPRESS_CHUNK_LEN: usize = 1 << 20;
+const RECOMPRESS_CHUNKS: usize = 8;
+
+/// Reads chunks of an object from a channel, as a blocking reader.
+struct ChannelReader {
+    chunks: UnboundedReceiver<anyhow::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    fn new(chunks: UnboundedReceiver<anyhow::Result<Bytes>>) -> Self {
+        ChannelReader {
+            chunks,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                None => return Ok(0),
+                Some(Ok(chunk)) => self.current = chunk,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Reads chunks of an object from its stream, as a blocking reader: for a blocking task, with
+/// a handle to the runtime the stream is read on.
+struct StreamReader {
+    stream: ByteStream,
+    handle: tokio::runtime::Handle,
+    current: Bytes,
+}
+
+impl StreamReader {
+    fn new(stream: ByteStream, handle: tokio::runtime::Handle) -> Self {
+        StreamReader {
+            stream,
+            handle,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl io::Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.handle.block_on(self.stream.next()) {
+                None => return Ok(0),
+                Some(Ok(chunk)) => self.current = chunk,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Writes chunks of an object to a channel, from a blocking task, for them to be written out.
+struct ChunkWriter(Sender<anyhow::Result<Bytes>>);
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "write stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        ops::Range,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use tokio_stream::StreamExt;
+
+    use super::{recompress, zstd_name, Archive, DeletionLatch, Fetcher, Object};
+    use crate::{
+        concurrency::Concurrency,
+        filter::{EmptyObjects, ObjectFilter},
+        source::{ByteStream, LogSource, ObjectInfo, ObjectStream},
+        testdata::{bzip2_compressed, gzipped, zstd_compressed, ENTRY},
+        Batch, LogSet,
+    };
+
+    /// In-memory store of objects, and a count of how many have been taken from listings.
+    #[derive(Default)]
+    struct MemorySource(Mutex<HashMap<String, Vec<u8>>>, Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl LogSource for MemorySource {
+        async fn list(&self, _prefix: &str, _sizes: bool) -> anyhow::Result<ObjectStream> {
+            let objects: Vec<_> = self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, data)| {
+                    Ok(ObjectInfo {
+                        name: name.clone(),
+                        size: Some(data.len() as u64),
+                        md5: None,
+                        modified: None,
+                    })
+                })
+                .collect();
+            let listed = Arc::clone(&self.1);
+            Ok(Box::pin(tokio_stream::iter(objects).map(move |object| {
+                listed.fetch_add(1, Ordering::SeqCst);
+                object
+            })))
+        }
+
+        async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+            Ok(self.0.lock().unwrap()[name].clone())
+        }
+
+        async fn read_stream(&self, name: &str) -> anyhow::Result<ByteStream> {
+            // Small chunks, so objects span several.
+            let chunks: Vec<_> = self.0.lock().unwrap()[name]
+                .chunks(7)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect();
+            Ok(Box::pin(tokio_stream::iter(chunks)))
+        }
+
+        async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+            let mut objects = self.0.lock().unwrap();
+            let data = objects[from].clone();
+            objects.insert(to.to_string(), data);
+            Ok(())
+        }
+
+        async fn write(&self, name: &str, data: Vec<u8>) -> anyhow::Result<()> {
+            self.0.lock().unwrap().insert(name.to_string(), data);
             Ok(())
         }
+
+        async fn delete(&self, name: &str) -> anyhow::Result<()> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    /// How long to wait for each object before taking the fetcher to have stalled.
+    const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Fetch every object, without completing any.
+    fn fetch_objects(rt: &tokio::runtime::Runtime, fetcher: &Arc<Fetcher>) -> Vec<Object> {
+        rt.block_on(async {
+            let mut objects = fetcher.fetch(4).await;
+            let mut fetched = Vec::new();
+            while let Some(object) = tokio::time::timeout(FETCH_TIMEOUT, objects.recv())
+                .await
+                .expect("fetcher stalled")
+            {
+                fetched.push(object.unwrap());
+            }
+            fetched
+        })
+    }
+
+    /// Fetch every object, all of them logs, without completing any.
+    fn fetch_all(rt: &tokio::runtime::Runtime, fetcher: &Arc<Fetcher>) -> Vec<LogSet> {
+        fetch_objects(rt, fetcher)
+            .into_iter()
+            .map(|object| match object {
+                Object::Logs(log_set) => log_set,
+                Object::Skipped { name, .. } => panic!("unexpected skip of {name}"),
+            })
+            .collect()
+    }
+
+    /// Read every entry of an object; how many there were.
+    fn count_entries(rt: &tokio::runtime::Runtime, log_set: &mut LogSet) -> usize {
+        rt.block_on(async {
+            let mut entries = 0;
+            while let Batch::Entries(batch) = log_set.next_batch().await.unwrap() {
+                entries += batch.len();
+            }
+            entries
+        })
+    }
+
+    /// Complete every object as crunched; their names, in order.
+    fn complete_all(rt: &tokio::runtime::Runtime, log_sets: Vec<LogSet>) -> Vec<String> {
+        let mut names: Vec<_> = log_sets
+            .into_iter()
+            .map(|log_set| {
+                let name = log_set.name.clone();
+                rt.block_on(log_set.complete(Ok(()))).unwrap();
+                name
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn fetches_from_custom_source() {
+        let source = MemorySource::default();
+        {
+            let mut objects = source.0.lock().unwrap();
+            objects.insert("logs.gz".to_string(), gzipped(2));
+            objects.insert("marker.txt".to_string(), b"marker".to_vec());
+            objects.insert("empty.gz".to_string(), Vec::new());
+        }
+        let fetcher = Arc::new(
+            Fetcher::new(source, true, ObjectFilter::default())
+                .with_empty_objects(EmptyObjects::Skip),
+        );
+
+        let rt = runtime();
+        let mut skipped = Vec::new();
+        for object in fetch_objects(&rt, &fetcher) {
+            match object {
+                Object::Logs(mut log_set) => {
+                    assert_eq!(log_set.name, "logs.gz");
+                    assert_eq!(count_entries(&rt, &mut log_set), 2);
+                    rt.block_on(log_set.complete(Ok(()))).unwrap();
+                }
+                Object::Skipped { name, .. } => skipped.push(name),
+            }
+        }
+        skipped.sort();
+        assert_eq!(skipped, vec!["empty.gz", "marker.txt"]);
+    }
+
+    #[test]
+    fn maps_entries_before_storing() {
+        let source = MemorySource::default();
+        source
+            .0
+            .lock()
+            .unwrap()
+            .insert("a.gz".to_string(), gzipped(3));
+        let fetcher = Arc::new(Fetcher::new(source, false, ObjectFilter::default()));
+
+        let rt = runtime();
+        let mirrored = Arc::new(AtomicUsize::new(0));
+        let seen = mirrored.clone();
+        let object = fetch_objects(&rt, &fetcher).pop().unwrap();
+        let Object::Logs(mut log_set) = object.map_entries(move |mut entries| {
+            seen.fetch_add(entries.len(), Ordering::SeqCst);
+            entries.truncate(1);
+            Ok(entries)
+        }) else {
+            panic!("unexpected skip");
+        };
+        assert_eq!(count_entries(&rt, &mut log_set), 1);
+        assert_eq!(mirrored.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn accepts_transcoded_objects() {
+        let source = MemorySource::default();
+        {
+            let mut objects = source.0.lock().unwrap();
+            objects.insert("logs.gz".to_string(), gzipped(1));
+            // As GCS sends an object stored with Content-Encoding: gzip.
+            objects.insert(
+                "transcoded.gz".to_string(),
+                format!("{ENTRY}\n{ENTRY}\n").into(),
+            );
+        }
+        let fetcher =
+            Arc::new(Fetcher::new(source, true, ObjectFilter::default()).with_transcoding(true));
+
+        let rt = runtime();
+        let mut log_sets = fetch_all(&rt, &fetcher);
+        let entries: HashMap<_, _> = log_sets
+            .iter_mut()
+            .map(|log_set| (log_set.name.clone(), count_entries(&rt, log_set)))
+            .collect();
+        complete_all(&rt, log_sets);
+        assert_eq!(entries["logs.gz"], 1);
+        assert_eq!(entries["transcoded.gz"], 2);
+    }
+
+    #[test]
+    fn releases_slots_once_downloaded() {
+        let source = MemorySource::default();
+        {
+            let mut objects = source.0.lock().unwrap();
+            objects.insert("a.gz".to_string(), gzipped(50));
+            objects.insert("b.gz".to_string(), gzipped(50));
+        }
+        let fetcher = Arc::new(
+            Fetcher::new(source, false, ObjectFilter::default())
+                .with_concurrency(Concurrency::new(1, 1)),
+        );
+
+        // The first isn't crunched before the second is fetched, but it's downloaded, so the
+        // second can be; otherwise, fetching stalls.
+        let rt = runtime();
+        let log_sets = fetch_all(&rt, &fetcher);
+        assert_eq!(log_sets.len(), 2);
+        for mut log_set in log_sets {
+            assert_eq!(count_entries(&rt, &mut log_set), 50);
+        }
+    }
+
+    #[test]
+    fn bounds_fetches_in_flight() {
+        let source = MemorySource::default();
+        {
+            let mut objects = source.0.lock().unwrap();
+            for i in 0..100 {
+                objects.insert(format!("{i}.gz"), gzipped(1));
+            }
+        }
+        let listed = Arc::clone(&source.1);
+        let fetcher = Arc::new(Fetcher::new(source, false, ObjectFilter::default()));
+
+        let rt = runtime();
+        rt.block_on(async {
+            let _objects = fetcher.fetch(4).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+        // While nothing's taken: a fetch per permit, and one more listed while waiting for a
+        // permit.
+        assert!(listed.load(Ordering::SeqCst) <= 5);
+        let log_sets = fetch_all(&rt, &fetcher);
+        assert_eq!(complete_all(&rt, log_sets).len(), 100);
+    }
+
+    #[test]
+    fn resumes_after_budget() {
+        let source = MemorySource::default();
+        {
+            let mut objects = source.0.lock().unwrap();
+            for name in ["a.gz", "b.gz", "c.gz"] {
+                objects.insert(name.to_string(), gzipped(1));
+            }
+        }
+        let fetcher = Arc::new(
+            Fetcher::new(source, true, ObjectFilter::default()).with_budget(Some(2), None),
+        );
+
+        let rt = runtime();
+        let run = || complete_all(&rt, fetch_all(&rt, &fetcher)).len();
+        assert_eq!(run(), 2);
+        // The first run cleaned up what it fetched, so the next picks up the rest.
+        assert_eq!(run(), 1);
+    }
+
+    #[test]
+    fn fetches_oldest_first() {
+        let source = MemorySource::default();
+        {
+            let mut objects = source.0.lock().unwrap();
+            for name in [
+                "a-2024-06-03T00:00:00.000.log.gz",
+                "b-2024-06-01T00:00:00.000.log.gz",
+                "c-2024-06-02T00:00:00.000.log.gz",
+            ] {
+                objects.insert(name.to_string(), gzipped(1));
+            }
+        }
+        let fetcher = Arc::new(
+            Fetcher::new(source, true, ObjectFilter::default())
+                .with_budget(Some(2), None)
+                .with_oldest_first(true),
+        );
+
+        let rt = runtime();
+        let run = || complete_all(&rt, fetch_all(&rt, &fetcher));
+        // The newest object is the one left for the next run.
+        assert_eq!(
+            run(),
+            vec![
+                "b-2024-06-01T00:00:00.000.log.gz",
+                "c-2024-06-02T00:00:00.000.log.gz"
+            ]
+        );
+        assert_eq!(run(), vec!["a-2024-06-03T00:00:00.000.log.gz"]);
+    }
+
+    #[test]
+    fn archives_processed_objects() {
+        let rt = runtime();
+        for zstd_level in [None, Some(3)] {
+            let source = MemorySource::default();
+            source
+                .0
+                .lock()
+                .unwrap()
+                .insert("a.gz".to_string(), gzipped(1));
+            let archive = Archive::new("processed/".to_string(), None)
+                .unwrap()
+                .with_zstd(zstd_level);
+            let fetcher =
+                Arc::new(Fetcher::new(source, true, ObjectFilter::default()).with_archive(archive));
+            assert_eq!(complete_all(&rt, fetch_all(&rt, &fetcher)).len(), 1);
+            // The archived copy isn't fetched again.
+            assert!(fetch_all(&rt, &fetcher).is_empty());
+            let archived = match zstd_level {
+                None => "processed/a.gz",
+                Some(_) => "processed/a.zst",
+            };
+            let data = rt.block_on(fetcher.source.read(archived)).unwrap();
+            let data = crate::decompress(data.as_slice()).unwrap();
+            assert_eq!(std::io::read_to_string(data).unwrap(), format!("{ENTRY}\n"));
+        }
+    }
+
+    #[test]
+    fn stops_deleting_after_an_error() {
+        let source = MemorySource::default();
+        for name in ["a.gz", "b.gz"] {
+            source
+                .0
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), gzipped(1));
+        }
+        let fetcher = Arc::new(
+            Fetcher::new(source, true, ObjectFilter::default())
+                .with_deletion_latch(DeletionLatch::default()),
+        );
+
+        let rt = runtime();
+        let run = || {
+            let mut log_sets = fetch_all(&rt, &fetcher);
+            let fetched = log_sets.len();
+            let failed = log_sets.remove(0);
+            assert!(rt
+                .block_on(failed.complete(Err(anyhow::anyhow!("bad"))))
+                .is_err());
+            complete_all(&rt, log_sets);
+            fetched
+        };
+        assert_eq!(run(), 2);
+        // The one that crunched was left in place too.
+        assert_eq!(run(), 2);
+    }
+
+    #[test]
+    fn recompresses_with_zstd() {
+        let plain = format!("{ENTRY}\n").repeat(100);
+        for (compressed, level) in [
+            (gzipped(100), 3),
+            (zstd_compressed(100), 19),
+            (bzip2_compressed(100), 22),
+            (plain.clone().into_bytes(), 1),
+        ] {
+            let mut zstd = Vec::new();
+            recompress(compressed.as_slice(), &mut zstd, level).unwrap();
+            // One layer of zstd, over the entries as they were.
+            assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), plain.as_bytes());
+        }
+        let mut zstd = Vec::new();
+        assert!(recompress(&gzipped(100)[..100], &mut zstd, 3).is_err());
+        assert_eq!(zstd_name("logs/a.log.gz"), "logs/a.log.zst");
+        assert_eq!(zstd_name("logs/a.log"), "logs/a.log.zst");
+    }
+
+    #[test]
+    fn fails_objects_that_dont_match_listing() {
+        /// Lists objects as a byte longer than they are, as if the download were cut short.
+        struct ShortReads(MemorySource);
+
+        #[async_trait]
+        impl LogSource for ShortReads {
+            async fn list(&self, prefix: &str, sizes: bool) -> anyhow::Result<ObjectStream> {
+                let objects = self.0.list(prefix, sizes).await?;
+                Ok(Box::pin(objects.map(|object| {
+                    object.map(|object| ObjectInfo {
+                        size: object.size.map(|size| size + 1),
+                        ..object
+                    })
+                })))
+            }
+
+            async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+                self.0.read(name).await
+            }
+
+            async fn read_stream(&self, name: &str) -> anyhow::Result<ByteStream> {
+                self.0.read_stream(name).await
+            }
+
+            async fn delete(&self, name: &str) -> anyhow::Result<()> {
+                self.0.delete(name).await
+            }
+        }
+
+        let source = MemorySource::default();
+        source
+            .0
+            .lock()
+            .unwrap()
+            .insert("a.gz".to_string(), gzipped(1));
+        let fetcher = Arc::new(
+            Fetcher::new(ShortReads(source), true, ObjectFilter::default()).with_verify(true),
+        );
+
+        let rt = runtime();
+        let mut log_set = fetch_all(&rt, &fetcher).pop().unwrap();
+        let err = rt.block_on(async {
+            loop {
+                match log_set.next_batch().await {
+                    Ok(Batch::Entries(_)) => continue,
+                    Ok(Batch::End { .. }) => panic!("short read wasn't caught"),
+                    Err(e) => break e,
+                }
+            }
+        });
+        assert!(format!("{err:#}").contains("listed as"), "{err:#}");
+    }
+
+    #[test]
+    fn downloads_ranges_in_order() {
+        /// Reads objects only in ranges.
+        struct Ranged(MemorySource);
+
+        #[async_trait]
+        impl LogSource for Ranged {
+            async fn list(&self, prefix: &str, sizes: bool) -> anyhow::Result<ObjectStream> {
+                self.0.list(prefix, sizes).await
+            }
+
+            async fn read(&self, _name: &str) -> anyhow::Result<Vec<u8>> {
+                panic!("read the whole object");
+            }
+
+            async fn read_range(
+                &self,
+                name: &str,
+                range: Range<u64>,
+            ) -> anyhow::Result<Option<Bytes>> {
+                let data = self.0.read(name).await?;
+                let range = range.start as usize..(range.end as usize).min(data.len());
+                Ok(Some(Bytes::copy_from_slice(&data[range])))
+            }
+
+            async fn delete(&self, name: &str) -> anyhow::Result<()> {
+                self.0.delete(name).await
+            }
+        }
+
+        let source = MemorySource::default();
+        let data = gzipped(10);
+        let chunk_size = data.len() as u64 / 7;
+        source.0.lock().unwrap().insert("a.gz".to_string(), data);
+        let fetcher = Arc::new(
+            Fetcher::new(Ranged(source), true, ObjectFilter::default())
+                .with_parallel_ranges(Some(chunk_size), 3),
+        );
+
+        let rt = runtime();
+        let mut log_set = fetch_all(&rt, &fetcher).pop().unwrap();
+        assert_eq!(count_entries(&rt, &mut log_set), 10);
     }
 }
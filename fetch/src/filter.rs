@@ -0,0 +1,570 @@
+//! Rules for which objects in the bucket are log files.
+//!
+//! Buckets pick up stray non-log objects (manifests, lifecycle markers, my own test uploads).
+//! Rather than failing to parse those, we pass over them and record that we did.
+
+use std::{fmt, time::Duration};
+
+use anyhow::{bail, Context};
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use regex_lite::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{bundle::ZIP_MAGIC, format::LogFormat};
+
+/// Magic numbers at the start of compressed data.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+
+/// How much of the start of an object `check_contents` needs to see.
+pub(crate) const CONTENTS_CHECK_LEN: usize = ZSTD_MAGIC.len();
+
+/// How much of the start of an uncompressed object `check_contents` sees at most, looking for
+/// the end of its first line: enough for a header row.
+pub(crate) const PLAIN_CHECK_LEN: usize = 1024;
+
+/// How an object (or a stream) is compressed, per the magic number at its start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    /// Not compressed, or not in a way we know of.
+    Plain,
+}
+
+impl Compression {
+    /// Detect the compression from at least CONTENTS_CHECK_LEN bytes of the start.
+    pub(crate) fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if data.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if data.starts_with(&BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else {
+            Compression::Plain
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+            Compression::Plain => "uncompressed",
+        })
+    }
+}
+
+/// What to do with zero-byte objects.
+///
+/// Fastly sometimes writes empty log files. Checking for them requires object metadata
+/// from the listing, which some backends have to make extra requests for; so it's opt-in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum EmptyObjects {
+    /// Fetch empty objects like any other (and fail to decode them).
+    #[default]
+    Fetch,
+    /// Skip empty objects without fetching them.
+    Skip,
+    /// Delete empty objects without fetching them.
+    Delete,
+}
+
+/// Rules for skipping objects that aren't log files,
+/// and for selecting which log files to crunch in a run.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectFilter {
+    /// If nonempty, only objects whose names end in one of these are fetched.
+    extensions: Vec<String>,
+    /// Objects whose names match any of these are not fetched.
+    skip: Vec<Regex>,
+    /// Only objects whose names start with this are selected.
+    prefix: String,
+    /// If nonempty, only objects matching one of these globs are selected.
+    globs: Vec<Regex>,
+    /// Only objects with timestamps in this window are selected.
+    window: Option<TimeWindow>,
+    /// strftime-style format of the timestamps in object names, if not the default.
+    name_time_format: Option<String>,
+}
+
+/// What a filter selects, as a backfill manifest records it, so it's applied with the same.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    /// The globs, as regexes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub globs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip: Vec<String>,
+    pub name_time_format: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<NaiveDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<NaiveDateTime>,
+    /// The window's `last`, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seconds: Option<i64>,
+}
+
+/// Default format of the timestamps in object names, as Fastly writes them:
+/// e.g. `2024-06-01T00:00:00.000-abc123.log.gz`.
+pub const DEFAULT_NAME_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+/// A range of times, matched against the timestamps in object names.
+#[derive(Debug, Clone)]
+struct TimeWindow {
+    /// Inclusive start.
+    since: Option<NaiveDateTime>,
+    /// Exclusive end.
+    until: Option<NaiveDateTime>,
+    /// How far back from now to start, as of each check; so a long-running process keeps
+    /// moving the window along.
+    last: Option<TimeDelta>,
+}
+
+impl TimeWindow {
+    fn contains(&self, time: NaiveDateTime) -> bool {
+        let recent = self.last.is_none_or(|last| {
+            Utc::now()
+                .naive_utc()
+                .checked_sub_signed(last)
+                .is_none_or(|since| time >= since)
+        });
+        recent
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time < until)
+    }
+}
+
+/// Find the timestamp in an object's name: the first position in its last path segment
+/// where the format matches.
+fn name_time(name: &str, format: &str) -> Option<NaiveDateTime> {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    file_name.char_indices().find_map(|(i, _)| {
+        let rest = &file_name[i..];
+        NaiveDateTime::parse_and_remainder(rest, format)
+            .map(|(time, _)| time)
+            .or_else(|_| {
+                NaiveDate::parse_and_remainder(rest, format)
+                    .map(|(date, _)| date.and_hms_opt(0, 0, 0).unwrap())
+            })
+            .ok()
+    })
+}
+
+/// Parse a time for `--since` or `--until`: a date, or a date and time, in UTC.
+pub fn parse_time(s: &str) -> anyhow::Result<NaiveDateTime> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(time);
+        }
+    }
+    bail!("could not parse time {s}: expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS")
+}
+
+/// Parse a duration like "90s", "5m", "6h", or "7d".
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let unit_at = s
+        .find(|c: char| !c.is_ascii_digit())
+        .with_context(|| format!("could not parse duration {s}: no unit (s, m, h, or d)"))?;
+    let (count, unit) = s.split_at(unit_at);
+    let count: u64 = count
+        .parse()
+        .with_context(|| format!("could not parse duration {s}"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("could not parse duration {s}: unknown unit {unit:?} (expected s, m, h, or d)"),
+    };
+    count
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .with_context(|| format!("duration {s} is too long"))
+}
+
+/// Parse a size like "2GiB", "500MiB", or "1048576" (bytes).
+pub fn parse_bytes(s: &str) -> anyhow::Result<u64> {
+    let unit_at = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (count, unit) = s.split_at(unit_at);
+    let count: u64 = count
+        .parse()
+        .with_context(|| format!("could not parse size {s}"))?;
+    let shift = match unit {
+        "" | "B" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        "TiB" => 40,
+        _ => bail!(
+            "could not parse size {s}: unknown unit {unit:?} (expected KiB, MiB, GiB, or TiB)"
+        ),
+    };
+    count
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size {s} is too large"))
+}
+
+impl ObjectFilter {
+    /// Create a filter from a list of allowed extensions and a list of skip patterns (regexes).
+    pub fn new(
+        extensions: impl IntoIterator<Item = String>,
+        skip: impl IntoIterator<Item = String>,
+    ) -> anyhow::Result<Self> {
+        let skip: anyhow::Result<Vec<Regex>> = skip
+            .into_iter()
+            .map(|pattern| {
+                Regex::new(&pattern).with_context(|| format!("invalid skip pattern {pattern}"))
+            })
+            .collect();
+        Ok(ObjectFilter {
+            extensions: extensions.into_iter().collect(),
+            skip: skip?,
+            ..Default::default()
+        })
+    }
+
+    /// Only select objects whose names start with the prefix, e.g. `2024/06/`.
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        ObjectFilter {
+            prefix: prefix.into(),
+            ..self
+        }
+    }
+
+    /// Only select objects matching one of the globs, if any are given.
+    ///
+    /// `*` and `?` match within a path segment, `**` matches across segments.
+    /// A glob without a `/` is matched against the last segment of the name (the "file name"),
+    /// so `*.log.gz` matches objects in any directory.
+    pub fn with_globs(self, globs: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let globs: anyhow::Result<Vec<Regex>> = globs
+            .into_iter()
+            .map(|glob| {
+                Regex::new(&glob_to_regex(&glob)).with_context(|| format!("invalid glob {glob}"))
+            })
+            .collect();
+        Ok(ObjectFilter {
+            globs: globs?,
+            ..self
+        })
+    }
+
+    /// Only select objects whose names have timestamps in the window: at or after `since`,
+    /// and before `until`. `format` describes the timestamp, in strftime style.
+    ///
+    /// When a window is set, objects without a timestamp in their names aren't selected.
+    pub fn with_time_window(
+        self,
+        format: impl Into<String>,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Self {
+        let last = self.window.as_ref().and_then(|window| window.last);
+        let window = (since.is_some() || until.is_some() || last.is_some()).then_some(TimeWindow {
+            since,
+            until,
+            last,
+        });
+        ObjectFilter {
+            window,
+            name_time_format: Some(format.into()),
+            ..self
+        }
+    }
+
+    /// Only select objects whose names have timestamps within `last` of now, e.g. the last
+    /// 7 days; as with the time window, objects without timestamps aren't selected.
+    pub fn with_last(self, last: Duration) -> Self {
+        let last = Some(TimeDelta::from_std(last).unwrap_or(TimeDelta::max_value()));
+        let window = match self.window {
+            Some(window) => TimeWindow { last, ..window },
+            None => TimeWindow {
+                since: None,
+                until: None,
+                last,
+            },
+        };
+        ObjectFilter {
+            window: Some(window),
+            ..self
+        }
+    }
+
+    /// Narrow the time window to `since` (inclusive) until `until` (exclusive), keeping the
+    /// format and any `last`.
+    pub(crate) fn within(self, since: NaiveDateTime, until: NaiveDateTime) -> Self {
+        let last = self.window.as_ref().and_then(|window| window.last);
+        let since = self
+            .window
+            .as_ref()
+            .and_then(|window| window.since)
+            .map_or(since, |outer| outer.max(since));
+        let until = self
+            .window
+            .as_ref()
+            .and_then(|window| window.until)
+            .map_or(until, |outer| outer.min(until));
+        ObjectFilter {
+            window: Some(TimeWindow {
+                since: Some(since),
+                until: Some(until),
+                last,
+            }),
+            ..self
+        }
+    }
+
+    /// The timestamp in the object's name, per the format given with the time window.
+    pub fn name_time(&self, name: &str) -> Option<NaiveDateTime> {
+        let format = self
+            .name_time_format
+            .as_deref()
+            .unwrap_or(DEFAULT_NAME_TIME_FORMAT);
+        name_time(name, format)
+    }
+
+    /// What the filter selects.
+    pub(crate) fn selection(&self) -> Selection {
+        let regexes =
+            |regexes: &[Regex]| regexes.iter().map(|re| re.as_str().to_string()).collect();
+        let window = self.window.as_ref();
+        Selection {
+            prefix: self.prefix.clone(),
+            globs: regexes(&self.globs),
+            extensions: self.extensions.clone(),
+            skip: regexes(&self.skip),
+            name_time_format: self
+                .name_time_format
+                .as_deref()
+                .unwrap_or(DEFAULT_NAME_TIME_FORMAT)
+                .to_string(),
+            since: window.and_then(|window| window.since),
+            until: window.and_then(|window| window.until),
+            last_seconds: window
+                .and_then(|window| window.last)
+                .map(|last| last.num_seconds()),
+        }
+    }
+
+    /// The prefix that selected objects start with.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Check whether the object is selected for this run.
+    ///
+    /// Unlike objects skipped by `check_name`, unselected objects aren't recorded at all;
+    /// they're left for some other run.
+    pub fn selects(&self, name: &str) -> bool {
+        name.starts_with(&self.prefix)
+            && (self.globs.is_empty() || self.globs.iter().any(|re| re.is_match(name)))
+            && self.window.as_ref().is_none_or(|window| {
+                let time = self.name_time(name);
+                if time.is_none() {
+                    tracing::debug!("no timestamp in object name {name}");
+                }
+                time.is_some_and(|time| window.contains(time))
+            })
+    }
+
+    /// Check the object name, before fetching it.
+    ///
+    /// Returns the reason to skip the object, if it should be skipped.
+    pub fn check_name(&self, name: &str) -> Option<String> {
+        if !self.extensions.is_empty() && !self.extensions.iter().any(|ext| name.ends_with(ext)) {
+            return Some("unexpected extension".to_string());
+        }
+        self.skip
+            .iter()
+            .find(|re| re.is_match(name))
+            .map(|re| format!("matches skip pattern {}", re.as_str()))
+    }
+
+    /// Check the start of the object's contents, after starting to fetch it.
+    /// Uncompressed data is taken to be logs if `format` accepts it, so objects in the same
+    /// format that aren't logs (JSON manifests, say) need a skip pattern.
+    ///
+    /// Returns the reason to skip the object, if it should be skipped.
+    pub(crate) fn check_contents(&self, data: &[u8], format: &dyn LogFormat) -> Option<String> {
+        match Compression::detect(data) {
+            Compression::Plain if data.starts_with(&ZIP_MAGIC) => None,
+            Compression::Plain if format.accepts_plain(data) => None,
+            Compression::Plain => {
+                Some("not compressed, a zip archive, or logs in the format".to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Translate a glob into an (anchored) regex over object names.
+fn glob_to_regex(glob: &str) -> String {
+    // Globs without a directory part can match in any directory.
+    let mut re = if glob.contains('/') {
+        "^".to_string()
+    } else {
+        "^(.*/)?".to_string()
+    };
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re += ".*";
+            }
+            '*' => re += "[^/]*",
+            '?' => re += "[^/]",
+            c => re += &regex_lite::escape(&c.to_string()),
+        }
+    }
+    re + "$"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::{TimeDelta, Utc};
+
+    use crate::Format;
+
+    use super::{parse_bytes, parse_duration, parse_time, ObjectFilter, DEFAULT_NAME_TIME_FORMAT};
+
+    #[test]
+    fn skips_by_name() {
+        let filter = ObjectFilter::new([".log.gz".to_string()], ["^manifest".to_string()]).unwrap();
+        assert_eq!(
+            filter.check_name("2024-06-01T00:00:00.000-abc.log.gz"),
+            None
+        );
+        assert!(filter
+            .check_name("2024-06-01T00:00:00.000-abc.json")
+            .is_some());
+        assert!(filter.check_name("manifest.log.gz").is_some());
+    }
+
+    #[test]
+    fn selects_by_prefix_and_glob() {
+        let filter = ObjectFilter::default()
+            .with_prefix("2024/06/")
+            .with_globs(["*.log.gz".to_string()])
+            .unwrap();
+        assert!(filter.selects("2024/06/01/a.log.gz"));
+        assert!(!filter.selects("2024/06/01/a.json"));
+        assert!(!filter.selects("2024/07/01/a.log.gz"));
+        let filter = ObjectFilter::default()
+            .with_globs(["2024/*/a?.log.gz".to_string()])
+            .unwrap();
+        assert!(filter.selects("2024/06/ab.log.gz"));
+        assert!(!filter.selects("2024/06/01/ab.log.gz"));
+    }
+
+    #[test]
+    fn selects_by_name_time() {
+        let filter = ObjectFilter::default().with_time_window(
+            DEFAULT_NAME_TIME_FORMAT,
+            Some(parse_time("2024-06-01").unwrap()),
+            Some(parse_time("2024-06-02T12:00:00").unwrap()),
+        );
+        assert!(filter.selects("logs/2024-06-01T00:00:00.000-abc.log.gz"));
+        assert!(filter.selects("2024-06-02T11:59:59.999-abc.log.gz"));
+        assert!(!filter.selects("2024-06-02T12:00:00.000-abc.log.gz"));
+        assert!(!filter.selects("2024-05-31T23:00:00.000-abc.log.gz"));
+        assert!(!filter.selects("manifest.json"));
+        // Narrowed, as for a backfill batch, within the window it had.
+        let batch = filter.clone().within(
+            parse_time("2024-06-02").unwrap(),
+            parse_time("2024-06-03").unwrap(),
+        );
+        assert!(batch.selects("2024-06-02T11:59:59.999-abc.log.gz"));
+        assert!(!batch.selects("2024-06-02T12:00:00.000-abc.log.gz"));
+        assert!(!batch.selects("logs/2024-06-01T00:00:00.000-abc.log.gz"));
+        let filter = ObjectFilter::default().with_time_window(
+            "%Y%m%d",
+            Some(parse_time("2024-06-01").unwrap()),
+            None,
+        );
+        assert!(filter.selects("access-20240601.gz"));
+        assert!(!filter.selects("access-20240531.gz"));
+    }
+
+    #[test]
+    fn selects_recent_names() {
+        let filter = ObjectFilter::default().with_last(parse_duration("7d").unwrap());
+        let name = |days_ago| {
+            let time = Utc::now() - TimeDelta::days(days_ago);
+            format!("{}-abc.log.gz", time.format(DEFAULT_NAME_TIME_FORMAT))
+        };
+        assert!(filter.selects(&name(0)));
+        assert!(filter.selects(&name(6)));
+        assert!(!filter.selects(&name(8)));
+        assert!(!filter.selects("manifest.json"));
+    }
+
+    #[test]
+    fn sniffs_compression() {
+        let filter = ObjectFilter::default();
+        let json = Format::Fastly.parser(&BTreeMap::new(), false);
+        let check = |data: &[u8]| filter.check_contents(data, &*json);
+        assert_eq!(check(&[0x1f, 0x8b, 0x08, 0x00]), None);
+        assert_eq!(check(b"{\"hello\": 1}"), None);
+        assert_eq!(check(b"PK\x03\x04"), None);
+        assert_eq!(check(b"BZh9"), None);
+        assert_eq!(check(&[0x28, 0xb5, 0x2f, 0xfd]), None);
+        assert!(check(b"hello").is_some());
+        assert!(check(&[]).is_some());
+        assert!(check(b"time,url\n").is_some());
+
+        let csv = Format::Csv.parser(&BTreeMap::new(), false);
+        let check = |data: &[u8]| filter.check_contents(data, &*csv);
+        assert_eq!(check(b"time,url\n2024-06-01T00:00:00Z,/\n"), None);
+        assert_eq!(check(b"time,url"), None);
+        assert!(check(b"{\"hello\": 1}").is_some());
+        assert!(check(b"time\n,\n").is_some());
+        assert!(check(b"a,b\0").is_some());
+        let tsv = Format::Tsv.parser(&BTreeMap::new(), false);
+        assert_eq!(filter.check_contents(b"time\turl\n", &*tsv), None);
+        let cloudfront = Format::Cloudfront.parser(&BTreeMap::new(), false);
+        assert_eq!(
+            filter.check_contents(b"#Version: 1.0\n", &*cloudfront),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 86400)
+        );
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("18446744073709551615d").is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_bytes("1048576").unwrap(), 1 << 20);
+        assert_eq!(parse_bytes("2GiB").unwrap(), 2 << 30);
+        assert!(parse_bytes("2GB").is_err());
+        assert!(parse_bytes("GiB").is_err());
+    }
+}
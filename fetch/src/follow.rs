@@ -0,0 +1,140 @@
+//! Following local directories for new log files, with inotify.
+//!
+//! A file counts as new once it's finished: written and closed, as by a program writing
+//! rotated logs out, or moved into place, as by logrotate. Files still open for writing
+//! (the live access.log) don't count; leave them out of the run with the object filter.
+
+use std::{collections::HashMap, os::fd::AsFd, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+};
+
+/// How long the directories have to be quiet before a run: rotating logs tends to finish
+/// (or compress) several files in a row.
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// Watches on directories, and their subdirectories, for finished files.
+pub(crate) struct Follower {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Follower {
+    pub(crate) fn new(dirs: &[PathBuf]) -> anyhow::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+            .context("could not start inotify")?;
+        let mut follower = Follower {
+            inotify,
+            dirs: HashMap::new(),
+        };
+        for dir in dirs {
+            follower.add(dir.clone())?;
+        }
+        Ok(follower)
+    }
+
+    /// Watch `dir` and everything under it.
+    fn add(&mut self, dir: PathBuf) -> anyhow::Result<()> {
+        let wd = self
+            .inotify
+            .add_watch(
+                &dir,
+                AddWatchFlags::IN_CLOSE_WRITE
+                    | AddWatchFlags::IN_MOVED_TO
+                    | AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_ONLYDIR,
+            )
+            .with_context(|| format!("could not watch {}", dir.display()))?;
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("could not read directory {}", dir.display()))?;
+        self.dirs.insert(wd, dir);
+        for entry in entries {
+            let entry = entry.context("could not read directory entry")?;
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.add(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until a file is finished somewhere in the directories, and they've settled.
+    pub(crate) fn wait(&mut self) -> anyhow::Result<()> {
+        while !self.read(PollTimeout::NONE)? {}
+        while self.read(PollTimeout::try_from(SETTLE).expect("settle time fits"))? {}
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for events, and handle them.
+    /// Returns whether any of them finished a file (or might have).
+    fn read(&mut self, timeout: PollTimeout) -> anyhow::Result<bool> {
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(false),
+            Ok(_) => (),
+            Err(e) => return Err(e).context("could not wait for inotify events"),
+        }
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(false),
+            Err(e) => return Err(e).context("could not read inotify events"),
+        };
+        let mut finished = false;
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                // Lost track; a run lists everything anyway.
+                tracing::warn!("inotify queue overflowed");
+                finished = true;
+            } else if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.dirs.remove(&event.wd);
+            } else if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                // A new directory (or one moved in) may already have files in it.
+                if let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), &event.name) {
+                    let dir = dir.join(name);
+                    if let Err(e) = self.add(dir) {
+                        tracing::warn!("{e:#}");
+                    }
+                    finished = true;
+                }
+            } else if !event.mask.contains(AddWatchFlags::IN_CREATE) {
+                if let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), &event.name) {
+                    tracing::debug!("finished: {}", dir.join(name).display());
+                }
+                finished = true;
+            }
+        }
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::Follower;
+
+    #[test]
+    fn sees_finished_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a")).unwrap();
+        let mut follower = Follower::new(&[dir.path().to_path_buf()]).unwrap();
+        let timeout = nix::poll::PollTimeout::from(100u16);
+
+        // Still open for writing: not finished.
+        let mut file = fs::File::create(dir.path().join("a/access.log")).unwrap();
+        file.write_all(b"{}\n").unwrap();
+        file.sync_all().unwrap();
+        assert!(!follower.read(timeout).unwrap());
+        drop(file);
+        assert!(follower.read(timeout).unwrap());
+
+        // Rotated into place.
+        fs::write(dir.path().join("tmp"), b"{}\n").unwrap();
+        follower.read(timeout).unwrap();
+        fs::rename(dir.path().join("tmp"), dir.path().join("a/access.log.1")).unwrap();
+        assert!(follower.read(timeout).unwrap());
+    }
+}
@@ -0,0 +1,196 @@
+//! Formats of log files, and parsing them into entries.
+//!
+//! A format turns a log file, once it's decompressed, into entries. What reads the file (the
+//! fetcher, stdin, syslog) and what stores the entries don't need to know which format it is,
+//! so another can be added by implementing LogFormat for it.
+
+use std::{collections::BTreeMap, io::BufRead, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use serde::de::DeserializeOwned;
+use serde_json::{error::Category, Map, Value};
+
+use crate::{
+    cloudflare::Cloudflare,
+    cloudfront::CloudFront,
+    delimited::Delimited,
+    mapped::MappedJson,
+    record::{LogEntry, STRICT_KEYS},
+    streamhack::CommaHacker,
+};
+
+/// The formats logs can be in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Format {
+    /// Fastly's JSON, as LogEntry has it, or with keys mapped to fields; see the mapped module.
+    #[default]
+    Fastly,
+    /// CloudFront's standard logs; see the cloudfront module.
+    Cloudfront,
+    /// Cloudflare's Logpush JSON; see the cloudflare module.
+    Cloudflare,
+    /// Comma-separated values with a header row; see the delimited module.
+    Csv,
+    /// Tab-separated values with a header row; see the delimited module.
+    Tsv,
+}
+
+impl Format {
+    /// The parser for the format; `fields` maps fields to keys of Fastly's JSON, or to columns
+    /// of CSV and TSV. With `strict`, Fastly's JSON must have every key in STRICT_KEYS.
+    pub(crate) fn parser(
+        self,
+        fields: &BTreeMap<String, String>,
+        strict: bool,
+    ) -> Arc<dyn LogFormat> {
+        match self {
+            Format::Fastly if fields.is_empty() && !strict => Arc::new(FastlyJson),
+            Format::Fastly => Arc::new(MappedJson::new(fields, strict)),
+            Format::Cloudfront => Arc::new(CloudFront),
+            Format::Cloudflare => Arc::new(Cloudflare),
+            Format::Csv => Arc::new(Delimited::new(b',', fields)),
+            Format::Tsv => Arc::new(Delimited::new(b'\t', fields)),
+        }
+    }
+}
+
+/// A format of log files.
+pub(crate) trait LogFormat: Send + Sync {
+    /// Parse the entries in a log file, in order.
+    ///
+    /// After an Invalid entry, parsing goes on with the next, so it can be skipped; after an
+    /// entry that Ended, there are no more.
+    fn parse<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Box<dyn Iterator<Item = Result<LogEntry, EntryError>> + 'a>;
+
+    /// Whether uncompressed data starting with `start` looks like a log file in this format,
+    /// rather than something else in the bucket (a manifest, say). By default, that's JSON.
+    fn accepts_plain(&self, start: &[u8]) -> bool {
+        start.trim_ascii_start().starts_with(b"{")
+    }
+}
+
+/// Why an entry couldn't be parsed.
+#[derive(Debug)]
+pub(crate) enum EntryError {
+    /// The input ended partway through the entry, or couldn't be read: where a truncated
+    /// object gives out.
+    Ended(anyhow::Error),
+    /// The entry isn't valid in the format.
+    Invalid(anyhow::Error),
+}
+
+impl EntryError {
+    pub(crate) fn into_inner(self) -> anyhow::Error {
+        match self {
+            EntryError::Ended(e) | EntryError::Invalid(e) => e,
+        }
+    }
+}
+
+/// Fastly's JSON, as LogEntry has it: objects one after another, usually one per line.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct FastlyJson;
+
+impl LogFormat for FastlyJson {
+    fn parse<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Box<dyn Iterator<Item = Result<LogEntry, EntryError>> + 'a> {
+        Box::new(json_records(input))
+    }
+}
+
+/// The entry in a record of Fastly's JSON, once its keys are LogEntry's.
+pub(crate) fn fastly_entry(record: Map<String, Value>) -> Result<LogEntry, EntryError> {
+    serde_json::from_value(Value::Object(record))
+        .context("invalid entry")
+        .map_err(EntryError::Invalid)
+}
+
+/// Fail a record of Fastly's JSON that's missing any key in STRICT_KEYS.
+pub(crate) fn check_strict(record: &Map<String, Value>) -> Result<(), EntryError> {
+    let missing: Vec<&str> = STRICT_KEYS
+        .iter()
+        .copied()
+        .filter(|key| !record.contains_key(*key))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(EntryError::Invalid(anyhow!(
+            "entry is missing {}",
+            missing.join(", ")
+        )))
+    }
+}
+
+/// Records of JSON, one after another, as in Fastly's or Cloudflare's logs.
+///
+/// After invalid JSON, records pick up again on the next line.
+pub(crate) fn json_records<'a, T: DeserializeOwned + 'a>(
+    input: Box<dyn BufRead + 'a>,
+) -> impl Iterator<Item = Result<T, EntryError>> + 'a {
+    // Get rid of trailing commas at top-level JSON objects. Oops.
+    let mut reader = CommaHacker::new(input);
+    let mut ended = false;
+    std::iter::from_fn(move || {
+        if ended {
+            return None;
+        }
+        // A deserializer for each record, as one can't go on after an error.
+        let first = reader.next_line();
+        let result = serde_json::Deserializer::from_reader(&mut reader)
+            .into_iter()
+            .next()?;
+        // Depending on where the cut is, truncation shows up either as the JSON
+        // ending mid-entry or as some flavor of I/O error from the decompressor.
+        Some(result.map_err(|e| {
+            if e.is_eof() || e.is_io() {
+                ended = true;
+                EntryError::Ended(e.into())
+            } else {
+                // Column 0 is the newline ending the line before.
+                let line = (first + e.line()).saturating_sub(1 + usize::from(e.column() == 0));
+                reader.skip_past(line);
+                let context = match e.classify() {
+                    Category::Data => "invalid entry",
+                    _ => "invalid JSON",
+                };
+                EntryError::Invalid(anyhow::Error::new(e).context(context))
+            }
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryError, FastlyJson, LogFormat};
+    use crate::testdata::ENTRY;
+
+    #[test]
+    fn parses_fastly_json() {
+        let input = format!("{ENTRY}\n{ENTRY}\n");
+        let entries: Vec<_> = FastlyJson.parse(Box::new(input.as_bytes())).collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(Result::is_ok));
+
+        let cut = &input[..input.len() - 10];
+        let mut entries = FastlyJson.parse(Box::new(cut.as_bytes()));
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(entries.next(), Some(Err(EntryError::Ended(_)))));
+
+        let mut entries = FastlyJson.parse(Box::new(&b"{nope}\n"[..]));
+        assert!(matches!(entries.next(), Some(Err(EntryError::Invalid(_)))));
+
+        // Parsing picks up again on the line after invalid JSON.
+        let input = format!("{{\"clientIP\": \"192.0\n{ENTRY}\n");
+        let mut entries = FastlyJson.parse(Box::new(input.as_bytes()));
+        assert!(matches!(entries.next(), Some(Err(EntryError::Invalid(_)))));
+        assert!(entries.next().unwrap().is_ok());
+        assert!(entries.next().is_none());
+    }
+}
@@ -0,0 +1,149 @@
+//! Generations of GCS objects, which OpenDAL doesn't expose, through GCS's JSON API.
+//!
+//! Every version of a GCS object has a generation number. In a bucket with object versioning
+//! or soft delete, deleting an object keeps its last generation around for the bucket's
+//! retention window, and it can be restored from it. Cleanup records the generations of the
+//! objects it deletes, so `undelete` can put a run's objects back.
+
+use anyhow::{bail, Context};
+use reqsign::{GoogleCredentialLoader, GoogleSigner, GoogleTokenLoader};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+
+/// Google's API endpoint, unless the source gives another.
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// The scope OpenDAL asks for, unless the source gives another.
+const DEFAULT_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Looks up and restores generations of objects in a bucket.
+pub(crate) struct Generations {
+    client: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    tokens: GoogleTokenLoader,
+    signer: GoogleSigner,
+}
+
+impl Generations {
+    /// Authenticate as OpenDAL does: with the service account key, if given, otherwise the
+    /// default credentials.
+    pub(crate) fn new(
+        bucket: &str,
+        credentials: Option<&str>,
+        scope: Option<&str>,
+        endpoint: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::new();
+        let mut loader = GoogleCredentialLoader::default();
+        if let Some(path) = credentials {
+            loader = loader.with_path(path);
+        }
+        let mut tokens = GoogleTokenLoader::new(scope.unwrap_or(DEFAULT_SCOPE), client.clone());
+        if let Some(credential) = loader.load().context("could not load GCS credentials")? {
+            tokens = tokens.with_credentials(credential);
+        }
+        Ok(Generations {
+            client,
+            endpoint: endpoint
+                .unwrap_or(DEFAULT_ENDPOINT)
+                .trim_end_matches('/')
+                .to_string(),
+            bucket: bucket.to_string(),
+            tokens,
+            signer: GoogleSigner::new("storage"),
+        })
+    }
+
+    /// The object's URL in the JSON API, and `rest` after it.
+    fn url(&self, name: &str, rest: &str) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}{rest}",
+            self.endpoint,
+            encode(&self.bucket),
+            encode(name)
+        )
+    }
+
+    async fn send(&self, method: Method, url: String) -> anyhow::Result<reqwest::Response> {
+        let token = self
+            .tokens
+            .load()
+            .await
+            .context("could not get GCS token")?
+            .context("no GCS credentials")?;
+        let mut request = self.client.request(method.clone(), &url);
+        if method == Method::POST {
+            // Copies take the new object's metadata, for which none is as good as any.
+            request = request
+                .header("Content-Type", "application/json")
+                .body("{}");
+        }
+        let mut request = request.build().context("could not build GCS request")?;
+        self.signer
+            .sign(&mut request, &token)
+            .context("could not sign GCS request")?;
+        self.client
+            .execute(request)
+            .await
+            .with_context(|| format!("GCS request to {url} failed"))
+    }
+
+    /// The object's current generation.
+    pub(crate) async fn generation(&self, name: &str) -> anyhow::Result<String> {
+        #[derive(Deserialize)]
+        struct Object {
+            generation: String,
+        }
+        let response = self
+            .send(Method::GET, self.url(name, "?fields=generation"))
+            .await?
+            .error_for_status()
+            .with_context(|| format!("could not get generation of {name}"))?;
+        let object: Object = response
+            .json()
+            .await
+            .with_context(|| format!("could not read generation of {name}"))?;
+        Ok(object.generation)
+    }
+
+    /// Put a deleted object back from its generation, unless something's been written in its
+    /// place since.
+    pub(crate) async fn restore(&self, name: &str, generation: &str) -> anyhow::Result<()> {
+        // A noncurrent version, in a versioned bucket, is copied back over the name...
+        let copy = format!(
+            "/copyTo/b/{}/o/{}?sourceGeneration={generation}&ifGenerationMatch=0",
+            encode(&self.bucket),
+            encode(name)
+        );
+        let response = self.send(Method::POST, self.url(name, &copy)).await?;
+        let response = if response.status() == StatusCode::NOT_FOUND {
+            // ...while a soft-deleted object is restored in place.
+            let restore = format!("/restore?generation={generation}&ifGenerationMatch=0");
+            self.send(Method::POST, self.url(name, &restore)).await?
+        } else {
+            response
+        };
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::PRECONDITION_FAILED => bail!("{name} already exists"),
+            StatusCode::NOT_FOUND => {
+                bail!("generation {generation} of {name} is gone; the retention window is over")
+            }
+            status => bail!("could not restore {name} from generation {generation}: {status}"),
+        }
+    }
+}
+
+/// Percent-encode a path segment of a URL.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
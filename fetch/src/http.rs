@@ -0,0 +1,300 @@
+//! An HTTP endpoint for streamed logs, as Fastly's HTTPS logging endpoints send them: each POST
+//! is a batch of entries, one per line, gzipped or not.
+//!
+//! This speaks plain HTTP/1.1. Fastly only sends logs over HTTPS, so put it behind a proxy that
+//! terminates TLS (Caddy, nginx). Batches have to carry the shared secret in the
+//! `X-Cruncher-Secret` header; set it as a custom header on the logging endpoint.
+//!
+//! Before sending logs, Fastly checks that the endpoint wants them: it fetches
+//! `/.well-known/fastly/logging/challenge`, and expects the SHA-256 of its service ID back.
+//!
+//! Batches aren't a run each: they're queued, and those that come in within RUN_INTERVAL of
+//! each other are crunched in one run, each answered once it's stored. Fastly sends a batch
+//! again if it gets no answer, so each is recorded as an object named for it, by its
+//! `Idempotency-Key` header or else its SHA-256; one that's been stored already is answered
+//! without being stored again.
+
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::Incoming, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
+
+/// Header with the shared secret.
+pub(crate) const SECRET_HEADER: &str = "X-Cruncher-Secret";
+
+/// Header naming a batch, so it's only stored once however often it's sent.
+pub(crate) const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+const CHALLENGE_PATH: &str = "/.well-known/fastly/logging/challenge";
+
+/// Requests with more header than this are refused.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// Batches bigger than this are refused. Fastly's are 10 MB at most.
+const MAX_BODY_LEN: usize = 64 << 20;
+
+/// How long a client has to send a request's header, and then its body.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const BODY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long batches are gathered for a run, from the first to come in.
+pub(crate) const RUN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many batches can wait for a run before requests wait to queue theirs.
+pub(crate) const QUEUE_LEN: usize = 1024;
+
+/// Who the endpoint accepts batches from.
+pub(crate) struct Endpoint {
+    secret: String,
+    /// Fastly services to answer the challenge for; if empty, any.
+    service_ids: Vec<String>,
+}
+
+/// A batch, waiting for a run to store it.
+pub(crate) struct Posted {
+    /// The object it's recorded as.
+    pub(crate) name: String,
+    pub(crate) body: Bytes,
+    /// Where to send how it went.
+    pub(crate) done: oneshot::Sender<anyhow::Result<Stored>>,
+}
+
+/// What came of storing a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stored {
+    /// Stored, with this many entries.
+    Entries(usize),
+    /// Stored already, when it was sent before.
+    Duplicate,
+}
+
+impl Endpoint {
+    pub(crate) fn new(secret: String, service_ids: Vec<String>) -> Self {
+        Endpoint {
+            secret,
+            service_ids,
+        }
+    }
+
+    /// Serve connections from the listener, queueing authenticated batches for runs.
+    pub(crate) async fn serve(self: Arc<Self>, listener: TcpListener, queue: mpsc::Sender<Posted>) {
+        loop {
+            let (conn, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::error!("could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            let endpoint = Arc::clone(&self);
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let (endpoint, queue) = (Arc::clone(&endpoint), queue.clone());
+                    async move { Ok::<_, Infallible>(endpoint.respond(request, &queue).await) }
+                });
+                let result = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(HEADER_TIMEOUT)
+                    .max_buf_size(MAX_HEADER_LEN)
+                    .serve_connection(TokioIo::new(conn), service)
+                    .await;
+                if let Err(e) = result {
+                    tracing::debug!("error in serving connection from {peer}: {e}");
+                }
+            });
+        }
+    }
+
+    /// Answer a request, queueing its batch, if it has one, and waiting for it to be stored.
+    async fn respond(
+        &self,
+        request: Request<Incoming>,
+        queue: &mpsc::Sender<Posted>,
+    ) -> Response<Full<Bytes>> {
+        if request.method() == Method::GET && request.uri().path() == CHALLENGE_PATH {
+            return response(StatusCode::OK, self.challenge());
+        }
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+        };
+        if !header(SECRET_HEADER).is_some_and(|secret| same_secret(&secret, &self.secret)) {
+            return response(
+                StatusCode::UNAUTHORIZED,
+                format!("missing or wrong {SECRET_HEADER}\n"),
+            );
+        }
+        if request.method() != Method::POST {
+            return response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "only POST batches of logs\n".to_string(),
+            );
+        }
+        let key = header(IDEMPOTENCY_HEADER).filter(|key| !key.is_empty());
+        let body = Limited::new(request.into_body(), MAX_BODY_LEN);
+        let body = match tokio::time::timeout(BODY_TIMEOUT, body.collect()).await {
+            Ok(Ok(body)) => body.to_bytes(),
+            Ok(Err(e)) => {
+                return response(
+                    StatusCode::BAD_REQUEST,
+                    format!("could not read batch: {e}\n"),
+                )
+            }
+            Err(_) => {
+                return response(
+                    StatusCode::REQUEST_TIMEOUT,
+                    "timed out reading batch\n".to_string(),
+                )
+            }
+        };
+        let key = key.unwrap_or_else(|| format!("{:x}", Sha256::digest(&body)));
+        let (done, stored) = oneshot::channel();
+        let posted = Posted {
+            name: format!("http:{key}"),
+            body,
+            done,
+        };
+        if queue.send(posted).await.is_err() {
+            return response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not crunching batches\n".to_string(),
+            );
+        }
+        match stored.await {
+            Ok(Ok(Stored::Entries(count))) => response(StatusCode::OK, format!("ok {count}\n")),
+            Ok(Ok(Stored::Duplicate)) => response(StatusCode::OK, "ok, stored already\n".into()),
+            Ok(Err(e)) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("error {e:#}\n")),
+            Err(_) => response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "run stopped before storing batch\n".to_string(),
+            ),
+        }
+    }
+
+    /// The response to Fastly's challenge: the hashes of the services allowed to send logs.
+    fn challenge(&self) -> String {
+        if self.service_ids.is_empty() {
+            return "*\n".to_string();
+        }
+        self.service_ids
+            .iter()
+            .map(|id| format!("{:x}\n", Sha256::digest(id.as_bytes())))
+            .collect()
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+}
+
+/// Compare secrets without giving away how much of a guess was right by how long it took.
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::{Arc, Mutex},
+    };
+
+    use sha2::{Digest, Sha256};
+    use tokio::sync::mpsc;
+
+    use super::{Endpoint, Stored};
+
+    #[test]
+    fn accepts_authenticated_batches() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let endpoint = Arc::new(Endpoint::new(
+            "hunter2".to_string(),
+            vec!["svc".to_string()],
+        ));
+        let (queue, mut batches) = mpsc::channel(1);
+        rt.spawn(endpoint.serve(listener, queue));
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let queued = Arc::clone(&names);
+        rt.spawn(async move {
+            while let Some(posted) = batches.recv().await {
+                let lines = posted.body.iter().filter(|&&b| b == b'\n').count();
+                queued.lock().unwrap().push(posted.name);
+                posted.done.send(Ok(Stored::Entries(lines))).unwrap();
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(
+            client,
+            "GET /.well-known/fastly/logging/challenge HTTP/1.1\r\nHost: x\r\n\r\n\
+            POST / HTTP/1.1\r\nHost: x\r\nX-Cruncher-Secret: hunter2\r\n\
+            Idempotency-Key: batch-1\r\nContent-Length: 6\r\n\r\na\nb\nc\n\
+            POST / HTTP/1.1\r\nHost: x\r\nX-Cruncher-Secret: hunter3\r\nConnection: close\r\n\
+            Content-Length: 2\r\n\r\nd\n"
+        )
+        .unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+
+        let statuses: Vec<_> = responses
+            .lines()
+            .filter(|line| line.starts_with("HTTP/1.1"))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                "HTTP/1.1 200 OK",
+                "HTTP/1.1 200 OK",
+                "HTTP/1.1 401 Unauthorized"
+            ]
+        );
+        // SHA-256 of "svc".
+        assert!(responses
+            .contains("348c658682ae8701d3e9d21f191872491cf15e6acbb1681770b1cb787c1cf7ff\n"));
+        assert!(responses.contains("ok 3\n"));
+
+        // Without a key, a batch is named by its hash.
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(
+            client,
+            "POST / HTTP/1.1\r\nHost: x\r\nX-Cruncher-Secret: hunter2\r\nConnection: close\r\n\
+            Content-Length: 2\r\n\r\nd\n"
+        )
+        .unwrap();
+        client.read_to_string(&mut String::new()).unwrap();
+        assert_eq!(
+            *names.lock().unwrap(),
+            [
+                "http:batch-1".to_string(),
+                format!("http:{:x}", Sha256::digest(b"d\n"))
+            ]
+        );
+    }
+}
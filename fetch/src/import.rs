@@ -0,0 +1,242 @@
+//! Imports of history from other analytics tools, so reports can span from before the logs.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::DateTime;
+use sha2::{Digest, Sha256};
+
+use crate::{config::Sqlite, cruncher::Cruncher, csv::Records, record::LogEntry, BATCH_SIZE};
+
+/// Import a goatcounter CSV export (Settings → Export) into the database, each pageview as a
+/// request from `source`, e.g. "goatcounter", so reports can tell them apart.
+///
+/// Goatcounter doesn't keep addresses, sizes, or timings, so imported requests are from
+/// 0.0.0.0 with status 200, and no AS, bytes, or duration; only the path, referer, user
+/// agent, country, and time carry over. Events and bots are left out, as goatcounter leaves
+/// them out of its own pageview counts.
+///
+/// An export with the same contents as one already imported is skipped. It's imported in one
+/// transaction, with the record that it was, so one that fails part way can be imported again.
+/// Returns how many requests were imported.
+pub fn import_goatcounter(csv: &Path, db: &Path, source: &str) -> anyhow::Result<usize> {
+    let contents =
+        std::fs::read(csv).with_context(|| format!("could not read {}", csv.display()))?;
+    let name = csv.display().to_string();
+    let content_hash = format!("{:x}", Sha256::digest(&contents));
+    let cruncher = Cruncher::open(db, &Sqlite::default())?;
+    // Against every object, this one included: the same export under a new name is a repeat too.
+    if let Some(original) = cruncher.find_duplicate(None, "", &content_hash)? {
+        tracing::info!("skipping {name}: same contents as {original}, already imported");
+        return Ok(0);
+    }
+
+    let mut records = Records::new(contents.as_slice());
+    let header = records
+        .next()
+        .context("export is empty")?
+        .context("could not read header")?;
+    let columns = Columns::new(&header)?;
+    let tx = cruncher.begin()?;
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    for (i, record) in records.enumerate() {
+        // Line numbers, counting the header; records don't span lines in practice.
+        let line = i + 2;
+        let record = record.with_context(|| format!("in record at line {line}"))?;
+        if let Some(entry) = columns
+            .entry(&record, source)
+            .with_context(|| format!("in record at line {line}"))?
+        {
+            batch.push(entry);
+        }
+        if batch.len() == BATCH_SIZE {
+            tx.store(&batch)?;
+            count += batch.len();
+            batch.clear();
+        }
+    }
+    tx.store(&batch)?;
+    count += batch.len();
+    tx.record_object(
+        Some(source),
+        &name,
+        "ok",
+        Some("goatcounter import"),
+        Some(&content_hash),
+    )?;
+    tx.commit()?;
+    tracing::info!("imported {count} pageviews from {name}");
+    Ok(count)
+}
+
+/// Where each field is in a goatcounter export's records.
+struct Columns(HashMap<String, usize>);
+
+impl Columns {
+    fn new(header: &[String]) -> anyhow::Result<Self> {
+        let columns = Columns(
+            header
+                .iter()
+                .enumerate()
+                // The first column has the export format's version in front, e.g. "2Path".
+                .map(|(i, name)| {
+                    (
+                        name.trim_start_matches(|c: char| c.is_ascii_digit())
+                            .to_string(),
+                        i,
+                    )
+                })
+                .collect(),
+        );
+        for name in [
+            "Path",
+            "Event",
+            "UserAgent",
+            "Bot",
+            "Referrer",
+            "Referrer scheme",
+            "Location",
+            "Date",
+        ] {
+            columns.index(name)?;
+        }
+        Ok(columns)
+    }
+
+    fn index(&self, name: &str) -> anyhow::Result<usize> {
+        self.0
+            .get(name)
+            .copied()
+            .ok_or_else(|| anyhow!("export has no {name} column; is it from goatcounter?"))
+    }
+
+    fn get<'a>(&self, record: &'a [String], name: &str) -> anyhow::Result<&'a str> {
+        let i = self.index(name)?;
+        record
+            .get(i)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("record has no {name} field"))
+    }
+
+    /// The request for a pageview; None for events and bots.
+    fn entry(&self, record: &[String], source: &str) -> anyhow::Result<Option<LogEntry>> {
+        if self.get(record, "Event")? == "true" || !matches!(self.get(record, "Bot")?, "" | "0") {
+            return Ok(None);
+        }
+        // Goatcounter keeps referrers without their scheme, or names like "Hacker News" for
+        // generated (g) or campaign (c) ones.
+        let referrer = self.get(record, "Referrer")?;
+        let referer = match self.get(record, "Referrer scheme")? {
+            "h" if !referrer.is_empty() => format!("https://{referrer}"),
+            _ => referrer.to_string(),
+        };
+        // Countries, or regions like "US-CA".
+        let country_code = self
+            .get(record, "Location")?
+            .split('-')
+            .next()
+            .filter(|country| !country.is_empty())
+            .map(str::to_string);
+        let date = self.get(record, "Date")?;
+        let request_start_time = DateTime::parse_from_rfc3339(date)
+            .with_context(|| format!("invalid date {date}"))?
+            .to_utc();
+        Ok(Some(LogEntry {
+            client_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            asn: None,
+            country_code,
+            requests: 1,
+            ipv6: false,
+            http2: None,
+            url_path: self.get(record, "Path")?.to_string(),
+            query: None,
+            referer: Some(referer),
+            user_agent: Some(self.get(record, "UserAgent")?.to_string()),
+            cache_state: None,
+            response_status: 200,
+            response_bytes: None,
+            response_duration: None,
+            request_start_time: request_start_time.into(),
+            language: None,
+            request_header_bytes: None,
+            request_body_bytes: None,
+            cache_age: None,
+            cache_ttl: None,
+            request_method: None,
+            request_protocol: None,
+            host: None,
+            service_id: None,
+            raw_path: None,
+            tags: Vec::new(),
+            source: Some(source.to_string()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::import_goatcounter;
+
+    const EXPORT: &str = "\
+2Path,Title,Event,UserAgent,Browser,System,Session,Bot,Referrer,Referrer scheme,Screen size,\
+Location,FirstVisit,Date
+/writing/a/,\"A, an article\",false,Mozilla/5.0,Firefox 120,Linux,,0,news.ycombinator.com/item,h,\
+1920,US-CA,true,2020-02-20T10:19:50Z
+/writing/a/,\"A, an article\",false,Mozilla/5.0,Firefox 120,Linux,,0,Hacker News,g,1920,NL,false,\
+2020-02-21T10:19:50Z
+signup-click,,true,Mozilla/5.0,Firefox 120,Linux,,0,,,1920,NL,false,2020-02-21T10:20:00Z
+/,Home,false,Googlebot,,,,4,,,,,false,2020-02-21T10:21:00Z
+";
+
+    #[test]
+    fn imports_pageviews_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (csv, db) = (dir.path().join("export.csv"), dir.path().join("logs.db"));
+
+        // An export that fails part way imports nothing, and can be imported again once fixed.
+        std::fs::write(
+            &csv,
+            format!("{EXPORT}/b/,B,false,,,,,0,,,,,false,yesterday\n"),
+        )
+        .unwrap();
+        assert!(import_goatcounter(&csv, &db, "goatcounter").is_err());
+        std::fs::write(&csv, EXPORT).unwrap();
+
+        assert_eq!(import_goatcounter(&csv, &db, "goatcounter").unwrap(), 2);
+        assert_eq!(import_goatcounter(&csv, &db, "goatcounter").unwrap(), 0);
+
+        let rows: Vec<(String, Option<String>, String)> = rusqlite::Connection::open(&db)
+            .unwrap()
+            .prepare(
+                "SELECT referers.referer, country_code, sources.name FROM requests
+                    JOIN referers ON requests.referer = referers.id
+                    JOIN sources ON requests.source = sources.id
+                ORDER BY requests.id",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "https://news.ycombinator.com/item".to_string(),
+                    Some("US".to_string()),
+                    "goatcounter".to_string()
+                ),
+                (
+                    "Hacker News".to_string(),
+                    Some("NL".to_string()),
+                    "goatcounter".to_string()
+                ),
+            ]
+        );
+    }
+}
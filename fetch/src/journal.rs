@@ -0,0 +1,146 @@
+//! Reading entries from the systemd journal, through journalctl.
+//!
+//! Each run picks up after the last entry the previous run stored, by the journal's cursor,
+//! which is stored in the database alongside the entries.
+//!
+//! journalctl is killed if the journal is dropped before it's finished, e.g. after an error.
+
+use std::{
+    io::{self, BufRead},
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+/// A unit's (or syslog identifier's) entries in the journal, from journalctl.
+pub(crate) struct Journal {
+    child: Child,
+    lines: io::Lines<io::BufReader<ChildStdout>>,
+}
+
+/// A journal entry, as journalctl writes it with `--output json`.
+#[derive(Deserialize)]
+struct JournalEntry {
+    #[serde(rename = "__CURSOR")]
+    cursor: String,
+    /// Null if the message is too large, per journalctl's --all.
+    #[serde(rename = "MESSAGE")]
+    message: Option<Message>,
+}
+
+/// Messages that aren't valid UTF-8 come as arrays of bytes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Journal {
+    /// Start reading entries from `unit`'s journal, optionally only those with the syslog
+    /// `identifier`, after the entry at `cursor`, if given.
+    pub(crate) fn open(
+        unit: &str,
+        identifier: Option<&str>,
+        cursor: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut command = Command::new("journalctl");
+        command.args(["--output=json", "--no-pager", "--all", "--unit", unit]);
+        if let Some(identifier) = identifier {
+            command.args(["--identifier", identifier]);
+        }
+        if let Some(cursor) = cursor {
+            command.args(["--after-cursor", cursor]);
+        }
+        Self::spawn(command)
+    }
+
+    /// Start reading journalctl's output, or whatever stands in for it.
+    fn spawn(mut command: Command) -> anyhow::Result<Self> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("could not run journalctl")?;
+        let stdout = child.stdout.take().context("no output from journalctl")?;
+        Ok(Journal {
+            child,
+            lines: io::BufReader::new(stdout).lines(),
+        })
+    }
+
+    /// Check that journalctl exited successfully, once its entries have been read.
+    pub(crate) fn finish(mut self) -> anyhow::Result<()> {
+        let status = self.child.wait().context("could not wait for journalctl")?;
+        if !status.success() {
+            return Err(anyhow!("journalctl failed: {status}"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        // Once it's been waited for, this does nothing.
+        if let Err(e) = self.child.kill().and_then(|()| self.child.wait()) {
+            tracing::error!("could not stop journalctl: {}", e);
+        }
+    }
+}
+
+impl Iterator for Journal {
+    /// An entry, as a line of JSON for parse_entry.
+    type Item = anyhow::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        Some(line.context("could not read from journalctl"))
+    }
+}
+
+/// An entry's cursor and message.
+pub(crate) fn parse_entry(line: &str) -> anyhow::Result<(String, String)> {
+    let entry: JournalEntry =
+        serde_json::from_str(line).context("could not parse journal entry")?;
+    let message = match entry.message {
+        Some(Message::Text(text)) => text,
+        Some(Message::Bytes(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+        None => String::new(),
+    };
+    Ok((entry.cursor, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use super::{parse_entry, Journal};
+
+    #[test]
+    fn stops_journalctl_when_dropped() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo '{}'; exec sleep 60"]);
+        let mut journal = Journal::spawn(command).unwrap();
+        assert_eq!(journal.next().unwrap().unwrap(), "{}");
+        let pid = journal.child.id();
+        drop(journal);
+        assert!(!Path::new(&format!("/proc/{pid}")).exists());
+    }
+
+    #[test]
+    fn parses_journal_entries() {
+        assert_eq!(
+            parse_entry(
+                r#"{"__CURSOR":"s=1;i=2","MESSAGE":"{\"status\":\"200\"}","PRIORITY":"6"}"#
+            )
+            .unwrap(),
+            ("s=1;i=2".to_string(), r#"{"status":"200"}"#.to_string())
+        );
+        assert_eq!(
+            parse_entry(r#"{"__CURSOR":"s=1;i=3","MESSAGE":[104,105,255]}"#)
+                .unwrap()
+                .1,
+            "hi\u{fffd}"
+        );
+    }
+}
@@ -1,100 +1,2421 @@
+mod backfill;
+mod backup;
+mod bundle;
+#[cfg(feature = "charts")]
+mod chart;
+#[cfg(feature = "clap")]
+mod cli;
+mod cloudflare;
+mod cloudfront;
+mod concurrency;
+mod config;
 mod cruncher;
+mod csv;
+mod delimited;
+mod dimensions;
+mod doctor;
+mod explain;
+mod export;
 mod fetcher;
+mod filter;
+mod follow;
+mod format;
+mod gcs;
+mod http;
+mod import;
+mod journal;
+mod listing;
+mod mapped;
+mod nginx;
+mod notify;
+mod packs;
+mod pipeline;
+mod preview;
+mod quota;
 mod record;
+mod retry;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "asn-seed")]
+mod seed;
+mod setup;
+mod source;
 mod streamhack;
+mod syslog;
+mod table;
+mod tagging;
+#[cfg(test)]
+mod testdata;
+mod undelete;
+mod writer;
 
-use anyhow::Context;
-use record::LogEntry;
+use anyhow::{bail, Context};
+pub use record::LogEntry;
+use sha2::{Digest, Sha256};
 use std::{
-    io::{self},
+    cell::Cell,
+    io::{self, BufRead, Read},
     path::{Path, PathBuf},
+    pin::Pin,
     sync::Arc,
+    time::Duration,
 };
-use streamhack::CommaHacker;
 use tokio::runtime::Runtime;
+use tokio_stream::{Stream, StreamExt};
 
-use fetcher::Fetcher;
+pub use backfill::{BackfillBatch, BatchStatus, Manifest};
+pub use backup::Backup;
+#[cfg(feature = "clap")]
+pub use cli::{completions, man_page, parse_args, Shell};
+pub use concurrency::Concurrency;
+pub use config::{ComputedColumn, Config, Enrichment, Segment, Slo, Sqlite};
+pub use dimensions::{suggest_rewrites, Dimension, Dimensions, Rewrite, Suggestion, OVERFLOW};
+pub use doctor::Check;
+pub use explain::{explain, Plan};
+pub use export::export_public_db;
+pub use fetcher::Object;
+use fetcher::{Archive, DeletionLatch, Fetcher};
+pub use filter::{
+    parse_bytes, parse_duration, parse_time, EmptyObjects, ObjectFilter, Selection,
+    DEFAULT_NAME_TIME_FORMAT,
+};
+use filter::{Compression, CONTENTS_CHECK_LEN};
+use follow::Follower;
+pub use format::Format;
+use format::{EntryError, LogFormat};
+pub use import::import_goatcounter;
+use journal::Journal;
+use nginx::NginxEntry;
+pub use notify::{Message, RunSummary, Webhook, WebhookKind};
+pub use packs::Pack;
+pub use pipeline::Objects;
+pub use preview::Preview;
+pub use quota::Quotas;
+pub use retry::Retry;
+#[cfg(feature = "scripting")]
+pub use script::Script;
+pub use setup::{confirm, Setup};
+pub use source::{
+    ByteStream, LogSource, ObjectInfo, ObjectStream, OpendalSource, SourceSpec, UrlListSource,
+};
+pub use table::Table;
+pub use tagging::{reclassify, Pattern, Reclassified, TagRule};
+pub use undelete::{undelete, Undeleted};
+pub use writer::send as send_to_writer;
 
-/// LogSet is a handle to a set of logs.
-pub struct LogSet<T> {
+/// LogSet is a handle to a set of logs, which are parsed in batches as the object is read.
+pub struct LogSet {
     pub name: String,
-    pub data: Vec<T>,
-    source: Arc<Fetcher>,
+    /// The source it was fetched from, in runs over several.
+    pub source: Option<String>,
+    batches: Pin<Box<dyn Stream<Item = anyhow::Result<Batch>> + Send + Sync>>,
+    fetcher: Arc<Fetcher>,
 }
 
-impl TryFrom<LogSet<u8>> for LogSet<LogEntry> {
-    type Error = anyhow::Error;
+/// A piece of a log set.
+pub(crate) enum Batch {
+    /// Some of the object's entries.
+    Entries(Vec<LogEntry>),
+    /// The end of the object.
+    End {
+        /// The object ended early; the entries are those decoded before the truncation.
+        truncated: bool,
+        /// How many invalid entries were skipped.
+        skipped: usize,
+        /// SHA-256 of the object's contents, as fetched.
+        content_hash: String,
+    },
+}
 
-    fn try_from(value: LogSet<u8>) -> Result<Self, Self::Error> {
-        // Decompress the record.
-        let cursor = io::Cursor::new(value.data);
-        let cursor = flate2::bufread::GzDecoder::new(cursor);
-        // ...and get rid of trailing commas at top-level JSON objects. Oops.
-        let cursor = CommaHacker::new(std::io::BufReader::new(cursor));
-        let entries: anyhow::Result<Vec<LogEntry>> = serde_json::Deserializer::from_reader(cursor)
-            .into_iter()
-            .enumerate()
-            .map(|(i, result)| result.with_context(|| format!("JSON parse error in entry {i}")))
-            .collect();
-        Ok(LogSet {
-            data: entries.with_context(|| format!("in log set {}", &value.name))?,
-            name: value.name,
-            source: value.source,
-        })
+impl LogSet {
+    /// Wait for the next batch of the log set.
+    pub(crate) async fn next_batch(&mut self) -> anyhow::Result<Batch> {
+        self.batches
+            .next()
+            .await
+            .context("log set ended without completing")?
+    }
+
+    /// Pass the entries through `stage` as they're parsed, before they're stored: e.g. to
+    /// filter, sample, or mirror them. An error fails the log set.
+    pub fn map_entries<F>(self, mut stage: F) -> Self
+    where
+        F: FnMut(Vec<LogEntry>) -> anyhow::Result<Vec<LogEntry>> + Send + Sync + 'static,
+    {
+        let batches = self.batches.map(move |batch| match batch {
+            Ok(Batch::Entries(entries)) => stage(entries).map(Batch::Entries),
+            end => end,
+        });
+        LogSet {
+            batches: Box::pin(batches),
+            ..self
+        }
+    }
+}
+
+/// How many entries to parse, transform, and store at a time.
+const BATCH_SIZE: usize = 10_000;
+
+/// How many of the invalid entries skipped in an object to log the errors of.
+const SKIPPED_SAMPLES: usize = 3;
+
+/// How long each run lasts when receiving syslog; see Cruncher::serve_syslog.
+const SYSLOG_RUN_LENGTH: Duration = Duration::from_secs(5 * 60);
+
+/// How many batches of syslog messages can wait to be stored before connections wait to
+/// queue theirs.
+const SYSLOG_QUEUE_LEN: usize = 64;
+
+/// How many objects each source fetches at once to start, with adaptive concurrency.
+const INITIAL_CONCURRENCY: usize = 4;
+
+/// The stream, decompressed by whatever compression it starts with; all of it, if it's
+/// several compressed streams back-to-back, as from concatenated objects.
+pub(crate) fn decompress<'a>(mut input: impl io::Read + 'a) -> io::Result<Box<dyn io::Read + 'a>> {
+    let mut magic = Vec::new();
+    input
+        .by_ref()
+        .take(CONTENTS_CHECK_LEN as u64)
+        .read_to_end(&mut magic)?;
+    let compression = Compression::detect(&magic);
+    let input = io::Cursor::new(magic).chain(input);
+    Ok(match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(input)?),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(input)),
+        Compression::Plain => Box::new(input),
+    })
+}
+
+/// Decompress (as detected) and parse an object's entries in `format`, handing them to `emit`
+/// in batches.
+///
+/// An object may be a bundle of log files (a tar or zip archive); each is parsed in turn, with
+/// errors naming the member they're in.
+///
+/// If `salvage` is set, a truncated object (e.g. one that was only partially delivered)
+/// yields the entries before the truncation point, and is marked as truncated.
+/// Otherwise, truncation is an error.
+///
+/// If `skip_invalid` is set, invalid entries are skipped, and counted, up to `max_invalid`;
+/// otherwise, the first fails the object. An object with no valid entries, only invalid ones,
+/// fails either way.
+fn parse_object(
+    input: impl io::Read,
+    format: &dyn LogFormat,
+    salvage: bool,
+    skip_invalid: bool,
+    max_invalid: MaxInvalid,
+    mut emit: impl FnMut(Vec<LogEntry>) -> anyhow::Result<()>,
+) -> anyhow::Result<Parsed> {
+    let at_eof = Cell::new(false);
+    let mut input = io::BufReader::new(HashingReader {
+        inner: input,
+        hasher: Sha256::new(),
+        at_eof: &at_eof,
+    });
+    let mut truncated = false;
+    let mut skipped = Skipped::default();
+    let mut valid = 0;
+    let mut emit = |entries: Vec<LogEntry>| {
+        valid += entries.len();
+        emit(entries)
+    };
+    {
+        let mut member = |name: &str, data: &mut dyn BufRead| -> anyhow::Result<()> {
+            let skipped = skip_invalid.then_some(&mut skipped);
+            truncated |= parse_member(data, format, &at_eof, salvage, skipped, &mut emit)
+                .with_context(|| format!("in member {name}"))?;
+            Ok(())
+        };
+        let start = input.fill_buf().context("could not read object")?;
+        let complete = if start.starts_with(&bundle::ZIP_MAGIC) {
+            bundle::zip_members(&mut input, &mut member).map(|()| true)
+        } else {
+            // Decompress the record, if it's compressed (all of it, if it's several compressed
+            // streams back-to-back)...
+            let mut decoder: Box<dyn BufRead> = match Compression::detect(start) {
+                Compression::Gzip => Box::new(io::BufReader::new(
+                    flate2::bufread::MultiGzDecoder::new(&mut input),
+                )),
+                Compression::Zstd => Box::new(io::BufReader::new(
+                    zstd::stream::read::Decoder::with_buffer(&mut input)
+                        .context("could not start decompressing object")?,
+                )),
+                Compression::Bzip2 => Box::new(io::BufReader::new(
+                    bzip2::bufread::MultiBzDecoder::new(&mut input),
+                )),
+                Compression::Plain => Box::new(&mut input),
+            };
+            // ...and see if it's a tar archive.
+            let mut head = Vec::with_capacity(bundle::TAR_BLOCK);
+            match decoder
+                .by_ref()
+                .take(bundle::TAR_BLOCK as u64)
+                .read_to_end(&mut head)
+            {
+                Ok(_) if bundle::is_tar(&head) => {
+                    bundle::tar_members(&mut io::Cursor::new(head).chain(decoder), &mut member)
+                        .map(|()| true)
+                }
+                Ok(_) => {
+                    let stream = io::Cursor::new(head).chain(decoder);
+                    let skipped = skip_invalid.then_some(&mut skipped);
+                    truncated =
+                        parse_entries(stream, format, &at_eof, salvage, skipped, &mut emit)?;
+                    Ok(true)
+                }
+                // Cut off before the first block; what came out may still be entries.
+                Err(_) if salvage && at_eof.get() => {
+                    let head = io::Cursor::new(head);
+                    let skipped = skip_invalid.then_some(&mut skipped);
+                    parse_entries(head, format, &at_eof, salvage, skipped, &mut emit)?;
+                    Ok(false)
+                }
+                Err(e) => Err(e).context("could not decompress object"),
+            }
+        };
+        match complete {
+            Ok(true) => (),
+            // Cut off before the first block.
+            Ok(false) if salvage => truncated = true,
+            // Archives running out partway through, or failing to decompress at the end; an
+            // entry that doesn't parse still fails the object, however near the end it is.
+            Err(e) if salvage && at_eof.get() && e.chain().any(|e| e.is::<io::Error>()) => {
+                truncated = true
+            }
+            Ok(false) => bail!("truncated archive"),
+            Err(e) => return Err(e),
+        }
+    }
+    skipped.check(valid, max_invalid)?;
+    // Anything after the archive is part of the object's contents, too.
+    io::copy(&mut input, &mut io::sink()).context("could not read end of object")?;
+    let input = input.into_inner();
+    Ok(Parsed {
+        truncated,
+        skipped,
+        content_hash: format!("{:x}", input.hasher.finalize()),
+    })
+}
+
+/// What came of parsing an object.
+pub(crate) struct Parsed {
+    pub(crate) truncated: bool,
+    pub(crate) skipped: Skipped,
+    /// SHA-256 of the object's contents.
+    pub(crate) content_hash: String,
+}
+
+/// Invalid entries skipped in an object, rather than failing it.
+#[derive(Debug, Default)]
+pub(crate) struct Skipped {
+    pub(crate) count: usize,
+    /// The first few entries' errors, for the log.
+    pub(crate) samples: Vec<String>,
+}
+
+impl Skipped {
+    fn add(&mut self, i: usize, e: anyhow::Error) {
+        self.count += 1;
+        if self.samples.len() < SKIPPED_SAMPLES {
+            self.samples.push(format!("entry {i}: {e:#}"));
+        }
+    }
+
+    /// Fail if there were too many skipped, alongside `valid` entries: more than `max`, or
+    /// all of them.
+    fn check(&self, valid: usize, max: MaxInvalid) -> anyhow::Result<()> {
+        if valid == 0 && self.count > 0 {
+            bail!(
+                "all {} entries are invalid, e.g. {}",
+                self.count,
+                self.samples.join("; ")
+            );
+        }
+        if max.exceeded(self.count, valid) {
+            bail!(
+                "{} of {} entries are invalid, more than the {max} allowed, e.g. {}",
+                self.count,
+                self.count + valid,
+                self.samples.join("; ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parse a log file in a bundle, compressed or not. Returns whether it was truncated.
+///
+/// Uncompressed members that aren't in the format are skipped.
+fn parse_member(
+    data: &mut dyn BufRead,
+    format: &dyn LogFormat,
+    at_eof: &Cell<bool>,
+    salvage: bool,
+    skipped: Option<&mut Skipped>,
+    emit: &mut impl FnMut(Vec<LogEntry>) -> anyhow::Result<()>,
+) -> anyhow::Result<bool> {
+    let start = data.fill_buf().context("could not read member")?;
+    match Compression::detect(start) {
+        Compression::Gzip => {
+            let decoder = io::BufReader::new(flate2::bufread::MultiGzDecoder::new(data));
+            parse_entries(decoder, format, at_eof, salvage, skipped, emit)
+        }
+        Compression::Zstd => {
+            let decoder = zstd::stream::read::Decoder::with_buffer(data)
+                .context("could not start decompressing member")?;
+            parse_entries(
+                io::BufReader::new(decoder),
+                format,
+                at_eof,
+                salvage,
+                skipped,
+                emit,
+            )
+        }
+        Compression::Bzip2 => {
+            let decoder = io::BufReader::new(bzip2::bufread::MultiBzDecoder::new(data));
+            parse_entries(decoder, format, at_eof, salvage, skipped, emit)
+        }
+        Compression::Plain if format.accepts_plain(start) => {
+            parse_entries(data, format, at_eof, salvage, skipped, emit)
+        }
+        Compression::Plain if start.is_empty() => Ok(false),
+        Compression::Plain => {
+            tracing::info!("skipping member: not compressed, or logs in the format");
+            Ok(false)
+        }
+    }
+}
+
+/// Parse a stream of entries, handing them to `emit` in batches.
+///
+/// Returns whether the stream was truncated: if it ended early because the object did,
+/// per `at_eof`, and `salvage` is set. Invalid entries are noted in `skipped` and passed
+/// over, if it's given; otherwise, they're an error.
+fn parse_entries(
+    input: impl BufRead,
+    format: &dyn LogFormat,
+    at_eof: &Cell<bool>,
+    salvage: bool,
+    mut skipped: Option<&mut Skipped>,
+    emit: &mut impl FnMut(Vec<LogEntry>) -> anyhow::Result<()>,
+) -> anyhow::Result<bool> {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut truncated = false;
+    for (i, result) in format.parse(Box::new(input)).enumerate() {
+        match result {
+            Ok(entry) => batch.push(entry),
+            // Running out where the object did is truncation; elsewhere, it's a bad entry.
+            Err(EntryError::Ended(e)) if at_eof.get() => {
+                if !salvage {
+                    return Err(e).with_context(|| format!("truncated stream at entry {i}"));
+                }
+                truncated = true;
+                break;
+            }
+            Err(e) => match (e, skipped.as_deref_mut()) {
+                (EntryError::Invalid(e), Some(skipped)) => skipped.add(i, e),
+                (e, _) => {
+                    return Err(e.into_inner()).with_context(|| format!("parse error in entry {i}"))
+                }
+            },
+        }
+        if batch.len() == BATCH_SIZE {
+            emit(std::mem::replace(
+                &mut batch,
+                Vec::with_capacity(BATCH_SIZE),
+            ))?;
+        }
+    }
+    if !batch.is_empty() {
+        emit(batch)?;
+    }
+    Ok(truncated)
+}
+
+/// Hashes the data read through it, and notes when the underlying reader runs out.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: Sha256,
+    at_eof: &'a Cell<bool>,
+}
+
+impl<R: io::Read> io::Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.at_eof.set(true);
+        }
+        self.hasher.update(&buf[..n]);
+        Ok(n)
     }
 }
 
 /// Fetch and crunch the logs into the database.
 pub struct Cruncher {
-    pub gcs_path: String,
+    /// Where to fetch logs from. Objects from several sources are interleaved into one run,
+    /// and each request records which source it came from. Stdin can only be used alone.
+    pub sources: Vec<SourceSpec>,
     pub database: PathBuf,
+    /// What format the logs are in; except the journal's, which are nginx's
+    pub format: Format,
+    /// Fail Fastly entries that are missing any field, rather than storing NULL for it
+    pub strict_fields: bool,
+    /// How many objects to fetch at once; with adaptive_concurrency, the most to fetch at once.
     pub concurrency: usize,
 
+    /// Start with a few fetches at once, and raise or lower it by throughput and errors
+    pub adaptive_concurrency: bool,
+
+    /// Fetch and parse at most this many bytes of objects at once, by their listed sizes,
+    /// split between the sources
+    pub max_inflight_bytes: Option<u64>,
+
+    /// Download objects larger than this in parts of this size, several at a time
+    pub range_chunk_size: Option<u64>,
+    /// How many parts of an object to download at a time
+    pub range_parallelism: usize,
+
     /// Delete the logs after completion
     pub cleanup: bool,
+
+    /// With cleanup, move processed objects under this prefix before deleting them
+    pub archive_prefix: Option<String>,
+
+    /// With cleanup, move processed objects to this store (under archive_prefix, if given)
+    pub archive_to: Option<SourceSpec>,
+
+    /// Recompress archived objects with zstd at this level
+    pub archive_zstd_level: Option<i32>,
+
+    /// With cleanup, move objects that fail to process under this prefix
+    pub dead_letter_prefix: Option<String>,
+
+    /// After any error in the run, delete (or move) nothing more, even objects that crunched
+    pub stop_deleting_on_error: bool,
+
+    /// Which objects to treat as logs
+    pub filter: ObjectFilter,
+
+    /// What to do with empty objects
+    pub empty_objects: EmptyObjects,
+
+    /// Keep the complete entries from truncated objects, rather than failing them
+    pub salvage_truncated: bool,
+
+    /// Skip invalid entries, storing the rest of their objects, rather than failing the objects
+    pub skip_invalid_entries: bool,
+
+    /// With skip_invalid_entries, fail objects (or streams) with more invalid entries than
+    /// this anyway
+    pub max_invalid_entries: MaxInvalid,
+
+    /// Don't crunch objects with the same contents as an already-processed object
+    pub skip_duplicates: bool,
+
+    /// Check each object's size and MD5 against the listing, failing it if they don't match
+    pub verify: bool,
+
+    /// Keep the database friendly to tools reading or replicating it live (Datasette,
+    /// Litestream): WAL mode, write locks held only while writing, and a checkpoint after
+    /// each run. (Nothing here VACUUMs the database; leave that for downtime.)
+    pub serve_friendly: bool,
+
+    /// Store no requests, only the daily rollups: a tiny database, for stats alone
+    pub aggregate_only: bool,
+
+    /// Store each request only once, by its dedup key, however many objects it's read from,
+    /// from now on (Some(true)), or stop (Some(false)); if None, as the database already does
+    pub dedup_requests: Option<bool>,
+
+    /// Store the query strings split from paths, rather than dropping them
+    pub store_queries: bool,
+
+    /// Normalize paths before storing them (see LogEntry::normalize_path), keeping each as
+    /// logged in requests.raw_path where that changes it
+    pub normalize_paths: bool,
+
+    /// Lowercase paths as they're normalized
+    pub lowercase_paths: bool,
+
+    /// Fetch the oldest objects first, by the timestamps in their names or their mtimes
+    pub oldest_first: bool,
+
+    /// Keep each source's listing in this directory, and only list objects newer than it has,
+    /// where the store and object names allow
+    pub listing_cache: Option<PathBuf>,
+
+    /// Take uncompressed objects to be ones that GCS decompresses as it sends them
+    /// (decompressive transcoding), which can't be verified or resumed
+    pub gcs_transcoding: bool,
+
+    /// Fetch at most this many objects in the run
+    pub max_objects: Option<usize>,
+
+    /// Stop fetching new objects once this many bytes have been fetched
+    pub max_bytes: Option<u64>,
+
+    /// Stop the run if too many of its first objects fail
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    /// Settings from the configuration file
+    pub config: Config,
+
+    /// Packs of views and enrichments to apply to the database
+    pub packs: Vec<Pack>,
+
+    /// Script to transform, tag, or drop entries before they are stored
+    #[cfg(feature = "scripting")]
+    pub script: Option<Script>,
 }
 
 impl Cruncher {
+    /// Check the settings and configuration before starting, reporting everything wrong with
+    /// them at once.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        if self.sources.is_empty() {
+            problems.push("no sources to fetch from".to_string());
+        }
+        if self.sources.len() > 1 {
+            for source in &self.sources {
+                if matches!(source, SourceSpec::Stdin | SourceSpec::Journald { .. }) {
+                    problems.push(format!("{source} can't be read alongside other sources"));
+                }
+            }
+        }
+        if let Some(dir) = self
+            .database
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            if !dir.is_dir() {
+                problems.push(format!(
+                    "the database's directory {} doesn't exist",
+                    dir.display()
+                ));
+            }
+        }
+        if self.concurrency == 0 {
+            problems.push("concurrency must be at least 1".to_string());
+        }
+        for (setting, value) in [
+            ("max_objects", self.max_objects.map(|n| n as u64)),
+            ("max_inflight_bytes", self.max_inflight_bytes),
+            ("range_chunk_size", self.range_chunk_size),
+        ] {
+            if value == Some(0) {
+                problems.push(format!("{setting} must be more than 0"));
+            }
+        }
+        if self.range_chunk_size.is_some() && self.range_parallelism == 0 {
+            problems.push("range_parallelism must be at least 1".to_string());
+        }
+        let moving = [
+            ("archive_prefix", self.archive_prefix.is_some()),
+            ("archive_to", self.archive_to.is_some()),
+            ("dead_letter_prefix", self.dead_letter_prefix.is_some()),
+            ("stop_deleting_on_error", self.stop_deleting_on_error),
+        ];
+        for (setting, _) in moving.iter().filter(|(_, set)| *set) {
+            if !self.cleanup {
+                problems.push(format!(
+                    "{setting} does nothing without cleanup, which leaves objects in place"
+                ));
+            }
+        }
+        if self.archive_to.is_none() && self.archive_prefix.as_deref() == Some("") {
+            problems.push("archiving within the source needs a prefix to archive to".to_string());
+        }
+        if let Some(level) = self.archive_zstd_level {
+            if self.archive_prefix.is_none() && self.archive_to.is_none() {
+                problems.push("archive_zstd_level needs an archive to recompress into".to_string());
+            }
+            if !(1..=22).contains(&level) {
+                problems.push(format!("archive_zstd_level must be 1 to 22, not {level}"));
+            }
+        }
+        if let MaxInvalid::Percent(percent) = self.max_invalid_entries {
+            if !(0.0..=100.0).contains(&percent) {
+                problems.push(format!(
+                    "max_invalid_entries must be from 0% to 100%, not {percent}%"
+                ));
+            }
+        }
+        if let Some(breaker) = &self.circuit_breaker {
+            if breaker.objects == 0 {
+                problems.push("the circuit breaker needs at least 1 object to judge".to_string());
+            }
+            if !(0.0..100.0).contains(&breaker.max_failure_percent) {
+                problems.push(format!(
+                    "the circuit breaker's failure rate must be at least 0% and under 100%, \
+                    not {}%",
+                    breaker.max_failure_percent
+                ));
+            }
+        }
+        let journal = self
+            .sources
+            .iter()
+            .any(|source| matches!(source, SourceSpec::Journald { .. }));
+        if journal && self.format != Format::default() {
+            problems.push(format!(
+                "the journal has nginx's logs, which can't be read as {:?}",
+                self.format
+            ));
+        }
+        if self.lowercase_paths && !self.normalize_paths {
+            problems.push("lowercase_paths does nothing without normalize_paths".to_string());
+        }
+        if self.strict_fields && self.format != Format::Fastly {
+            problems.push(format!(
+                "strict_fields only applies to Fastly's JSON, not {:?}",
+                self.format
+            ));
+        }
+        if self.stop_deleting_on_error && self.dead_letter_prefix.is_some() {
+            // The error that would send an object there stops deletions first.
+            problems.push(
+                "dead_letter_prefix does nothing with stop_deleting_on_error, which leaves \
+                failed objects in place"
+                    .to_string(),
+            );
+        }
+        if self.aggregate_only {
+            // These all work on the requests table, which aggregation-only databases leave empty.
+            let quotas = &self.config.quotas;
+            for (setting, set) in [
+                ("dedup_requests", self.dedup_requests == Some(true)),
+                ("store_queries", self.store_queries),
+                ("[[slo]]", !self.config.slo.is_empty()),
+                ("[[column]]", !self.config.columns.is_empty()),
+                ("[[tag]]", !self.config.tags.is_empty()),
+                (
+                    "[quotas]",
+                    quotas.per_ip.is_some()
+                        || quotas.per_asn.is_some()
+                        || quotas.per_country.is_some(),
+                ),
+            ] {
+                if set {
+                    problems.push(format!(
+                        "{setting} does nothing with aggregate_only, which stores no requests"
+                    ));
+                }
+            }
+        }
+        for source in self.config.service_ids.keys() {
+            if !self.sources.iter().any(|spec| spec.to_string() == *source) {
+                problems.push(format!(
+                    "[service_ids]: {source:?} isn't one of the sources; these are: {}",
+                    self.sources
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        if self.dead_letter_prefix.as_deref() == Some("") {
+            problems.push("the dead-letter prefix must not be empty".to_string());
+        }
+        if let Some(dir) = self.listing_cache.as_ref().filter(|dir| dir.exists()) {
+            if !dir.is_dir() {
+                problems.push(format!("listing cache {} isn't a directory", dir.display()));
+            }
+        }
+        problems.extend(
+            self.config
+                .problems()
+                .into_iter()
+                .map(|problem| format!("config: {problem}")),
+        );
+        if problems.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "{} problem(s) with the settings:\n  - {}",
+            problems.len(),
+            problems.join("\n  - ")
+        )
+    }
+
+    /// Check what a run depends on, without running: that each store's credentials can list
+    /// and read objects (and delete them, with cleanup), that PeeringDB and Spamhaus can be
+    /// reached, that the database can be written, and that the FD limit leaves room for the
+    /// concurrency. See the doctor module.
+    pub fn doctor(&self, rt: &Runtime) -> Vec<Check> {
+        let mut checks = Vec::new();
+        for source in &self.sources {
+            if matches!(source, SourceSpec::Stdin | SourceSpec::Journald { .. }) {
+                continue;
+            }
+            let result = self.open_fetcher(source).and_then(|fetcher| {
+                rt.block_on(fetcher.preflight())?;
+                Ok(if self.cleanup {
+                    "can list, read, and delete objects".to_string()
+                } else {
+                    "can list and read objects".to_string()
+                })
+            });
+            checks.push(Check::new(format!("source {source}"), result));
+        }
+        if self.config.enrichment.peeringdb {
+            checks.push(Check::new(
+                "PeeringDB",
+                rt.block_on(doctor::check_peeringdb()),
+            ));
+        }
+        checks.push(Check::new(
+            "Spamhaus",
+            rt.block_on(doctor::check_spamhaus()),
+        ));
+        checks.push(Check::new(
+            format!("database {}", self.database.display()),
+            doctor::check_database(&self.database),
+        ));
+        // Adaptive runs start with a few fetches at once, and only go up to what the limit
+        // leaves room for.
+        let concurrency = if self.adaptive_concurrency {
+            INITIAL_CONCURRENCY.min(self.concurrency)
+        } else {
+            self.concurrency
+        };
+        checks.push(Check::new("FD limit", doctor::check_fd_limit(concurrency)));
+        checks
+    }
+
+    /// What the enrichments at the end of a run would do, tried on `sample` requests each,
+    /// without changing the database. See the preview module.
+    pub fn preview_enrichments(&self, sample: usize) -> anyhow::Result<Vec<Preview>> {
+        let cruncher = cruncher::Cruncher::open(&self.database, &self.config.sqlite)?;
+        cruncher.preview_enrichments(
+            &self.config.columns,
+            &self.packs,
+            self.config.enrichment.peeringdb,
+            sample,
+        )
+    }
+
+    /// List the sources, and plan a backfill of what a run would fetch, in batches of `span`
+    /// by the timestamps in the objects' names. See the backfill module.
+    pub fn plan_backfill(&self, rt: &Runtime, span: Duration) -> anyhow::Result<Manifest> {
+        let mut objects = Vec::new();
+        for (label, fetcher) in self.open_fetchers()? {
+            let fetcher = self.configure(fetcher)?;
+            let selected = rt.block_on(fetcher.selected()).with_context(|| {
+                format!("could not list {}", label.as_deref().unwrap_or("source"))
+            })?;
+            objects.extend(selected);
+        }
+        let sources = self.sources.iter().map(ToString::to_string).collect();
+        Manifest::plan(
+            sources,
+            self.filter.selection(),
+            objects
+                .iter()
+                .map(|object| (self.filter.name_time(&object.name), object.size)),
+            span,
+        )
+    }
+
+    /// Crunch the batches of the backfill planned in the manifest at `path` that aren't done,
+    /// one run each, oldest first; or only the first `max_batches` of them. The manifest is
+    /// saved after each batch, and the backfill stops at a batch that fails, which the next
+    /// apply starts with. Returns the manifest as it was left.
+    pub fn apply_backfill(
+        mut self,
+        rt: &Runtime,
+        path: &Path,
+        max_batches: Option<usize>,
+    ) -> anyhow::Result<Manifest> {
+        if self.max_objects.is_some() || self.max_bytes.is_some() {
+            anyhow::bail!("backfill batches are crunched whole, without a budget for the run");
+        }
+        let mut manifest = Manifest::load(path)?;
+        let sources: Vec<String> = self.sources.iter().map(ToString::to_string).collect();
+        if manifest.sources != sources {
+            anyhow::bail!(
+                "the backfill was planned for {}, not {}",
+                manifest.sources.join(", "),
+                sources.join(", ")
+            );
+        }
+        if manifest.filter != self.filter.selection() {
+            anyhow::bail!(
+                "the backfill was planned with another object filter: {}, not {}",
+                serde_json::to_string(&manifest.filter)?,
+                serde_json::to_string(&self.filter.selection())?
+            );
+        }
+        let filter = self.filter.clone();
+        let mut applied = 0;
+        while let Some(i) = manifest.next() {
+            if max_batches.is_some_and(|max| applied >= max) {
+                break;
+            }
+            let (since, until) = (manifest.batches[i].since, manifest.batches[i].until);
+            tracing::info!(
+                "backfilling {since} to {until}: batch {} of {}",
+                i + 1,
+                manifest.batches.len()
+            );
+            self.filter = filter.clone().within(since, until);
+            let result = self
+                .open_fetchers()
+                .and_then(|fetchers| self.crunch_with(rt, fetchers));
+            let batch = &mut manifest.batches[i];
+            batch.finished = Some(chrono::Utc::now());
+            batch.error = match result {
+                Ok(summary) => {
+                    batch.run_id = Some(summary.run_id);
+                    batch.crunched += summary.ok + summary.skipped;
+                    if summary.errors > 0 {
+                        Some(format!(
+                            "{} of {} objects failed",
+                            summary.errors,
+                            summary.ok + summary.errors + summary.skipped
+                        ))
+                    } else if batch.crunched < batch.objects {
+                        Some(format!(
+                            "only {} of the {} objects planned were crunched",
+                            batch.crunched, batch.objects
+                        ))
+                    } else {
+                        None
+                    }
+                }
+                Err(e) => Some(format!("{e:#}")),
+            };
+            batch.status = match batch.error {
+                Some(_) => BatchStatus::Failed,
+                None => BatchStatus::Done,
+            };
+            let failed = batch.status == BatchStatus::Failed;
+            manifest.save(path)?;
+            if failed {
+                break;
+            }
+            applied += 1;
+        }
+        Ok(manifest)
+    }
+
     /// Fetch and crunch the logs.
     pub fn crunch(self, rt: &Runtime) -> anyhow::Result<()> {
-        let fetcher = Fetcher::new_gcs(&self.gcs_path, self.cleanup)
-            .context("could not initialize fetcher")?;
-        let fetcher = Arc::new(fetcher);
-
-        let mut log_sets = rt.block_on(async { fetcher.fetch(self.concurrency).await });
-
-        rt.block_on(async move {
-            let mut ok = 0;
-            let mut err = 0;
-            let cruncher = cruncher::Cruncher::new(&self.database)?;
-            while let Some(log_set) = log_sets.recv().await {
-                let log_set = log_set.context("got error in streaming log sets")?;
+        match self.sources.as_slice() {
+            [SourceSpec::Stdin] => self.crunch_stream(rt, io::stdin().lock()),
+            [source @ SourceSpec::Journald { .. }] => self.crunch_journal(rt, source),
+            _ => {
+                let fetchers = self.open_fetchers()?;
+                self.crunch_with(rt, fetchers).map(|_| ())
+            }
+        }
+    }
+
+    /// Fetch and crunch the logs, over and over: after each run, wait out the interval and
+    /// start again, picking up whatever objects are new. Runs until killed.
+    ///
+    /// A failed run is logged (and notified) and doesn't stop the next one. Runs never overlap,
+    /// and the database is closed between them, so other tools can have it in the meantime.
+    pub fn watch(self, rt: &Runtime, interval: Duration) -> anyhow::Result<()> {
+        if self.sources.contains(&SourceSpec::Stdin) {
+            anyhow::bail!("cannot watch stdin; use a bucket or directory as the source");
+        }
+        loop {
+            let result = match self.sources.as_slice() {
+                [source @ SourceSpec::Journald { .. }] => self.crunch_journal(rt, source),
+                _ => self
+                    .open_fetchers()
+                    .and_then(|fetchers| self.crunch_with(rt, fetchers))
+                    .map(|_| ()),
+            };
+            if let Err(e) = result {
+                tracing::error!("run failed: {:#}", e);
+            }
+            tracing::info!("next run in {:?}", interval);
+            std::thread::sleep(interval);
+        }
+    }
+
+    /// Crunch the logs in local directories, then again each time a log file is finished in
+    /// one (written and closed, or moved into place, as by logrotate). Runs until killed.
+    ///
+    /// Like `watch`, each run lists every object; so without cleanup (or skip_duplicates),
+    /// files are crunched again on each run. Files still being written to are crunched as
+    /// they are when listed; leave them out with the filter.
+    pub fn follow(self, rt: &Runtime) -> anyhow::Result<()> {
+        let dirs = self
+            .sources
+            .iter()
+            .map(|source| match source {
+                SourceSpec::Fs { dir } => Ok(dir.clone()),
+                _ => Err(anyhow::anyhow!(
+                    "cannot follow {source}; only local directories can be followed"
+                )),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if dirs.is_empty() {
+            anyhow::bail!("no directories to follow");
+        }
+        // Before the first run, so files finished during it aren't missed.
+        let mut follower = Follower::new(&dirs)?;
+        loop {
+            let result = self
+                .open_fetchers()
+                .and_then(|fetchers| self.crunch_with(rt, fetchers));
+            if let Err(e) = result {
+                tracing::error!("run failed: {:#}", e);
+            }
+            tracing::info!("waiting for new log files");
+            follower.wait()?;
+        }
+    }
+
+    /// Set up a fetcher for each source, labeled with the source; none may be stdin.
+    fn open_fetchers(&self) -> anyhow::Result<Vec<(Option<String>, Fetcher)>> {
+        if self.sources.is_empty() {
+            anyhow::bail!("no sources to fetch from");
+        }
+        self.sources
+            .iter()
+            .map(|source| {
+                let fetcher = self
+                    .open_fetcher(source)
+                    .with_context(|| format!("could not initialize fetcher for {source}"))?;
+                Ok((Some(source.to_string()), fetcher))
+            })
+            .collect()
+    }
+
+    fn open_fetcher(&self, source: &SourceSpec) -> anyhow::Result<Fetcher> {
+        open_fetcher(source, self.cleanup, self.filter.clone())
+    }
+
+    /// Fetch and crunch the logs from a custom source, rather than `sources`.
+    pub fn crunch_source(
+        self,
+        rt: &Runtime,
+        source: impl LogSource + 'static,
+    ) -> anyhow::Result<()> {
+        let fetcher = Fetcher::new(source, self.cleanup, self.filter.clone());
+        self.crunch_with(rt, vec![(None, fetcher)]).map(|_| ())
+    }
+
+    /// Mark entries with the source they came from, and its service ID, if it has one and they
+    /// don't.
+    fn label(&self, entries: &mut [LogEntry], source: &Option<String>) {
+        let service_id = source
+            .as_ref()
+            .and_then(|source| self.config.service_ids.get(source));
+        for entry in entries {
+            entry.source.clone_from(source);
+            if entry.service_id.is_none() {
+                entry.service_id = service_id.cloned();
+            }
+        }
+    }
+
+    /// The format the logs are in.
+    fn log_format(&self) -> Arc<dyn LogFormat> {
+        self.format.parser(&self.config.fields, self.strict_fields)
+    }
+
+    /// Apply the run's settings to a fetcher.
+    fn configure(&self, mut fetcher: Fetcher) -> anyhow::Result<Fetcher> {
+        if self.archive_prefix.is_some() || self.archive_to.is_some() {
+            let store = self
+                .archive_to
+                .as_ref()
+                .map(OpendalSource::open)
+                .transpose()
+                .context("could not open archive")?;
+            let prefix = self.archive_prefix.clone().unwrap_or_default();
+            let archive = Archive::new(prefix, store)?.with_zstd(self.archive_zstd_level);
+            fetcher = fetcher.with_archive(archive);
+        }
+        match &self.dead_letter_prefix {
+            Some(prefix) if prefix.is_empty() => {
+                anyhow::bail!("the dead-letter prefix must not be empty")
+            }
+            Some(prefix) => fetcher = fetcher.with_dead_letter(prefix.clone()),
+            None => {}
+        }
+        Ok(fetcher
+            .with_format(self.log_format())
+            .with_empty_objects(self.empty_objects)
+            .with_salvage_truncated(self.salvage_truncated)
+            .with_skip_invalid(self.skip_invalid_entries, self.max_invalid_entries)
+            .with_budget(self.max_objects, self.max_bytes)
+            .with_verify(self.verify)
+            .with_oldest_first(self.oldest_first)
+            .with_transcoding(self.gcs_transcoding)
+            .with_parallel_ranges(self.range_chunk_size, self.range_parallelism)
+            .with_retry(self.config.retry.clone()))
+    }
+
+    /// Fetch from each of the (labeled) fetchers, and crunch what they fetch as one run.
+    fn crunch_with(
+        &self,
+        rt: &Runtime,
+        fetchers: Vec<(Option<String>, Fetcher)>,
+    ) -> anyhow::Result<RunSummary> {
+        let result = rt.block_on(async {
+            let mut objects = self.objects_from(fetchers).await?;
+            let summary = self.store(&mut objects).await;
+            objects.finish();
+            summary
+        });
+        rt.block_on(notify_result(&self.config.notify, &result));
+        result
+    }
+
+    /// Start fetching from the sources, returning the objects as they're fetched (and parsed),
+    /// interleaved; see the pipeline module.
+    pub async fn objects(&self) -> anyhow::Result<Objects> {
+        self.objects_from(self.open_fetchers()?).await
+    }
+
+    /// Start fetching from each of the (labeled) fetchers, with the run's settings.
+    async fn objects_from(
+        &self,
+        fetchers: Vec<(Option<String>, Fetcher)>,
+    ) -> anyhow::Result<Objects> {
+        // Split the concurrency, and the memory budget, between the sources.
+        let concurrency = (self.concurrency / fetchers.len().max(1)).max(1);
+        let max_inflight_bytes = self
+            .max_inflight_bytes
+            .map(|bytes| bytes / fetchers.len().max(1) as u64);
+        // One latch for the run, so an error from any source stops deletions from all of them.
+        let latch = self.stop_deleting_on_error.then(DeletionLatch::default);
+        let fetchers = fetchers
+            .into_iter()
+            .map(|(label, fetcher)| {
+                let mut fetcher = self
+                    .configure(fetcher)?
+                    .with_max_inflight_bytes(max_inflight_bytes);
+                if let Some(dir) = &self.listing_cache {
+                    // One file per source, named for it.
+                    let name: String = label
+                        .as_deref()
+                        .unwrap_or("source")
+                        .chars()
+                        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                        .collect();
+                    fetcher = fetcher.with_listing_cache(dir.join(format!("{name}.json")));
+                }
+                if self.adaptive_concurrency {
+                    fetcher = fetcher
+                        .with_concurrency(Concurrency::new(INITIAL_CONCURRENCY, concurrency));
+                }
+                if let Some(latch) = &latch {
+                    fetcher = fetcher.with_deletion_latch(latch.clone());
+                }
+                Ok((label, Arc::new(fetcher)))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for (_, fetcher) in &fetchers {
+            fetcher.preflight().await?;
+        }
+        // Interleave the sources' objects, as they're fetched.
+        let (tx, objects) = tokio::sync::mpsc::channel(self.concurrency.max(1));
+        for (label, fetcher) in &fetchers {
+            let mut fetched = fetcher.fetch(concurrency).await;
+            let (label, tx) = (label.clone(), tx.clone());
+            tokio::spawn(async move {
+                while let Some(mut object) = fetched.recv().await {
+                    match &mut object {
+                        Ok(Object::Logs(log_set)) => log_set.source.clone_from(&label),
+                        Ok(Object::Skipped { source, .. }) => source.clone_from(&label),
+                        Err(_) => (),
+                    }
+                    if tx.send(object).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Objects::new(fetchers, objects))
+    }
+
+    /// Store the log sets in `objects` as one run, recording each object's outcome, and
+    /// completing the log set (cleaning it up, archiving it, and so on) once it's stored.
+    ///
+    /// The stream is usually from `objects`, maybe with stages of one's own applied; see the
+    /// pipeline module. An error in the stream ends the run.
+    pub async fn store(
+        &self,
+        objects: impl Stream<Item = anyhow::Result<Object>>,
+    ) -> anyhow::Result<RunSummary> {
+        let mut objects = std::pin::pin!(objects);
+        let mut ok = 0;
+        let mut err = 0;
+        let mut skipped = 0;
+        let mut failed = Vec::new();
+        let cruncher = self.open_database()?;
+        let run_id = self.start_run(&cruncher).await?;
+        // Runs that stop early are still finished, with what they got through.
+        let stored: anyhow::Result<()> = async {
+            while let Some(object) = objects.next().await {
+                let mut log_set = match object.context("got error in streaming log sets")? {
+                    Object::Logs(log_set) => log_set,
+                    Object::Skipped {
+                        source,
+                        name,
+                        reason,
+                    } => {
+                        skipped += 1;
+                        cruncher.record_object(
+                            source.as_deref(),
+                            &name,
+                            "skipped",
+                            Some(&reason),
+                            None,
+                        )?;
+                        continue;
+                    }
+                };
                 tracing::info!("processing log set {}", &log_set.name);
-                let crunch_result = cruncher
-                    .crunch(&log_set.data)
+                let result = self
+                    .crunch_log_set(&cruncher, &mut log_set)
+                    .await
                     .with_context(|| format!("error in processing log file {}", log_set.name));
-                tracing::info!(
-                    "completed log set {}, result: {}",
-                    &log_set.name,
-                    if crunch_result.is_ok() { "ok" } else { "error" }
-                );
-                if crunch_result.is_ok() {
-                    ok += 1
-                } else {
-                    err += 1
+                let (status, detail, content_hash, crunch_result) = match result {
+                    Ok(Crunched::Stored {
+                        truncated,
+                        skipped,
+                        content_hash,
+                    }) => {
+                        ok += 1;
+                        let mut details = Vec::new();
+                        if truncated {
+                            details.push("truncated object".to_string());
+                        }
+                        if skipped > 0 {
+                            details.push(format!("skipped {skipped} invalid entries"));
+                        }
+                        let (status, detail) = if details.is_empty() {
+                            ("ok", None)
+                        } else {
+                            ("partial", Some(details.join("; ")))
+                        };
+                        (status, detail, Some(content_hash), Ok(()))
+                    }
+                    Ok(Crunched::Duplicate {
+                        original,
+                        content_hash,
+                    }) => {
+                        tracing::info!(
+                            "skipping log set {}: same contents as {}",
+                            &log_set.name,
+                            &original
+                        );
+                        skipped += 1;
+                        let detail = format!("same contents as {original}");
+                        // The contents are already in the database, so this is as good as done.
+                        ("duplicate", Some(detail), Some(content_hash), Ok(()))
+                    }
+                    Err(e) => {
+                        err += 1;
+                        failed.push(log_set.name.clone());
+                        ("error", Some(format!("{e:#}")), None, Err(e))
+                    }
                 };
+                tracing::info!("completed log set {}, result: {}", &log_set.name, status);
+                cruncher.record_object(
+                    log_set.source.as_deref(),
+                    &log_set.name,
+                    status,
+                    detail.as_deref(),
+                    content_hash.as_deref(),
+                )?;
                 let name = log_set.name.clone();
-                if let Err(e) = log_set.complete(crunch_result).await {
-                    tracing::error!("error finalizing log set {}: {}", &name, e);
+                let source = log_set.source.clone();
+                match log_set.complete(crunch_result).await {
+                    Ok(Some(generation)) => {
+                        // It's deleted either way; without the record, undelete can't find it.
+                        if let Err(e) =
+                            cruncher.record_deletion(run_id, source.as_deref(), &name, &generation)
+                        {
+                            tracing::error!("could not record deletion of {name}: {e:#}");
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => tracing::error!("error finalizing log set {}: {}", &name, e),
+                }
+                // The objects still to come are left as they are, to look into.
+                if let Some(reason) = self
+                    .circuit_breaker
+                    .as_ref()
+                    .and_then(|breaker| breaker.tripped(ok, err))
+                {
+                    bail!("stopping the run early: {reason}");
                 }
             }
-            tracing::info!("crunched {} logsets: {} ok, {} errors", ok + err, ok, err);
+            Ok(())
+        }
+        .await;
+        if let Err(e) = stored {
+            if let Err(finish) = cruncher.finish_run(run_id, ok, err, skipped) {
+                tracing::error!("could not finish run {run_id}: {finish:#}");
+            }
+            return Err(e);
+        }
+        tracing::info!(
+            "crunched {} logsets: {} ok, {} errors, {} skipped",
+            ok + err,
+            ok,
+            err,
+            skipped
+        );
+        let summary = RunSummary {
+            run_id,
+            ok,
+            errors: err,
+            skipped,
+            failed,
+        };
+        self.wrap_up(&cruncher, &summary).await?;
+        Ok(summary)
+    }
+
+    /// Store a log set's entries as they're parsed, all in one transaction.
+    ///
+    /// With `skip_duplicates`, the object's hash is only known once it's all been read;
+    /// a duplicate's entries are rolled back then.
+    ///
+    /// With `serve_friendly`, the entries are held in memory until the object has been read,
+    /// so the write lock isn't held while waiting on the network.
+    async fn crunch_log_set(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        log_set: &mut LogSet,
+    ) -> anyhow::Result<Crunched> {
+        let tx = if self.serve_friendly {
+            None
+        } else {
+            Some(cruncher.begin()?)
+        };
+        let mut buffered = Vec::new();
+        loop {
+            match log_set.next_batch().await? {
+                Batch::Entries(mut entries) => {
+                    self.label(&mut entries, &log_set.source);
+                    self.transform(&mut entries)?;
+                    match &tx {
+                        Some(tx) => tx.store(&entries)?,
+                        None => buffered.append(&mut entries),
+                    }
+                }
+                Batch::End {
+                    truncated,
+                    skipped,
+                    content_hash,
+                } => {
+                    if self.skip_duplicates {
+                        if let Some(original) = cruncher.find_duplicate(
+                            log_set.source.as_deref(),
+                            &log_set.name,
+                            &content_hash,
+                        )? {
+                            return Ok(Crunched::Duplicate {
+                                original,
+                                content_hash,
+                            });
+                        }
+                    }
+                    match tx {
+                        Some(tx) => tx.commit()?,
+                        None => cruncher.crunch(&buffered)?,
+                    }
+                    return Ok(Crunched::Stored {
+                        truncated,
+                        skipped,
+                        content_hash,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Crunch logs from a single stream, e.g. stdin, rather than fetching from `source`.
+    ///
+    /// The stream holds JSON entries, one after another, and may be gzipped; several gzip
+    /// members back-to-back (as from `gsutil cat`) are fine. Entries are stored in batches as
+    /// they are read, so if the stream fails partway, the entries before the failure are kept.
+    pub fn crunch_stream(self, rt: &Runtime, input: impl io::Read) -> anyhow::Result<()> {
+        let result = rt.block_on(async {
+            let cruncher = self.open_database()?;
+            self.stream_run(&cruncher, input).await
+        });
+        let result = result.map(|(summary, _)| summary);
+        rt.block_on(notify_result(&self.config.notify, &result));
+        result.map(|_| ())
+    }
+
+    /// Own the database, and crunch streams of entries that other processes send to the socket,
+    /// rather than fetching from the source; see the writer module. Runs until killed.
+    ///
+    /// Each stream is a run of its own, as with crunch_stream.
+    pub fn serve(self, rt: &Runtime, socket: &Path) -> anyhow::Result<()> {
+        let cruncher = self.open_database()?;
+        let listener = writer::listen(socket)?;
+        tracing::info!("serving as the writer on {}", socket.display());
+        std::thread::scope(|scope| {
+            for conn in listener.incoming() {
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::error!("could not accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let (this, cruncher) = (&self, &cruncher);
+                scope.spawn(move || {
+                    let result = rt.block_on(this.stream_run(cruncher, &conn));
+                    let count = result.as_ref().map(|(_, count)| *count);
+                    if let Err(e) = writer::reply(&conn, count) {
+                        tracing::error!("could not reply to client: {}", e);
+                    }
+                    let result = result.map(|(summary, _)| summary);
+                    rt.block_on(notify_result(&this.config.notify, &result));
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// Own the database, and crunch batches of entries POSTed to `addr`, e.g. "127.0.0.1:8080",
+    /// by Fastly's HTTPS logging; see the http module. Runs until killed.
+    ///
+    /// Batches that come in within http::RUN_INTERVAL of each other are crunched in one run.
+    /// Each is recorded as an object, so one that's sent again isn't stored again.
+    pub fn serve_http(
+        self,
+        rt: &Runtime,
+        addr: &str,
+        secret: String,
+        service_ids: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let cruncher = self.open_database()?;
+        let endpoint = Arc::new(http::Endpoint::new(secret, service_ids));
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("could not listen on {addr}"))?;
+            tracing::info!("listening for logs on {addr}");
+            let (queue, mut batches) = tokio::sync::mpsc::channel(http::QUEUE_LEN);
+            tokio::spawn(endpoint.serve(listener, queue));
+            while let Some(first) = batches.recv().await {
+                tokio::time::sleep(http::RUN_INTERVAL).await;
+                let mut posted = vec![first];
+                while let Ok(next) = batches.try_recv() {
+                    posted.push(next);
+                }
+                let result = self.http_run(&cruncher, posted).await;
+                if let Err(e) = &result {
+                    tracing::error!("error in run: {:#}", e);
+                }
+                notify_result(&self.config.notify, &result).await;
+            }
+            Ok(())
+        })
+    }
+
+    /// Crunch batches POSTed to the HTTP endpoint as a run, answering each as it's stored.
+    async fn http_run(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        posted: Vec<http::Posted>,
+    ) -> anyhow::Result<RunSummary> {
+        let run_id = self.start_run(cruncher).await?;
+        let mut summary = RunSummary {
+            run_id,
+            ..Default::default()
+        };
+        for batch in posted {
+            let result = self.crunch_posted(cruncher, &batch.name, &batch.body);
+            match &result {
+                Ok(http::Stored::Entries(count)) => {
+                    tracing::info!("crunched {count} entries from {}", batch.name);
+                    summary.ok += 1;
+                }
+                Ok(http::Stored::Duplicate) => {
+                    tracing::info!("skipping {}: stored already", batch.name);
+                    summary.skipped += 1;
+                }
+                Err(e) => {
+                    tracing::error!("error in crunching {}: {:#}", batch.name, e);
+                    summary.errors += 1;
+                    summary.failed.push(batch.name.clone());
+                }
+            }
+            // The client may have given up waiting; the batch is stored all the same.
+            let _ = batch.done.send(result);
+        }
+        self.wrap_up(cruncher, &summary).await?;
+        Ok(summary)
+    }
+
+    /// Own the database, and crunch entries streamed to `addr` by Fastly's syslog logging,
+    /// over TLS if given a certificate and key (PEM files), from the `allowed` addresses;
+    /// see the syslog module. Runs until killed.
+    ///
+    /// Entries from all connections are crunched in runs, each SYSLOG_RUN_LENGTH or so long,
+    /// so enrichments and notifications keep up with connections that stay open.
+    pub fn serve_syslog(
+        self,
+        rt: &Runtime,
+        addr: &str,
+        tls: Option<(&Path, &Path)>,
+        allowed: &[String],
+    ) -> anyhow::Result<()> {
+        let cruncher = self.open_database()?;
+        let receiver = syslog::Receiver::bind(addr, tls, allowed)?;
+        let addr = receiver
+            .local_addr()
+            .map_or_else(|_| addr.to_string(), |addr| addr.to_string());
+        tracing::info!("receiving syslog on {addr}");
+        let (queue, batches) = std::sync::mpsc::sync_channel(SYSLOG_QUEUE_LEN);
+        std::thread::scope(|scope| {
+            let (this, receiver) = (&self, &receiver);
+            scope.spawn(move || {
+                for conn in receiver.incoming() {
+                    let (peer, conn) = match conn.and_then(|conn| Ok((conn.peer_addr()?, conn))) {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::error!("could not accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    if !receiver.allows(peer.ip()) {
+                        tracing::warn!("refusing syslog connection from {peer}: not allowed");
+                        continue;
+                    }
+                    let queue = queue.clone();
+                    scope.spawn(move || {
+                        let result = receiver
+                            .accept(conn)
+                            .and_then(|mut messages| this.read_syslog(&mut messages, &queue));
+                        match result {
+                            Ok(()) => tracing::info!("syslog connection from {peer} closed"),
+                            Err(e) => tracing::error!("error in syslog from {peer}: {:#}", e),
+                        }
+                    });
+                }
+            });
+            self.crunch_syslog(rt, &cruncher, &batches);
+        });
+        Ok(())
+    }
+
+    /// Parse and transform the messages from a syslog connection, queueing them to be stored,
+    /// until it's closed.
+    fn read_syslog(
+        &self,
+        messages: &mut syslog::Messages<impl io::Read>,
+        queue: &std::sync::mpsc::SyncSender<Vec<LogEntry>>,
+    ) -> anyhow::Result<()> {
+        let format = self.log_format();
+        while let Some(messages) = messages
+            .next_batch(BATCH_SIZE)
+            .context("could not read from connection")?
+        {
+            let mut batch = Vec::with_capacity(messages.len());
+            for message in messages {
+                // One bad message shouldn't cost the rest of the connection.
+                for entry in format.parse(Box::new(message.as_bytes())) {
+                    match entry {
+                        Ok(entry) => batch.push(entry),
+                        Err(e) => {
+                            tracing::warn!("could not parse syslog message: {:#}", e.into_inner())
+                        }
+                    }
+                }
+            }
+            self.transform(&mut batch)?;
+            queue
+                .send(batch)
+                .map_err(|_| anyhow::anyhow!("not crunching syslog"))?;
+        }
+        Ok(())
+    }
+
+    /// Store the batches queued from syslog connections, a run at a time: each starts with
+    /// the first batch to come in, and lasts SYSLOG_RUN_LENGTH.
+    fn crunch_syslog(
+        &self,
+        rt: &Runtime,
+        cruncher: &cruncher::Cruncher,
+        batches: &std::sync::mpsc::Receiver<Vec<LogEntry>>,
+    ) {
+        while let Ok(first) = batches.recv() {
+            let started = std::time::Instant::now();
+            let result = rt.block_on(self.run_with(cruncher, || {
+                let mut count = 0;
+                let mut batch = Some(first);
+                while let Some(entries) = batch {
+                    cruncher.crunch(&entries)?;
+                    count += entries.len();
+                    let left = SYSLOG_RUN_LENGTH.saturating_sub(started.elapsed());
+                    batch = batches.recv_timeout(left).ok();
+                }
+                Ok(count)
+            }));
+            let result = result.map(|(summary, _)| summary);
+            rt.block_on(notify_result(&self.config.notify, &result));
+        }
+    }
+
+    /// Crunch nginx's access log entries from a unit's journal, picking up after the last run's.
+    fn crunch_journal(&self, rt: &Runtime, source: &SourceSpec) -> anyhow::Result<()> {
+        let result = rt.block_on(async {
+            let cruncher = self.open_database()?;
+            self.run_with(&cruncher, || self.crunch_journal_entries(&cruncher, source))
+                .await
+        });
+        let result = result.map(|(summary, _)| summary);
+        rt.block_on(notify_result(&self.config.notify, &result));
+        result.map(|_| ())
+    }
+
+    /// Store the journal's access log entries, a batch at a time, each batch with the cursor
+    /// of the last entry read. Returns how many were stored.
+    ///
+    /// The unit's other messages, e.g. from systemd, or nginx's error log, aren't JSON, and
+    /// are passed over; JSON that isn't an access log entry is skipped and counted, so it
+    /// doesn't hold up the entries after it.
+    fn crunch_journal_entries(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        source: &SourceSpec,
+    ) -> anyhow::Result<usize> {
+        let SourceSpec::Journald { unit, identifier } = source else {
+            bail!("{source} is not a journal");
+        };
+        let name = match identifier {
+            Some(identifier) => format!("{unit}:{identifier}"),
+            None => unit.to_string(),
+        };
+        let mut journal = Journal::open(
+            unit,
+            identifier.as_deref(),
+            cruncher.journal_cursor(&name)?.as_deref(),
+        )?;
+        let source = Some(source.to_string());
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut store = |batch: &mut Vec<LogEntry>, cursor: &str| -> anyhow::Result<()> {
+            self.label(batch, &source);
+            self.transform(batch)?;
+            let tx = cruncher.begin()?;
+            tx.store(batch)?;
+            tx.record_journal_cursor(&name, cursor)?;
+            tx.commit()?;
+            count += batch.len();
+            batch.clear();
+            Ok(())
+        };
+        let mut skipped = Skipped::default();
+        let mut cursor = None;
+        for (i, line) in journal.by_ref().enumerate() {
+            let (entry_cursor, message) = match journal::parse_entry(&line?) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    skipped.add(i, e);
+                    continue;
+                }
+            };
+            if message.trim_start().starts_with('{') {
+                match serde_json::from_str::<NginxEntry>(&message) {
+                    Ok(entry) => batch.push(entry.into()),
+                    Err(e) => skipped.add(
+                        i,
+                        anyhow::Error::new(e).context("not an nginx access log entry"),
+                    ),
+                }
+            }
+            if batch.len() == BATCH_SIZE {
+                store(&mut batch, &entry_cursor)
+                    .with_context(|| format!("in batch ending at entry {i}"))?;
+            }
+            cursor = Some(entry_cursor);
+        }
+        if let Some(cursor) = &cursor {
+            store(&mut batch, cursor).context("in final batch")?;
+        }
+        journal.finish()?;
+        if skipped.count > 0 {
+            tracing::warn!(
+                "skipped {} invalid journal entries, e.g. {}",
+                skipped.count,
+                skipped.samples.join("; ")
+            );
+        }
+        Ok(count)
+    }
+
+    /// Crunch a stream of entries as a run, returning its summary and how many entries it stored.
+    async fn stream_run(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        input: impl io::Read,
+    ) -> anyhow::Result<(RunSummary, usize)> {
+        self.run_with(cruncher, || self.crunch_entries(cruncher, input))
+            .await
+    }
+
+    /// Crunch entries as a run, returning its summary and how many entries it stored.
+    async fn run_with(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        crunch: impl FnOnce() -> anyhow::Result<usize>,
+    ) -> anyhow::Result<(RunSummary, usize)> {
+        let run_id = self.start_run(cruncher).await?;
+        let crunch_result = crunch();
+        let summary = match &crunch_result {
+            Ok(count) => {
+                tracing::info!("crunched {} entries from stream", count);
+                RunSummary {
+                    run_id,
+                    ok: 1,
+                    ..Default::default()
+                }
+            }
+            Err(e) => {
+                tracing::error!("error in crunching stream: {:#}", e);
+                RunSummary {
+                    run_id,
+                    errors: 1,
+                    ..Default::default()
+                }
+            }
+        };
+        self.wrap_up(cruncher, &summary).await?;
+        crunch_result.map(|count| (summary, count))
+    }
+
+    /// Parse, transform, and store entries from the stream, returning how many were stored.
+    fn crunch_entries(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        input: impl io::Read,
+    ) -> anyhow::Result<usize> {
+        self.read_entries(input, |batch| cruncher.crunch(batch))
+    }
+
+    /// Crunch a batch POSTed to the HTTP endpoint, recording it as the object `name` in the
+    /// same transaction, unless it's been stored already.
+    fn crunch_posted(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        name: &str,
+        body: &[u8],
+    ) -> anyhow::Result<http::Stored> {
+        let tx = cruncher.begin()?;
+        if tx.stored(None, name)? {
+            return Ok(http::Stored::Duplicate);
+        }
+        let count = self.read_entries(body, |batch| tx.store(batch))?;
+        let hash = format!("{:x}", Sha256::digest(body));
+        tx.record_object(None, name, "ok", None, Some(&hash))?;
+        tx.commit()?;
+        Ok(http::Stored::Entries(count))
+    }
+
+    /// Parse and transform entries from the stream, passing them to `store` in batches;
+    /// returns how many were stored.
+    fn read_entries(
+        &self,
+        input: impl io::Read,
+        mut store: impl FnMut(&[LogEntry]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<usize> {
+        let input = io::BufReader::new(decompress(input).context("could not read from stream")?);
+
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut store = |batch: &mut Vec<LogEntry>| -> anyhow::Result<()> {
+            self.transform(batch)?;
+            store(batch)?;
+            count += batch.len();
+            batch.clear();
+            Ok(())
+        };
+        let mut skipped = Skipped::default();
+        for (i, entry) in self.log_format().parse(Box::new(input)).enumerate() {
+            match entry {
+                Ok(entry) => batch.push(entry),
+                Err(EntryError::Invalid(e)) if self.skip_invalid_entries => skipped.add(i, e),
+                Err(e) => {
+                    return Err(e.into_inner()).with_context(|| format!("parse error in entry {i}"))
+                }
+            }
+            if batch.len() == BATCH_SIZE {
+                store(&mut batch).with_context(|| format!("in batch ending at entry {i}"))?;
+            }
+        }
+        store(&mut batch).context("in final batch")?;
+        // What's stored is kept, as a stream can't be left for another try like an object.
+        skipped
+            .check(count, self.max_invalid_entries)
+            .with_context(|| format!("after storing {count} entries"))?;
+        if skipped.count > 0 {
+            tracing::warn!(
+                "skipped {} invalid entries, e.g. {}",
+                skipped.count,
+                skipped.samples.join("; ")
+            );
+        }
+        Ok(count)
+    }
+
+    /// Open the database, and bring it in line with the config and packs.
+    fn open_database(&self) -> anyhow::Result<cruncher::Cruncher> {
+        let limits =
+            dimensions::Limits::new(&self.config.dimensions).context("invalid dimension limits")?;
+        let cruncher = cruncher::Cruncher::open(&self.database, &self.config.sqlite)?
+            .with_limits(limits)
+            .with_quotas(self.config.quotas.clone())
+            .with_aggregate_only(self.aggregate_only);
+        if self.serve_friendly {
+            cruncher.use_wal()?;
+        }
+        cruncher.install_packs(&self.packs)?;
+        cruncher.set_site_hosts(&self.config.site_hosts)?;
+        cruncher.set_segments(&self.config.segments)?;
+        cruncher.set_computed_columns(&self.config.columns)?;
+        if let Some(unique) = self.dedup_requests {
+            cruncher.set_unique_requests(unique)?;
+        }
+        Ok(cruncher)
+    }
+
+    /// Record the start of a run, after syncing what requests are flagged by as they're stored.
+    async fn start_run(&self, cruncher: &cruncher::Cruncher) -> anyhow::Result<i64> {
+        if let Err(err) = cruncher.sync_droplist().await {
+            tracing::error!("could not sync the droplist: {:#}", err);
+        }
+        cruncher.start_run()
+    }
+
+    /// Record the end of a run, and update everything that depends on its results.
+    async fn wrap_up(
+        &self,
+        cruncher: &cruncher::Cruncher,
+        summary: &RunSummary,
+    ) -> anyhow::Result<()> {
+        cruncher.finish_run(summary.run_id, summary.ok, summary.errors, summary.skipped)?;
+        cruncher.evaluate_slos(summary.run_id, &self.config.slo)?;
+        if let Err(err) = cruncher.compute_columns() {
+            tracing::error!("errors in updating computed columns: {:#}", err);
+        }
+        if let Some(retention) = &self.config.retention {
+            let kept = chrono::Days::new(retention.raw_days.saturating_sub(1).into());
+            let before = chrono::Utc::now().date_naive() - kept;
+            match cruncher.prune_requests(before) {
+                Ok(0) => (),
+                Ok(pruned) => tracing::info!("pruned {pruned} requests from before {before}"),
+                Err(err) => tracing::error!("could not prune old requests: {:#}", err),
+            }
+        }
+        if let Err(err) = cruncher.enrich(&self.packs) {
+            tracing::error!("errors in running pack enrichments: {:#}", err);
+        }
+        if self.config.enrichment.peeringdb {
             if let Err(err) = cruncher.asn_catchup().await {
                 tracing::error!("errors in updating ASN table: {}", err);
             } else {
                 tracing::info!("ASN table up to date");
             }
-            Ok(())
+        }
+        if self.serve_friendly {
+            if let Err(err) = cruncher.checkpoint() {
+                tracing::error!("could not checkpoint the database: {:#}", err);
+            }
+        }
+        if let Some(backup) = &self.config.backup {
+            if let Err(err) = backup::run(cruncher, backup).await {
+                tracing::error!("could not back up the database: {:#}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the per-entry transformations.
+    // Scripts can drop entries, so this takes the Vec even when built without them.
+    #[allow(clippy::ptr_arg)]
+    fn transform(&self, entries: &mut Vec<LogEntry>) -> anyhow::Result<()> {
+        for entry in entries.iter_mut() {
+            entry.split_query();
+            if !self.store_queries {
+                entry.query = None;
+            }
+            if self.normalize_paths {
+                entry.normalize_path(self.lowercase_paths);
+            }
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(script) = &self.script {
+            *entries = script.apply(std::mem::take(entries))?;
+        }
+        tagging::apply(&self.config.tags, entries);
+        Ok(())
+    }
+}
+
+/// Stops a run when too many of its first objects fail, as when the log format has changed:
+/// rather than churn through the rest of the objects, failing (or worse, cleaning up) each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreaker {
+    /// How many of the first objects to judge the run by.
+    pub objects: usize,
+    /// The most of them that may fail, as a percentage.
+    pub max_failure_percent: f64,
+}
+
+impl CircuitBreaker {
+    /// Why to stop, after `ok` objects were stored and `failed` failed; if it's already clear
+    /// that too many of the first objects will have failed.
+    fn tripped(&self, ok: usize, failed: usize) -> Option<String> {
+        let allowed = self.objects as f64 * self.max_failure_percent / 100.0;
+        (ok + failed <= self.objects && failed as f64 > allowed).then(|| {
+            format!(
+                "{failed} of the first {} objects failed, more than {}%; has the log format \
+                changed? The rest are left in place",
+                ok + failed,
+                self.max_failure_percent
+            )
         })
     }
 }
+
+/// How many invalid entries an object may have, skipped, before it fails instead: as it
+/// would if its contents were garbage, so it isn't cleaned up. By default, 1%.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaxInvalid {
+    /// At most this many.
+    Count(usize),
+    /// At most this percentage of its entries.
+    Percent(f64),
+}
+
+impl MaxInvalid {
+    /// Whether `invalid` entries are too many, of `invalid + valid`.
+    fn exceeded(self, invalid: usize, valid: usize) -> bool {
+        match self {
+            MaxInvalid::Count(count) => invalid > count,
+            MaxInvalid::Percent(percent) => {
+                invalid as f64 > (invalid + valid) as f64 * percent / 100.0
+            }
+        }
+    }
+}
+
+impl Default for MaxInvalid {
+    fn default() -> Self {
+        MaxInvalid::Percent(1.0)
+    }
+}
+
+impl std::fmt::Display for MaxInvalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MaxInvalid::Count(count) => write!(f, "{count}"),
+            MaxInvalid::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+/// Parse a limit on invalid entries: a count, like "100", or a percentage, like "0.5%".
+pub fn parse_max_invalid(s: &str) -> anyhow::Result<MaxInvalid> {
+    match s.strip_suffix('%') {
+        Some(percent) => percent
+            .trim()
+            .parse()
+            .map(MaxInvalid::Percent)
+            .with_context(|| format!("could not parse percentage {s}")),
+        None => s
+            .parse()
+            .map(MaxInvalid::Count)
+            .with_context(|| format!("could not parse count {s}")),
+    }
+}
+
+/// What became of a log set that was read successfully.
+enum Crunched {
+    Stored {
+        truncated: bool,
+        /// How many invalid entries were skipped.
+        skipped: usize,
+        content_hash: String,
+    },
+    /// Not stored, because an object with the same contents already was.
+    Duplicate {
+        original: String,
+        content_hash: String,
+    },
+}
+
+/// Send the summary of a run, or an alert that it failed.
+async fn notify_result(webhooks: &[Webhook], result: &anyhow::Result<RunSummary>) {
+    let message = match result {
+        Ok(summary) => Message::summary(summary),
+        Err(e) => Message::failure(e),
+    };
+    notify::send_all(webhooks, &message).await;
+}
+
+/// A fetcher for a store; stdin and the journal are read otherwise.
+pub(crate) fn open_fetcher(
+    source: &SourceSpec,
+    cleanup: bool,
+    filter: ObjectFilter,
+) -> anyhow::Result<Fetcher> {
+    match source {
+        SourceSpec::Gcs {
+            bucket,
+            credentials,
+            scope,
+            endpoint,
+        } => Fetcher::new_gcs(
+            bucket,
+            credentials.as_deref(),
+            scope.as_deref(),
+            endpoint.as_deref(),
+            cleanup,
+            filter,
+        ),
+        SourceSpec::S3 {
+            bucket,
+            region,
+            endpoint,
+            virtual_host_style,
+            access_key_id,
+            secret_key_file,
+        } => Fetcher::new_s3(
+            bucket,
+            region.as_deref(),
+            endpoint.as_deref(),
+            *virtual_host_style,
+            access_key_id.as_deref(),
+            secret_key_file.as_deref(),
+            cleanup,
+            filter,
+        ),
+        SourceSpec::Sftp {
+            host,
+            user,
+            root,
+            key,
+            known_hosts,
+        } => Fetcher::new_sftp(
+            host,
+            user.as_deref(),
+            root,
+            key.as_deref(),
+            known_hosts.as_deref(),
+            cleanup,
+            filter,
+        ),
+        SourceSpec::Fs { dir } => Fetcher::new_fs(dir, cleanup, filter),
+        SourceSpec::Urls { manifest } => Fetcher::new_urls(manifest, cleanup, filter),
+        SourceSpec::Stdin => {
+            anyhow::bail!("stdin can't be read alongside other sources")
+        }
+        SourceSpec::Journald { .. } => {
+            anyhow::bail!("the journal can't be read alongside other sources")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path, time::Duration};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::{
+        cruncher,
+        format::FastlyJson,
+        parse_max_invalid, parse_object,
+        record::LogEntry,
+        testdata::{bzip2_compressed, gzipped, tarred, zstd_compressed, ENTRY},
+        BatchStatus, CircuitBreaker, Config, Cruncher, EmptyObjects, Format, MaxInvalid, Object,
+        ObjectFilter, SourceSpec, Sqlite,
+    };
+
+    /// Parse the whole object, returning the entries, whether it was truncated, and its hash.
+    fn parse_all(data: &[u8], salvage: bool) -> anyhow::Result<(Vec<LogEntry>, bool, String)> {
+        let mut entries = Vec::new();
+        let parsed = parse_object(
+            data,
+            &FastlyJson,
+            salvage,
+            false,
+            MaxInvalid::default(),
+            |batch| {
+                entries.extend(batch);
+                Ok(())
+            },
+        )?;
+        Ok((entries, parsed.truncated, parsed.content_hash))
+    }
+
+    #[test]
+    fn skips_invalid_entries() {
+        let data = format!("{ENTRY}\n{{\"clientIP\": \"nope\"}}\n{{garbage\n{ENTRY}\n");
+        assert!(parse_all(data.as_bytes(), false).is_err());
+        let parse = |data: &str, max| {
+            let mut entries = 0;
+            parse_object(data.as_bytes(), &FastlyJson, false, true, max, |batch| {
+                entries += batch.len();
+                Ok(())
+            })
+            .map(|parsed| (entries, parsed.skipped))
+        };
+        let (entries, skipped) = parse(&data, MaxInvalid::Percent(100.0)).unwrap();
+        assert_eq!(entries, 2);
+        assert_eq!(skipped.count, 2);
+        assert!(skipped.samples[0].starts_with("entry 1: invalid entry"));
+
+        // Up to a limit.
+        assert!(parse(&data, MaxInvalid::Count(2)).is_ok());
+        assert!(parse(&data, MaxInvalid::Count(1)).is_err());
+        assert!(parse(&data, MaxInvalid::Percent(50.0)).is_ok());
+        assert!(parse(&data, MaxInvalid::Percent(49.0)).is_err());
+        assert!(parse(&data, MaxInvalid::default()).is_err());
+        assert_eq!(parse_max_invalid("0.5%").unwrap(), MaxInvalid::Percent(0.5));
+        assert_eq!(parse_max_invalid("100").unwrap(), MaxInvalid::Count(100));
+        assert!(parse_max_invalid("lots").is_err());
+
+        // With nothing valid, it's not the entries that are wrong.
+        let data = "{garbage\n{\"clientIP\": \"nope\"}\n";
+        assert!(parse(data, MaxInvalid::Percent(100.0)).is_err());
+    }
+
+    #[test]
+    fn parses_complete_stream() {
+        let data = gzipped(3);
+        let (entries, truncated, hash) = parse_all(&data, false).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(!truncated);
+        assert_eq!(hash, format!("{:x}", Sha256::digest(&data)));
+    }
+
+    #[test]
+    fn parses_concatenated_streams() {
+        let (entries, _, _) = parse_all(&[gzipped(2), gzipped(3)].concat(), false).unwrap();
+        assert_eq!(entries.len(), 5);
+        let zstd = [zstd_compressed(2), zstd_compressed(3)].concat();
+        let (entries, _, _) = parse_all(&zstd, false).unwrap();
+        assert_eq!(entries.len(), 5);
+        let bzip2 = [bzip2_compressed(2), bzip2_compressed(3)].concat();
+        let (entries, _, _) = parse_all(&bzip2, false).unwrap();
+        assert_eq!(entries.len(), 5);
+    }
+
+    #[test]
+    fn parses_zstd_and_bzip2() {
+        let (entries, truncated, _) = parse_all(&zstd_compressed(3), false).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(!truncated);
+        let (entries, _, _) = parse_all(&bzip2_compressed(2), false).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let tar = tarred(&[
+            ("a.log.zst", &zstd_compressed(2)),
+            ("b.log.bz2", &bzip2_compressed(1)),
+        ]);
+        let (entries, _, _) =
+            parse_all(&zstd::encode_all(tar.as_slice(), 1).unwrap(), false).unwrap();
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn salvages_truncated_stream() {
+        let data = gzipped(3);
+        // Cut partway through the last entry.
+        let data = &data[..data.len() - 8 - ENTRY.len() / 2];
+        assert!(parse_all(data, false).is_err());
+        let (entries, truncated, _) = parse_all(data, true).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn parses_bundled_files() {
+        let plain = format!("{ENTRY}\n");
+        let tar = tarred(&[
+            ("a.log.gz", &gzipped(2)),
+            ("b.log", plain.as_bytes()),
+            ("README", b"not logs"),
+        ]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&tar).unwrap();
+        let (entries, truncated, _) = parse_all(&encoder.finish().unwrap(), false).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(!truncated);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder
+            .write_all(&tarred(&[
+                ("a.log", plain.as_bytes()),
+                ("b.log", b"{nope}\n"),
+            ]))
+            .unwrap();
+        let err = parse_all(&encoder.finish().unwrap(), false).unwrap_err();
+        assert!(
+            format!("{err:#}").starts_with("in member b.log: parse error in entry 0: invalid JSON")
+        );
+    }
+
+    #[test]
+    fn trips_circuit_breaker() {
+        let breaker = CircuitBreaker {
+            objects: 10,
+            max_failure_percent: 50.0,
+        };
+        assert_eq!(breaker.tripped(1, 5), None);
+        // Whatever the rest of the first 10 do, more than half of them failed.
+        assert!(breaker.tripped(0, 6).is_some());
+        assert!(breaker.tripped(4, 6).is_some());
+        // Past the first 10, failures are left to the run.
+        assert_eq!(breaker.tripped(4, 7), None);
+    }
+
+    /// A cruncher with every option at its default.
+    fn cruncher(source: SourceSpec, database: &Path, format: Format) -> Cruncher {
+        Cruncher {
+            sources: vec![source],
+            database: database.to_path_buf(),
+            format,
+            strict_fields: false,
+            concurrency: 1,
+            cleanup: false,
+            archive_prefix: None,
+            archive_to: None,
+            archive_zstd_level: None,
+            dead_letter_prefix: None,
+            stop_deleting_on_error: false,
+            verify: false,
+            filter: ObjectFilter::default(),
+            empty_objects: EmptyObjects::default(),
+            salvage_truncated: false,
+            skip_invalid_entries: false,
+            max_invalid_entries: MaxInvalid::default(),
+            skip_duplicates: false,
+            serve_friendly: false,
+            aggregate_only: false,
+            dedup_requests: None,
+            store_queries: false,
+            normalize_paths: false,
+            lowercase_paths: false,
+            oldest_first: false,
+            adaptive_concurrency: false,
+            max_inflight_bytes: None,
+            range_chunk_size: None,
+            range_parallelism: 1,
+            listing_cache: None,
+            gcs_transcoding: false,
+            max_objects: None,
+            max_bytes: None,
+            circuit_breaker: None,
+            config: Config::default(),
+            packs: Vec::new(),
+            #[cfg(feature = "scripting")]
+            script: None,
+        }
+    }
+
+    #[test]
+    fn finishes_runs_that_stop_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("logs.db");
+        let cruncher = cruncher(SourceSpec::Stdin, &database, Format::default());
+        let objects = tokio_stream::iter([
+            Ok(Object::Skipped {
+                source: None,
+                name: "marker.txt".to_string(),
+                reason: "not logs".to_string(),
+            }),
+            Err(anyhow::anyhow!("listing failed")),
+        ]);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        assert!(rt.block_on(cruncher.store(objects)).is_err());
+        let (finished, skipped): (Option<String>, usize) = rusqlite::Connection::open(&database)
+            .unwrap()
+            .query_row("SELECT finished_at, skipped FROM runs", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert!(finished.is_some());
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn crunches_plain_and_gzipped_streams() {
+        let cruncher = cruncher(SourceSpec::Stdin, ":memory:".as_ref(), Format::default());
+        let db = cruncher::Cruncher::open(&cruncher.database, &Sqlite::default()).unwrap();
+        let plain = format!("{ENTRY}\n").repeat(2);
+        assert_eq!(cruncher.crunch_entries(&db, plain.as_bytes()).unwrap(), 2);
+        // Two gzip members back-to-back, as from concatenated objects.
+        let concatenated = [gzipped(2), gzipped(3)].concat();
+        assert_eq!(
+            cruncher
+                .crunch_entries(&db, concatenated.as_slice())
+                .unwrap(),
+            5
+        );
+        let zstd = zstd_compressed(2);
+        assert_eq!(cruncher.crunch_entries(&db, zstd.as_slice()).unwrap(), 2);
+        let bzip2 = bzip2_compressed(3);
+        assert_eq!(cruncher.crunch_entries(&db, bzip2.as_slice()).unwrap(), 3);
+
+        // Invalid entries fail the stream, unless they're to be skipped.
+        let invalid = format!("{ENTRY}\n{{garbage\n{ENTRY}\n");
+        assert!(cruncher.crunch_entries(&db, invalid.as_bytes()).is_err());
+        let mut cruncher = cruncher;
+        cruncher.skip_invalid_entries = true;
+        assert!(cruncher.crunch_entries(&db, invalid.as_bytes()).is_err());
+        cruncher.max_invalid_entries = MaxInvalid::Count(1);
+        assert_eq!(cruncher.crunch_entries(&db, invalid.as_bytes()).unwrap(), 2);
+    }
+
+    #[test]
+    fn keeps_dedup_requests_until_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("logs.db");
+        let mut cruncher = cruncher(SourceSpec::Stdin, &database, Format::default());
+        let twice = format!("{ENTRY}\n").repeat(2);
+        let mut crunch = |dedup_requests| {
+            cruncher.dedup_requests = dedup_requests;
+            let db = cruncher.open_database().unwrap();
+            cruncher.crunch_entries(&db, twice.as_bytes()).unwrap();
+            db.count_requests().unwrap()
+        };
+        assert_eq!(crunch(Some(true)), 1);
+        // Runs that don't say keep it up.
+        assert_eq!(crunch(None), 1);
+        assert_eq!(crunch(Some(false)), 3);
+        assert_eq!(crunch(None), 5);
+    }
+
+    #[test]
+    fn crunches_posted_batches_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("logs.db");
+        let cruncher = cruncher(SourceSpec::Stdin, &database, Format::default());
+        let db = cruncher.open_database().unwrap();
+        let body = format!("{ENTRY}\n").repeat(3);
+        assert_eq!(
+            cruncher
+                .crunch_posted(&db, "http:batch-1", body.as_bytes())
+                .unwrap(),
+            crate::http::Stored::Entries(3)
+        );
+        assert_eq!(
+            cruncher
+                .crunch_posted(&db, "http:batch-1", body.as_bytes())
+                .unwrap(),
+            crate::http::Stored::Duplicate
+        );
+        // A batch that fails leaves nothing behind, so it can be sent again.
+        let invalid = format!("{ENTRY}\n{{garbage\n");
+        assert!(cruncher
+            .crunch_posted(&db, "http:batch-2", invalid.as_bytes())
+            .is_err());
+        assert_eq!(db.count_requests().unwrap(), 3);
+        assert_eq!(
+            cruncher
+                .crunch_posted(&db, "http:batch-2", body.as_bytes())
+                .unwrap(),
+            crate::http::Stored::Entries(3)
+        );
+    }
+
+    #[test]
+    fn crunches_uncompressed_csv_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+        std::fs::create_dir(&logs).unwrap();
+        std::fs::write(
+            logs.join("2024-06-01.csv"),
+            "request_start_time,client_ip,url_path,response_status\n\
+            2024-06-01T12:30:00Z,192.0.2.1,/,200\n\
+            2024-06-01T12:30:01Z,192.0.2.2,/writing/,404\n",
+        )
+        .unwrap();
+        std::fs::write(logs.join("README"), "not logs\n").unwrap();
+        let database = dir.path().join("logs.db");
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        cruncher(SourceSpec::Fs { dir: logs }, &database, Format::Csv)
+            .crunch(&rt)
+            .unwrap();
+        let db = rusqlite::Connection::open(&database).unwrap();
+        let requests: i64 = db
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(requests, 2);
+    }
+
+    #[test]
+    fn applies_backfill_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+        std::fs::create_dir(&logs).unwrap();
+        for (name, n) in [
+            ("2024-06-01T00:00:00.000-a.log.gz", 2),
+            ("2024-06-01T12:00:00.000-b.log.gz", 1),
+            ("2024-06-02T00:00:00.000-c.log.gz", 3),
+        ] {
+            std::fs::write(logs.join(name), gzipped(n)).unwrap();
+        }
+        let database = dir.path().join("logs.db");
+        let path = dir.path().join("backfill.json");
+        let backfill = |filter| Cruncher {
+            filter,
+            ..cruncher(
+                SourceSpec::Fs { dir: logs.clone() },
+                &database,
+                Format::default(),
+            )
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let manifest = backfill(ObjectFilter::default())
+            .plan_backfill(&rt, day)
+            .unwrap();
+        assert_eq!(manifest.batches.len(), 2);
+        manifest.save(&path).unwrap();
+
+        // Not with another filter.
+        let err = backfill(ObjectFilter::default().with_prefix("2024-06-02"))
+            .apply_backfill(&rt, &path, None)
+            .unwrap_err();
+        assert!(err.to_string().contains("another object filter"));
+
+        // An object gone missing since the plan fails its batch.
+        std::fs::remove_file(logs.join("2024-06-02T00:00:00.000-c.log.gz")).unwrap();
+        let manifest = backfill(ObjectFilter::default())
+            .apply_backfill(&rt, &path, None)
+            .unwrap();
+        assert_eq!(manifest.batches[0].status, BatchStatus::Done);
+        assert_eq!(manifest.batches[0].crunched, 2);
+        assert_eq!(manifest.batches[1].status, BatchStatus::Failed);
+        assert_eq!(
+            manifest.batches[1].error.as_deref(),
+            Some("only 0 of the 1 objects planned were crunched")
+        );
+    }
+}
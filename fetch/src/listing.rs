@@ -0,0 +1,193 @@
+//! A cache of a source's last listing, so a run only has to list what's new.
+//!
+//! Listing a bucket of millions of objects is slow, and each page of the listing is a request
+//! to pay for. When object names sort by their timestamps, as Fastly's do
+//! (`2024-06-01T00:00:00.000-...`), new objects sort after the ones already listed; so a run
+//! can take the objects up to a marker from the cache, and list only the names after it.
+//! The marker trails the newest name by OVERLAP, since the objects for a log period can land
+//! a while after later ones.
+//!
+//! The cache holds the listed objects that are still in the store, as far as the fetcher
+//! knows: not cleaned up, archived, or moved to dead letters. Objects whose names don't have
+//! timestamps aren't taken into account for the marker; if one turns up before it, it's missed
+//! until the cache is removed.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use chrono::{NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+use crate::source::ObjectInfo;
+
+/// How far behind the newest object the marker is kept.
+const OVERLAP: TimeDelta = TimeDelta::hours(3);
+
+/// A listing, as saved.
+#[derive(Default, Serialize, Deserialize)]
+struct Listing {
+    /// The prefix listed; a listing of another prefix can't stand in for this one.
+    prefix: String,
+    /// Every object in the store named up to (and including) this is in `objects`.
+    marker: Option<String>,
+    objects: BTreeMap<String, ObjectInfo>,
+}
+
+/// The cached listing of one source, and the listing in progress.
+pub(crate) struct ListingCache {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    listing: Listing,
+    /// Objects removed from the store during the run.
+    gone: HashSet<String>,
+    /// Whether the listing was complete, so it can be saved.
+    finished: bool,
+}
+
+impl ListingCache {
+    /// A cache kept in the file at `path`, which is created if it doesn't exist.
+    pub(crate) fn new(path: PathBuf) -> Self {
+        ListingCache {
+            path,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Start a listing of `prefix`. Returns the marker to list after, and the objects up to
+    /// it, if the cache has them.
+    pub(crate) fn start(&self, prefix: &str) -> Option<(String, Vec<ObjectInfo>)> {
+        let cached = match self.load() {
+            Ok(cached) => cached,
+            Err(e) => {
+                tracing::warn!("ignoring listing cache: {e:#}");
+                None
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        *state = State::default();
+        state.listing.prefix = prefix.to_string();
+        let cached = cached.filter(|cached| cached.prefix == prefix)?;
+        let marker = cached.marker?;
+        state.listing.marker = Some(marker.clone());
+        let objects = cached
+            .objects
+            .into_values()
+            .filter(|object| object.name <= marker)
+            .collect();
+        Some((marker, objects))
+    }
+
+    fn load(&self) -> anyhow::Result<Option<Listing>> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("could not read {}", self.path.display()))
+            }
+        };
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("could not parse {}", self.path.display()))
+    }
+
+    /// Add a listed object.
+    pub(crate) fn record(&self, object: &ObjectInfo) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .listing
+            .objects
+            .insert(object.name.clone(), object.clone());
+    }
+
+    /// Note that an object is no longer in the store.
+    pub(crate) fn remove(&self, name: &str) {
+        self.state.lock().unwrap().gone.insert(name.to_string());
+    }
+
+    /// Finish the listing, moving the marker along if the names sort by their timestamps,
+    /// per `name_time`.
+    pub(crate) fn finish(&self, name_time: impl Fn(&str) -> Option<NaiveDateTime>) {
+        let mut state = self.state.lock().unwrap();
+        let times: Vec<_> = state
+            .listing
+            .objects
+            .keys()
+            .filter_map(|name| Some((name, name_time(name)?)))
+            .collect();
+        if times.windows(2).any(|pair| pair[1].1 < pair[0].1) {
+            tracing::warn!("object names don't sort by their timestamps; listing everything");
+            state.listing.marker = None;
+        } else if let Some(&(_, newest)) = times.last() {
+            let marker = times
+                .iter()
+                .rev()
+                .find(|(_, time)| *time <= newest - OVERLAP)
+                .map(|(name, _)| name.to_string());
+            // Everything up to the old marker is still in the cache, or gone.
+            state.listing.marker = state.listing.marker.take().max(marker);
+        }
+        state.finished = true;
+    }
+
+    /// Save the listing, without the objects that are gone, if it was finished.
+    pub(crate) fn save(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.finished {
+            return Ok(());
+        }
+        let State { listing, gone, .. } = &mut *state;
+        listing.objects.retain(|name, _| !gone.contains(name));
+        let contents = serde_json::to_vec(listing).context("could not serialize listing")?;
+        // Replace it whole, so a crash doesn't leave half a cache.
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, contents)
+            .and_then(|()| std::fs::rename(&temp, &self.path))
+            .with_context(|| format!("could not write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ListingCache;
+    use crate::{filter::ObjectFilter, source::ObjectInfo};
+
+    fn object(name: &str) -> ObjectInfo {
+        ObjectInfo {
+            name: name.to_string(),
+            size: Some(1),
+            md5: None,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn keeps_marker_behind_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("listing.json");
+        let filter = ObjectFilter::default();
+        let cache = ListingCache::new(path.clone());
+        assert!(cache.start("").is_none());
+        for name in [
+            "2024-06-01T00:00:00.000-a.log.gz",
+            "2024-06-01T01:00:00.000-a.log.gz",
+            "2024-06-01T05:00:00.000-a.log.gz",
+        ] {
+            cache.record(&object(name));
+        }
+        cache.remove("2024-06-01T00:00:00.000-a.log.gz");
+        cache.finish(|name| filter.name_time(name));
+        cache.save().unwrap();
+
+        let (marker, objects) = cache.start("").unwrap();
+        assert_eq!(marker, "2024-06-01T01:00:00.000-a.log.gz");
+        assert_eq!(objects.len(), 1);
+        assert!(cache.start("2024-07-").is_none());
+    }
+}
@@ -0,0 +1,186 @@
+//! Fastly JSON from a custom log format that names fields its own way, or that must have
+//! every field.
+//!
+//! Each field is read from the key LogEntry gives it (`clientIP`, `urlPath`, ...), unless the
+//! `[fields]` section of the config names another:
+//!
+//! ```toml
+//! [fields]
+//! client_ip = "client_ip"
+//! request_start_time = "timestamp"
+//! ```
+//!
+//! Values are coerced to what the field takes where the meaning is clear: `true` and `"yes"`
+//! for flags, whole numbers written as floats (`200.0`), `AS64496` for the AS.
+
+use std::{collections::BTreeMap, io::BufRead, sync::Arc};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    format::{check_strict, fastly_entry, json_records, EntryError, LogFormat},
+    record::LogEntry,
+};
+
+/// How a field's value is coerced.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    /// Taken as it is.
+    Plain,
+    /// Text that's needed, where null is empty.
+    Text,
+    /// A whole number.
+    Number,
+    /// A flag.
+    Flag,
+}
+
+/// The fields that can be mapped, with LogEntry's key for each.
+const KEYS: &[(&str, &str, Kind)] = &[
+    ("client_ip", "clientIP", Kind::Plain),
+    ("request_start_time", "reqStartTime", Kind::Plain),
+    ("url_path", "urlPath", Kind::Text),
+    ("query", "urlQuery", Kind::Plain),
+    ("response_status", "respStatus", Kind::Number),
+    ("asn", "ispID", Kind::Number),
+    ("country_code", "countryCode", Kind::Plain),
+    ("requests", "requests", Kind::Number),
+    ("ipv6", "isIPv6", Kind::Flag),
+    ("http2", "isH2", Kind::Flag),
+    ("referer", "httpReferer", Kind::Plain),
+    ("user_agent", "httpUA", Kind::Plain),
+    ("cache_state", "cacheState", Kind::Plain),
+    ("response_bytes", "respTotalBytes", Kind::Number),
+    ("response_duration", "timeElapsed", Kind::Number),
+    ("language", "acceptLanguage", Kind::Plain),
+    ("request_header_bytes", "reqHeaderBytes", Kind::Number),
+    ("request_body_bytes", "reqBodyBytes", Kind::Number),
+    ("cache_age", "objAge", Kind::Plain),
+    ("cache_ttl", "objTTL", Kind::Plain),
+    ("request_method", "reqMethod", Kind::Plain),
+    ("request_protocol", "reqProtocol", Kind::Plain),
+    ("host", "httpHost", Kind::Plain),
+    ("service_id", "serviceID", Kind::Plain),
+];
+
+/// Whether `field` can be mapped to a key of the JSON.
+pub(crate) fn is_field(field: &str) -> bool {
+    KEYS.iter().any(|(name, _, _)| *name == field)
+}
+
+/// Fastly's JSON, with keys mapped to fields by the config.
+pub(crate) struct MappedJson {
+    /// Which key each field is in, where it isn't LogEntry's.
+    fields: Arc<BTreeMap<String, String>>,
+    /// Whether every field must be there; see format::check_strict.
+    strict: bool,
+}
+
+impl MappedJson {
+    pub(crate) fn new(fields: &BTreeMap<String, String>, strict: bool) -> Self {
+        MappedJson {
+            fields: Arc::new(fields.clone()),
+            strict,
+        }
+    }
+}
+
+impl LogFormat for MappedJson {
+    fn parse<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Box<dyn Iterator<Item = Result<LogEntry, EntryError>> + 'a> {
+        let (fields, strict) = (Arc::clone(&self.fields), self.strict);
+        Box::new(
+            json_records(input).map(move |record: Result<Map<String, Value>, _>| {
+                let record = remap(record?, &fields);
+                if strict {
+                    check_strict(&record)?;
+                }
+                fastly_entry(record)
+            }),
+        )
+    }
+}
+
+/// The record with LogEntry's keys, and values it takes; fields it has no key for are dropped.
+fn remap(mut record: Map<String, Value>, fields: &BTreeMap<String, String>) -> Map<String, Value> {
+    // Take every value out before putting any back, in case a key is another field's name.
+    let values: Vec<_> = KEYS
+        .iter()
+        .map(|(field, key, kind)| {
+            let from = fields.get(*field).map_or(*key, String::as_str);
+            (*key, record.remove(from).map(|value| coerce(*kind, value)))
+        })
+        .collect();
+    values
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .collect()
+}
+
+/// The value as the field takes it, where it can be told what was meant.
+fn coerce(kind: Kind, value: Value) -> Value {
+    match (kind, value) {
+        (Kind::Text, Value::Null) => Value::String(String::new()),
+        (Kind::Text, Value::Number(n)) => Value::String(n.to_string()),
+        (Kind::Number, Value::Number(n)) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.fract() == 0.0 && f >= 0.0 => Value::from(f as u64),
+            _ => Value::Number(n),
+        },
+        (Kind::Number, Value::String(s)) => match s.strip_prefix("AS") {
+            Some(asn) => Value::String(asn.to_string()),
+            None => Value::String(s),
+        },
+        (Kind::Flag, Value::String(s)) => match s.to_ascii_lowercase().as_str() {
+            "true" | "yes" => Value::Bool(true),
+            "false" | "no" | "" => Value::Bool(false),
+            _ => Value::String(s),
+        },
+        (_, value) => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::MappedJson;
+    use crate::format::{EntryError, LogFormat};
+
+    #[test]
+    fn maps_keys_to_fields() {
+        let input = r#"
+            {"client_ip": "2001:db8::1", "ispID": "AS64496", "countryCode": "nl",
+             "isH2": "true", "urlPath": "/", "httpReferer": null, "httpUA": "curl/8.0",
+             "cacheState": "HIT", "status": 200.0, "respTotalBytes": "512",
+             "timeElapsed": 1500, "timestamp": "2024-06-01T12:30:00Z"}
+        "#;
+        let fields = BTreeMap::from([
+            ("client_ip".to_string(), "client_ip".to_string()),
+            ("response_status".to_string(), "status".to_string()),
+            ("request_start_time".to_string(), "timestamp".to_string()),
+        ]);
+        let format = MappedJson::new(&fields, false);
+        let entries: Vec<_> = format
+            .parse(Box::new(input.as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let entry = &entries[0];
+        assert_eq!(entry.http2, Some(true));
+        assert_eq!(entry.asn, Some(64496));
+        assert_eq!(entry.requests, 1);
+        assert_eq!(entry.referer, None);
+        assert_eq!(entry.response_status, 200);
+        assert_eq!(entry.response_duration, Some(Duration::from_micros(1500)));
+
+        // Without the mapping, there's no clientIP.
+        let mut entries =
+            MappedJson::new(&BTreeMap::new(), false).parse(Box::new(input.as_bytes()));
+        assert!(matches!(entries.next(), Some(Err(EntryError::Invalid(_)))));
+
+        // Strictly, there's no requests or isIPv6 either.
+        let mut entries = MappedJson::new(&fields, true).parse(Box::new(input.as_bytes()));
+        assert!(matches!(entries.next(), Some(Err(EntryError::Invalid(_)))));
+    }
+}
@@ -0,0 +1,131 @@
+//! Access logs from nginx on the origin, in JSON, so origin traffic can be crunched alongside
+//! the CDN's.
+//!
+//! nginx's JSON is whatever its `log_format` says; this reads entries with nginx's own variable
+//! names, as from:
+//!
+//! ```nginx
+//! log_format cruncher escape=json
+//!     '{"remote_addr":"$remote_addr","time_iso8601":"$time_iso8601",'
+//!     '"request_uri":"$request_uri","status":"$status","bytes_sent":"$bytes_sent",'
+//!     '"request_time":"$request_time","http_referer":"$http_referer",'
+//!     '"http_user_agent":"$http_user_agent","server_protocol":"$server_protocol",'
+//!     '"request_method":"$request_method","host":"$host",'
+//!     '"http_accept_language":"$http_accept_language",'
+//!     '"upstream_cache_status":"$upstream_cache_status"}';
+//! access_log syslog:server=unix:/dev/log,tag=nginx_access,nohostname cruncher;
+//! ```
+//!
+//! The last four fields are optional. nginx doesn't know the client's AS or country, so those
+//! are left NULL.
+
+use std::{net::IpAddr, time::Duration};
+
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+
+use crate::record::{
+    deserialize_language, deserialize_number_from_string, request_host, request_method,
+    request_protocol, LogEntry,
+};
+
+/// An access log entry in the format above.
+#[derive(Debug, Deserialize)]
+pub(crate) struct NginxEntry {
+    remote_addr: IpAddr,
+    time_iso8601: DateTime<FixedOffset>,
+    request_uri: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    status: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    bytes_sent: usize,
+    /// Seconds, with millisecond resolution.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    request_time: f64,
+    http_referer: String,
+    http_user_agent: String,
+    server_protocol: String,
+    #[serde(default)]
+    request_method: Option<String>,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_language")]
+    http_accept_language: Option<String>,
+    #[serde(default)]
+    upstream_cache_status: Option<String>,
+}
+
+impl From<NginxEntry> for LogEntry {
+    fn from(entry: NginxEntry) -> Self {
+        // Fastly's urlPath doesn't include the query.
+        let (url_path, query) = match entry.request_uri.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (entry.request_uri, None),
+        };
+        LogEntry {
+            client_ip: entry.remote_addr,
+            asn: None,
+            country_code: None,
+            requests: 1,
+            ipv6: entry.remote_addr.is_ipv6(),
+            http2: Some(entry.server_protocol == "HTTP/2.0"),
+            url_path,
+            query,
+            referer: Some(entry.http_referer),
+            user_agent: Some(entry.http_user_agent),
+            cache_state: entry.upstream_cache_status,
+            response_status: entry.status,
+            response_bytes: Some(entry.bytes_sent),
+            response_duration: Some(
+                Duration::try_from_secs_f64(entry.request_time).unwrap_or_default(),
+            ),
+            request_start_time: entry.time_iso8601.to_utc().into(),
+            language: entry.http_accept_language,
+            request_header_bytes: None,
+            request_body_bytes: None,
+            cache_age: None,
+            cache_ttl: None,
+            request_method: entry.request_method.as_deref().and_then(request_method),
+            request_protocol: request_protocol(&entry.server_protocol),
+            host: entry.host.as_deref().and_then(request_host),
+            service_id: None,
+            raw_path: None,
+            tags: Vec::new(),
+            source: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::NginxEntry;
+    use crate::record::LogEntry;
+
+    #[test]
+    fn converts_nginx_entry() {
+        let entry: NginxEntry = serde_json::from_str(
+            r#"{"remote_addr":"2001:db8::1","time_iso8601":"2024-07-01T02:00:00+02:00",
+            "request_uri":"/writing/a/?utm_source=feed","status":"304","bytes_sent":"180",
+            "request_time":"0.012","http_referer":"","http_user_agent":"curl/8.0",
+            "server_protocol":"HTTP/2.0","request_method":"GET",
+            "http_accept_language":"de-DE,de;q=0.9","upstream_cache_status":""}"#,
+        )
+        .unwrap();
+        let entry = LogEntry::from(entry);
+        assert_eq!(entry.url_path, "/writing/a/");
+        assert_eq!(entry.query.as_deref(), Some("utm_source=feed"));
+        assert!(entry.ipv6);
+        assert_eq!(entry.http2, Some(true));
+        assert_eq!(entry.request_method.as_deref(), Some("GET"));
+        assert_eq!(entry.request_protocol.as_deref(), Some("HTTP/2"));
+        assert_eq!(entry.response_status, 304);
+        assert_eq!(entry.response_duration, Some(Duration::from_millis(12)));
+        assert_eq!(
+            entry.request_start_time.time.to_rfc3339(),
+            "2024-07-01T00:00:00+00:00"
+        );
+        assert_eq!(entry.language.as_deref(), Some("de"));
+    }
+}
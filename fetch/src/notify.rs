@@ -0,0 +1,183 @@
+//! Notifications about runs, posted to chat webhooks.
+//!
+//! Each run sends a short summary to each configured webhook; runs with errors, or that fail
+//! outright, are sent as alerts. Webhooks with `alerts_only` set skip the routine summaries.
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::json;
+
+/// The chat service a webhook belongs to; they take slightly different payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookKind {
+    Slack,
+    Discord,
+}
+
+/// A webhook to notify, from the `[[notify]]` sections of the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub kind: WebhookKind,
+    pub url: String,
+    /// Channel to post to, overriding the webhook's default.
+    /// Only Slack honors this; a Discord webhook always posts to its own channel.
+    pub channel: Option<String>,
+    /// Only send alerts, not a summary of every run.
+    #[serde(default)]
+    pub alerts_only: bool,
+}
+
+/// Outcome of a completed run.
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    pub run_id: i64,
+    pub ok: usize,
+    pub errors: usize,
+    pub skipped: usize,
+    /// Names of the objects that could not be processed.
+    pub failed: Vec<String>,
+}
+
+/// How many failed objects to list in an alert; the rest are in processed_objects.
+const MAX_FAILED_LISTED: usize = 5;
+
+/// A message to post.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub title: String,
+    pub lines: Vec<String>,
+    pub alert: bool,
+}
+
+impl Message {
+    /// Summarize a completed run. This is an alert if any objects failed.
+    pub fn summary(summary: &RunSummary) -> Self {
+        let mut lines = vec![format!(
+            "{} ok, {} errors, {} skipped",
+            summary.ok, summary.errors, summary.skipped
+        )];
+        lines.extend(
+            summary
+                .failed
+                .iter()
+                .take(MAX_FAILED_LISTED)
+                .map(|name| format!("failed: {name}")),
+        );
+        if summary.failed.len() > MAX_FAILED_LISTED {
+            lines.push(format!(
+                "...and {} more",
+                summary.failed.len() - MAX_FAILED_LISTED
+            ));
+        }
+        Message {
+            title: format!("log-cruncher run {}", summary.run_id),
+            lines,
+            alert: summary.errors > 0,
+        }
+    }
+
+    /// Alert that a run failed before completing.
+    pub fn failure(err: &anyhow::Error) -> Self {
+        Message {
+            title: "log-cruncher run failed".to_string(),
+            lines: vec![format!("{err:#}")],
+            alert: true,
+        }
+    }
+
+    /// The JSON payload to post to a webhook of this kind.
+    fn payload(&self, webhook: &Webhook) -> serde_json::Value {
+        let marker = if self.alert { ":warning: " } else { "" };
+        match webhook.kind {
+            WebhookKind::Slack => {
+                let text = format!("{marker}*{}*\n{}", self.title, self.lines.join("\n"));
+                let mut payload = json!({ "text": text });
+                if let Some(channel) = &webhook.channel {
+                    payload["channel"] = json!(channel);
+                }
+                payload
+            }
+            WebhookKind::Discord => {
+                let text = format!("{marker}**{}**\n{}", self.title, self.lines.join("\n"));
+                json!({ "content": text })
+            }
+        }
+    }
+}
+
+impl Webhook {
+    /// Post the message to this webhook, unless it's not wanted here.
+    pub async fn send(&self, client: &reqwest::Client, message: &Message) -> anyhow::Result<()> {
+        if self.alerts_only && !message.alert {
+            return Ok(());
+        }
+        client
+            .post(&self.url)
+            .json(&message.payload(self))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("could not post to {:?} webhook", self.kind))?;
+        Ok(())
+    }
+}
+
+/// Post the message to all the webhooks, logging (rather than returning) failures.
+pub async fn send_all(webhooks: &[Webhook], message: &Message) {
+    if webhooks.is_empty() {
+        return;
+    }
+    let client = reqwest::Client::new();
+    for webhook in webhooks {
+        if let Err(e) = webhook.send(&client, message).await {
+            tracing::error!("could not send notification: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Message, RunSummary, Webhook, WebhookKind};
+
+    #[test]
+    fn formats_payloads() {
+        let message = Message::summary(&RunSummary {
+            run_id: 7,
+            ok: 3,
+            errors: 1,
+            skipped: 0,
+            failed: vec!["2024-07-01.log.gz".to_string()],
+        });
+        assert!(message.alert);
+        let slack = Webhook {
+            kind: WebhookKind::Slack,
+            url: String::new(),
+            channel: Some("#logs".to_string()),
+            alerts_only: false,
+        };
+        assert_eq!(
+            message.payload(&slack),
+            json!({
+                "text": ":warning: *log-cruncher run 7*\n3 ok, 1 errors, 0 skipped\n\
+                    failed: 2024-07-01.log.gz",
+                "channel": "#logs",
+            })
+        );
+        let discord = Webhook {
+            kind: WebhookKind::Discord,
+            channel: None,
+            ..slack
+        };
+        assert_eq!(
+            message.payload(&discord),
+            json!({
+                "content": ":warning: **log-cruncher run 7**\n3 ok, 1 errors, 0 skipped\n\
+                    failed: 2024-07-01.log.gz",
+            })
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! "Packs" of SQL: shareable analyses that don't need changes to the crate.
+//!
+//! A pack is a directory with any of:
+//! - `views.sql`: views (or tables, or indices) to create in the database when it is opened.
+//!   These run on every open, so should use `IF NOT EXISTS`.
+//! - `enrich.sql`: statements to run at the end of each crunch, e.g. to fill derived tables.
+//! - `reports/*.sql`: report queries, run with `reports/pack.sh`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// A pack of SQL, loaded from a directory.
+#[derive(Debug, Clone)]
+pub struct Pack {
+    pub name: String,
+    pub views: Option<String>,
+    pub enrich: Option<String>,
+    pub reports: Vec<PathBuf>,
+}
+
+impl Pack {
+    /// Load a pack from its directory.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let name = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .with_context(|| format!("pack directory {} has no name", dir.display()))?;
+        let read_optional = |file: &str| -> anyhow::Result<Option<String>> {
+            let path = dir.join(file);
+            if !path.exists() {
+                return Ok(None);
+            }
+            std::fs::read_to_string(&path)
+                .map(Some)
+                .with_context(|| format!("could not read {}", path.display()))
+        };
+        let mut reports = Vec::new();
+        let reports_dir = dir.join("reports");
+        if reports_dir.is_dir() {
+            for entry in std::fs::read_dir(&reports_dir)
+                .with_context(|| format!("could not list {}", reports_dir.display()))?
+            {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "sql") {
+                    reports.push(path);
+                }
+            }
+            reports.sort();
+        }
+        Ok(Pack {
+            views: read_optional("views.sql")?,
+            enrich: read_optional("enrich.sql")?,
+            name,
+            reports,
+        })
+    }
+
+    /// Find all the packs in a directory: each subdirectory is a pack.
+    pub fn discover(dir: &Path) -> anyhow::Result<Vec<Self>> {
+        let mut dirs = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("could not list pack directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+        dirs.sort();
+        dirs.iter().map(|dir| Self::load(dir)).collect()
+    }
+}
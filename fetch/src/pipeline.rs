@@ -0,0 +1,67 @@
+//! A run, as stages of streams that library users can put stages of their own between.
+//!
+//! `Cruncher::objects` starts fetching, and returns the objects as a stream of `Object`s, as
+//! they're fetched. Each log set in it is a stream of its entries, in batches, as they're
+//! parsed; `map_entries` adds a stage to it, e.g. to filter, sample, or mirror them.
+//! `Cruncher::store` takes the stream, stores each log set as it comes, and records the run;
+//! then `Objects::finish` wraps up the fetching. So a run that leaves out health checks is:
+//!
+//! ```text
+//! let mut objects = cruncher.objects().await?;
+//! let checked = (&mut objects).map(|object| {
+//!     object.map(|object| {
+//!         object.map_entries(|mut entries| {
+//!             entries.retain(|entry| entry.url_path != "/healthz");
+//!             Ok(entries)
+//!         })
+//!     })
+//! });
+//! cruncher.store(checked).await?;
+//! objects.finish();
+//! ```
+//!
+//! Log sets are cleaned up (or archived) as they're stored; one left out of the stream isn't.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::sync::mpsc::Receiver;
+use tokio_stream::Stream;
+
+use crate::fetcher::{Fetcher, Object};
+
+/// The objects fetched from a run's sources, interleaved, as they're fetched.
+pub struct Objects {
+    fetchers: Vec<(Option<String>, Arc<Fetcher>)>,
+    objects: Receiver<anyhow::Result<Object>>,
+}
+
+impl Objects {
+    pub(crate) fn new(
+        fetchers: Vec<(Option<String>, Arc<Fetcher>)>,
+        objects: Receiver<anyhow::Result<Object>>,
+    ) -> Self {
+        Objects { fetchers, objects }
+    }
+
+    /// Finish fetching, once the objects are stored: save the sources' listing caches.
+    pub fn finish(self) {
+        for (label, fetcher) in &self.fetchers {
+            if let Err(e) = fetcher.save_listing_cache() {
+                let label = label.as_deref().unwrap_or("source");
+                tracing::error!("could not save listing cache for {label}: {e:#}");
+            }
+        }
+    }
+}
+
+impl Stream for Objects {
+    type Item = anyhow::Result<Object>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.objects.poll_recv(cx)
+    }
+}
@@ -0,0 +1,339 @@
+//! What the enrichments at the end of a run would do, tried on a sample of requests: which
+//! columns each fills, with example values, and how long it and its API calls would take
+//! over all the requests it has yet to do.
+//!
+//! Everything is tried in a transaction that's rolled back, so the database is left as it was.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use rusqlite::{types::Value, Connection, OptionalExtension};
+
+use crate::{config::ComputedColumn, cruncher::COMPUTED_INPUTS, packs::Pack};
+
+/// How many example values to show.
+const EXAMPLES: usize = 5;
+
+/// What an enrichment would do.
+#[derive(Debug)]
+pub struct Preview {
+    /// Which enrichment: a computed column, a pack, or AS names.
+    pub name: String,
+    /// Requests (or, for AS names, ASes) it has yet to do.
+    pub pending: usize,
+    /// How many of those the sample had.
+    pub sampled: usize,
+    /// How long the sample took.
+    pub elapsed: Duration,
+    /// The columns it fills, as table.column.
+    pub columns: Vec<String>,
+    /// Example values, from the sample.
+    pub examples: Vec<String>,
+    /// Calls it would make to PeeringDB and Spamhaus.
+    pub api_calls: usize,
+}
+
+impl Preview {
+    /// How long it would take over every pending request, going by the sample.
+    pub fn estimate(&self) -> Option<Duration> {
+        (self.sampled > 0).then(|| {
+            self.elapsed
+                .mul_f64(self.pending as f64 / self.sampled as f64)
+        })
+    }
+}
+
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {} to do", self.name, self.pending)?;
+        if let Some(estimate) = self.estimate() {
+            writeln!(
+                f,
+                "  sampled {} in {:.1?}; about {:.1?} for all",
+                self.sampled, self.elapsed, estimate
+            )?;
+        }
+        if self.api_calls > 0 {
+            writeln!(f, "  up to {} API calls", self.api_calls)?;
+        }
+        if !self.columns.is_empty() {
+            writeln!(f, "  fills {}", self.columns.join(", "))?;
+        }
+        if !self.examples.is_empty() {
+            writeln!(f, "  e.g. {}", self.examples.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Try the computed columns, the packs' enrichments, and naming ASes (if `name_asns`), on up
+/// to `sample` requests each; nothing is kept.
+pub(crate) fn preview(
+    conn: &Connection,
+    columns: &[ComputedColumn],
+    packs: &[Pack],
+    name_asns: bool,
+    sample: usize,
+) -> anyhow::Result<Vec<Preview>> {
+    let tx = conn
+        .unchecked_transaction()
+        .context("could not begin transaction")?;
+    tx.execute_batch(COMPUTED_INPUTS)
+        .context("could not create computed_inputs view")?;
+    let mut previews = Vec::new();
+    for column in columns {
+        previews.push(
+            preview_column(&tx, column, sample)
+                .with_context(|| format!("could not preview computed column {}", column.name))?,
+        );
+    }
+    for pack in packs {
+        if let Some(enrich) = &pack.enrich {
+            let preview = tx
+                .execute_batch("SAVEPOINT pack")
+                .context("could not begin savepoint")
+                .and_then(|()| preview_pack(&tx, pack, enrich, sample));
+            tx.execute_batch("ROLLBACK TO pack; RELEASE pack")
+                .context("could not roll back savepoint")?;
+            previews
+                .push(preview.with_context(|| format!("could not preview pack {}", pack.name))?);
+        }
+    }
+    if name_asns {
+        previews.push(preview_asns(&tx).context("could not preview AS names")?);
+    }
+    // Dropping the transaction rolls it back.
+    Ok(previews)
+}
+
+/// A computed column, over the requests it hasn't been computed for: all of them, if it's
+/// new or its expression has changed.
+fn preview_column(
+    conn: &Connection,
+    column: &ComputedColumn,
+    sample: usize,
+) -> anyhow::Result<Preview> {
+    let through: i64 = conn
+        .query_row(
+            "SELECT through FROM computed_columns WHERE name = ? AND expr = ?",
+            [&column.name, &column.expr],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let pending: usize = conn.query_row(
+        "SELECT COUNT(*) FROM requests WHERE id > ?",
+        [through],
+        |row| row.get(0),
+    )?;
+    let start = Instant::now();
+    let values: Vec<Value> = conn
+        .prepare(&format!(
+            "SELECT ({}) FROM computed_inputs WHERE id > ?1 ORDER BY id LIMIT ?2",
+            column.expr
+        ))
+        .context("invalid expression")?
+        .query_map((through, sample), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let elapsed = start.elapsed();
+    Ok(Preview {
+        name: format!("computed column {}", column.name),
+        pending,
+        sampled: values.len(),
+        elapsed,
+        columns: vec![format!("requests.{}", column.name)],
+        examples: examples(values),
+        api_calls: 0,
+    })
+}
+
+/// A pack's enrichment, over a sample of the latest requests: it sees them as the requests
+/// table, as a temporary table of the same name hides the real one.
+fn preview_pack(
+    conn: &Connection,
+    pack: &Pack,
+    enrich: &str,
+    sample: usize,
+) -> anyhow::Result<Preview> {
+    let pending: usize = conn.query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))?;
+    let counts = table_counts(conn)?;
+    conn.execute(
+        "CREATE TEMP TABLE requests AS SELECT * FROM main.requests ORDER BY id DESC LIMIT ?",
+        [sample],
+    )
+    .context("could not sample requests")?;
+    let sampled: usize =
+        conn.query_row("SELECT COUNT(*) FROM temp.requests", [], |row| row.get(0))?;
+    if let Some(views) = &pack.views {
+        conn.execute_batch(views)
+            .context("could not create views")?;
+    }
+    let start = Instant::now();
+    conn.execute_batch(enrich)
+        .context("could not run enrichment")?;
+    let elapsed = start.elapsed();
+    let mut columns = Vec::new();
+    let mut examples = Vec::new();
+    for (table, count) in table_counts(conn)? {
+        if counts.get(&table) == Some(&count) {
+            continue;
+        }
+        let mut statement = conn.prepare(&format!(
+            r#"SELECT * FROM main."{table}" ORDER BY rowid DESC LIMIT {EXAMPLES}"#
+        ))?;
+        columns.extend(
+            statement
+                .column_names()
+                .into_iter()
+                .map(|column| format!("{table}.{column}")),
+        );
+        let width = statement.column_count();
+        let rows: Vec<String> = statement
+            .query_map([], |row| {
+                let values: Vec<String> = (0..width)
+                    .map(|i| row.get::<_, Value>(i).map(|value| show(&value)))
+                    .collect::<Result<_, _>>()?;
+                Ok(format!("{table}({})", values.join(", ")))
+            })?
+            .collect::<Result<_, _>>()?;
+        examples.extend(rows);
+    }
+    Ok(Preview {
+        name: format!("pack {}", pack.name),
+        pending,
+        sampled,
+        elapsed,
+        columns,
+        examples,
+        api_calls: 0,
+    })
+}
+
+/// Naming ASes: a call to PeeringDB for each unnamed one, and one for Spamhaus's list if any
+/// of those fail; the built-in snapshot, with asn-seed, names some without a call.
+fn preview_asns(conn: &Connection) -> anyhow::Result<Preview> {
+    #[cfg(feature = "asn-seed")]
+    crate::seed::seed_asns(conn)?;
+    let unnamed: Vec<u32> = conn
+        .prepare("SELECT asn FROM autonomous_systems WHERE name IS NULL ORDER BY asn")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(Preview {
+        name: "AS names".to_string(),
+        pending: unnamed.len(),
+        sampled: 0,
+        elapsed: Duration::ZERO,
+        columns: vec!["autonomous_systems.name".to_string()],
+        examples: unnamed
+            .iter()
+            .take(EXAMPLES)
+            .map(|asn| format!("AS{asn}"))
+            .collect(),
+        api_calls: if unnamed.is_empty() {
+            0
+        } else {
+            unnamed.len() + 1
+        },
+    })
+}
+
+/// How many rows each table in the database has, but requests.
+fn table_counts(conn: &Connection) -> anyhow::Result<BTreeMap<String, usize>> {
+    let tables: Vec<String> = conn
+        .prepare(
+            "SELECT name FROM main.sqlite_schema
+            WHERE type = 'table' AND name != 'requests' AND name NOT LIKE 'sqlite_%'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    tables
+        .into_iter()
+        .map(|table| {
+            let count = conn.query_row(
+                &format!(r#"SELECT COUNT(*) FROM main."{table}""#),
+                [],
+                |row| row.get(0),
+            )?;
+            Ok((table, count))
+        })
+        .collect()
+}
+
+/// The most common values, with how many times each came up.
+fn examples(values: Vec<Value>) -> Vec<String> {
+    let mut counts = BTreeMap::new();
+    for value in &values {
+        *counts.entry(show(value)).or_insert(0) += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts
+        .into_iter()
+        .take(EXAMPLES)
+        .map(|(value, count)| format!("{value} ({count})"))
+        .collect()
+}
+
+fn show(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(s) => format!("{s:?}"),
+        Value::Blob(b) => format!("<{} bytes>", b.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::ComputedColumn, cruncher::Cruncher, packs::Pack, testdata::entry, Sqlite};
+
+    #[test]
+    fn previews_without_changes() {
+        let cruncher = Cruncher::open(":memory:".as_ref(), &Sqlite::default()).unwrap();
+        let mut other = entry();
+        other.response_status = 404;
+        cruncher.crunch(&[entry(), entry(), other]).unwrap();
+        let column = ComputedColumn {
+            name: "error".to_string(),
+            expr: "response_status >= 400".to_string(),
+        };
+        let pack = Pack {
+            name: "errors".to_string(),
+            views: Some("CREATE TABLE IF NOT EXISTS errors (id INTEGER);".to_string()),
+            enrich: Some(
+                "INSERT INTO errors SELECT id FROM requests WHERE response_status >= 400;"
+                    .to_string(),
+            ),
+            reports: Vec::new(),
+        };
+        let preview = || {
+            cruncher
+                .preview_enrichments(
+                    std::slice::from_ref(&column),
+                    std::slice::from_ref(&pack),
+                    true,
+                    2,
+                )
+                .unwrap()
+        };
+        let previews = preview();
+        assert_eq!(previews[0].pending, 3);
+        assert_eq!(previews[0].sampled, 2);
+        assert_eq!(previews[0].examples, ["0 (2)"]);
+        // The pack sees the latest two requests, one of which is an error.
+        assert_eq!(previews[1].columns, ["errors.id"]);
+        assert_eq!(previews[1].examples, ["errors(3)"]);
+        assert_eq!(previews[2].examples, ["AS64496"]);
+        assert_eq!(previews[2].api_calls, 2);
+
+        // Nothing was kept, so it's all the same again.
+        let again = preview();
+        assert_eq!(again[0].pending, 3);
+        assert_eq!(again[1].examples, ["errors(3)"]);
+    }
+}
@@ -0,0 +1,80 @@
+-- Rollups for the public database; see export.rs.
+-- Run with the public database attached as `public`, and min_requests in temp.export_params.
+
+-- Clients are counted from the requests, and for days whose requests were pruned, as they were
+-- counted then; not at all in aggregation-only databases.
+CREATE TABLE public.daily_totals AS
+SELECT
+  daily_totals.date AS date
+, daily_totals.requests AS requests
+, daily_totals.response_bytes AS response_bytes
+  -- A count of addresses, not the addresses themselves.
+, COALESCE(
+    daily_totals.clients + clients.clients
+  , daily_totals.clients
+  , clients.clients
+  ) AS clients
+FROM daily_totals
+    LEFT JOIN (
+        SELECT date(request_start_time) AS date, COUNT(DISTINCT client_ip) AS clients
+        FROM requests
+        GROUP BY 1
+    ) AS clients ON daily_totals.date = clients.date
+ORDER BY 1;
+
+CREATE TABLE public.daily_paths AS
+SELECT
+  daily_paths.date AS date
+, paths.path AS path
+, daily_paths.requests AS requests
+, daily_paths.response_bytes AS response_bytes
+FROM daily_paths
+    JOIN paths ON daily_paths.url_path = paths.id
+WHERE daily_paths.requests >= (SELECT min_requests FROM temp.export_params)
+ORDER BY 1, 2;
+
+CREATE TABLE public.daily_statuses AS
+SELECT
+  date
+, status
+, requests
+FROM daily_statuses
+ORDER BY 1, 2;
+
+CREATE TABLE public.daily_countries AS
+SELECT
+  date
+, NULLIF(country, '') AS country
+, requests
+FROM daily_countries
+WHERE requests >= (SELECT min_requests FROM temp.export_params)
+ORDER BY 1, 2;
+
+-- Hosts only: full referer URLs can carry tokens and private paths.
+CREATE TABLE public.daily_referer_hosts AS
+SELECT
+  daily_referers.date AS date
+, referers.host AS referer_host
+, SUM(daily_referers.requests) AS requests
+FROM daily_referers
+    JOIN referers ON daily_referers.referer = referers.id
+WHERE referers.host IS NOT NULL AND referers.host != ''
+  AND referers.host NOT IN (SELECT host FROM site_hosts)
+GROUP BY 1, 2
+HAVING SUM(daily_referers.requests) >= (SELECT min_requests FROM temp.export_params)
+ORDER BY 1, 2;
+
+CREATE TABLE public.slo_results AS
+SELECT
+  name
+, window_start
+, window_end
+, requests
+, fast_requests
+, good_requests
+, latency_target
+, error_target
+FROM slo_results
+ORDER BY window_end, name;
+
+CREATE INDEX public.daily_paths_by_path ON daily_paths(path);
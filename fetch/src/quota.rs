@@ -0,0 +1,179 @@
+//! Ingest quotas: how many requests from each client IP, AS, or country are stored verbatim
+//! per hour. Past its quota, a client's requests are only counted, so a flood (a DDoS, or an
+//! aggressive crawler) doesn't grow the database by orders of magnitude.
+//!
+//! Usage and overflow are kept in the quota_usage table, per hour and key, in the same
+//! transaction as the requests; so they hold across runs, and roll back with the object. They're
+//! counted up over each batch of entries, and written once at the end of it. Hours are pruned
+//! along with the requests, as they're only needed for requests that come in late.
+
+use std::collections::HashMap;
+
+use rusqlite::{named_params, Connection, OptionalExtension};
+use serde::Deserialize;
+
+use crate::record::LogEntry;
+
+/// Requests stored per hour, from the `[quotas]` section of the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quotas {
+    /// Requests stored per client IP.
+    pub per_ip: Option<u64>,
+    /// Requests stored per AS; requests without one count as the same AS.
+    pub per_asn: Option<u64>,
+    /// Requests stored per country; requests without one count as the same country.
+    pub per_country: Option<u64>,
+}
+
+impl Quotas {
+    pub(crate) fn problems(&self) -> Vec<String> {
+        [
+            ("per_ip", self.per_ip),
+            ("per_asn", self.per_asn),
+            ("per_country", self.per_country),
+        ]
+        .into_iter()
+        .filter(|(_, quota)| *quota == Some(0))
+        .map(|(name, _)| format!("{name} must be at least 1"))
+        .collect()
+    }
+
+    /// Whether to store `entry`: if it's within all its quotas, it's counted against them;
+    /// if not, it's counted as overflow of the quotas it's past. Counts go in `usage`, for
+    /// saving at the end of the batch.
+    pub(crate) fn admit(
+        &self,
+        conn: &Connection,
+        usage: &mut Usage,
+        entry: &LogEntry,
+    ) -> rusqlite::Result<bool> {
+        let quotas = [
+            ("ip", self.per_ip, entry.client_ip.to_string()),
+            (
+                "asn",
+                self.per_asn,
+                entry.asn.map(|asn| asn.to_string()).unwrap_or_default(),
+            ),
+            (
+                "country",
+                self.per_country,
+                entry.country_code.clone().unwrap_or_default(),
+            ),
+        ];
+        if quotas.iter().all(|(_, quota, _)| quota.is_none()) {
+            return Ok(true);
+        }
+        let hour = entry
+            .request_start_time
+            .time
+            .format("%Y-%m-%dT%H")
+            .to_string();
+        let mut over = Vec::new();
+        for (kind, quota, key) in &quotas {
+            let Some(quota) = quota else {
+                continue;
+            };
+            if usage.count(conn, &hour, kind, key)?.stored >= *quota {
+                over.push((kind, key));
+            }
+        }
+        if over.is_empty() {
+            for (kind, quota, key) in &quotas {
+                if quota.is_some() {
+                    usage.count(conn, &hour, kind, key)?.add(1, 0, 0);
+                }
+            }
+            return Ok(true);
+        }
+        for (kind, key) in over {
+            usage.count(conn, &hour, kind, key)?.add(
+                0,
+                entry.requests,
+                entry.response_bytes.unwrap_or(0),
+            );
+        }
+        Ok(false)
+    }
+}
+
+/// Quota usage over a batch of entries: what was stored before it, read once for each hour and
+/// key, and what the batch adds.
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    counts: HashMap<(String, &'static str, String), Count>,
+}
+
+#[derive(Debug, Default)]
+struct Count {
+    /// Requests stored, before the batch and in it.
+    stored: u64,
+    /// What the batch adds.
+    added: u64,
+    overflow_requests: usize,
+    overflow_bytes: usize,
+}
+
+impl Count {
+    fn add(&mut self, stored: u64, requests: usize, bytes: usize) {
+        self.stored += stored;
+        self.added += stored;
+        self.overflow_requests += requests;
+        self.overflow_bytes += bytes;
+    }
+}
+
+impl Usage {
+    /// The count for the hour and key, read from the database the first time in the batch.
+    fn count(
+        &mut self,
+        conn: &Connection,
+        hour: &str,
+        kind: &'static str,
+        key: &str,
+    ) -> rusqlite::Result<&mut Count> {
+        let id = (hour.to_string(), kind, key.to_string());
+        if !self.counts.contains_key(&id) {
+            let stored: Option<u64> = conn
+                .prepare_cached(
+                    "SELECT stored FROM quota_usage WHERE hour = ? AND kind = ? AND key = ?",
+                )?
+                .query_row((hour, kind, key), |row| row.get(0))
+                .optional()?;
+            let count = Count {
+                stored: stored.unwrap_or(0),
+                ..Count::default()
+            };
+            self.counts.insert(id.clone(), count);
+        }
+        Ok(self.counts.get_mut(&id).unwrap())
+    }
+
+    /// Add what the batch counted to the quota_usage table.
+    pub(crate) fn save(self, conn: &Connection) -> rusqlite::Result<()> {
+        let mut insert = conn.prepare_cached(
+            r#"
+            INSERT INTO quota_usage (hour, kind, key, stored, overflow_requests, overflow_bytes)
+            VALUES (:hour, :kind, :key, :stored, :requests, :bytes)
+            ON CONFLICT (hour, kind, key) DO UPDATE SET
+                stored = stored + :stored
+            ,   overflow_requests = overflow_requests + :requests
+            ,   overflow_bytes = overflow_bytes + :bytes;
+            "#,
+        )?;
+        for ((hour, kind, key), count) in self.counts {
+            if count.added == 0 && count.overflow_requests == 0 {
+                continue;
+            }
+            insert.execute(named_params! {
+                ":hour": hour,
+                ":kind": kind,
+                ":key": key,
+                ":stored": count.added,
+                ":requests": count.overflow_requests,
+                ":bytes": count.overflow_bytes,
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -4,62 +4,176 @@
 //!
 //! https://www.fastly.com/documentation/guides/integrations/logging/#custom-log-formatter
 
-use std::{fmt::Display, net::IpAddr, str::FromStr, time::Duration};
+use std::{borrow::Cow, fmt::Display, net::IpAddr, str::FromStr, time::Duration};
 
 use chrono::{DateTime, FixedOffset, Utc};
-use rusqlite::{named_params, Transaction};
+use rusqlite::{named_params, Connection};
 use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
 
 /// JSON log structure from Fastly.
 ///
-/// This is specific to my log setup -- these are the fields I have configured.
-#[derive(Debug, Deserialize)]
+/// This is specific to my log setup -- these are the fields I have configured. Only the client,
+/// path, status, and start time are needed; without the others, they're stored as NULL
+/// (`requests` is 1, and `isIPv6` is from the address). See STRICT_KEYS for requiring them.
+#[derive(Debug, Clone, Deserialize)]
 pub struct LogEntry {
     #[serde(rename = "clientIP")]
-    client_ip: IpAddr,
+    pub client_ip: IpAddr,
 
     // ASNs were 2-byte until ~2007;
     // RFC 6793 formalized 4-byte ASN for BGP in 2021.
-    #[serde(rename = "ispID", deserialize_with = "deserialize_number_from_string")]
-    asn: u32,
+    #[serde(
+        rename = "ispID",
+        default,
+        deserialize_with = "deserialize_optional_number_from_string"
+    )]
+    pub asn: Option<u32>,
 
     #[serde(rename = "countryCode")]
-    country_code: Option<String>,
+    pub country_code: Option<String>,
 
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    requests: usize,
+    #[serde(
+        default = "one_request",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub requests: usize,
     #[serde(
         rename = "isIPv6",
+        default,
         deserialize_with = "deserialize_bool_from_bitstring"
     )]
-    ipv6: bool,
-    #[serde(rename = "isH2", deserialize_with = "deserialize_bool_from_bitstring")]
-    http2: bool,
+    pub ipv6: bool,
+    #[serde(
+        rename = "isH2",
+        default,
+        deserialize_with = "deserialize_optional_bool_from_bitstring"
+    )]
+    pub http2: Option<bool>,
     #[serde(rename = "urlPath")]
-    url_path: String,
-    #[serde(rename = "httpReferer")]
-    referer: String,
-    #[serde(rename = "httpUA")]
-    user_agent: String,
-    #[serde(rename = "cacheState")]
-    cache_state: String,
+    pub url_path: String,
+    /// The query string, without the `?`, where the log has it apart from the path; see
+    /// split_query for where it doesn't.
+    #[serde(rename = "urlQuery", default)]
+    pub query: Option<String>,
+    #[serde(rename = "httpReferer", default)]
+    pub referer: Option<String>,
+    #[serde(rename = "httpUA", default)]
+    pub user_agent: Option<String>,
+    #[serde(rename = "cacheState", default)]
+    pub cache_state: Option<String>,
     #[serde(
         rename = "respStatus",
         deserialize_with = "deserialize_number_from_string"
     )]
-    response_status: usize,
+    pub response_status: usize,
     #[serde(
         rename = "respTotalBytes",
-        deserialize_with = "deserialize_number_from_string"
+        default,
+        deserialize_with = "deserialize_optional_number_from_string"
     )]
-    response_bytes: usize,
+    pub response_bytes: Option<usize>,
     #[serde(
         rename = "timeElapsed",
-        deserialize_with = "deserialize_duration_from_usec_string"
+        default,
+        deserialize_with = "deserialize_optional_duration_from_usec_string"
     )]
-    response_duration: Duration,
+    pub response_duration: Option<Duration>,
     #[serde(rename = "reqStartTime", deserialize_with = "deserialize_start_time")]
-    request_start_time: DateTime<Utc>,
+    pub request_start_time: StartTime,
+
+    /// Primary language from the Accept-Language header, if the log format includes it.
+    #[serde(
+        rename = "acceptLanguage",
+        default,
+        deserialize_with = "deserialize_language"
+    )]
+    pub language: Option<String>,
+
+    /// Request header and body sizes (req.header_bytes_read, req.body_bytes_read),
+    /// if the log format includes them.
+    #[serde(
+        rename = "reqHeaderBytes",
+        default,
+        deserialize_with = "deserialize_optional_number_from_string"
+    )]
+    pub request_header_bytes: Option<usize>,
+    #[serde(
+        rename = "reqBodyBytes",
+        default,
+        deserialize_with = "deserialize_optional_number_from_string"
+    )]
+    pub request_body_bytes: Option<usize>,
+
+    /// Age of the cached object (obj.age) and its remaining TTL (obj.ttl), in seconds,
+    /// if the log format includes them. The TTL is negative when serving stale.
+    #[serde(
+        rename = "objAge",
+        default,
+        deserialize_with = "deserialize_optional_number_from_string"
+    )]
+    pub cache_age: Option<f64>,
+    #[serde(
+        rename = "objTTL",
+        default,
+        deserialize_with = "deserialize_optional_number_from_string"
+    )]
+    pub cache_ttl: Option<f64>,
+
+    /// Request method (req.method) and protocol (req.proto), if the log format includes them.
+    /// Methods are upper-cased, and protocols named as Fastly does: HTTP/1.1, HTTP/2, HTTP/3.
+    #[serde(rename = "reqMethod", default, deserialize_with = "deserialize_method")]
+    pub request_method: Option<String>,
+    #[serde(
+        rename = "reqProtocol",
+        default,
+        deserialize_with = "deserialize_protocol"
+    )]
+    pub request_protocol: Option<String>,
+
+    /// The Host header, for services that front several domains; lowercased, without a port.
+    #[serde(rename = "httpHost", default, deserialize_with = "deserialize_host")]
+    pub host: Option<String>,
+
+    /// The Fastly service (req.service_id), if the log format includes it; otherwise, it's
+    /// from the source, per the `[service_ids]` section of the config.
+    #[serde(rename = "serviceID", default)]
+    pub service_id: Option<String>,
+
+    /// The path as logged, where normalize_path changed it.
+    #[serde(skip)]
+    pub raw_path: Option<String>,
+
+    /// Tags assigned at ingest time; not part of the log format.
+    #[serde(skip)]
+    pub tags: Vec<String>,
+
+    /// Which source the entry was fetched from, in runs over several; not part of the log format.
+    #[serde(skip)]
+    pub source: Option<String>,
+}
+
+/// The keys that Fastly's JSON must have in strict mode: every one the log format has always
+/// had, as it was before they were optional.
+pub(crate) const STRICT_KEYS: &[&str] = &[
+    "clientIP",
+    "ispID",
+    "countryCode",
+    "requests",
+    "isIPv6",
+    "isH2",
+    "urlPath",
+    "httpReferer",
+    "httpUA",
+    "cacheState",
+    "respStatus",
+    "respTotalBytes",
+    "timeElapsed",
+    "reqStartTime",
+];
+
+fn one_request() -> usize {
+    1
 }
 
 fn get_ipv4(ip: &IpAddr) -> Option<String> {
@@ -77,7 +191,7 @@ fn get_ipv6(ip: &IpAddr) -> Option<String> {
 }
 
 // From serde_aux crate, under MIT license
-fn deserialize_number_from_string<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+pub(crate) fn deserialize_number_from_string<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     D: Deserializer<'de>,
     T: FromStr + serde::Deserialize<'de>,
@@ -96,13 +210,52 @@ where
     }
 }
 
-// From serde_aux crate, under MIT license
-fn deserialize_duration_from_usec_string<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+/// Like deserialize_number_from_string, but null or an empty string is None.
+fn deserialize_optional_number_from_string<'de, T, D>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + serde::Deserialize<'de>,
+    <T as FromStr>::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrInt<T> {
+        String(String),
+        Number(T),
+    }
+
+    match Option::<StringOrInt<T>>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(StringOrInt::String(s)) if s.is_empty() => Ok(None),
+        Some(StringOrInt::String(s)) => s.parse::<T>().map(Some).map_err(serde::de::Error::custom),
+        Some(StringOrInt::Number(i)) => Ok(Some(i)),
+    }
+}
+
+/// A duration in microseconds, as a number or a string; null or an empty string is None.
+fn deserialize_optional_duration_from_usec_string<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let number = deserialize_number_from_string(deserializer)?;
-    Ok(Duration::from_micros(number))
+    let number = deserialize_optional_number_from_string(deserializer)?;
+    Ok(number.map(Duration::from_micros))
+}
+
+/// Like deserialize_bool_from_bitstring, but null is None.
+fn deserialize_optional_bool_from_bitstring<'de, D>(
+    deserializer: D,
+) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Bit(#[serde(deserialize_with = "deserialize_bool_from_bitstring")] bool);
+
+    Ok(Option::<Bit>::deserialize(deserializer)?.map(|Bit(bit)| bit))
 }
 
 // Based on serde_aux crate, under MIT license
@@ -138,51 +291,314 @@ where
     }
 }
 
-/// Deserializes the start time.
-/// In older logs, it was an RFC2822 string;
-/// in newer ones, it's an epoch time.
-fn deserialize_start_time<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+/// Deserializes an Accept-Language header into its most-preferred language.
+pub(crate) fn deserialize_language<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    enum StringLike {
-        String(String),
-        Number(i64),
+    let header = Option::<String>::deserialize(deserializer)?;
+    Ok(header.as_deref().and_then(primary_language))
+}
+
+fn deserialize_method<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let method = Option::<String>::deserialize(deserializer)?;
+    Ok(method.as_deref().and_then(request_method))
+}
+
+fn deserialize_protocol<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let protocol = Option::<String>::deserialize(deserializer)?;
+    Ok(protocol.as_deref().and_then(request_protocol))
+}
+
+fn deserialize_host<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let host = Option::<String>::deserialize(deserializer)?;
+    Ok(host.as_deref().and_then(request_host))
+}
+
+/// The host in a Host header, lowercased and without the port; None if it's empty.
+pub(crate) fn request_host(header: &str) -> Option<String> {
+    let header = header.trim();
+    let host = match header.strip_prefix('[') {
+        // IPv6 literal; the port, if any, is after the bracket.
+        Some(v6) => v6.split(']').next()?,
+        // A bare IPv6 address, which can't have a port.
+        None if header.matches(':').count() > 1 => header,
+        None => header.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+/// The method, upper-cased; None if it's empty.
+pub(crate) fn request_method(method: &str) -> Option<String> {
+    let method = method.trim();
+    (!method.is_empty()).then(|| method.to_ascii_uppercase())
+}
+
+/// The protocol as Fastly names it, where others add a minor version: "HTTP/2.0" -> "HTTP/2".
+/// None if it's empty.
+pub(crate) fn request_protocol(protocol: &str) -> Option<String> {
+    match protocol.trim().to_ascii_uppercase().as_str() {
+        "" => None,
+        "HTTP/2.0" => Some("HTTP/2".to_string()),
+        "HTTP/3.0" => Some("HTTP/3".to_string()),
+        protocol => Some(protocol.to_string()),
     }
+}
+
+/// The language with the highest quality value in an Accept-Language header,
+/// normalized to its lowercase primary subtag: "en-US,en;q=0.9,de;q=0.8" -> "en".
+///
+/// Returns None for empty or unparseable headers, or if only "*" is accepted.
+fn primary_language(header: &str) -> Option<String> {
+    let mut best: Option<(&str, f32)> = None;
+    for item in header.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let tag = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok());
+        let (Some(quality), Some(primary)) = (quality, tag.split(['-', '_']).next()) else {
+            continue;
+        };
+        let valid =
+            (2..=3).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic());
+        // Ties go to the first listed.
+        if valid && quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((primary, quality));
+        }
+    }
+    best.map(|(primary, _)| primary.to_ascii_lowercase())
+}
+
+/// The host part of a referer URL, lowercased, without userinfo or port.
+fn referer_host(referer: &str) -> Option<String> {
+    let (_, rest) = referer.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal; the port, if any, is after the bracket.
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
 
-    match StringLike::deserialize(deserializer)? {
-        StringLike::Number(i) => {
-            // Only at a 1 second granularity.
-            DateTime::from_timestamp(i, 0)
-                .ok_or("error in generating timestamp")
-                .map_err(serde::de::Error::custom)
+/// How a start time was written in Fastly's JSON, for auditing a change of log format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeFormat {
+    Rfc2822,
+    Rfc3339,
+    /// Unix time in seconds, whole or fractional.
+    UnixSeconds,
+    UnixMillis,
+    UnixMicros,
+    UnixNanos,
+}
+
+impl TimeFormat {
+    /// The name stored in requests.time_format.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimeFormat::Rfc2822 => "rfc2822",
+            TimeFormat::Rfc3339 => "rfc3339",
+            TimeFormat::UnixSeconds => "unix",
+            TimeFormat::UnixMillis => "unix_ms",
+            TimeFormat::UnixMicros => "unix_us",
+            TimeFormat::UnixNanos => "unix_ns",
         }
-        StringLike::String(s) => {
+    }
+}
+
+/// When a request started, and how that was written, in Fastly's JSON: objects that span a
+/// change of log format can have both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartTime {
+    pub time: DateTime<Utc>,
+    /// None if it wasn't from Fastly's JSON.
+    pub format: Option<TimeFormat>,
+}
+
+impl From<DateTime<Utc>> for StartTime {
+    fn from(time: DateTime<Utc>) -> Self {
+        StartTime { time, format: None }
+    }
+}
+
+/// A start time as it's written.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawStartTime {
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+/// The path with its percent-escapes decoded; but for `/`, `?`, and `#`, whose escapes are
+/// kept (in upper case), as decoding them would change the path. Left as it is if what it
+/// decodes to isn't UTF-8.
+fn percent_decode(path: &str) -> Cow<'_, str> {
+    if !path.contains('%') {
+        return Cow::Borrowed(path);
+    }
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(b @ (b'/' | b'?' | b'#')) => decoded.extend(format!("%{b:02X}").bytes()),
+            Some(b) => decoded.push(b),
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+                continue;
+            }
+        }
+        i += 3;
+    }
+    String::from_utf8(decoded).map_or(Cow::Borrowed(path), Cow::Owned)
+}
+
+/// Deserializes the start time, noting how it was written.
+/// In older logs, it was an RFC2822 string;
+/// in newer ones, it's an epoch time.
+fn deserialize_start_time<'de, D>(deserializer: D) -> Result<StartTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_start_time(RawStartTime::deserialize(deserializer)?)
+        .map(|(time, format)| StartTime {
+            time,
+            format: Some(format),
+        })
+        .map_err(serde::de::Error::custom)
+}
+
+fn parse_start_time(start: RawStartTime) -> Result<(DateTime<Utc>, TimeFormat), &'static str> {
+    match start {
+        RawStartTime::Integer(i) => from_unix(i),
+        RawStartTime::Float(f) => from_unix_seconds(f),
+        RawStartTime::String(s) => {
             if let Ok(v) = DateTime::<FixedOffset>::parse_from_rfc2822(&s) {
-                Ok(v.into())
+                Ok((v.into(), TimeFormat::Rfc2822))
             } else if let Ok(v) = DateTime::<FixedOffset>::parse_from_rfc3339(&s) {
-                Ok(v.into())
+                Ok((v.into(), TimeFormat::Rfc3339))
             } else if let Ok(i) = s.parse::<i64>() {
-                DateTime::from_timestamp(i, 0)
-                    .ok_or("error in generating timestamp")
-                    .map_err(serde::de::Error::custom)
+                from_unix(i)
+            } else if let Ok(f) = s.parse::<f64>() {
+                from_unix_seconds(f)
             } else {
-                Err(serde::de::Error::custom(
-                    "unknown string format for timestamp",
-                ))
+                Err("unknown string format for timestamp")
             }
         }
     }
 }
 
+/// A Unix time in whatever unit it's in: seconds won't reach 10^11 for a few thousand years,
+/// while finer units are well past it.
+fn from_unix(i: i64) -> Result<(DateTime<Utc>, TimeFormat), &'static str> {
+    let (time, format) = match i.unsigned_abs() {
+        0..100_000_000_000 => (DateTime::from_timestamp(i, 0), TimeFormat::UnixSeconds),
+        100_000_000_000..100_000_000_000_000 => {
+            (DateTime::from_timestamp_millis(i), TimeFormat::UnixMillis)
+        }
+        100_000_000_000_000..100_000_000_000_000_000 => {
+            (DateTime::from_timestamp_micros(i), TimeFormat::UnixMicros)
+        }
+        _ => (
+            Some(DateTime::from_timestamp_nanos(i)),
+            TimeFormat::UnixNanos,
+        ),
+    };
+    time.map(|time| (time, format))
+        .ok_or("error in generating timestamp")
+}
+
+fn from_unix_seconds(f: f64) -> Result<(DateTime<Utc>, TimeFormat), &'static str> {
+    if !f.is_finite() {
+        return Err("error in generating timestamp");
+    }
+    DateTime::from_timestamp_micros((f * 1e6).round() as i64)
+        .map(|time| (time, TimeFormat::UnixSeconds))
+        .ok_or("error in generating timestamp")
+}
+
 impl LogEntry {
+    /// Move a query string (and drop a fragment) in the path to the query, so each path is
+    /// stored once however it's queried.
+    pub fn split_query(&mut self) {
+        if let Some(i) = self.url_path.find('#') {
+            self.url_path.truncate(i);
+        }
+        if let Some((path, query)) = self.url_path.split_once('?') {
+            if self.query.is_none() {
+                self.query = Some(query.to_string());
+            }
+            self.url_path = path.to_string();
+        }
+    }
+
+    /// Store the path the same however it's written: percent-escapes decoded, runs of slashes
+    /// collapsed, and, with `lowercase`, in lower case. A trailing slash is kept, as reports
+    /// tell pages (`/writing/foo/`) from their resources by it. The path as logged is kept in
+    /// raw_path.
+    pub fn normalize_path(&mut self, lowercase: bool) {
+        let mut path = String::with_capacity(self.url_path.len());
+        for c in percent_decode(&self.url_path).chars() {
+            if !(c == '/' && path.ends_with('/')) {
+                path.push(c);
+            }
+        }
+        if lowercase {
+            path = path.to_lowercase();
+        }
+        if path != self.url_path {
+            let raw = std::mem::replace(&mut self.url_path, path);
+            self.raw_path.get_or_insert(raw);
+        }
+    }
+
+    /// A key for the request, the same wherever and whenever it's read: a hash of the client,
+    /// start time, path, size, and status. With a unique index on it, the same request can't
+    /// be stored twice, even from different objects.
+    pub fn dedup_key(&self) -> [u8; 16] {
+        let mut hasher = Sha256::new();
+        for field in [
+            self.client_ip.to_string(),
+            self.request_start_time.time.to_rfc3339(),
+            self.url_path.clone(),
+            self.response_bytes
+                .map_or(String::new(), |bytes| bytes.to_string()),
+            self.response_status.to_string(),
+        ] {
+            // Length-prefixed, so fields can't run into each other.
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field);
+        }
+        hasher.finalize()[..16].try_into().unwrap()
+    }
+
     /// Store this log entry as part of a transaction.
     ///
     /// We insert multiple objects as part of a single transaction to avoid duplicates;
     /// we consume an entire file (multiple records) at once.
-    pub fn store(&self, tx: &Transaction) -> Result<(), rusqlite::Error> {
+    ///
+    /// A request already stored, by its dedup key, is left out, where the key is unique.
+    pub fn store(&self, tx: &Connection) -> Result<(), rusqlite::Error> {
         let ipv4 = get_ipv4(&self.client_ip);
         let ipv6 = get_ipv6(&self.client_ip);
         let _ = tx
@@ -191,24 +607,36 @@ impl LogEntry {
             )
             .unwrap()
             .execute([&ipv4, &ipv6])?;
-        tx.prepare_cached("INSERT INTO paths (path) VALUES (?) ON CONFLICT DO NOTHING;")
-            .unwrap()
-            .execute([&self.url_path])?;
-        tx.prepare_cached("INSERT INTO referers (referer) VALUES (?) ON CONFLICT DO NOTHING;")
-            .unwrap()
-            .execute([&self.referer])?;
-        tx.prepare_cached(
-            "INSERT INTO autonomous_systems (asn) VALUES (?) ON CONFLICT DO NOTHING;",
-        )
-        .unwrap()
-        .execute([&self.asn])?;
-        tx.prepare_cached(
-            "INSERT INTO user_agents (user_agent) VALUES (?) ON CONFLICT DO NOTHING;",
-        )
-        .unwrap()
-        .execute([&self.user_agent])?;
-        tx.prepare_cached(
-            r#"
+        self.store_path_and_referer(tx)?;
+        if let Some(asn) = self.asn {
+            tx.prepare_cached(
+                "INSERT INTO autonomous_systems (asn) VALUES (?) ON CONFLICT DO NOTHING;",
+            )?
+            .execute([asn])?;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            tx.prepare_cached(
+                "INSERT INTO user_agents (user_agent) VALUES (?) ON CONFLICT DO NOTHING;",
+            )?
+            .execute([user_agent])?;
+        }
+        if let Some(host) = &self.host {
+            tx.prepare_cached("INSERT INTO hosts (host) VALUES (?) ON CONFLICT DO NOTHING;")?
+                .execute([host])?;
+        }
+        if let Some(service_id) = &self.service_id {
+            tx.prepare_cached(
+                "INSERT INTO services (service_id) VALUES (?) ON CONFLICT DO NOTHING;",
+            )?
+            .execute([service_id])?;
+        }
+        if let Some(source) = &self.source {
+            tx.prepare_cached("INSERT INTO sources (name) VALUES (?) ON CONFLICT DO NOTHING;")?
+                .execute([source])?;
+        }
+        let stored = tx
+            .prepare_cached(
+                r#"
 INSERT INTO requests (
   client_ip
 , asn
@@ -224,6 +652,21 @@ INSERT INTO requests (
 , url_path
 , referer
 , user_agent
+, language
+, source
+, request_header_bytes
+, request_body_bytes
+, cache_age
+, cache_ttl
+, request_method
+, request_protocol
+, host
+, droplisted
+, service
+, query
+, time_format
+, raw_path
+, dedup_key
 ) VALUES (
   ( SELECT id FROM client_ips WHERE ipv4 = :client_ipv4 OR ipv6 = :client_ipv6)
 , :asn
@@ -239,25 +682,375 @@ INSERT INTO requests (
 , ( SELECT id FROM paths WHERE path = :url_path)
 , ( SELECT id FROM referers WHERE referer = :referer)
 , ( SELECT id FROM user_agents WHERE user_agent = :user_agent)
-);"#,
+, :language
+, ( SELECT id FROM sources WHERE name = :source)
+, :request_header_bytes
+, :request_body_bytes
+, :cache_age
+, :cache_ttl
+, :request_method
+, :request_protocol
+, ( SELECT id FROM hosts WHERE host = :host)
+, CASE WHEN :asn IS NULL OR NOT EXISTS (SELECT 1 FROM droplists) THEN NULL
+  ELSE EXISTS (SELECT 1 FROM autonomous_systems WHERE asn = :asn AND droplist IS NOT NULL)
+  END
+, ( SELECT id FROM services WHERE service_id = :service_id)
+, :query
+, :time_format
+, :raw_path
+, :dedup_key
+)
+ON CONFLICT DO NOTHING;"#,
+            )?
+            .execute(named_params! {
+                ":client_ipv4": &ipv4,
+                ":client_ipv6": &ipv6,
+                ":asn": self.asn,
+                ":country_code": &self.country_code,
+                ":requests": self.requests,
+                // Without isIPv6, it's from the address.
+                ":ipv6": self.ipv6 || self.client_ip.is_ipv6(),
+                ":http2": self.http2,
+                ":cache_state": &self.cache_state,
+                ":response_bytes": self.response_bytes,
+                ":response_status": self.response_status,
+                ":response_duration": self.response_duration.map(|d| d.as_secs_f32()),
+                ":request_start_time": &self.request_start_time.time.to_rfc3339(),
+                ":url_path": &self.url_path,
+                ":user_agent": &self.user_agent,
+                ":referer": &self.referer,
+                ":language": &self.language,
+                ":source": &self.source,
+                ":request_header_bytes": self.request_header_bytes,
+                ":request_body_bytes": self.request_body_bytes,
+                ":cache_age": self.cache_age,
+                ":cache_ttl": self.cache_ttl,
+                ":request_method": &self.request_method,
+                ":request_protocol": &self.request_protocol,
+                ":host": &self.host,
+                ":service_id": &self.service_id,
+                ":query": &self.query,
+                ":time_format": self.request_start_time.format.map(TimeFormat::as_str),
+                ":raw_path": &self.raw_path,
+                ":dedup_key": self.dedup_key(),
+            })?;
+        if stored == 0 {
+            return Ok(());
+        }
+        let request_id = tx.last_insert_rowid();
+        for tag in &self.tags {
+            tx.prepare_cached("INSERT INTO tags (tag) VALUES (?) ON CONFLICT DO NOTHING;")?
+                .execute([tag])?;
+            tx.prepare_cached(
+                r#"
+INSERT INTO request_tags (request, tag)
+VALUES (:request, ( SELECT id FROM tags WHERE tag = :tag))
+ON CONFLICT DO NOTHING;"#,
+            )?
+            .execute(named_params! {":request": request_id, ":tag": tag})?;
+        }
+        self.roll_up(tx)
+    }
+
+    /// Count this log entry in the rollups, without storing the request itself: for
+    /// aggregation-only databases.
+    pub fn store_aggregates(&self, tx: &Connection) -> Result<(), rusqlite::Error> {
+        self.store_path_and_referer(tx)?;
+        self.roll_up(tx)
+    }
+
+    /// Store the dimensions that the rollups refer to.
+    fn store_path_and_referer(&self, tx: &Connection) -> Result<(), rusqlite::Error> {
+        tx.prepare_cached("INSERT INTO paths (path) VALUES (?) ON CONFLICT DO NOTHING;")?
+            .execute([&self.url_path])?;
+        if let Some(referer) = &self.referer {
+            tx.prepare_cached(
+                "INSERT INTO referers (referer, host) VALUES (?, ?) ON CONFLICT DO NOTHING;",
+            )?
+            .execute((referer, referer_host(referer)))?;
+        }
+        Ok(())
+    }
+
+    /// Count this log entry in the daily rollups, and the bandwidth per minute.
+    fn roll_up(&self, tx: &Connection) -> Result<(), rusqlite::Error> {
+        let start = self.request_start_time.time.to_rfc3339();
+        let response_bytes = self.response_bytes.unwrap_or(0);
+        tx.prepare_cached(
+            r#"
+INSERT INTO daily_totals (date, requests, response_bytes)
+VALUES (date(:request_start_time), 1, :response_bytes)
+ON CONFLICT (date) DO
+UPDATE SET
+  requests = requests + 1
+, response_bytes = response_bytes + :response_bytes
+;"#,
+        )?
+        .execute(named_params! {
+            ":request_start_time": &start,
+            ":response_bytes": response_bytes,
+        })?;
+        tx.prepare_cached(
+            r#"
+INSERT INTO minute_bandwidth (minute, requests, response_bytes)
+VALUES (strftime('%Y-%m-%d %H:%M:00', :request_start_time), 1, :response_bytes)
+ON CONFLICT (minute) DO
+UPDATE SET
+  requests = requests + 1
+, response_bytes = response_bytes + :response_bytes
+;"#,
+        )?
+        .execute(named_params! {
+            ":request_start_time": &start,
+            ":response_bytes": response_bytes,
+        })?;
+        tx.prepare_cached(
+            r#"
+INSERT INTO daily_statuses (date, status, requests)
+VALUES (date(:request_start_time), :status, 1)
+ON CONFLICT (date, status) DO UPDATE SET requests = requests + 1
+;"#,
+        )?
+        .execute(named_params! {
+            ":request_start_time": &start,
+            ":status": self.response_status,
+        })?;
+        tx.prepare_cached(
+            r#"
+INSERT INTO daily_countries (date, country, requests)
+VALUES (date(:request_start_time), :country, 1)
+ON CONFLICT (date, country) DO UPDATE SET requests = requests + 1
+;"#,
+        )?
+        .execute(named_params! {
+            ":request_start_time": &start,
+            ":country": self.country_code.as_deref().unwrap_or_default(),
+        })?;
+        if let Some(referer) = &self.referer {
+            tx.prepare_cached(
+                r#"
+INSERT INTO daily_referers (date, referer, requests)
+VALUES (
+  date(:request_start_time)
+, ( SELECT id FROM referers WHERE referer = :referer)
+, 1
+)
+ON CONFLICT (date, referer) DO UPDATE SET requests = requests + 1
+;"#,
+            )?
+            .execute(named_params! {
+                ":request_start_time": &start,
+                ":referer": referer,
+            })?;
+        }
+        tx.prepare_cached(
+            r#"
+INSERT INTO daily_paths (date, url_path, requests, response_bytes)
+VALUES (
+  date(:request_start_time)
+, ( SELECT id FROM paths WHERE path = :url_path)
+, 1
+, :response_bytes
+)
+ON CONFLICT (date, url_path) DO
+UPDATE SET
+  requests = requests + 1
+, response_bytes = response_bytes + :response_bytes
+;"#,
         )?
         .execute(named_params! {
-            ":client_ipv4": &ipv4,
-            ":client_ipv6": &ipv6,
-            ":asn": self.asn as usize,
-            ":country_code": &self.country_code,
-            ":requests": self.requests,
-            ":ipv6": self.ipv6,
-            ":http2": self.http2,
-            ":cache_state": &self.cache_state,
-            ":response_bytes": self.response_bytes,
-            ":response_status": self.response_status,
-            ":response_duration": self.response_duration.as_secs_f32(),
-            ":request_start_time": &self.request_start_time.to_rfc3339(),
+            ":request_start_time": &start,
             ":url_path": &self.url_path,
-            ":user_agent": &self.user_agent,
-            ":referer": &self.referer,
+            ":response_bytes": response_bytes,
         })
         .map(|_| ())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::{primary_language, referer_host, request_host, LogEntry, TimeFormat};
+    use crate::{
+        format::{EntryError, Format},
+        streamhack::CommaHacker,
+        testdata::{entry, ENTRY},
+    };
+
+    #[test]
+    fn parses_fastly_entry() {
+        let entry = entry();
+        assert_eq!(entry.client_ip.to_string(), "192.0.2.1");
+        assert_eq!(entry.asn, Some(64496));
+        assert!(!entry.ipv6);
+        assert_eq!(entry.http2, Some(true));
+        assert_eq!(entry.response_status, 200);
+        assert_eq!(entry.response_duration, Some(Duration::from_micros(1500)));
+        assert_eq!(
+            entry.request_start_time.time.to_rfc3339(),
+            "2024-07-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn splits_query_from_path() {
+        let mut entry = entry();
+        entry.url_path = "/search?q=rust#results".to_string();
+        entry.split_query();
+        assert_eq!(entry.url_path, "/search");
+        assert_eq!(entry.query.as_deref(), Some("q=rust"));
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        let normalize = |path: &str, lowercase| {
+            let mut entry = entry();
+            entry.url_path = path.to_string();
+            entry.normalize_path(lowercase);
+            (entry.url_path, entry.raw_path)
+        };
+        assert_eq!(
+            normalize("/Foo%20Bar", false),
+            ("/Foo Bar".to_string(), Some("/Foo%20Bar".to_string()))
+        );
+        assert_eq!(normalize("/Foo Bar", false), ("/Foo Bar".to_string(), None));
+        assert_eq!(normalize("//a///b//", false).0, "/a/b/");
+        assert_eq!(
+            normalize("/writing/foo/", false),
+            ("/writing/foo/".to_string(), None)
+        );
+        assert_eq!(normalize("/writing//foo//", false).0, "/writing/foo/");
+        assert_eq!(normalize("/", false), ("/".to_string(), None));
+        assert_eq!(normalize("/A%2fB/%41", true).0, "/a%2fb/a");
+        // Escapes that aren't, or aren't UTF-8, are left.
+        assert_eq!(normalize("/100%/%zz", false).0, "/100%/%zz");
+        assert_eq!(normalize("/%FF/", false).0, "/%FF/");
+    }
+
+    #[test]
+    fn parses_optional_fields() {
+        let entry = entry();
+        assert_eq!(entry.request_header_bytes, None);
+        assert_eq!(entry.request_body_bytes, None);
+
+        let with_sizes = ENTRY.replace(
+            r#""reqStartTime""#,
+            concat!(
+                r#""reqHeaderBytes": "412", "reqBodyBytes": "", "#,
+                r#""objAge": "30.000", "objTTL": -5, "#,
+                r#""reqStartTime""#
+            ),
+        );
+        let entry: LogEntry =
+            serde_json::from_reader(CommaHacker::new(with_sizes.as_bytes())).unwrap();
+        assert_eq!(entry.request_header_bytes, Some(412));
+        assert_eq!(entry.request_body_bytes, None);
+        assert_eq!(entry.cache_age, Some(30.0));
+        assert_eq!(entry.cache_ttl, Some(-5.0));
+        assert_eq!(entry.request_method, None);
+
+        let with_method = ENTRY.replace(
+            r#""reqStartTime""#,
+            concat!(
+                r#""reqMethod": "post", "reqProtocol": "HTTP/2.0", "#,
+                r#""httpHost": "WWW.Example.com:443", "#,
+                r#""serviceID": "SU1Z0isxPaozGVKXdv0eY", "reqStartTime""#
+            ),
+        );
+        let entry: LogEntry =
+            serde_json::from_reader(CommaHacker::new(with_method.as_bytes())).unwrap();
+        assert_eq!(entry.request_method.as_deref(), Some("POST"));
+        assert_eq!(entry.request_protocol.as_deref(), Some("HTTP/2"));
+        assert_eq!(entry.host.as_deref(), Some("www.example.com"));
+        assert_eq!(entry.service_id.as_deref(), Some("SU1Z0isxPaozGVKXdv0eY"));
+
+        let without = ENTRY
+            .replace(r#""httpReferer": "", "#, "")
+            .replace(r#""timeElapsed": "1500", "#, "");
+        let entry: LogEntry =
+            serde_json::from_reader(CommaHacker::new(without.as_bytes())).unwrap();
+        assert_eq!(entry.referer, None);
+        assert_eq!(entry.response_duration, None);
+        let strict = Format::Fastly.parser(&BTreeMap::new(), true);
+        let mut entries = strict.parse(Box::new(without.as_bytes()));
+        assert!(matches!(entries.next(), Some(Err(EntryError::Invalid(_)))));
+    }
+
+    #[test]
+    fn parses_mixed_start_times() {
+        let input = [
+            r#""reqStartTime": "Mon, 01 Jul 2024 00:00:00 +0000""#,
+            r#""reqStartTime": "2024-07-01T00:00:00.250Z""#,
+            r#""reqStartTime": 1719792000500"#,
+            r#""reqStartTime": 1719792000.75"#,
+        ]
+        .map(|start| ENTRY.replace(r#""reqStartTime": 1719792000"#, start))
+        .join("\n");
+        let entries: Vec<_> = Format::Fastly
+            .parser(&BTreeMap::new(), false)
+            .parse(Box::new(input.as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let times: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.request_start_time.time.timestamp_millis() - 1719792000000)
+            .collect();
+        assert_eq!(times, [0, 250, 500, 750]);
+        let formats: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.request_start_time.format)
+            .collect();
+        assert_eq!(
+            formats,
+            [
+                Some(TimeFormat::Rfc2822),
+                Some(TimeFormat::Rfc3339),
+                Some(TimeFormat::UnixMillis),
+                Some(TimeFormat::UnixSeconds),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalizes_accept_language() {
+        assert_eq!(
+            primary_language("en-US,en;q=0.9,de;q=0.8").as_deref(),
+            Some("en")
+        );
+        assert_eq!(
+            primary_language("de;q=0.5, FR-ca;q=0.7").as_deref(),
+            Some("fr")
+        );
+        assert_eq!(primary_language("*"), None);
+        assert_eq!(primary_language(""), None);
+    }
+
+    #[test]
+    fn parses_referer_hosts() {
+        assert_eq!(
+            referer_host("https://News.YCombinator.com/item?id=1").as_deref(),
+            Some("news.ycombinator.com")
+        );
+        assert_eq!(
+            referer_host("http://user@example.com:8080").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(referer_host("http://[::1]:80/").as_deref(), Some("::1"));
+        assert_eq!(referer_host(""), None);
+    }
+
+    #[test]
+    fn parses_request_hosts() {
+        assert_eq!(
+            request_host("WWW.Example.com:443").as_deref(),
+            Some("www.example.com")
+        );
+        assert_eq!(
+            request_host("[2001:DB8::1]:8080").as_deref(),
+            Some("2001:db8::1")
+        );
+        assert_eq!(request_host("2001:db8::1").as_deref(), Some("2001:db8::1"));
+        assert_eq!(request_host(" :80"), None);
+    }
+}
@@ -0,0 +1,465 @@
+//! Retries for transient storage errors.
+//!
+//! A LogSource is wrapped in a RetryingSource, which retries listing, reading, and deleting
+//! with exponential backoff when the error looks transient: a timeout, a dropped connection,
+//! rate limiting, or a server error. Other errors (not found, permission denied) fail at once.
+//!
+//! A streamed read is retried when it's opened. If it breaks partway through, it's picked up
+//! from where it broke, with a range request, as long as the store can do that and it keeps
+//! failing no more often than the retries allow; otherwise it fails the object, which is left
+//! in place for the next run.
+
+use std::{io, ops::Range, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use backon::{BackoffBuilder, ExponentialBackoff, ExponentialBuilder, Retryable};
+use serde::Deserialize;
+use tokio_stream::StreamExt;
+
+use crate::source::{ByteStream, LogSource, ObjectStream};
+
+/// How to retry storage operations, from the `[retry]` section of the config file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retry {
+    /// Attempts per operation, including the first; 1 disables retries.
+    pub max_attempts: usize,
+    /// Delay before the first retry.
+    pub min_delay_ms: u64,
+    /// Upper bound on the delay between retries.
+    pub max_delay_ms: u64,
+    /// Multiplier for the delay after each retry.
+    pub factor: f32,
+    /// Add a random amount, up to the delay again, to each delay, so that concurrent
+    /// fetches don't retry in lockstep.
+    pub jitter: bool,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_attempts: 4,
+            min_delay_ms: 500,
+            max_delay_ms: 30_000,
+            factor: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl Retry {
+    /// What's wrong with the settings, if anything.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.max_attempts == 0 {
+            problems.push("max_attempts must be at least 1".to_string());
+        }
+        if self.min_delay_ms > self.max_delay_ms {
+            problems.push(format!(
+                "min_delay_ms ({}) is more than max_delay_ms ({})",
+                self.min_delay_ms, self.max_delay_ms
+            ));
+        }
+        if self.factor < 1.0 || self.factor.is_nan() {
+            problems.push(format!("factor must be at least 1, not {}", self.factor));
+        }
+        problems
+    }
+
+    pub(crate) fn backoff(&self) -> ExponentialBuilder {
+        let backoff = ExponentialBuilder::default()
+            .with_max_times(self.max_attempts.saturating_sub(1))
+            .with_min_delay(Duration::from_millis(self.min_delay_ms))
+            .with_max_delay(Duration::from_millis(self.max_delay_ms))
+            .with_factor(self.factor);
+        if self.jitter {
+            backoff.with_jitter()
+        } else {
+            backoff
+        }
+    }
+}
+
+/// Whether an error is worth retrying.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<opendal::Error>() {
+            e.is_temporary()
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|status| {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                })
+        } else if let Some(e) = cause.downcast_ref::<io::Error>() {
+            matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::Interrupted
+            )
+        } else {
+            false
+        }
+    })
+}
+
+fn log_retry(operation: &str, name: &str, err: &anyhow::Error, delay: Duration) {
+    tracing::warn!(
+        "{operation} {name:?} failed, retrying in {delay:?}: {:#}",
+        err
+    );
+}
+
+/// A LogSource that retries transient failures of another.
+pub struct RetryingSource {
+    inner: Arc<dyn LogSource>,
+    retry: Retry,
+    resume: bool,
+}
+
+impl RetryingSource {
+    pub fn new(inner: Box<dyn LogSource>, retry: Retry) -> Self {
+        RetryingSource {
+            inner: inner.into(),
+            retry,
+            resume: true,
+        }
+    }
+
+    /// Whether to pick up streamed reads that break partway through; on by default.
+    pub fn with_resume(self, resume: bool) -> Self {
+        RetryingSource { resume, ..self }
+    }
+}
+
+/// A streamed read, and what it takes to pick it up again.
+struct Resumable {
+    inner: Arc<dyn LogSource>,
+    name: String,
+    retry: Retry,
+    stream: ByteStream,
+    /// How much has been read.
+    offset: u64,
+    /// Delays before the next attempts, since the read last made progress.
+    delays: Option<ExponentialBackoff>,
+}
+
+impl Resumable {
+    /// The next chunk, picking the read up again if it broke.
+    async fn next(&mut self) -> Option<anyhow::Result<bytes::Bytes>> {
+        loop {
+            let err = match self.stream.next().await? {
+                Ok(chunk) => {
+                    self.offset += chunk.len() as u64;
+                    self.delays = None;
+                    return Some(Ok(chunk));
+                }
+                Err(err) if is_transient(&err) => err,
+                Err(err) => return Some(Err(err)),
+            };
+            match self.resume(err).await {
+                Ok(stream) => self.stream = stream,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    /// Start reading again from where the read broke, with `err`.
+    async fn resume(&mut self, mut err: anyhow::Error) -> anyhow::Result<ByteStream> {
+        loop {
+            let delays = self
+                .delays
+                .get_or_insert_with(|| self.retry.backoff().build());
+            let Some(delay) = delays.next() else {
+                return Err(err);
+            };
+            tracing::warn!(
+                "reading {:?} broke at byte {}, resuming in {delay:?}: {err:#}",
+                self.name,
+                self.offset
+            );
+            tokio::time::sleep(delay).await;
+            match self.inner.read_stream_from(&self.name, self.offset).await {
+                Ok(Some(stream)) => return Ok(stream),
+                // The store can't pick it up; fail as it did.
+                Ok(None) => return Err(err),
+                Err(e) if is_transient(&e) => err = e,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for RetryingSource {
+    async fn list(&self, prefix: &str, sizes: bool) -> anyhow::Result<ObjectStream> {
+        (|| self.inner.list(prefix, sizes))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("listing", prefix, err, delay))
+            .await
+    }
+
+    async fn list_after(
+        &self,
+        prefix: &str,
+        sizes: bool,
+        start_after: &str,
+    ) -> anyhow::Result<Option<ObjectStream>> {
+        (|| self.inner.list_after(prefix, sizes, start_after))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("listing", prefix, err, delay))
+            .await
+    }
+
+    async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        (|| self.inner.read(name))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("reading", name, err, delay))
+            .await
+    }
+
+    async fn read_stream(&self, name: &str) -> anyhow::Result<ByteStream> {
+        let stream = (|| self.inner.read_stream(name))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("reading", name, err, delay))
+            .await?;
+        if !self.resume {
+            return Ok(stream);
+        }
+        let resumable = Resumable {
+            inner: Arc::clone(&self.inner),
+            name: name.to_string(),
+            retry: self.retry.clone(),
+            stream,
+            offset: 0,
+            delays: None,
+        };
+        let stream = futures::stream::unfold(Some(resumable), |resumable| async move {
+            let mut resumable = resumable?;
+            let chunk = resumable.next().await?;
+            // Once it's failed for good, it's done.
+            let resumable = chunk.is_ok().then_some(resumable);
+            Some((chunk, resumable))
+        });
+        // Readers may look for more after the end, as other streams of objects allow.
+        Ok(Box::pin(stream.fuse()))
+    }
+
+    async fn read_stream_from(
+        &self,
+        name: &str,
+        offset: u64,
+    ) -> anyhow::Result<Option<ByteStream>> {
+        (|| self.inner.read_stream_from(name, offset))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("reading", name, err, delay))
+            .await
+    }
+
+    async fn read_range(
+        &self,
+        name: &str,
+        range: Range<u64>,
+    ) -> anyhow::Result<Option<bytes::Bytes>> {
+        (|| self.inner.read_range(name, range.clone()))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("reading", name, err, delay))
+            .await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        (|| self.inner.copy(from, to))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("copying", from, err, delay))
+            .await
+    }
+
+    async fn write(&self, name: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        (|| self.inner.write(name, data.clone()))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("writing", name, err, delay))
+            .await
+    }
+
+    /// A stream can't be sent again, so this is only tried once.
+    async fn write_stream(&self, name: &str, data: ByteStream) -> anyhow::Result<()> {
+        self.inner.write_stream(name, data).await
+    }
+
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        (|| self.inner.delete(name))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("deleting", name, err, delay))
+            .await
+    }
+
+    async fn generation(&self, name: &str) -> anyhow::Result<Option<String>> {
+        (|| self.inner.generation(name))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("looking up", name, err, delay))
+            .await
+    }
+
+    async fn restore(&self, name: &str, generation: &str) -> anyhow::Result<()> {
+        (|| self.inner.restore(name, generation))
+            .retry(&self.retry.backoff())
+            .when(is_transient)
+            .notify(|err, delay| log_retry("restoring", name, err, delay))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use tokio_stream::StreamExt;
+
+    use super::{Retry, RetryingSource};
+    use crate::source::{ByteStream, LogSource, ObjectStream};
+
+    /// Fails reads with the given error kind a number of times, then succeeds.
+    struct FlakySource {
+        failures: AtomicUsize,
+        kind: opendal::ErrorKind,
+    }
+
+    #[async_trait]
+    impl LogSource for FlakySource {
+        async fn list(&self, _prefix: &str, _sizes: bool) -> anyhow::Result<ObjectStream> {
+            Ok(Box::pin(tokio_stream::empty()))
+        }
+
+        async fn read(&self, _name: &str) -> anyhow::Result<Vec<u8>> {
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                let mut err = opendal::Error::new(self.kind, "flaky");
+                if self.kind == opendal::ErrorKind::Unexpected {
+                    err = err.set_temporary();
+                }
+                return Err(anyhow::Error::new(err).context("could not read"));
+            }
+            Ok(b"ok".to_vec())
+        }
+
+        async fn delete(&self, _name: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let retry = Retry {
+            min_delay_ms: 0,
+            max_delay_ms: 0,
+            ..Retry::default()
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let read = |failures, kind| {
+            let source = RetryingSource::new(
+                Box::new(FlakySource {
+                    failures: AtomicUsize::new(failures),
+                    kind,
+                }),
+                retry.clone(),
+            );
+            rt.block_on(source.read("logs.gz")).map(|_| ())
+        };
+        assert!(read(3, opendal::ErrorKind::Unexpected).is_ok());
+        // One too many for the attempts allowed.
+        assert!(read(4, opendal::ErrorKind::Unexpected).is_err());
+        // Not worth retrying at all.
+        assert!(read(1, opendal::ErrorKind::NotFound).is_err());
+    }
+
+    /// Serves an object whose reads break every few bytes, recording where they start.
+    #[derive(Default)]
+    struct BreakingSource(Arc<Mutex<Vec<u64>>>);
+
+    const CONTENTS: &[u8] = b"0123456789";
+
+    #[async_trait]
+    impl LogSource for BreakingSource {
+        async fn list(&self, _prefix: &str, _sizes: bool) -> anyhow::Result<ObjectStream> {
+            Ok(Box::pin(tokio_stream::empty()))
+        }
+
+        async fn read(&self, _name: &str) -> anyhow::Result<Vec<u8>> {
+            Ok(CONTENTS.to_vec())
+        }
+
+        async fn read_stream(&self, name: &str) -> anyhow::Result<ByteStream> {
+            Ok(self.read_stream_from(name, 0).await?.unwrap())
+        }
+
+        async fn read_stream_from(
+            &self,
+            _name: &str,
+            offset: u64,
+        ) -> anyhow::Result<Option<ByteStream>> {
+            self.0.lock().unwrap().push(offset);
+            let rest = &CONTENTS[offset as usize..];
+            let mut chunks = vec![Ok(Bytes::copy_from_slice(&rest[..rest.len().min(3)]))];
+            if rest.len() > 3 {
+                let err = io::Error::from(io::ErrorKind::ConnectionReset);
+                chunks.push(Err(anyhow::Error::new(err).context("connection lost")));
+            }
+            Ok(Some(Box::pin(tokio_stream::iter(chunks))))
+        }
+
+        async fn delete(&self, _name: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resumes_broken_reads() {
+        let retry = Retry {
+            max_attempts: 2,
+            min_delay_ms: 0,
+            max_delay_ms: 0,
+            ..Retry::default()
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let starts = Arc::default();
+        let source = RetryingSource::new(Box::new(BreakingSource(Arc::clone(&starts))), retry);
+        let contents = rt.block_on(async {
+            let mut stream = source.read_stream("logs.gz").await.unwrap();
+            let mut contents = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                contents.extend_from_slice(&chunk.unwrap());
+            }
+            assert!(stream.next().await.is_none());
+            contents
+        });
+        assert_eq!(contents, CONTENTS);
+        // More breaks than attempts allowed, but each read made progress.
+        assert_eq!(*starts.lock().unwrap(), vec![0, 3, 6, 9]);
+    }
+}
@@ -19,6 +19,18 @@ CREATE TABLE IF NOT EXISTS user_agents (
 , user_agent TEXT NOT NULL UNIQUE
 ) STRICT;
 
+-- Host headers, lowercased and without ports, for services that front several domains.
+CREATE TABLE IF NOT EXISTS hosts (
+  id INTEGER PRIMARY KEY NOT NULL
+, host TEXT NOT NULL UNIQUE
+) STRICT;
+
+-- Fastly service IDs, for databases that hold several services.
+CREATE TABLE IF NOT EXISTS services (
+  id INTEGER PRIMARY KEY NOT NULL
+, service_id TEXT NOT NULL UNIQUE
+) STRICT;
+
 CREATE TABLE IF NOT EXISTS requests (
   id INTEGER PRIMARY KEY NOT NULL
 , client_ip INTEGER
@@ -48,3 +60,194 @@ CREATE TABLE IF NOT EXISTS autonomous_systems(
 , name TEXT NULL
 , droplist TEXT NULL
 );
+
+-- When each droplist was last synced into autonomous_systems. Until one has been, requests
+-- aren't flagged either way.
+CREATE TABLE IF NOT EXISTS droplists (
+  name TEXT PRIMARY KEY NOT NULL
+, synced TEXT NOT NULL
+) STRICT;
+
+-- What happened to each object we've seen in storage.
+CREATE TABLE IF NOT EXISTS processed_objects (
+  name TEXT PRIMARY KEY NOT NULL
+, status TEXT NOT NULL -- "ok", "error", "skipped"
+, detail TEXT NULL
+, processed_at TEXT NOT NULL
+) STRICT;
+
+-- One row per run of the cruncher.
+CREATE TABLE IF NOT EXISTS runs (
+  id INTEGER PRIMARY KEY NOT NULL
+, started_at TEXT NOT NULL
+, finished_at TEXT NULL
+, ok INTEGER NULL
+, errors INTEGER NULL
+, skipped INTEGER NULL
+) STRICT;
+
+-- Rollup: requests and bytes per path per day.
+-- Maintained as requests are stored.
+CREATE TABLE IF NOT EXISTS daily_paths (
+  date TEXT NOT NULL
+, url_path INTEGER NOT NULL
+, requests INTEGER NOT NULL
+, response_bytes INTEGER NOT NULL
+
+, PRIMARY KEY (date, url_path)
+, FOREIGN KEY(url_path) REFERENCES paths(id)
+) STRICT;
+
+-- Rollups: requests and bytes per day, and requests per status, country, and referer per day.
+-- Maintained as requests are stored, and the only record of them in aggregation-only
+-- databases, which don't store requests.
+CREATE TABLE IF NOT EXISTS daily_totals (
+  date TEXT PRIMARY KEY NOT NULL
+, requests INTEGER NOT NULL
+, response_bytes INTEGER NOT NULL
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS daily_statuses (
+  date TEXT NOT NULL
+, status INTEGER NOT NULL
+, requests INTEGER NOT NULL
+
+, PRIMARY KEY (date, status)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS daily_countries (
+  date TEXT NOT NULL
+, country TEXT NOT NULL -- '' where unknown
+, requests INTEGER NOT NULL
+
+, PRIMARY KEY (date, country)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS daily_referers (
+  date TEXT NOT NULL
+, referer INTEGER NOT NULL
+, requests INTEGER NOT NULL
+
+, PRIMARY KEY (date, referer)
+, FOREIGN KEY(referer) REFERENCES referers(id)
+) STRICT;
+
+-- Snapshot of the per-path rollup totals as of the end of each run, for the paths whose totals
+-- it changed: a path's totals as of a run are in its latest snapshot up to that run.
+CREATE TABLE IF NOT EXISTS path_snapshots (
+  run_id INTEGER NOT NULL
+, url_path INTEGER NOT NULL
+, requests INTEGER NOT NULL
+, response_bytes INTEGER NOT NULL
+
+, PRIMARY KEY (run_id, url_path)
+, FOREIGN KEY(run_id) REFERENCES runs(id)
+, FOREIGN KEY(url_path) REFERENCES paths(id)
+) STRICT;
+
+-- Compliance with each configured service-level objective, evaluated at the end of a run.
+CREATE TABLE IF NOT EXISTS slo_results (
+  run_id INTEGER NOT NULL
+, name TEXT NOT NULL
+, window_start TEXT NOT NULL
+, window_end TEXT NOT NULL
+, requests INTEGER NOT NULL
+, fast_requests INTEGER NOT NULL
+, good_requests INTEGER NOT NULL -- not server errors
+, latency_target REAL NOT NULL
+, error_target REAL NOT NULL
+
+, PRIMARY KEY (run_id, name)
+, FOREIGN KEY(run_id) REFERENCES runs(id)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS tags (
+  id INTEGER PRIMARY KEY NOT NULL
+, tag TEXT NOT NULL UNIQUE
+) STRICT;
+
+-- Tags assigned to requests at ingest time.
+CREATE TABLE IF NOT EXISTS request_tags (
+  request INTEGER NOT NULL
+, tag INTEGER NOT NULL
+
+, PRIMARY KEY (request, tag)
+, FOREIGN KEY(request) REFERENCES requests(id)
+, FOREIGN KEY(tag) REFERENCES tags(id)
+) STRICT;
+
+-- The site's own hostnames, from the config file.
+-- Referers from these are internal navigation, not external referrals.
+CREATE TABLE IF NOT EXISTS site_hosts (
+  host TEXT PRIMARY KEY NOT NULL
+) STRICT;
+
+-- Named filters for reports, from the config file: SQL expressions over the columns of
+-- the reqs view in reports/joins.sql.
+CREATE TABLE IF NOT EXISTS segments (
+  name TEXT PRIMARY KEY NOT NULL
+, filter TEXT NOT NULL
+) STRICT;
+
+-- Columns of requests computed from other fields, per the [[column]] sections of the config.
+-- `through` is the last request they've been computed for.
+CREATE TABLE IF NOT EXISTS computed_columns (
+  name TEXT PRIMARY KEY NOT NULL
+, expr TEXT NOT NULL
+, through INTEGER NOT NULL
+) STRICT;
+
+-- How far each journal (a unit, or unit:identifier) has been read, as a journalctl cursor.
+CREATE TABLE IF NOT EXISTS journal_cursors (
+  journal TEXT PRIMARY KEY NOT NULL
+, cursor TEXT NOT NULL
+) STRICT;
+
+-- Copies of the database uploaded to object storage, per the [backup] section of the config.
+CREATE TABLE IF NOT EXISTS backups (
+  id INTEGER PRIMARY KEY NOT NULL
+, finished_at TEXT NOT NULL
+, object TEXT NOT NULL
+, bytes INTEGER NOT NULL
+) STRICT;
+
+-- Where requests were fetched from, in runs over several sources (e.g. gs://bucket).
+CREATE TABLE IF NOT EXISTS sources (
+  id INTEGER PRIMARY KEY NOT NULL
+, name TEXT NOT NULL UNIQUE
+) STRICT;
+
+-- Objects cleanup deleted from stores that keep deleted objects for a while (versioned GCS
+-- buckets), with the generations to restore them from; see the undelete module.
+CREATE TABLE IF NOT EXISTS deleted_objects (
+  run_id INTEGER NOT NULL
+, source TEXT NULL -- as in the sources table; NULL if the run had no label for it
+, name TEXT NOT NULL
+, generation TEXT NOT NULL
+, deleted_at TEXT NOT NULL
+, restored_at TEXT NULL
+
+, FOREIGN KEY(run_id) REFERENCES runs(id)
+) STRICT;
+
+-- Requests stored per hour from each client IP, AS, and country with a quota, and those past
+-- the quota, counted rather than stored; see the quota module.
+CREATE TABLE IF NOT EXISTS quota_usage (
+  hour TEXT NOT NULL -- e.g. 2024-06-01T12
+, kind TEXT NOT NULL -- "ip", "asn", or "country"
+, key TEXT NOT NULL
+, stored INTEGER NOT NULL
+, overflow_requests INTEGER NOT NULL
+, overflow_bytes INTEGER NOT NULL
+
+, PRIMARY KEY (hour, kind, key)
+) STRICT;
+
+-- Rollup: requests and bytes per minute, for bandwidth over time without scanning requests.
+-- Minutes are as datetime() writes them, e.g. "2024-07-01 12:30:00".
+-- Maintained as requests are stored, like the daily rollups.
+CREATE TABLE IF NOT EXISTS minute_bandwidth (
+  minute TEXT PRIMARY KEY NOT NULL
+, requests INTEGER NOT NULL
+, response_bytes INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,169 @@
+//! Per-entry transformation with a Rhai script.
+//!
+//! The script defines a function `transform(entry)`, which is called with each parsed entry
+//! as a map. It returns the (possibly modified) map to store the entry, or `()` to drop it.
+//!
+//! These keys can be modified: `url_path`, `referer`, `user_agent`, `country_code`,
+//! `cache_state`, and `tags` (an array of strings). The rest are there for reference:
+//! `client_ip`, `asn`, `host`, `service_id`, `request_method`, `response_status`,
+//! `response_bytes`, `response_duration` (seconds), and `request_start_time` (RFC 3339).
+//! Fields the log doesn't have are `()`.
+//!
+//! For example:
+//! ```rhai
+//! fn transform(entry) {
+//!     if entry.user_agent != () && entry.user_agent.contains("blackbox") { return (); }
+//!     if entry.url_path.ends_with(".xml") { entry.tags.push("feeds"); }
+//!     entry
+//! }
+//! ```
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use crate::record::LogEntry;
+
+/// A compiled transformation script.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    /// Compile a script from a file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| anyhow!("could not compile script {}: {e}", path.display()))?;
+        Ok(Script { engine, ast })
+    }
+
+    /// Run the script over the entries, returning those that are kept.
+    pub fn apply(&self, entries: Vec<LogEntry>) -> anyhow::Result<Vec<LogEntry>> {
+        let mut kept = Vec::with_capacity(entries.len());
+        let mut scope = Scope::new();
+        for (i, mut entry) in entries.into_iter().enumerate() {
+            let result: Dynamic = self
+                .engine
+                .call_fn(&mut scope, &self.ast, "transform", (to_map(&entry),))
+                .map_err(|e| anyhow!("script error in entry {i}: {e}"))?;
+            if result.is_unit() {
+                continue;
+            }
+            let map = result
+                .try_cast::<Map>()
+                .ok_or_else(|| anyhow!("script returned neither a map nor () for entry {i}"))?;
+            update_from_map(&mut entry, map).with_context(|| format!("in entry {i}"))?;
+            kept.push(entry);
+        }
+        Ok(kept)
+    }
+}
+
+fn to_map(entry: &LogEntry) -> Map {
+    // Fields the log doesn't have are ().
+    fn optional<T: Into<Dynamic>>(value: Option<T>) -> Dynamic {
+        value.map_or(Dynamic::UNIT, Into::into)
+    }
+    let mut map = Map::new();
+    map.insert("client_ip".into(), entry.client_ip.to_string().into());
+    map.insert("asn".into(), optional(entry.asn.map(i64::from)));
+    map.insert("country_code".into(), optional(entry.country_code.clone()));
+    map.insert("url_path".into(), entry.url_path.clone().into());
+    map.insert("referer".into(), optional(entry.referer.clone()));
+    map.insert("user_agent".into(), optional(entry.user_agent.clone()));
+    map.insert("cache_state".into(), optional(entry.cache_state.clone()));
+    map.insert("host".into(), optional(entry.host.clone()));
+    map.insert("service_id".into(), optional(entry.service_id.clone()));
+    map.insert(
+        "request_method".into(),
+        optional(entry.request_method.clone()),
+    );
+    map.insert(
+        "response_status".into(),
+        (entry.response_status as i64).into(),
+    );
+    map.insert(
+        "response_bytes".into(),
+        optional(entry.response_bytes.map(|bytes| bytes as i64)),
+    );
+    map.insert(
+        "response_duration".into(),
+        optional(entry.response_duration.map(|d| d.as_secs_f64())),
+    );
+    map.insert(
+        "request_start_time".into(),
+        entry.request_start_time.time.to_rfc3339().into(),
+    );
+    let tags: Array = entry.tags.iter().cloned().map(Dynamic::from).collect();
+    map.insert("tags".into(), tags.into());
+    map
+}
+
+fn update_from_map(entry: &mut LogEntry, mut map: Map) -> anyhow::Result<()> {
+    let mut take_string = |key: &str| -> anyhow::Result<Option<String>> {
+        match map.remove(key) {
+            None => Ok(None),
+            Some(v) if v.is_unit() => Ok(None),
+            Some(v) => v
+                .into_string()
+                .map(Some)
+                .map_err(|t| anyhow!("script set {key} to a {t}, not a string")),
+        }
+    };
+    if let Some(v) = take_string("url_path")? {
+        entry.url_path = v;
+    }
+    if let Some(v) = take_string("referer")? {
+        entry.referer = Some(v);
+    }
+    if let Some(v) = take_string("user_agent")? {
+        entry.user_agent = Some(v);
+    }
+    if let Some(v) = take_string("cache_state")? {
+        entry.cache_state = Some(v);
+    }
+    entry.country_code = take_string("country_code")?;
+    if let Some(tags) = map.remove("tags") {
+        let tags = tags
+            .into_typed_array::<String>()
+            .map_err(|t| anyhow!("script set tags to a {t}, not an array of strings"))?;
+        entry.tags = tags;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Script;
+    use crate::testdata::entry;
+
+    #[test]
+    fn transforms_tags_and_drops() {
+        let engine = rhai::Engine::new();
+        let script = Script {
+            ast: engine
+                .compile(
+                    r#"
+                    fn transform(entry) {
+                        if entry.user_agent.contains("bot") { return (); }
+                        entry.url_path = "/rewritten";
+                        entry.tags.push("scripted");
+                        entry
+                    }
+                    "#,
+                )
+                .unwrap(),
+            engine,
+        };
+        let mut bot = entry();
+        bot.user_agent = Some("Googlebot".to_string());
+        let kept = script.apply(vec![entry(), bot]).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].url_path, "/rewritten");
+        assert_eq!(kept[0].tags, vec!["scripted".to_string()]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Names of the big networks, built in, so a fresh database has them before PeeringDB has
+//! answered for each.
+//!
+//! The snapshot is a gzipped TSV of AS number and name, one per line, of the networks in
+//! PeeringDB; asns.tsv.gz.do makes it, with `redo src/asns.tsv.gz`. Names from it only fill
+//! ASes that have none; PeeringDB is still asked about the rest, and its names aren't
+//! replaced.
+
+use std::io::{BufRead, BufReader};
+
+use anyhow::Context;
+use rusqlite::{named_params, Connection};
+
+/// AS numbers and names, as of the snapshot.
+const SNAPSHOT: &[u8] = include_bytes!("asns.tsv.gz");
+
+/// The AS numbers and names in the snapshot.
+fn snapshot() -> anyhow::Result<Vec<(u32, String)>> {
+    let reader = BufReader::new(flate2::read::GzDecoder::new(SNAPSHOT));
+    let mut names = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.context("could not read ASN snapshot")?;
+        let (asn, name) = line
+            .split_once('\t')
+            .with_context(|| format!("invalid line {} in ASN snapshot", i + 1))?;
+        let asn = asn
+            .parse()
+            .with_context(|| format!("invalid ASN on line {} of ASN snapshot", i + 1))?;
+        names.push((asn, name.to_string()));
+    }
+    Ok(names)
+}
+
+/// Name the unnamed ASes that the snapshot has; returns how many were named.
+pub(crate) fn seed_asns(conn: &Connection) -> anyhow::Result<usize> {
+    let mut update = conn
+        .prepare("UPDATE autonomous_systems SET name = :name WHERE asn = :asn AND name IS NULL")
+        .context("invalid query to seed ASN names")?;
+    let mut named = 0;
+    for (asn, name) in snapshot()? {
+        named += update
+            .execute(named_params! { ":asn": asn, ":name": name })
+            .with_context(|| format!("could not seed name of ASN {asn}"))?;
+    }
+    Ok(named)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{seed_asns, snapshot};
+
+    #[test]
+    fn names_unnamed_asns() {
+        assert!(snapshot().unwrap().iter().any(|(asn, _)| *asn == 7922));
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE autonomous_systems (asn INTEGER PRIMARY KEY, name TEXT NULL);
+            INSERT INTO autonomous_systems VALUES (7922, NULL), (13335, 'CF'), (64496, NULL);",
+        )
+        .unwrap();
+        assert_eq!(seed_asns(&conn).unwrap(), 1);
+        let name: Option<String> = conn
+            .query_row(
+                "SELECT name FROM autonomous_systems WHERE asn = 13335",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("CF"));
+    }
+}
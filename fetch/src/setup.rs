@@ -0,0 +1,420 @@
+//! Setting up a new site, for the setup binary: a few questions about where the logs are and
+//! what to keep, a check that the store and the database can be reached with the answers, and a
+//! config file written from them.
+//!
+//! Where the logs are and where the database goes are crunch_gcs's arguments, not settings in
+//! the config file, so the file starts with the command to crunch with it.
+
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::runtime::Runtime;
+
+use crate::{doctor, open_fetcher, Check, ObjectFilter, Pack, SourceSpec};
+
+/// What a `[[tag]]` rule for bots looks for in user agents.
+const BOT_USER_AGENTS: &str = "(?i)bot|crawl|spider|slurp|preview";
+
+/// What's percent-encoded in source parameters: all but what paths and IDs are usually made of.
+const PARAM_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+/// LogEntry's key for the client's country in Fastly's JSON.
+const COUNTRY_KEY: &str = "countryCode";
+
+/// The answers to setup's questions.
+#[derive(Debug, Clone)]
+pub struct Setup {
+    /// Where the logs are, as crunch_gcs takes it, credentials and all.
+    pub source: String,
+    /// SQLite database to crunch into.
+    pub database: PathBuf,
+    /// Days of requests to keep; all of them, if None.
+    pub retention_days: Option<u32>,
+    /// The site's own hostnames, for telling internal navigation from referrals.
+    pub site_hosts: Vec<String>,
+    /// Whether to tag requests from bots, by their user agents.
+    pub tag_bots: bool,
+    /// Whether to name the ASes requests come from, from PeeringDB.
+    pub name_asns: bool,
+    /// The key the logs have the client's country in, if it isn't LogEntry's.
+    pub country_key: Option<String>,
+    /// Directory of SQL packs to apply after each run.
+    pub packs: Option<PathBuf>,
+}
+
+impl Setup {
+    /// Ask each question on `output`, reading the answers from `input`. A blank answer takes
+    /// the default, and one that doesn't make sense is asked again.
+    pub fn ask(input: &mut dyn BufRead, output: &mut dyn Write) -> anyhow::Result<Self> {
+        let mut prompt = Prompt { input, output };
+        let source = prompt.ask(
+            "Where are the logs? A GCS bucket, or a gs://, s3://, sftp://, or file:// URL",
+            None,
+            |answer| {
+                let spec: SourceSpec = answer.parse()?;
+                if spec == SourceSpec::Stdin {
+                    bail!("setup is for a store to fetch from, not stdin");
+                }
+                Ok(if answer.contains("://") {
+                    answer.to_string()
+                } else {
+                    format!("gs://{answer}")
+                })
+            },
+        )?;
+        let source = match source.parse()? {
+            SourceSpec::Gcs {
+                credentials: None, ..
+            } => {
+                let key = prompt.ask(
+                    "Service account key (JSON) for the bucket; blank for the default credentials",
+                    Some(""),
+                    existing_file,
+                )?;
+                with_params(source, &[("credentials", key)])
+            }
+            SourceSpec::S3 {
+                access_key_id: None,
+                ..
+            } => {
+                let id = prompt.ask(
+                    "Access key ID for the bucket; blank for the AWS credential chain",
+                    Some(""),
+                    |answer| Ok(answer.to_string()),
+                )?;
+                let secret = if id.is_empty() {
+                    String::new()
+                } else {
+                    prompt.ask("File holding the secret key", None, existing_file)?
+                };
+                with_params(
+                    source,
+                    &[("access_key_id", id), ("secret_key_file", secret)],
+                )
+            }
+            SourceSpec::Sftp { key: None, .. } => {
+                let key = prompt.ask(
+                    "Private key for the server; blank for the SSH agent",
+                    Some(""),
+                    existing_file,
+                )?;
+                with_params(source, &[("key", key)])
+            }
+            _ => source,
+        };
+        let database = prompt.ask(
+            "SQLite database to crunch into",
+            Some("logs.db"),
+            |answer| Ok(PathBuf::from(answer)),
+        )?;
+        let retention_days = prompt.ask(
+            "Days of requests to keep, after which only the daily rollups have them; \
+            blank to keep them all",
+            Some(""),
+            |answer| match answer {
+                "" => Ok(None),
+                days => match days.parse() {
+                    Ok(0) | Err(_) => bail!("not a number of days: {days}"),
+                    Ok(days) => Ok(Some(days)),
+                },
+            },
+        )?;
+        let site_hosts = prompt.ask(
+            "The site's hostnames, comma-separated, so referers from them count as internal \
+            navigation; blank for none",
+            Some(""),
+            |answer| {
+                Ok(answer
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect())
+            },
+        )?;
+        let tag_bots = prompt.confirm(
+            "Tag requests from bots (by user agent) as \"bot\", for reports to leave out?",
+            false,
+        )?;
+        let name_asns = prompt.confirm(
+            "Name the ASes (networks) requests come from, by looking them up in PeeringDB after \
+            each run?",
+            true,
+        )?;
+        let country_key = prompt.ask(
+            &format!(
+                "Key of the client's country code (Fastly's client.geo.country_code) in the logs; \
+                blank for {COUNTRY_KEY}"
+            ),
+            Some(""),
+            |answer| Ok((!answer.is_empty() && answer != COUNTRY_KEY).then(|| answer.to_string())),
+        )?;
+        let packs = prompt.ask(
+            "Directory of SQL packs to apply after each run; blank for none",
+            Some(""),
+            |answer| match answer {
+                "" => Ok(None),
+                dir => {
+                    let dir = PathBuf::from(dir);
+                    let packs = Pack::discover(&dir)?;
+                    if packs.is_empty() {
+                        bail!("no packs in {}", dir.display());
+                    }
+                    Ok(Some(dir))
+                }
+            },
+        )?;
+        Ok(Setup {
+            source,
+            database,
+            retention_days,
+            site_hosts,
+            tag_bots,
+            name_asns,
+            country_key,
+            packs,
+        })
+    }
+
+    /// Check that the store can be listed, read, and cleaned up with the credentials given,
+    /// and that the database can be written; see the doctor module.
+    pub fn check(&self, rt: &Runtime) -> Vec<Check> {
+        let mut checks = Vec::new();
+        let result = self.source.parse().and_then(|source: SourceSpec| {
+            if matches!(source, SourceSpec::Journald { .. }) {
+                return Ok("read by journalctl; not checked".to_string());
+            }
+            let fetcher = open_fetcher(&source, true, ObjectFilter::default())?;
+            rt.block_on(fetcher.preflight())?;
+            Ok("can list, read, and delete objects".to_string())
+        });
+        checks.push(Check::new(format!("source {}", self.source), result));
+        checks.push(Check::new(
+            format!("database {}", self.database.display()),
+            doctor::check_database(&self.database),
+        ));
+        checks
+    }
+
+    /// The command to crunch with the config file at `config`.
+    pub fn command(&self, config: &Path) -> String {
+        let mut words = vec![
+            "crunch_gcs".to_string(),
+            quote(&self.source),
+            quote(&self.database.to_string_lossy()),
+            "--config".to_string(),
+            quote(&config.to_string_lossy()),
+        ];
+        if let Some(packs) = &self.packs {
+            words.push("--packs".to_string());
+            words.push(quote(&packs.to_string_lossy()));
+        }
+        words.join(" ")
+    }
+
+    /// The config file, to be written to `config`.
+    pub fn config(&self, config: &Path) -> String {
+        let mut toml = format!(
+            "# Written by setup. Crunch with:\n#   {}\n",
+            self.command(config)
+        );
+        if !self.site_hosts.is_empty() {
+            let hosts: Vec<_> = self.site_hosts.iter().map(|host| string(host)).collect();
+            toml += &format!("\nsite_hosts = [{}]\n", hosts.join(", "));
+        }
+        if let Some(key) = &self.country_key {
+            toml += &format!("\n[fields]\ncountry_code = {}\n", string(key));
+        }
+        if !self.name_asns {
+            toml += "\n[enrichment]\npeeringdb = false\n";
+        }
+        if let Some(days) = self.retention_days {
+            toml += &format!("\n[retention]\nraw_days = {days}\n");
+        }
+        if self.tag_bots {
+            toml += &format!(
+                "\n[[tag]]\ntag = \"bot\"\nuser_agent = {}\n",
+                string(BOT_USER_AGENTS)
+            );
+        }
+        toml
+    }
+}
+
+/// Questions on a terminal, or whatever stands in for one.
+struct Prompt<'a> {
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+}
+
+impl Prompt<'_> {
+    /// Ask until `parse` takes the answer; a blank answer is `default`, if there is one.
+    fn ask<T>(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+        parse: impl Fn(&str) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        loop {
+            match default {
+                Some(default) if !default.is_empty() => {
+                    write!(self.output, "{question} [{default}]: ")
+                }
+                _ => write!(self.output, "{question}: "),
+            }
+            .and_then(|()| self.output.flush())
+            .context("could not write question")?;
+            let mut answer = String::new();
+            if self
+                .input
+                .read_line(&mut answer)
+                .context("could not read answer")?
+                == 0
+            {
+                bail!("no answer to {question:?}");
+            }
+            let answer = match (answer.trim(), default) {
+                ("", None) => continue,
+                ("", Some(default)) => default,
+                (answer, _) => answer,
+            };
+            match parse(answer) {
+                Ok(value) => return Ok(value),
+                Err(e) => writeln!(self.output, "  {e:#}").context("could not write question")?,
+            }
+        }
+    }
+
+    /// Ask a yes-or-no question.
+    fn confirm(&mut self, question: &str, default: bool) -> anyhow::Result<bool> {
+        let question = format!("{question} {}", if default { "[Y/n]" } else { "[y/N]" });
+        // The default is shown in the question, as [Y/n] or [y/N].
+        self.ask(&question, Some(""), |answer| {
+            match answer.to_ascii_lowercase().as_str() {
+                "" => Ok(default),
+                "y" | "yes" => Ok(true),
+                "n" | "no" => Ok(false),
+                _ => bail!("y or n"),
+            }
+        })
+    }
+}
+
+/// Ask a yes-or-no question on `output`, reading the answer from `input`.
+pub fn confirm(
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    question: &str,
+    default: bool,
+) -> anyhow::Result<bool> {
+    Prompt { input, output }.confirm(question, default)
+}
+
+/// The path, if it's blank or a file that exists.
+fn existing_file(answer: &str) -> anyhow::Result<String> {
+    if !answer.is_empty() && !Path::new(answer).is_file() {
+        bail!("no such file: {answer}");
+    }
+    Ok(answer.to_string())
+}
+
+/// The source with the nonblank parameters added to it, percent-encoded.
+fn with_params(source: String, params: &[(&str, String)]) -> String {
+    params
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .fold(source, |source, (key, value)| {
+            let separator = if source.contains('?') { '&' } else { '?' };
+            let value = utf8_percent_encode(value, PARAM_VALUE);
+            format!("{source}{separator}{key}={value}")
+        })
+}
+
+/// The word, quoted for the shell where it needs to be.
+fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._:@=,+-".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// The string as a TOML string.
+fn string(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{with_params, Setup};
+    use crate::{Config, SourceSpec};
+
+    #[test]
+    fn writes_config_from_answers() {
+        let dir = tempfile::tempdir().unwrap();
+        let answers = format!(
+            "file://{}\n{}\nsoon\n30\nexample.com, www.example.com\ny\nn\nclient_country\n\n",
+            dir.path().display(),
+            dir.path().join("logs.db").display()
+        );
+        let mut output = Vec::new();
+        let setup = Setup::ask(&mut answers.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("not a number of days: soon"));
+        assert_eq!(setup.retention_days, Some(30));
+
+        let config: Config = toml::from_str(&setup.config("my config.toml".as_ref())).unwrap();
+        assert_eq!(config.retention.unwrap().raw_days, 30);
+        assert_eq!(config.site_hosts, ["example.com", "www.example.com"]);
+        assert_eq!(config.tags[0].tag, "bot");
+        assert!(!config.enrichment.peeringdb);
+        assert_eq!(config.fields["country_code"], "client_country");
+        assert_eq!(
+            setup.command("my config.toml".as_ref()),
+            format!(
+                "crunch_gcs file://{0} {0}/logs.db --config 'my config.toml'",
+                dir.path().display()
+            )
+        );
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        assert!(setup.check(&rt).iter().all(|check| check.passed()));
+
+        // Credentials go in the source.
+        let answers = "my-bucket\nno-such-key.json\n\nlogs.db\n\n\n\n\n\n\n";
+        let setup = Setup::ask(&mut answers.as_bytes(), &mut Vec::new()).unwrap();
+        assert_eq!(setup.source, "gs://my-bucket");
+        assert_eq!(setup.retention_days, None);
+        assert_eq!(setup.database, Path::new("logs.db"));
+        assert!(!setup.tag_bots);
+        assert!(setup.name_asns);
+        assert_eq!(setup.country_key, None);
+        assert_eq!(setup.config("c.toml".as_ref()).lines().count(), 2);
+
+        // Parameters are encoded, to be decoded when the source is parsed.
+        let source = with_params(
+            "gs://my-bucket".to_string(),
+            &[("credentials", "/my keys/a&b.json".to_string())],
+        );
+        assert_eq!(source, "gs://my-bucket?credentials=/my%20keys/a%26b.json");
+        assert!(matches!(
+            source.parse().unwrap(),
+            SourceSpec::Gcs { credentials: Some(key), .. } if key == Path::new("/my keys/a&b.json")
+        ));
+    }
+}
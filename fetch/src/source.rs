@@ -0,0 +1,985 @@
+//! Backing stores for log objects.
+//!
+//! The Fetcher handles filtering, parsing, and cleanup;
+//! a LogSource only needs to know how to list, read, and delete objects.
+
+use std::{
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use opendal::{layers::TracingLayer, Metakey, Operator};
+use serde::{Deserialize, Serialize};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::gcs::Generations;
+
+/// Where to find logs.
+///
+/// Parsed from a URL-ish string: `gs://bucket?credentials=/etc/sa.json`,
+/// `s3://bucket?region=us-east-2`,
+/// `s3://bucket?endpoint=https://ACCOUNT.r2.cloudflarestorage.com&access_key_id=...` (an R2
+/// bucket, with `&secret_key_file=...` too),
+/// `sftp://user@host:22/logs?key=/home/me/.ssh/id_ed25519`, `file:///var/log/fastly`.
+/// `urls:///path/to/manifest` reads a list of URLs to download.
+/// `journald://nginx.service?identifier=nginx_access` reads nginx's JSON access logs from the
+/// journal.
+/// A bare name is taken to be a GCS bucket; `-` is standard input. Parameter values can be
+/// percent-encoded, e.g. a key file with `&` or a space in its path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceSpec {
+    Gcs {
+        bucket: String,
+        /// Service account key (JSON) to authenticate with, rather than the default credentials.
+        credentials: Option<PathBuf>,
+        /// OAuth scope to request, e.g. `https://www.googleapis.com/auth/devstorage.read_only`.
+        scope: Option<String>,
+        /// API endpoint to use instead of Google's, e.g. fake-gcs-server's in tests.
+        endpoint: Option<String>,
+    },
+    S3 {
+        bucket: String,
+        region: Option<String>,
+        /// Endpoint of an S3-compatible store to use instead of AWS, e.g. MinIO's or R2's.
+        endpoint: Option<String>,
+        /// Address buckets as `bucket.endpoint` rather than `endpoint/bucket`.
+        virtual_host_style: bool,
+        /// Static access key to authenticate with, rather than the AWS credential chain.
+        access_key_id: Option<String>,
+        /// File holding the secret for `access_key_id`; kept out of the spec, which shows up
+        /// in process listings and logs.
+        secret_key_file: Option<PathBuf>,
+    },
+    Sftp {
+        /// `host` or `host:port`.
+        host: String,
+        user: Option<String>,
+        /// Directory on the server holding the logs.
+        root: String,
+        /// Private key to authenticate with, rather than the SSH agent or defaults.
+        key: Option<PathBuf>,
+        /// How to check the server's host key: `strict` (the default), `add`, or `accept`.
+        known_hosts: Option<String>,
+    },
+    Fs {
+        dir: PathBuf,
+    },
+    /// URLs listed in a manifest file, e.g. pre-signed URLs exported from another system.
+    Urls {
+        manifest: PathBuf,
+    },
+    /// A single stream of entries on stdin, rather than a store of objects.
+    Stdin,
+    /// nginx's JSON access log entries in a unit's journal; see the nginx module.
+    Journald {
+        unit: String,
+        /// Only entries with this syslog identifier, e.g. the access log's `tag`, rather
+        /// than all of the unit's.
+        identifier: Option<String>,
+    },
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(SourceSpec::Stdin);
+        }
+        let Some((scheme, rest)) = s.split_once("://") else {
+            return Ok(SourceSpec::Gcs {
+                bucket: s.to_string(),
+                credentials: None,
+                scope: None,
+                endpoint: None,
+            });
+        };
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut params = Vec::new();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| anyhow!("source parameter {param} has no value"))?;
+            let value = percent_encoding::percent_decode_str(value)
+                .decode_utf8()
+                .with_context(|| format!("source parameter {key} isn't UTF-8"))?;
+            params.push((key, value.into_owned()));
+        }
+        let mut take = |key: &str| {
+            params
+                .iter()
+                .position(|(k, _)| *k == key)
+                .map(|i| params.remove(i).1)
+        };
+        let spec = match scheme {
+            "gs" | "gcs" => SourceSpec::Gcs {
+                bucket: location.to_string(),
+                credentials: take("credentials").map(PathBuf::from),
+                scope: take("scope"),
+                endpoint: take("endpoint"),
+            },
+            "s3" => SourceSpec::S3 {
+                bucket: location.to_string(),
+                region: take("region"),
+                endpoint: take("endpoint"),
+                virtual_host_style: take("virtual_host_style")
+                    .map(|v| v.parse::<bool>())
+                    .transpose()
+                    .context("virtual_host_style must be true or false")?
+                    .unwrap_or(false),
+                access_key_id: take("access_key_id"),
+                secret_key_file: take("secret_key_file").map(PathBuf::from),
+            },
+            "sftp" => {
+                let (authority, root) = location
+                    .split_once('/')
+                    .map(|(authority, root)| (authority, format!("/{root}")))
+                    .unwrap_or((location, String::new()));
+                let (user, host) = match authority.split_once('@') {
+                    Some((user, host)) => (Some(user.to_string()), host),
+                    None => (None, authority),
+                };
+                if host.is_empty() {
+                    bail!("sftp source needs a host");
+                }
+                SourceSpec::Sftp {
+                    host: host.to_string(),
+                    user,
+                    root,
+                    key: take("key").map(PathBuf::from),
+                    known_hosts: take("known_hosts"),
+                }
+            }
+            "file" => SourceSpec::Fs {
+                dir: PathBuf::from(location),
+            },
+            "urls" => SourceSpec::Urls {
+                manifest: PathBuf::from(location),
+            },
+            "journald" => {
+                if location.is_empty() {
+                    bail!("journald source needs a unit");
+                }
+                SourceSpec::Journald {
+                    unit: location.to_string(),
+                    identifier: take("identifier"),
+                }
+            }
+            _ => bail!("unknown source scheme {scheme}"),
+        };
+        if let Some((key, _)) = params.first() {
+            bail!("unknown parameter {key} for {scheme} source");
+        }
+        Ok(spec)
+    }
+}
+
+/// The spec as a URL, without the parameters for getting at it, but with those that say which
+/// store it is (an endpoint, a journal identifier); e.g. to label where requests came from.
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceSpec::Gcs {
+                bucket,
+                endpoint: Some(endpoint),
+                ..
+            } => write!(f, "gs://{bucket}?endpoint={endpoint}"),
+            SourceSpec::Gcs { bucket, .. } => write!(f, "gs://{bucket}"),
+            SourceSpec::S3 {
+                bucket,
+                endpoint: Some(endpoint),
+                ..
+            } => write!(f, "s3://{bucket}?endpoint={endpoint}"),
+            SourceSpec::S3 { bucket, .. } => write!(f, "s3://{bucket}"),
+            SourceSpec::Sftp {
+                host, user, root, ..
+            } => match user {
+                Some(user) => write!(f, "sftp://{user}@{host}{root}"),
+                None => write!(f, "sftp://{host}{root}"),
+            },
+            SourceSpec::Fs { dir } => write!(f, "file://{}", dir.display()),
+            SourceSpec::Urls { manifest } => write!(f, "urls://{}", manifest.display()),
+            SourceSpec::Stdin => write!(f, "-"),
+            SourceSpec::Journald {
+                unit,
+                identifier: Some(identifier),
+            } => write!(f, "journald://{unit}?identifier={identifier}"),
+            SourceSpec::Journald { unit, .. } => write!(f, "journald://{unit}"),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SourceSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A listed object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    /// Name of the object, as passed to `read` and `delete`.
+    pub name: String,
+    /// Size of the object in bytes, if the listing provided it.
+    pub size: Option<u64>,
+    /// MD5 digest of the contents, if the listing provided it.
+    pub md5: Option<[u8; 16]>,
+    /// When the object was last modified, if the listing provided it.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Stream of objects from a listing.
+pub type ObjectStream = Pin<Box<dyn Stream<Item = anyhow::Result<ObjectInfo>> + Send>>;
+
+/// Contents of an object, in chunks as they arrive.
+pub type ByteStream = Pin<Box<dyn Stream<Item = anyhow::Result<Bytes>> + Send>>;
+
+/// A store of log objects.
+#[async_trait]
+pub trait LogSource: Send + Sync {
+    /// List the objects in the store whose names start with `prefix`.
+    ///
+    /// The prefix is a hint to make listing cheaper; the listing may include other objects,
+    /// which the caller filters out.
+    /// If `sizes` is set, the listing should include object sizes, even if that's more expensive,
+    /// and MD5 digests and modification times if the store has them.
+    async fn list(&self, prefix: &str, sizes: bool) -> anyhow::Result<ObjectStream>;
+
+    /// List only the objects whose names sort after `start_after`, as `list` otherwise.
+    ///
+    /// Returns None if the store can't start a listing partway.
+    async fn list_after(
+        &self,
+        prefix: &str,
+        sizes: bool,
+        start_after: &str,
+    ) -> anyhow::Result<Option<ObjectStream>> {
+        let _ = (prefix, sizes, start_after);
+        Ok(None)
+    }
+
+    /// Read the full contents of an object.
+    async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Read the contents of an object as a stream of chunks, so that large objects don't
+    /// need to be held in memory.
+    ///
+    /// By default, this reads the whole object at once; sources that can stream should.
+    async fn read_stream(&self, name: &str) -> anyhow::Result<ByteStream> {
+        let data = self.read(name).await?;
+        Ok(Box::pin(tokio_stream::once(Ok(Bytes::from(data)))))
+    }
+
+    /// Read the contents of an object from `offset` on, as `read_stream`; for picking up a
+    /// read that broke partway through.
+    ///
+    /// Returns None if the store can't start a read partway.
+    async fn read_stream_from(
+        &self,
+        name: &str,
+        offset: u64,
+    ) -> anyhow::Result<Option<ByteStream>> {
+        let _ = (name, offset);
+        Ok(None)
+    }
+
+    /// Read part of an object: the bytes in `range`.
+    ///
+    /// Returns None if the store can't read parts of objects.
+    async fn read_range(&self, name: &str, range: Range<u64>) -> anyhow::Result<Option<Bytes>> {
+        let _ = (name, range);
+        Ok(None)
+    }
+
+    /// Copy an object to a new name in the same store, for archiving it.
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let _ = to;
+        bail!("cannot copy {from}: this source does not support copying objects")
+    }
+
+    /// Write an object, replacing any existing object of the same name.
+    async fn write(&self, name: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let _ = data;
+        bail!("cannot write {name}: this source does not support writing objects")
+    }
+
+    /// Write an object from a stream of chunks, so that large objects don't need to be held in
+    /// memory. An error in the stream leaves no object behind.
+    ///
+    /// By default, this collects the stream and writes it at once; sources that can stream should.
+    async fn write_stream(&self, name: &str, mut data: ByteStream) -> anyhow::Result<()> {
+        let mut contents = Vec::new();
+        while let Some(chunk) = data.next().await {
+            contents.extend_from_slice(&chunk?);
+        }
+        self.write(name, contents).await
+    }
+
+    /// Delete an object, once it has been processed.
+    async fn delete(&self, name: &str) -> anyhow::Result<()>;
+
+    /// The generation of an object to restore it from once it's deleted, in stores that keep
+    /// deleted objects for a while (versioned GCS buckets).
+    ///
+    /// Returns None if the store doesn't.
+    async fn generation(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let _ = name;
+        Ok(None)
+    }
+
+    /// Put back a deleted object from its generation, as `generation` gave it.
+    async fn restore(&self, name: &str, generation: &str) -> anyhow::Result<()> {
+        let _ = generation;
+        bail!("cannot restore {name}: this source does not keep deleted objects")
+    }
+}
+
+/// Parse an MD5 digest as stores report it: base64 from GCS, or hex from S3's ETags.
+///
+/// S3 ETags of multipart uploads aren't MD5s of the contents, so they don't parse.
+fn parse_md5(s: &str) -> Option<[u8; 16]> {
+    use base64::Engine;
+
+    let bytes = if s.len() == 32 {
+        (0..16)
+            .map(|i| u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?
+    } else {
+        base64::engine::general_purpose::STANDARD.decode(s).ok()?
+    };
+    bytes.try_into().ok()
+}
+
+/// How much of a streamed write to upload at a time; stores round it to their part sizes.
+const WRITE_CHUNK_SIZE: usize = 8 << 20;
+
+/// A LogSource backed by an OpenDAL operator.
+///
+/// Listings are recursive, in every store: objects under a prefix with more slashes after it
+/// (as with a Fastly path like `%Y/%m/%d/`), or in subdirectories, are listed too.
+pub struct OpendalSource {
+    operator: Operator,
+    generations: Option<Generations>,
+}
+
+impl OpendalSource {
+    pub fn new(operator: Operator) -> Self {
+        OpendalSource {
+            operator,
+            generations: None,
+        }
+    }
+
+    /// A GCS bucket.
+    ///
+    /// Credentials come from `credentials`, a service account key, if given; otherwise from
+    /// the defaults (GOOGLE_APPLICATION_CREDENTIALS, or the metadata server).
+    pub fn gcs(
+        bucket: &str,
+        credentials: Option<&Path>,
+        scope: Option<&str>,
+        endpoint: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = opendal::services::Gcs::default();
+        builder.bucket(bucket);
+        let credentials = credentials
+            .map(|credentials| {
+                credentials.to_str().with_context(|| {
+                    format!("non-UTF-8 credentials path {}", credentials.display())
+                })
+            })
+            .transpose()?;
+        if let Some(credentials) = credentials {
+            builder.credential_path(credentials);
+        }
+        if let Some(scope) = scope {
+            builder.scope(scope);
+        }
+        if let Some(endpoint) = endpoint {
+            builder.endpoint(endpoint);
+        }
+        let generations = Generations::new(bucket, credentials, scope, endpoint)?;
+        Ok(OpendalSource {
+            generations: Some(generations),
+            ..Self::new(Operator::new(builder)?.layer(TracingLayer).finish())
+        })
+    }
+
+    /// An S3 bucket, on AWS or an S3-compatible store at `endpoint`.
+    ///
+    /// Credentials come from `access_key_id` and the secret in `secret_key_file`, if given;
+    /// otherwise from the standard AWS chain: environment variables, then profiles.
+    /// If no region is given, it also comes from the environment or profile; for other
+    /// endpoints, it defaults to us-east-1, which MinIO and R2 both accept.
+    ///
+    /// Buckets are addressed path-style (`endpoint/bucket`), as MinIO expects, unless
+    /// `virtual_host_style` is set.
+    pub fn s3(
+        bucket: &str,
+        region: Option<&str>,
+        endpoint: Option<&str>,
+        virtual_host_style: bool,
+        access_key_id: Option<&str>,
+        secret_key_file: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = opendal::services::S3::default();
+        builder.bucket(bucket);
+        if let Some(endpoint) = endpoint {
+            builder.endpoint(endpoint);
+            builder.region(region.unwrap_or("us-east-1"));
+        } else if let Some(region) = region {
+            builder.region(region);
+        }
+        if virtual_host_style {
+            builder.enable_virtual_host_style();
+        }
+        match (access_key_id, secret_key_file) {
+            (Some(access_key_id), Some(secret_key_file)) => {
+                let secret = std::fs::read_to_string(secret_key_file).with_context(|| {
+                    format!(
+                        "could not read secret key from {}",
+                        secret_key_file.display()
+                    )
+                })?;
+                builder.access_key_id(access_key_id);
+                builder.secret_access_key(secret.trim());
+            }
+            (None, None) => (),
+            _ => bail!("access_key_id and secret_key_file must be given together"),
+        }
+        Ok(Self::new(
+            Operator::new(builder)?.layer(TracingLayer).finish(),
+        ))
+    }
+
+    /// A directory on an SFTP server.
+    ///
+    /// Authentication goes through OpenSSH: the key, if given, otherwise the agent and
+    /// the user's SSH configuration.
+    pub fn sftp(
+        host: &str,
+        user: Option<&str>,
+        root: &str,
+        key: Option<&Path>,
+        known_hosts: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = opendal::services::Sftp::default();
+        builder.endpoint(&format!("ssh://{host}"));
+        builder.root(root);
+        if let Some(user) = user {
+            builder.user(user);
+        }
+        if let Some(key) = key {
+            builder.key(
+                key.to_str()
+                    .with_context(|| format!("non-UTF-8 key path {}", key.display()))?,
+            );
+        }
+        if let Some(known_hosts) = known_hosts {
+            builder.known_hosts_strategy(known_hosts);
+        }
+        Ok(Self::new(
+            Operator::new(builder)?.layer(TracingLayer).finish(),
+        ))
+    }
+
+    /// A local directory.
+    pub fn fs(dir: &Path) -> anyhow::Result<Self> {
+        let mut builder = opendal::services::Fs::default();
+        builder.root(
+            dir.to_str()
+                .with_context(|| format!("non-UTF-8 directory name {}", dir.display()))?,
+        );
+        Ok(Self::new(
+            Operator::new(builder)?.layer(TracingLayer).finish(),
+        ))
+    }
+
+    /// The store a spec refers to, if it's a store of objects.
+    pub fn open(spec: &SourceSpec) -> anyhow::Result<Self> {
+        match spec {
+            SourceSpec::Gcs {
+                bucket,
+                credentials,
+                scope,
+                endpoint,
+            } => Self::gcs(
+                bucket,
+                credentials.as_deref(),
+                scope.as_deref(),
+                endpoint.as_deref(),
+            ),
+            SourceSpec::S3 {
+                bucket,
+                region,
+                endpoint,
+                virtual_host_style,
+                access_key_id,
+                secret_key_file,
+            } => Self::s3(
+                bucket,
+                region.as_deref(),
+                endpoint.as_deref(),
+                *virtual_host_style,
+                access_key_id.as_deref(),
+                secret_key_file.as_deref(),
+            ),
+            SourceSpec::Sftp {
+                host,
+                user,
+                root,
+                key,
+                known_hosts,
+            } => Self::sftp(
+                host,
+                user.as_deref(),
+                root,
+                key.as_deref(),
+                known_hosts.as_deref(),
+            ),
+            SourceSpec::Fs { dir } => Self::fs(dir),
+            SourceSpec::Urls { .. } | SourceSpec::Stdin | SourceSpec::Journald { .. } => {
+                bail!("{spec:?} is not a store of objects")
+            }
+        }
+    }
+
+    /// Stream an object's contents from `offset` on, with range requests where it takes them.
+    async fn stream_from(&self, name: &str, offset: u64) -> anyhow::Result<ByteStream> {
+        let stream = self
+            .operator
+            .reader(name)
+            .await
+            .with_context(|| format!("failed to start read of object {}: ", name))?
+            .into_bytes_stream(offset..)
+            .await
+            .with_context(|| format!("failed to start read of object {}: ", name))?;
+        let name = name.to_string();
+        Ok(Box::pin(stream.map(move |chunk| {
+            chunk.with_context(|| format!("failed to read object contents {}: ", name))
+        })))
+    }
+
+    /// List objects under the prefix, after `start_after` if given.
+    async fn list_from(
+        &self,
+        prefix: &str,
+        sizes: bool,
+        start_after: Option<&str>,
+    ) -> anyhow::Result<ObjectStream> {
+        // Listing works by directory, so start from the directory containing the prefix.
+        let dir = prefix.rfind('/').map_or("", |i| &prefix[..=i]);
+        let mut lister = self
+            .operator
+            .lister_with(dir)
+            .recursive(true)
+            .metakey(Metakey::Mode);
+        if let Some(start_after) = start_after {
+            lister = lister.start_after(start_after);
+        }
+        if sizes {
+            lister = lister.metakey(
+                Metakey::Mode
+                    | Metakey::ContentLength
+                    | Metakey::ContentMd5
+                    | Metakey::LastModified,
+            );
+        }
+        let lister = lister
+            .await
+            .context("could not list entries from storage")?;
+        Ok(Box::pin(lister.filter_map(move |entry| {
+            let entry = match entry.context("in listing bucket entries: ") {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            // Directories (or common prefixes) aren't objects to fetch.
+            if entry.metadata().is_dir() {
+                return None;
+            }
+            Some(Ok(ObjectInfo {
+                name: entry.path().to_string(),
+                size: sizes.then(|| entry.metadata().content_length()),
+                md5: entry.metadata().content_md5().and_then(parse_md5),
+                modified: entry.metadata().last_modified(),
+            }))
+        })))
+    }
+}
+
+#[async_trait]
+impl LogSource for OpendalSource {
+    async fn list(&self, prefix: &str, sizes: bool) -> anyhow::Result<ObjectStream> {
+        self.list_from(prefix, sizes, None).await
+    }
+
+    async fn list_after(
+        &self,
+        prefix: &str,
+        sizes: bool,
+        start_after: &str,
+    ) -> anyhow::Result<Option<ObjectStream>> {
+        if !self.operator.info().full_capability().list_with_start_after {
+            return Ok(None);
+        }
+        self.list_from(prefix, sizes, Some(start_after))
+            .await
+            .map(Some)
+    }
+
+    async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let rd = self
+            .operator
+            .reader(name)
+            .await
+            .with_context(|| format!("failed to start read of object {}: ", name))?;
+        let data = rd
+            .read(0..)
+            .await
+            .with_context(|| format!("failed to read object contents {}: ", name))?;
+        Ok(data.to_vec())
+    }
+
+    async fn read_stream(&self, name: &str) -> anyhow::Result<ByteStream> {
+        self.stream_from(name, 0).await
+    }
+
+    async fn read_stream_from(
+        &self,
+        name: &str,
+        offset: u64,
+    ) -> anyhow::Result<Option<ByteStream>> {
+        self.stream_from(name, offset).await.map(Some)
+    }
+
+    async fn read_range(&self, name: &str, range: Range<u64>) -> anyhow::Result<Option<Bytes>> {
+        let rd = self
+            .operator
+            .reader(name)
+            .await
+            .with_context(|| format!("failed to start read of object {}: ", name))?;
+        let data = rd
+            .read(range.clone())
+            .await
+            .with_context(|| format!("failed to read bytes {range:?} of object {}: ", name))?;
+        Ok(Some(data.to_bytes()))
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        if self.operator.info().full_capability().copy {
+            return self
+                .operator
+                .copy(from, to)
+                .await
+                .with_context(|| format!("could not copy object {from} to {to}: "));
+        }
+        let data = self.read(from).await?;
+        self.write(to, data).await
+    }
+
+    async fn write(&self, name: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        self.operator
+            .write(name, data)
+            .await
+            .with_context(|| format!("could not write object {}: ", name))
+    }
+
+    async fn write_stream(&self, name: &str, mut data: ByteStream) -> anyhow::Result<()> {
+        let mut writer = self
+            .operator
+            .writer_with(name)
+            .chunk(WRITE_CHUNK_SIZE)
+            .await
+            .with_context(|| format!("failed to start write of object {}: ", name))?;
+        let written: anyhow::Result<()> = async {
+            while let Some(chunk) = data.next().await {
+                writer.write(chunk?).await?;
+            }
+            writer.close().await?;
+            Ok(())
+        }
+        .await;
+        if written.is_err() {
+            if let Err(e) = writer.abort().await {
+                tracing::warn!("could not abort write of object {name}: {e}");
+            }
+        }
+        written.with_context(|| format!("could not write object {}: ", name))
+    }
+
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        self.operator
+            .delete(name)
+            .await
+            .with_context(|| format!("could not delete object {}: ", name))
+    }
+
+    async fn generation(&self, name: &str) -> anyhow::Result<Option<String>> {
+        match &self.generations {
+            Some(generations) => generations.generation(name).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn restore(&self, name: &str, generation: &str) -> anyhow::Result<()> {
+        match &self.generations {
+            Some(generations) => generations.restore(name, generation).await,
+            None => bail!("cannot restore {name}: this source does not keep deleted objects"),
+        }
+    }
+}
+
+/// A LogSource that downloads a fixed list of URLs.
+///
+/// Objects are named by their URL, query string and all, less the parameters that sign
+/// pre-signed URLs: so signatures don't end up in logs or the database, and a URL signed again
+/// names the same object. The URLs can't be deleted; `delete` does nothing.
+pub struct UrlListSource {
+    client: reqwest::Client,
+    /// Object name and URL of each entry.
+    urls: Vec<(String, String)>,
+}
+
+impl UrlListSource {
+    pub fn new(urls: impl IntoIterator<Item = String>) -> Self {
+        UrlListSource {
+            client: reqwest::Client::new(),
+            urls: urls.into_iter().map(|url| (unsigned(&url), url)).collect(),
+        }
+    }
+
+    /// Read the URLs from a manifest: either a JSON array of strings, or one URL per line.
+    /// In the line-by-line form, blank lines and lines starting with `#` are skipped.
+    pub fn load(manifest: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(manifest)
+            .with_context(|| format!("could not read URL manifest {}", manifest.display()))?;
+        let urls: Vec<String> = if contents.trim_start().starts_with('[') {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("could not parse URL manifest {}", manifest.display()))?
+        } else {
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from)
+                .collect()
+        };
+        Ok(Self::new(urls))
+    }
+}
+
+/// Query parameters that sign a URL, besides the X-Goog- and X-Amz- ones: those of GCS and
+/// S3's older signatures, and CloudFront's.
+const SIGNING_PARAMS: &[&str] = &[
+    "GoogleAccessId",
+    "Expires",
+    "Signature",
+    "AWSAccessKeyId",
+    "Policy",
+    "Key-Pair-Id",
+];
+
+/// The URL without the parameters that sign it.
+fn unsigned(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let signing = |param: &str| {
+        let key = param.split_once('=').map_or(param, |(key, _)| key);
+        let key = key.to_ascii_lowercase();
+        key.starts_with("x-goog-")
+            || key.starts_with("x-amz-")
+            || SIGNING_PARAMS.iter().any(|p| p.eq_ignore_ascii_case(&key))
+    };
+    let query: Vec<_> = query.split('&').filter(|param| !signing(param)).collect();
+    if query.is_empty() {
+        base.to_string()
+    } else {
+        format!("{base}?{}", query.join("&"))
+    }
+}
+
+#[async_trait]
+impl LogSource for UrlListSource {
+    async fn list(&self, _prefix: &str, _sizes: bool) -> anyhow::Result<ObjectStream> {
+        let objects: Vec<_> = self
+            .urls
+            .iter()
+            .map(|(name, _)| {
+                Ok(ObjectInfo {
+                    name: name.clone(),
+                    size: None,
+                    md5: None,
+                    modified: None,
+                })
+            })
+            .collect();
+        Ok(Box::pin(tokio_stream::iter(objects)))
+    }
+
+    async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let (_, url) = self
+            .urls
+            .iter()
+            .find(|(n, _)| n == name)
+            .with_context(|| format!("no URL for object {name}"))?;
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("could not download {name}"))?;
+        let data = response
+            .bytes()
+            .await
+            .with_context(|| format!("could not read contents of {name}"))?;
+        Ok(data.to_vec())
+    }
+
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        tracing::debug!("not deleting {}: URL sources are read-only", name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceSpec, UrlListSource};
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            "my-logs".parse::<SourceSpec>().unwrap(),
+            SourceSpec::Gcs {
+                bucket: "my-logs".to_string(),
+                credentials: None,
+                scope: None,
+                endpoint: None,
+            }
+        );
+        assert_eq!(
+            "gs://my-logs?credentials=/etc/sa.json&endpoint=http://localhost:4443"
+                .parse::<SourceSpec>()
+                .unwrap(),
+            SourceSpec::Gcs {
+                bucket: "my-logs".to_string(),
+                credentials: Some("/etc/sa.json".into()),
+                scope: None,
+                endpoint: Some("http://localhost:4443".to_string()),
+            }
+        );
+        assert_eq!(
+            "s3://my-logs?region=us-east-2"
+                .parse::<SourceSpec>()
+                .unwrap(),
+            SourceSpec::S3 {
+                bucket: "my-logs".to_string(),
+                region: Some("us-east-2".to_string()),
+                endpoint: None,
+                virtual_host_style: false,
+                access_key_id: None,
+                secret_key_file: None,
+            }
+        );
+        assert_eq!(
+            "s3://my-logs?endpoint=http://localhost:9000&access_key_id=minio\
+            &secret_key_file=/etc/minio_secret"
+                .parse::<SourceSpec>()
+                .unwrap(),
+            SourceSpec::S3 {
+                bucket: "my-logs".to_string(),
+                region: None,
+                endpoint: Some("http://localhost:9000".to_string()),
+                virtual_host_style: false,
+                access_key_id: Some("minio".to_string()),
+                secret_key_file: Some("/etc/minio_secret".into()),
+            }
+        );
+        // Values can be percent-encoded.
+        assert_eq!(
+            "sftp://logs.example.com?key=/home/me/my%20key%26more"
+                .parse::<SourceSpec>()
+                .unwrap(),
+            SourceSpec::Sftp {
+                host: "logs.example.com".to_string(),
+                user: None,
+                root: String::new(),
+                key: Some("/home/me/my key&more".into()),
+                known_hosts: None,
+            }
+        );
+        assert!("s3://my-logs?virtual_host_style=maybe"
+            .parse::<SourceSpec>()
+            .is_err());
+        assert_eq!(
+            "sftp://fastly@logs.example.com:2222/srv/logs?key=/etc/fastly_key"
+                .parse::<SourceSpec>()
+                .unwrap(),
+            SourceSpec::Sftp {
+                host: "logs.example.com:2222".to_string(),
+                user: Some("fastly".to_string()),
+                root: "/srv/logs".to_string(),
+                key: Some("/etc/fastly_key".into()),
+                known_hosts: None,
+            }
+        );
+        assert!("s3://my-logs?colour=blue".parse::<SourceSpec>().is_err());
+        assert!("ftp://my-logs".parse::<SourceSpec>().is_err());
+        assert_eq!("-".parse::<SourceSpec>().unwrap(), SourceSpec::Stdin);
+        assert_eq!(
+            "journald://nginx.service?identifier=nginx_access"
+                .parse::<SourceSpec>()
+                .unwrap(),
+            SourceSpec::Journald {
+                unit: "nginx.service".to_string(),
+                identifier: Some("nginx_access".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn labels_specs_without_credentials() {
+        for (spec, label) in [
+            ("my-logs", "gs://my-logs"),
+            ("s3://my-logs?region=us-east-2", "s3://my-logs"),
+            (
+                "s3://my-logs?endpoint=https://minio.example.com&access_key_id=minio",
+                "s3://my-logs?endpoint=https://minio.example.com",
+            ),
+            (
+                "sftp://fastly@logs.example.com/srv/logs?key=/etc/fastly_key",
+                "sftp://fastly@logs.example.com/srv/logs",
+            ),
+            ("file:///var/log/fastly", "file:///var/log/fastly"),
+        ] {
+            assert_eq!(spec.parse::<SourceSpec>().unwrap().to_string(), label);
+        }
+    }
+
+    #[test]
+    fn names_urls_without_signatures() {
+        let source = UrlListSource::new([
+            "https://example.com/a.log.gz?X-Goog-Signature=abc".to_string(),
+            "https://example.com/logs?day=1&X-Amz-Date=20240601&X-Amz-Signature=abc".to_string(),
+            "https://example.com/logs?day=2&Expires=1717200000&Signature=abc".to_string(),
+        ]);
+        let names: Vec<_> = source.urls.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "https://example.com/a.log.gz",
+                "https://example.com/logs?day=1",
+                "https://example.com/logs?day=2"
+            ]
+        );
+    }
+}
@@ -11,7 +11,11 @@ use regex_lite::Regex;
 
 pub struct CommaHacker<I> {
     input: I,
+    /// The line being read, and how much of it has been.
     buffer: Vec<u8>,
+    read: usize,
+    /// How many lines have been started.
+    lines: usize,
 }
 
 static TAILMATCH: OnceLock<Regex> = OnceLock::new();
@@ -20,8 +24,29 @@ impl<I> CommaHacker<I> {
         CommaHacker {
             input,
             buffer: Vec::new(),
+            read: 0,
+            lines: 0,
         }
     }
+
+    /// The number of the line the next byte is from, counting from 1.
+    pub fn next_line(&self) -> usize {
+        if self.read < self.buffer.len() {
+            self.lines
+        } else {
+            self.lines + 1
+        }
+    }
+
+    /// Go on from the line after `line`, as after an error in it: skipping the rest of it, or
+    /// going back to the start of the next, if that's been started.
+    pub fn skip_past(&mut self, line: usize) {
+        self.read = if self.lines > line {
+            0
+        } else {
+            self.buffer.len()
+        };
+    }
 }
 
 impl<I> CommaHacker<I>
@@ -29,9 +54,10 @@ where
     I: std::io::Read,
 {
     fn read_from_buffer(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let len = std::cmp::min(self.buffer.len(), buf.len());
-        buf[0..len].copy_from_slice(&self.buffer[0..len]);
-        self.buffer = Vec::from(&self.buffer[len..]);
+        let rest = &self.buffer[self.read..];
+        let len = std::cmp::min(rest.len(), buf.len());
+        buf[0..len].copy_from_slice(&rest[0..len]);
+        self.read += len;
         Ok(len)
     }
 }
@@ -41,14 +67,17 @@ where
     I: std::io::BufRead,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if !self.buffer.is_empty() {
+        if self.read < self.buffer.len() {
             return self.read_from_buffer(buf);
         }
         let mut s = String::new();
-        self.input.read_line(&mut s)?;
+        if self.input.read_line(&mut s)? > 0 {
+            self.lines += 1;
+        }
 
         let re = TAILMATCH.get_or_init(|| Regex::new(r#",\s*}\s*$"#).unwrap());
         self.buffer = re.replace(&s, "}").as_bytes().into();
+        self.read = 0;
         self.read_from_buffer(buf)
     }
 }
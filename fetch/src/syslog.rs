@@ -0,0 +1,276 @@
+//! A syslog receiver, for Fastly's syslog logging endpoints: entries arrive over a TCP (or TLS)
+//! connection as they're logged, rather than in objects in a bucket.
+//!
+//! Messages are one per line, as Fastly sends them with the "classic" or "blank" message
+//! types. The syslog header, if any, is dropped: the entry is the JSON object in the message.
+//! Connections are long-lived, so messages are handed over in batches, whenever a batch fills
+//! up or the connection has been quiet for a moment.
+//!
+//! Syslog has no authentication, so connections are only accepted from allowed addresses:
+//! loopback, unless others are listed (e.g. Fastly's, from its public IP list).
+
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    net::{IpAddr, TcpListener, TcpStream},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use native_tls::{Identity, TlsAcceptor};
+
+/// How long a message waits for others to be stored with it.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A listener for syslog connections.
+pub(crate) struct Receiver {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    /// Where connections are accepted from; if empty, only loopback.
+    allowed: Vec<Network>,
+}
+
+impl Receiver {
+    /// Listen on `addr`, or on loopback if it's only a port; with TLS, if given a certificate
+    /// (chain) and its PKCS #8 key, in PEM. Connections are accepted from the `allowed`
+    /// addresses or networks (e.g. "151.101.0.0/16"), or if there are none, only from loopback.
+    pub(crate) fn bind(
+        addr: &str,
+        tls: Option<(&Path, &Path)>,
+        allowed: &[String],
+    ) -> anyhow::Result<Self> {
+        let allowed = allowed
+            .iter()
+            .map(|network| network.parse())
+            .collect::<anyhow::Result<_>>()?;
+        let tls = tls
+            .map(|(cert, key)| {
+                let cert = std::fs::read(cert)
+                    .with_context(|| format!("could not read {}", cert.display()))?;
+                let key = std::fs::read(key)
+                    .with_context(|| format!("could not read {}", key.display()))?;
+                let identity = Identity::from_pkcs8(&cert, &key)
+                    .context("could not load TLS certificate and key")?;
+                TlsAcceptor::new(identity).context("could not set up TLS")
+            })
+            .transpose()?;
+        let addr = match addr.parse::<u16>() {
+            Ok(port) => format!("127.0.0.1:{port}"),
+            Err(_) => addr.to_string(),
+        };
+        let listener =
+            TcpListener::bind(&addr).with_context(|| format!("could not listen on {addr}"))?;
+        Ok(Receiver {
+            listener,
+            tls,
+            allowed,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub(crate) fn incoming(&self) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+        self.listener.incoming()
+    }
+
+    /// Whether connections from `peer` are accepted.
+    pub(crate) fn allows(&self, peer: IpAddr) -> bool {
+        let peer = peer.to_canonical();
+        if self.allowed.is_empty() {
+            peer.is_loopback()
+        } else {
+            self.allowed.iter().any(|network| network.contains(peer))
+        }
+    }
+
+    /// Start reading messages from a connection, after the TLS handshake if it's TLS.
+    pub(crate) fn accept(&self, conn: TcpStream) -> anyhow::Result<Messages<Box<dyn Read>>> {
+        let conn: Box<dyn Read> = match &self.tls {
+            Some(tls) => {
+                let conn = tls
+                    .accept(conn)
+                    .map_err(|e| anyhow!("TLS handshake failed: {e}"))?;
+                // Reads time out to flush batches, so only once the handshake is done.
+                conn.get_ref()
+                    .set_read_timeout(Some(FLUSH_INTERVAL))
+                    .context("could not set read timeout")?;
+                Box::new(conn)
+            }
+            None => {
+                conn.set_read_timeout(Some(FLUSH_INTERVAL))
+                    .context("could not set read timeout")?;
+                Box::new(conn)
+            }
+        };
+        Ok(Messages::new(conn))
+    }
+}
+
+/// Messages from a connection.
+pub(crate) struct Messages<R> {
+    reader: BufReader<R>,
+    /// The line being read; kept across reads that time out partway through it.
+    line: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Messages<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Messages {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// The next batch of at most `max` messages, once it's full, or it's waited long enough
+    /// for more; None once the connection is closed.
+    pub(crate) fn next_batch(&mut self, max: usize) -> io::Result<Option<Vec<String>>> {
+        let mut batch = Vec::new();
+        let mut started = None;
+        while !self.done && batch.len() < max {
+            if started.is_some_and(|started: Instant| started.elapsed() >= FLUSH_INTERVAL) {
+                break;
+            }
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => self.done = true,
+                Ok(_) if self.line.ends_with(b"\n") => (),
+                Ok(_) => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+            let line = std::mem::take(&mut self.line);
+            if let Some(message) = message(&String::from_utf8_lossy(&line)) {
+                batch.push(message.to_string());
+                started.get_or_insert_with(Instant::now);
+            }
+        }
+        Ok((!batch.is_empty() || !self.done).then_some(batch))
+    }
+}
+
+/// An address, or a network of them in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn contains(&self, addr: IpAddr) -> bool {
+        let bits = |addr| match addr {
+            IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+            IpAddr::V6(addr) => (u128::from(addr), 128),
+        };
+        let ((network, len), (addr, addr_len)) = (bits(self.addr), bits(addr));
+        let shift = len - u32::from(self.prefix);
+        len == addr_len
+            && network.checked_shr(shift).unwrap_or(0) == addr.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address {addr:?} in {s:?}"))?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .with_context(|| format!("invalid prefix length in {s:?}"))?,
+        };
+        if prefix > max {
+            bail!("prefix length {prefix} is too long for {addr}");
+        }
+        Ok(Network { addr, prefix })
+    }
+}
+
+/// The entry in a syslog message: the JSON object after the header, if any.
+pub(crate) fn message(line: &str) -> Option<&str> {
+    line.find('{').map(|start| line[start..].trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message, Messages, Network, Receiver};
+
+    #[test]
+    fn strips_headers() {
+        assert_eq!(
+            message("<134>2024-06-01T00:00:00Z cache-sjc1 my-service[123]: {\"a\": 1}\r\n"),
+            Some("{\"a\": 1}")
+        );
+        assert_eq!(message("{\"a\": 1}\n"), Some("{\"a\": 1}"));
+        assert_eq!(
+            message("<134>2024-06-01T00:00:00Z cache-sjc1 hello\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn batches_messages() {
+        let input = "<134>x y z: {\"a\": 1}\nnot json\n{\"a\": 2}\n{\"a\": 3}\n{\"a\": 4}";
+        let mut messages = Messages::new(input.as_bytes());
+        assert_eq!(
+            messages.next_batch(2).unwrap().unwrap(),
+            vec!["{\"a\": 1}", "{\"a\": 2}"]
+        );
+        // The last message is complete at the end of the stream, without a newline.
+        assert_eq!(
+            messages.next_batch(2).unwrap().unwrap(),
+            vec!["{\"a\": 3}", "{\"a\": 4}"]
+        );
+        assert_eq!(messages.next_batch(2).unwrap(), None);
+    }
+
+    #[test]
+    fn allows_loopback_by_default() {
+        let receiver = Receiver::bind("0", None, &[]).unwrap();
+        assert!(receiver.local_addr().unwrap().ip().is_loopback());
+        assert!(receiver.allows("127.0.0.1".parse().unwrap()));
+        assert!(receiver.allows("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!receiver.allows("151.101.2.3".parse().unwrap()));
+
+        let allowed = ["151.101.0.0/16".to_string()];
+        let receiver = Receiver::bind("127.0.0.1:0", None, &allowed).unwrap();
+        assert!(receiver.allows("151.101.2.3".parse().unwrap()));
+        assert!(!receiver.allows("127.0.0.1".parse().unwrap()));
+        assert!(Receiver::bind("0", None, &["nowhere".to_string()]).is_err());
+    }
+
+    #[test]
+    fn matches_networks() {
+        let network: Network = "151.101.0.0/16".parse().unwrap();
+        assert!(network.contains("151.101.2.3".parse().unwrap()));
+        assert!(!network.contains("151.102.2.3".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+        let network: Network = "2a04:4e40::/32".parse().unwrap();
+        assert!(network.contains("2a04:4e40:1::5".parse().unwrap()));
+        assert!(!network.contains("2a04:4e41::5".parse().unwrap()));
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+        let one: Network = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(one, "10.0.0.1/32".parse().unwrap());
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Query results, and rendering them for people.
+
+use anyhow::Context;
+use rusqlite::{types::ValueRef, Connection};
+use serde_json::Value;
+
+/// The results of a query.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    /// Run a query, collecting its results.
+    pub fn query(conn: &Connection, sql: &str) -> anyhow::Result<Self> {
+        let mut stmt = conn.prepare(sql).context("invalid query")?;
+        let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let mut rows = Vec::new();
+        let mut results = stmt.query([]).context("could not run query")?;
+        while let Some(row) = results.next().context("could not read query results")? {
+            let values = (0..columns.len())
+                .map(|i| {
+                    Ok(match row.get_ref(i)? {
+                        ValueRef::Null => Value::Null,
+                        ValueRef::Integer(i) => Value::from(i),
+                        ValueRef::Real(f) => Value::from(f),
+                        ValueRef::Text(t) => Value::from(String::from_utf8_lossy(t)),
+                        ValueRef::Blob(b) => Value::from(format!("<{} bytes>", b.len())),
+                    })
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows.push(values);
+        }
+        Ok(Table { columns, rows })
+    }
+
+    /// Render as aligned columns, like `sqlite3 -header -column`.
+    pub fn to_text(&self) -> String {
+        let cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(display).collect())
+            .collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                cells
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain([c.chars().count()])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let line = |values: &[String]| {
+            values
+                .iter()
+                .zip(&widths)
+                .map(|(v, w)| format!("{v:w$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+        let mut out = vec![
+            line(&self.columns),
+            line(&widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>()),
+        ];
+        out.extend(cells.iter().map(|row| line(row)));
+        out.join("\n") + "\n"
+    }
+
+    /// Render as an HTML page: the results as a table, after a chart of them if they're the
+    /// kind that can be charted (and this was built with the `charts` feature).
+    pub fn to_html(&self, title: &str) -> anyhow::Result<String> {
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+            <body>\n<h1>{0}</h1>\n",
+            escape(title)
+        );
+        #[cfg(feature = "charts")]
+        if let Some(svg) = crate::chart::render_svg(self)? {
+            out += &svg;
+            out += "\n";
+        }
+        out += "<table>\n<tr>";
+        for column in &self.columns {
+            out += &format!("<th>{}</th>", escape(column));
+        }
+        out += "</tr>\n";
+        for row in &self.rows {
+            out += "<tr>";
+            for value in row {
+                out += &format!("<td>{}</td>", escape(&display(value)));
+            }
+            out += "</tr>\n";
+        }
+        out += "</table>\n</body>\n</html>\n";
+        Ok(out)
+    }
+
+    /// Render with a Jinja template.
+    ///
+    /// The template sees `columns` (a list of names) and `rows` (a list of maps by column name).
+    pub fn render(&self, template: &str) -> anyhow::Result<String> {
+        let rows: Vec<serde_json::Map<String, Value>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().cloned())
+                    .collect()
+            })
+            .collect();
+        let env = minijinja::Environment::new();
+        env.render_str(
+            template,
+            minijinja::context! { columns => &self.columns, rows => rows },
+        )
+        .context("could not render template")
+    }
+}
+
+/// Display a value without JSON quoting.
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Escape text for HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::Table;
+
+    fn table() -> Table {
+        let conn = Connection::open_in_memory().unwrap();
+        Table::query(
+            &conn,
+            "SELECT '/' AS path, 10 AS count UNION ALL SELECT '/about/', 2",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_text() {
+        assert_eq!(
+            table().to_text(),
+            "path     count\n-------  -----\n/        10\n/about/  2\n"
+        );
+    }
+
+    #[test]
+    fn renders_template() {
+        let out = table()
+            .render("{% for row in rows %}{{ row.path }}={{ row.count }};{% endfor %}")
+            .unwrap();
+        assert_eq!(out, "/=10;/about/=2;");
+    }
+}
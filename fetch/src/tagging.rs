@@ -0,0 +1,312 @@
+//! Tagging rules, from the `[[tag]]` sections of the config file.
+//!
+//! Each rule assigns a tag to the requests that match all of its conditions, e.g.
+//!
+//! ```toml
+//! [[tag]]
+//! tag = "rss-readers"
+//! path = '\.xml$'
+//! user_agent = '(?i)feed|rss|reader'
+//!
+//! [[tag]]
+//! tag = "image-hotlink"
+//! path = '\.(png|jpe?g|gif|webp)$'
+//! referer = '!^(|https?://(www\.)?example\.com/.*)$'
+//! ```
+//!
+//! Tags end up in `request_tags`, alongside those from scripts.
+
+use std::{fmt, path::Path};
+
+use anyhow::{bail, Context};
+use regex_lite::Regex;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    cruncher::Cruncher,
+    dimensions::{Changed, Limits},
+    record::LogEntry,
+};
+
+/// A regex, or with a leading `!`, a regex that must not match.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+    negated: bool,
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = regex_lite::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (negated, pattern) = match s.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, s.as_str()),
+        };
+        Ok(Pattern {
+            regex: Regex::new(pattern)?,
+            negated,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Pattern::try_from(s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Pattern {
+    fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value) != self.negated
+    }
+}
+
+/// A rule assigning a tag; a request gets the tag if it meets every condition given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagRule {
+    pub tag: String,
+    pub path: Option<Pattern>,
+    pub user_agent: Option<Pattern>,
+    pub referer: Option<Pattern>,
+    /// Any of these autonomous system numbers.
+    #[serde(default)]
+    pub asn: Vec<u32>,
+    /// Any of these statuses: exact, like "404", or a class, like "5xx".
+    #[serde(default)]
+    pub status: Vec<String>,
+}
+
+impl TagRule {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        self.matches_request(&Request {
+            path: &entry.url_path,
+            user_agent: entry.user_agent.as_deref(),
+            referer: entry.referer.as_deref(),
+            asn: entry.asn,
+            status: entry.response_status,
+        })
+    }
+
+    /// Whether the rule looks at any of the values that changed.
+    pub(crate) fn looks_at(&self, changed: &Changed) -> bool {
+        (self.path.is_some() && changed.path)
+            || (self.user_agent.is_some() && changed.user_agent)
+            || (self.referer.is_some() && changed.referer)
+    }
+
+    pub(crate) fn matches_request(&self, request: &Request) -> bool {
+        let pattern = |pattern: &Option<Pattern>, value: &str| {
+            pattern.as_ref().is_none_or(|p| p.matches(value))
+        };
+        // Without a user agent or referer, they're matched as empty.
+        pattern(&self.path, request.path)
+            && pattern(&self.user_agent, request.user_agent.unwrap_or_default())
+            && pattern(&self.referer, request.referer.unwrap_or_default())
+            && (self.asn.is_empty() || request.asn.is_some_and(|asn| self.asn.contains(&asn)))
+            && (self.status.is_empty()
+                || self
+                    .status
+                    .iter()
+                    .any(|status| status_matches(status, request.status)))
+    }
+}
+
+/// What rules look at in a request, as stored or as logged.
+pub(crate) struct Request<'a> {
+    pub path: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub asn: Option<u32>,
+    pub status: usize,
+}
+
+fn status_matches(pattern: &str, status: usize) -> bool {
+    match pattern.strip_suffix("xx") {
+        Some(class) => class == (status / 100).to_string(),
+        None => pattern == status.to_string(),
+    }
+}
+
+/// Add the tags of every rule each entry matches.
+pub fn apply(rules: &[TagRule], entries: &mut [LogEntry]) {
+    for entry in entries {
+        for rule in rules {
+            if rule.matches(entry) && !entry.tags.contains(&rule.tag) {
+                entry.tags.push(rule.tag.clone());
+            }
+        }
+    }
+}
+
+/// How far reclassifying has got.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reclassified {
+    /// Requests looked at so far.
+    pub requests: usize,
+    /// Requests stored, in all.
+    pub total: usize,
+    /// Tags given to requests that now match a rule.
+    pub added: usize,
+    /// Tags taken from requests that no longer match any rule with that tag.
+    pub removed: usize,
+    /// Requests with tags left as they were, as rules for them look at values that were
+    /// rewritten or capped by `[dimensions]` when stored: the rules saw other values then.
+    pub kept: usize,
+}
+
+impl fmt::Display for Reclassified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} requests; {} tags added, {} removed; {} requests kept their tags",
+            self.requests, self.total, self.added, self.removed, self.kept
+        )
+    }
+}
+
+/// Apply the config's tagging rules to the requests already in the database, after the rules
+/// have changed: `batch` requests per transaction, oldest first, calling `progress` after each.
+///
+/// Requests that match a rule now get its tag, and those that don't lose it. Tags that no rule
+/// gives (from scripts, or from rules since removed) are left alone; so are tags from rules
+/// that look at values `[dimensions]` rewrote or capped, unless another rule gives them.
+pub fn reclassify(
+    db: &Path,
+    config: &Config,
+    batch: usize,
+    mut progress: impl FnMut(&Reclassified),
+) -> anyhow::Result<Reclassified> {
+    if batch == 0 {
+        bail!("batches need at least one request");
+    }
+    let limits = Limits::new(&config.dimensions).context("invalid dimension limits")?;
+    let cruncher = Cruncher::open(db, &config.sqlite)?.with_limits(limits);
+    let mut reclassified = Reclassified {
+        total: cruncher.count_requests()?,
+        ..Reclassified::default()
+    };
+    let mut after = 0;
+    while let Some(done) = cruncher.reclassify_batch(&config.tags, after, batch)? {
+        after = done.last;
+        reclassified.requests += done.requests;
+        reclassified.added += done.added;
+        reclassified.removed += done.removed;
+        reclassified.kept += done.kept;
+        progress(&reclassified);
+    }
+    Ok(reclassified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, reclassify, TagRule};
+    use crate::{cruncher::Cruncher, dimensions::Limits, testdata::entry, Config, Sqlite};
+
+    #[test]
+    fn tags_matching_entries() {
+        #[derive(serde::Deserialize)]
+        struct Rules {
+            tag: Vec<TagRule>,
+        }
+        let rules: Rules = toml::from_str(
+            r#"
+            [[tag]]
+            tag = "curl"
+            user_agent = '^curl/'
+            status = ["2xx"]
+
+            [[tag]]
+            tag = "external"
+            referer = '!^$'
+
+            [[tag]]
+            tag = "other-network"
+            asn = [13335]
+            "#,
+        )
+        .unwrap();
+        let mut entries = vec![entry(), entry()];
+        entries[1].referer = Some("https://example.com/".to_string());
+        apply(&rules.tag, &mut entries);
+        assert_eq!(entries[0].tags, vec!["curl"]);
+        assert_eq!(entries[1].tags, vec!["curl", "external"]);
+    }
+
+    #[test]
+    fn reclassifies_stored_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("logs.db");
+        let cruncher = Cruncher::open(&db, &Sqlite::default()).unwrap();
+        let mut tagged = entry();
+        tagged.tags.push("old".to_string());
+        let mut bot = entry();
+        bot.user_agent = Some("Googlebot/2.1".to_string());
+        cruncher.crunch(&[tagged, entry(), bot]).unwrap();
+        drop(cruncher);
+
+        let config: Config = toml::from_str(
+            r#"
+            [[tag]]
+            tag = "bot"
+            user_agent = '(?i)bot'
+
+            [[tag]]
+            tag = "old"
+            status = ["5xx"]
+            "#,
+        )
+        .unwrap();
+        let mut batches = 0;
+        let done = reclassify(&db, &config, 2, |_| batches += 1).unwrap();
+        assert_eq!(batches, 2);
+        assert_eq!((done.requests, done.total), (3, 3));
+        assert_eq!((done.added, done.removed), (1, 1));
+        // Again, there's nothing to change.
+        let again = reclassify(&db, &config, 100, |_| ()).unwrap();
+        assert_eq!((again.added, again.removed), (0, 0));
+    }
+
+    #[test]
+    fn keeps_tags_on_capped_and_rewritten_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("logs.db");
+        let config: Config = toml::from_str(
+            r#"
+            [dimensions.user_agents]
+            max_values = 1
+
+            [dimensions.paths]
+            normalize = [{ pattern = "^/item/[0-9]+$", replace = "/item/:id" }]
+
+            [[tag]]
+            tag = "bot"
+            user_agent = '(?i)bot'
+
+            [[tag]]
+            tag = "item-7"
+            path = '^/item/7$'
+            "#,
+        )
+        .unwrap();
+        let cruncher = Cruncher::open(&db, &Sqlite::default())
+            .unwrap()
+            .with_limits(Limits::new(&config.dimensions).unwrap());
+        let mut bot = entry();
+        bot.user_agent = Some("Googlebot/2.1".to_string());
+        let mut item = entry();
+        item.url_path = "/item/7".to_string();
+        let mut entries = vec![entry(), bot, item];
+        apply(&config.tags, &mut entries);
+        cruncher.crunch(&entries).unwrap();
+        drop(cruncher);
+
+        // The bot's user agent is stored as OVERFLOW, and the item's path as /item/:id; neither
+        // matches its rule now, but both did when stored.
+        let done = reclassify(&db, &config, 100, |_| ()).unwrap();
+        assert_eq!((done.added, done.removed, done.kept), (0, 0, 2));
+    }
+}
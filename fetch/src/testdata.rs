@@ -0,0 +1,56 @@
+//! Synthetic log data for tests.
+
+use std::io::Write;
+
+use crate::{record::LogEntry, streamhack::CommaHacker};
+
+/// A log entry in my Fastly format, trailing comma and all.
+pub const ENTRY: &str = concat!(
+    r#"{"clientIP": "192.0.2.1", "ispID": "64496", "countryCode": "US", "requests": "1", "#,
+    r#""isIPv6": "0", "isH2": "1", "urlPath": "/", "httpReferer": "", "httpUA": "curl/8.0", "#,
+    r#""cacheState": "HIT", "respStatus": "200", "respTotalBytes": "1024", "#,
+    r#""timeElapsed": "1500", "#,
+    r#""reqStartTime": 1719792000, }"#
+);
+
+/// A gzipped log object with `n` copies of ENTRY.
+///
+/// Uncompressed, so offsets in the output track offsets in the input.
+pub fn gzipped(n: usize) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::none());
+    for _ in 0..n {
+        writeln!(encoder, "{ENTRY}").unwrap();
+    }
+    encoder.finish().unwrap()
+}
+
+/// A zstd-compressed log object with `n` copies of ENTRY.
+pub fn zstd_compressed(n: usize) -> Vec<u8> {
+    zstd::encode_all(format!("{ENTRY}\n").repeat(n).as_bytes(), 1).unwrap()
+}
+
+/// A bzip2-compressed log object with `n` copies of ENTRY.
+pub fn bzip2_compressed(n: usize) -> Vec<u8> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+    for _ in 0..n {
+        writeln!(encoder, "{ENTRY}").unwrap();
+    }
+    encoder.finish().unwrap()
+}
+
+/// A tar archive of the given files.
+pub fn tarred(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, name, *data).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+/// ENTRY, parsed.
+pub fn entry() -> LogEntry {
+    let reader = CommaHacker::new(std::io::BufReader::new(std::io::Cursor::new(ENTRY)));
+    serde_json::from_reader(reader).unwrap()
+}
@@ -0,0 +1,108 @@
+//! Putting back the objects a run's cleanup deleted, from stores that keep deleted objects for
+//! a while (versioned GCS buckets): a way back if a run turns out to have lost logs.
+//!
+//! Cleanup records the generation of each object it deletes, where the store has them. Within
+//! the bucket's retention window, those generations can be restored. The next run crunches the
+//! restored objects again, so restore them for a database that doesn't have their entries:
+//! one from before the run, or a new one.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, bail, Context};
+use tokio::runtime::Runtime;
+
+use crate::{
+    config::Sqlite,
+    cruncher::Cruncher,
+    source::{LogSource, OpendalSource, SourceSpec},
+};
+
+/// What was put back.
+#[derive(Debug, Default)]
+pub struct Undeleted {
+    pub restored: usize,
+    pub failed: usize,
+}
+
+/// Restore the objects run `run_id` deleted, that haven't been already.
+///
+/// Objects are restored to the sources the run recorded, or to those in `sources` with the
+/// same labels, e.g. to give credentials the labels leave out.
+pub fn undelete(
+    rt: &Runtime,
+    db: &Path,
+    run_id: i64,
+    sources: &[SourceSpec],
+) -> anyhow::Result<Undeleted> {
+    let cruncher = Cruncher::open(db, &Sqlite::default())?;
+    let deleted = cruncher.deleted_objects(run_id)?;
+    if deleted.is_empty() {
+        bail!("run {run_id} has no deleted objects left to restore");
+    }
+    let mut stores = HashMap::new();
+    let mut undeleted = Undeleted::default();
+    rt.block_on(async {
+        for object in &deleted {
+            let store = stores
+                .entry(object.source.clone())
+                .or_insert_with(|| open_store(object.source.as_deref(), sources));
+            let result = match store {
+                Ok(store) => store.restore(&object.name, &object.generation).await,
+                Err(e) => Err(anyhow!("{e:#}")),
+            };
+            match result {
+                Ok(()) => {
+                    cruncher.record_restored(run_id, object)?;
+                    tracing::info!("restored {}", object.name);
+                    undeleted.restored += 1;
+                }
+                Err(e) => {
+                    tracing::error!("could not restore {}: {e:#}", object.name);
+                    undeleted.failed += 1;
+                }
+            }
+        }
+        Ok(undeleted)
+    })
+}
+
+/// Open the store that the run labeled `label`.
+fn open_store(label: Option<&str>, sources: &[SourceSpec]) -> anyhow::Result<OpendalSource> {
+    let spec = match (label, sources) {
+        (Some(label), _) => match sources.iter().find(|spec| spec.to_string() == label) {
+            Some(spec) => spec.clone(),
+            None => label
+                .parse()
+                .with_context(|| format!("could not parse source {label}"))?,
+        },
+        (None, [spec]) => spec.clone(),
+        (None, _) => bail!("the run didn't record the source; give the one source to restore to"),
+    };
+    OpendalSource::open(&spec).with_context(|| format!("could not open {spec}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cruncher::Cruncher, Sqlite};
+
+    #[test]
+    fn lists_deletions_until_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("undelete.db");
+        let cruncher = Cruncher::open(&path, &Sqlite::default()).unwrap();
+        let run_id = cruncher.start_run().unwrap();
+        for name in ["a.log.gz", "b.log.gz"] {
+            cruncher
+                .record_deletion(run_id, Some("gs://logs"), name, "1717245000000000")
+                .unwrap();
+        }
+        let deleted = cruncher.deleted_objects(run_id).unwrap();
+        assert_eq!(deleted.len(), 2);
+        cruncher.record_restored(run_id, &deleted[0]).unwrap();
+        let deleted = cruncher.deleted_objects(run_id).unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].name, "b.log.gz");
+        assert!(cruncher.deleted_objects(run_id + 1).unwrap().is_empty());
+        drop(cruncher);
+    }
+}
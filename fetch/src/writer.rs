@@ -0,0 +1,96 @@
+//! A single writer for the database, serving other processes over a Unix socket.
+//!
+//! SQLite allows one writer at a time; overlapping runs (say, a scheduled fetch and an ad-hoc
+//! stream from stdin) fight over the lock. Instead, one long-lived process can own the database
+//! and accept streams of entries from the others.
+//!
+//! The protocol is as simple as it gets: a client connects, writes a stream of entries (the same
+//! as it would on stdin, gzipped or not), and shuts down its side of the connection. The writer
+//! crunches the stream, then replies with a line: `ok <count>` or `error <message>`.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+
+/// Listen on the socket, replacing a stale socket file from a writer that has exited.
+pub(crate) fn listen(socket: &Path) -> anyhow::Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("another writer is already serving on {}", socket.display());
+        }
+        std::fs::remove_file(socket)
+            .with_context(|| format!("could not remove stale socket {}", socket.display()))?;
+    }
+    UnixListener::bind(socket).with_context(|| format!("could not listen on {}", socket.display()))
+}
+
+/// Reply to a client with the result of crunching its stream.
+pub(crate) fn reply(
+    mut conn: &UnixStream,
+    result: Result<usize, &anyhow::Error>,
+) -> io::Result<()> {
+    match result {
+        Ok(count) => writeln!(conn, "ok {count}"),
+        // Keep the reply to one line.
+        Err(e) => writeln!(conn, "error {}", format!("{e:#}").replace('\n', " ")),
+    }
+}
+
+/// Send a stream of entries to the writer serving on the socket,
+/// returning how many entries it stored.
+pub fn send(socket: &Path, mut input: impl io::Read) -> anyhow::Result<usize> {
+    let mut conn = UnixStream::connect(socket)
+        .with_context(|| format!("could not connect to writer at {}", socket.display()))?;
+    io::copy(&mut input, &mut conn).context("could not send entries to writer")?;
+    conn.shutdown(std::net::Shutdown::Write)
+        .context("could not finish sending entries to writer")?;
+    let mut response = String::new();
+    BufReader::new(&conn)
+        .read_line(&mut response)
+        .context("could not read response from writer")?;
+    match response.trim_end().split_once(' ') {
+        Some(("ok", count)) => count
+            .parse()
+            .with_context(|| format!("bad response from writer: {response:?}")),
+        Some(("error", message)) => Err(anyhow!("writer failed: {message}")),
+        _ => Err(anyhow!("bad response from writer: {response:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{listen, reply, send};
+
+    #[test]
+    fn sends_stream_and_reads_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("writer.sock");
+        let listener = listen(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            for error in [false, true] {
+                let (conn, _) = listener.accept().unwrap();
+                let mut received = String::new();
+                (&conn).read_to_string(&mut received).unwrap();
+                let result = if error {
+                    Err(anyhow::anyhow!("bad\nentry"))
+                } else {
+                    Ok(received.lines().count())
+                };
+                reply(&conn, result.as_ref().copied()).unwrap();
+            }
+        });
+        assert_eq!(send(&socket, "{}\n{}\n".as_bytes()).unwrap(), 2);
+        let err = send(&socket, "{}\n".as_bytes()).unwrap_err();
+        assert_eq!(format!("{err:#}"), "writer failed: bad entry");
+        server.join().unwrap();
+        // The first writer has exited, leaving its socket file behind; the next one takes over.
+        assert!(socket.exists());
+        drop(listen(&socket).unwrap());
+    }
+}
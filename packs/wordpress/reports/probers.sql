@@ -0,0 +1,12 @@
+.print 'Top WordPress-probing ASNs, last week:'
+SELECT
+    wordpress_probes.asn AS asn
+,   autonomous_systems.name AS asn_name
+,   COUNT(*) AS probes
+,   COUNT(DISTINCT wordpress_probes.client_ip) AS clients
+FROM wordpress_probes
+    LEFT JOIN autonomous_systems ON wordpress_probes.asn = autonomous_systems.asn
+WHERE wordpress_probes.time > datetime('now', '-7 days')
+GROUP BY wordpress_probes.asn
+ORDER BY probes DESC
+LIMIT 20;
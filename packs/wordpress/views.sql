@@ -0,0 +1,14 @@
+-- Requests probing for WordPress, which this site doesn't run.
+CREATE VIEW IF NOT EXISTS wordpress_probes AS
+SELECT
+    requests.id AS request
+,   requests.client_ip AS client_ip
+,   requests.asn AS asn
+,   requests.request_start_time AS time
+,   paths.path AS url_path
+FROM requests
+    JOIN paths ON requests.url_path = paths.id
+WHERE paths.path LIKE '/wp-%'
+   OR paths.path LIKE '%/wp-login.php'
+   OR paths.path LIKE '%/xmlrpc.php'
+;
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Usage: reports/asof.sh [--segment name] (date or datetime, e.g. 2024-03-02)
+# Shows the top paths as of the last crunch before that time.
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+SEGMENTED=0
+[ -z "$SEGMENT" ] || SEGMENTED=1
+exec sqlite3 -header -column -cmd "$SEGMENT_SQL" -cmd ".parameter set @asof \"'$1'\"" \
+    -cmd ".parameter set @segmented $SEGMENTED" "$DB" <asof.sql
@@ -0,0 +1,44 @@
+-- Top paths as of the last run to finish before @asof,
+-- from the snapshots taken at the end of each run: each path's latest up to then.
+-- Snapshots aren't segmented, so with a segment (@segmented), the counts come from the
+-- segment's requests made up to then instead, so requests deleted since don't count.
+-- Run via asof.sh, which sets @asof and @segmented.
+
+.read joins.sql
+
+.print 'As of run:'
+SELECT id AS run_id, started_at, finished_at
+FROM runs
+WHERE finished_at IS NOT NULL AND finished_at <= datetime(@asof)
+ORDER BY finished_at DESC
+LIMIT 1;
+
+.print ''
+.print 'Top paths:'
+WITH
+    asof_runs AS (
+        SELECT id, finished_at FROM runs
+        WHERE finished_at IS NOT NULL AND finished_at <= datetime(@asof)
+    ),
+    latest AS (
+        SELECT url_path, MAX(run_id) AS run_id
+        FROM path_snapshots
+        WHERE NOT @segmented AND run_id IN (SELECT id FROM asof_runs)
+        GROUP BY url_path
+    ),
+    counts AS (
+        SELECT paths.path AS path, path_snapshots.requests AS count
+        FROM latest
+            JOIN path_snapshots USING (url_path, run_id)
+            LEFT JOIN paths ON path_snapshots.url_path = paths.id
+        UNION ALL
+        SELECT url_path, COUNT(*)
+        FROM reqs
+        WHERE @segmented
+          AND datetime(time) <= (SELECT datetime(MAX(finished_at)) FROM asof_runs)
+        GROUP BY url_path
+    )
+SELECT substr(path, 0, 70) AS top_path, count
+FROM counts
+ORDER BY count DESC
+LIMIT 10;
@@ -0,0 +1,14 @@
+#!/bin/sh
+# Usage: reports/bandwidth.sh [--segment name] [hours] [minutes per row]
+# Requests and egress over time, from the per-minute rollup.
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+SEGMENTED=0
+[ -z "$SEGMENT" ] || SEGMENTED=1
+HOURS="${1:-24}"
+STEP="${2:-15}"
+exec sqlite3 -cmd "$SEGMENT_SQL" -cmd ".parameter set @segmented $SEGMENTED" \
+    -cmd ".parameter set @hours $HOURS" -cmd ".parameter set @step $STEP" "$DB" <bandwidth.sql
@@ -0,0 +1,34 @@
+-- Requests and egress per @step minutes over the last @hours, with the average bit rate.
+-- Reads minute_bandwidth, not requests, so it's quick over any span. The rollup isn't
+-- segmented, so with a segment (@segmented), it reads the segment's requests instead.
+-- Run via bandwidth.sh, which sets @segmented, @hours (default 24), and @step (default 15).
+--
+-- For a Grafana panel over the SQLite data source, the same query by minute:
+--   SELECT minute AS time, response_bytes * 8 / 60.0 AS bps FROM minute_bandwidth
+--   WHERE minute >= datetime($__from / 1000, 'unixepoch') ORDER BY minute;
+
+.read joins.sql
+.headers on
+.mode column
+
+WITH
+    minutes AS (
+        SELECT minute, requests, response_bytes
+        FROM minute_bandwidth
+        WHERE NOT @segmented
+        UNION ALL
+        SELECT strftime('%Y-%m-%d %H:%M:00', time), COUNT(*), COALESCE(SUM(size), 0)
+        FROM reqs
+        WHERE @segmented AND datetime(time) > datetime('now', '-' || @hours || ' hours')
+        GROUP BY 1
+    )
+SELECT
+    datetime((unixepoch(minute) / (@step * 60)) * (@step * 60), 'unixepoch') AS start
+,   SUM(requests) AS requests
+,   SUM(response_bytes) AS egress_bytes
+,   ROUND(SUM(response_bytes) * 8.0 / (@step * 60)) AS bits_per_second
+FROM minutes
+WHERE minute > datetime('now', '-' || @hours || ' hours')
+GROUP BY 1
+ORDER BY 1
+;
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Usage: reports/cache.sh [--segment name] [days]
+# Effective cache lifetimes per path, for tuning Cache-Control.
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+DAYS="${1:-7}"
+exec sqlite3 -cmd "$SEGMENT_SQL" -cmd ".parameter set @days $DAYS" "$DB" <cache.sql
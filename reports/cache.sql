@@ -0,0 +1,25 @@
+-- Effective cache lifetimes per path, from the objAge / objTTL fields.
+-- "max_hit_age" is the oldest copy served from cache: how long objects really stay cached,
+-- which is often much less than "ttl" (the TTL assigned on a miss) when they're evicted early.
+-- "stale" counts hits served past their TTL.
+-- Run via cache.sh, which sets @days (default 7).
+
+.read joins.sql
+.headers on
+.mode column
+
+SELECT
+    url_path
+,   COUNT(*) AS requests
+,   ROUND(100.0 * SUM(cache_state LIKE 'HIT%') / COUNT(*), 1) AS hit_pct
+,   ROUND(AVG(CASE WHEN cache_state LIKE 'HIT%' THEN cache_age END)) AS avg_hit_age
+,   ROUND(MAX(CASE WHEN cache_state LIKE 'HIT%' THEN cache_age END)) AS max_hit_age
+,   ROUND(AVG(CASE WHEN cache_state = 'MISS' THEN cache_ttl END)) AS ttl
+,   SUM(cache_ttl < 0) AS stale
+FROM alltime
+WHERE time > datetime('now', '-' || @days || ' days')
+  AND cache_age IS NOT NULL
+GROUP BY url_path
+ORDER BY requests DESC
+LIMIT 50
+;
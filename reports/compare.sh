@@ -0,0 +1,19 @@
+#!/bin/sh
+# Usage: reports/compare.sh [--segment name] (new.db) (baseline.db)
+# Compares totals and top-N lists between two crunched databases.
+#
+# The segment, named in the new database, applies to both. The baseline's requests are copied
+# (with the columns compared) to a temporary database through joins.sql, so they're
+# segmented the same way as the new database's.
+
+set -eu
+REPORTS="$(dirname "$0")"
+if [ "${1:-}" = "--segment" ]; then DB="${3:-}"; else DB="${1:-}"; fi
+. "$REPORTS/segment.sh"
+BASELINE="$(mktemp)"
+trap 'rm -f "$BASELINE"' EXIT
+sqlite3 -cmd "$SEGMENT_SQL" -cmd ".read $REPORTS/joins.sql" "$2" \
+    "ATTACH DATABASE '$BASELINE' AS baseline;
+     CREATE TABLE baseline.reqs AS SELECT time, size, url_path, user_agent FROM reqs;"
+sqlite3 -header -column -cmd "$SEGMENT_SQL" -cmd ".read $REPORTS/joins.sql" \
+    -cmd "ATTACH DATABASE '$BASELINE' AS baseline" "$1" <"$REPORTS/compare.sql"
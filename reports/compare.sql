@@ -0,0 +1,85 @@
+-- Differences between the main database and a "baseline" database,
+-- e.g. to check that a re-crunch or a parser change produced equivalent data.
+-- Run via compare.sh, which attaches the baseline's requests as baseline.reqs, with the
+-- columns of reqs compared here; both are restricted to the segment, if there is one.
+
+.print 'Totals:'
+WITH
+    new AS (
+        SELECT COUNT(*) AS requests, SUM(size) AS bytes
+        ,   min(time) AS first, max(time) AS last
+        ,   COUNT(DISTINCT url_path) AS paths, COUNT(DISTINCT user_agent) AS user_agents
+        FROM temp.reqs
+    ),
+    old AS (
+        SELECT COUNT(*) AS requests, SUM(size) AS bytes
+        ,   min(time) AS first, max(time) AS last
+        ,   COUNT(DISTINCT url_path) AS paths, COUNT(DISTINCT user_agent) AS user_agents
+        FROM baseline.reqs
+    )
+SELECT 'requests' AS total, old.requests AS baseline, new.requests AS new
+,   new.requests - old.requests AS difference
+FROM new, old
+UNION ALL
+SELECT 'bytes', old.bytes, new.bytes, new.bytes - old.bytes FROM new, old
+UNION ALL
+SELECT 'first request', old.first, new.first, NULL FROM new, old
+UNION ALL
+SELECT 'last request', old.last, new.last, NULL FROM new, old
+UNION ALL
+SELECT 'paths', old.paths, new.paths, new.paths - old.paths FROM new, old
+UNION ALL
+SELECT 'user agents', old.user_agents, new.user_agents, new.user_agents - old.user_agents
+FROM new, old
+;
+
+.print ''
+.print 'Days with different request counts:'
+WITH
+    new AS (SELECT date(time) AS date, COUNT(*) AS count FROM temp.reqs GROUP BY 1),
+    old AS (SELECT date(time) AS date, COUNT(*) AS count FROM baseline.reqs GROUP BY 1),
+    dates AS (SELECT date FROM new UNION SELECT date FROM old)
+SELECT dates.date, old.count AS baseline, new.count AS new
+FROM dates
+    LEFT JOIN new ON dates.date = new.date
+    LEFT JOIN old ON dates.date = old.date
+WHERE new.count IS NOT old.count
+ORDER BY dates.date
+LIMIT 50;
+
+.print ''
+.print 'Top paths:'
+-- Paths are compared by their text, since IDs differ between databases.
+WITH
+    new AS (SELECT url_path AS path, COUNT(*) AS count,
+                   row_number() OVER (ORDER BY COUNT(*) DESC) AS rank
+            FROM temp.reqs WHERE url_path IS NOT NULL
+            GROUP BY url_path),
+    old AS (SELECT url_path AS path, COUNT(*) AS count,
+                   row_number() OVER (ORDER BY COUNT(*) DESC) AS rank
+            FROM baseline.reqs WHERE url_path IS NOT NULL
+            GROUP BY url_path)
+SELECT substr(new.path, 0, 60) AS top_path
+,   old.rank AS baseline_rank, new.rank AS new_rank
+,   old.count AS baseline, new.count AS new
+FROM new LEFT JOIN old ON new.path = old.path
+WHERE new.rank <= 20
+ORDER BY new.rank;
+
+.print ''
+.print 'Top user agents:'
+WITH
+    new AS (SELECT user_agent, COUNT(*) AS count,
+                   row_number() OVER (ORDER BY COUNT(*) DESC) AS rank
+            FROM temp.reqs WHERE user_agent IS NOT NULL
+            GROUP BY user_agent),
+    old AS (SELECT user_agent, COUNT(*) AS count,
+                   row_number() OVER (ORDER BY COUNT(*) DESC) AS rank
+            FROM baseline.reqs WHERE user_agent IS NOT NULL
+            GROUP BY user_agent)
+SELECT substr(new.user_agent, 0, 60) AS top_agent
+,   old.rank AS baseline_rank, new.rank AS new_rank
+,   old.count AS baseline, new.count AS new
+FROM new LEFT JOIN old ON new.user_agent = old.user_agent
+WHERE new.rank <= 20
+ORDER BY new.rank;
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Usage: reports/countries.sh [--segment name] [days]
+# Per-country request and byte totals as JSON, for map visualizations.
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+DAYS="${1:-28}"
+exec sqlite3 -list -cmd "$SEGMENT_SQL" -cmd ".parameter set @days $DAYS" "$DB" <countries.sql
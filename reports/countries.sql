@@ -0,0 +1,17 @@
+-- Requests and bytes by country, as a JSON object keyed by ISO 3166-1 alpha-2 code:
+--   {"US": {"requests": 1234, "bytes": 567890}, ...}
+-- which joins onto the ISO_A2 property of e.g. Natural Earth's country GeoJSON.
+-- Run via countries.sh, which sets @days (default 28).
+
+.read joins.sql
+
+SELECT json_group_object(country, json_object('requests', requests, 'bytes', bytes))
+FROM (
+    SELECT country, COUNT(*) AS requests, SUM(size) AS bytes
+    FROM alltime
+    WHERE time > datetime('now', '-' || @days || ' days')
+      -- Fastly uses placeholders like "**" when it doesn't know.
+      AND country GLOB '[A-Z][A-Z]'
+    GROUP BY country
+    ORDER BY country
+);
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Usage: reports/digest.sh [--segment name] [days]
+# Markdown digest of the last week (or the given number of days).
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+DAYS="${1:-7}"
+exec sqlite3 -cmd "$SEGMENT_SQL" -cmd ".parameter set @days $DAYS" "$DB" <digest.sql
@@ -0,0 +1,118 @@
+-- Compact digest of the last period, formatted to paste into e-mail or a blog post.
+-- Run via digest.sh, which sets @days (default 7).
+
+.read joins.sql
+.mode markdown
+.headers on
+
+.print '## Traffic'
+.print ''
+SELECT
+    (SELECT COUNT(*) FROM alltime WHERE time > datetime('now', '-' || @days || ' days'))
+        AS requests
+,   (SELECT COUNT(*) FROM alltime
+        WHERE time > datetime('now', '-' || (2 * @days) || ' days')
+          AND time <= datetime('now', '-' || @days || ' days'))
+        AS previous_period
+,   (SELECT COUNT(DISTINCT client_ip) FROM alltime
+        WHERE time > datetime('now', '-' || @days || ' days'))
+        AS clients
+;
+
+.print ''
+.print '## Top content'
+.print ''
+SELECT substr(url_path, 0, 70) AS article, COUNT(*) AS views
+FROM alltime
+WHERE time > datetime('now', '-' || @days || ' days')
+  AND (url_path LIKE '%/writing/%/' OR url_path LIKE '%/reading/%')
+  AND url_path NOT LIKE '%.xml'
+GROUP BY url_path
+ORDER BY views DESC
+LIMIT 10;
+
+.print ''
+.print '## New referers'
+.print ''
+-- Referers never seen before this period.
+SELECT substr(referer, 0, 70) AS referer, COUNT(*) AS count
+FROM alltime
+WHERE time > datetime('now', '-' || @days || ' days')
+  AND referer NOT NULL
+  AND referer != ''
+  AND NOT same_site
+GROUP BY referer
+HAVING min(time) > datetime('now', '-' || @days || ' days')
+   AND referer NOT IN (
+        SELECT referer FROM alltime
+        WHERE time <= datetime('now', '-' || @days || ' days')
+          AND referer NOT NULL
+   )
+ORDER BY count DESC
+LIMIT 10;
+
+.print ''
+.print '## Bots'
+.print ''
+SELECT
+    SUM(user_agent LIKE '%bot%' OR user_agent LIKE '%crawl%' OR user_agent LIKE '%spider%')
+        AS bot_requests
+,   COUNT(*) AS all_requests
+,   round(100.0 * SUM(user_agent LIKE '%bot%' OR user_agent LIKE '%crawl%' OR user_agent LIKE '%spider%')
+        / COUNT(*), 1) AS bot_percent
+FROM alltime_allreq
+WHERE time > datetime('now', '-' || @days || ' days');
+
+.print ''
+.print '## Error spikes'
+.print ''
+-- Days in the period with more than twice the period's average daily server errors.
+WITH daily AS (
+    SELECT date, COUNT(*) AS errors
+    FROM alltime_allreq
+    WHERE time > datetime('now', '-' || @days || ' days')
+      AND CAST(status AS INTEGER) >= 500
+    GROUP BY date
+)
+SELECT date, errors
+FROM daily
+WHERE errors > 2 * (SELECT 1.0 * SUM(errors) / @days FROM daily)
+ORDER BY date;
+
+.print ''
+.print '### Sample errors from spike days'
+.print ''
+-- The most common path/agent/network combinations among each spike day's server errors,
+-- to start an investigation from.
+WITH daily AS (
+    SELECT date, COUNT(*) AS errors
+    FROM alltime_allreq
+    WHERE time > datetime('now', '-' || @days || ' days')
+      AND CAST(status AS INTEGER) >= 500
+    GROUP BY date
+)
+, spikes AS (
+    SELECT date FROM daily
+    WHERE errors > 2 * (SELECT 1.0 * SUM(errors) / @days FROM daily)
+)
+, samples AS (
+    SELECT
+        date
+    ,   status
+    ,   substr(url_path, 0, 50) AS path
+    ,   substr(user_agent, 0, 40) AS user_agent
+    ,   client_asn AS asn
+    ,   substr(asn_name, 0, 30) AS network
+    ,   COUNT(*) AS errors
+    ,   min(time) AS first
+    ,   max(time) AS last
+    ,   row_number() OVER (PARTITION BY date ORDER BY COUNT(*) DESC) AS rank
+    FROM alltime_allreq
+    WHERE date IN (SELECT date FROM spikes)
+      AND CAST(status AS INTEGER) >= 500
+    GROUP BY date, status, url_path, user_agent, client_asn
+)
+SELECT date, status, path, user_agent, asn, network, errors, first, last
+FROM samples
+WHERE rank <= 5
+ORDER BY date, rank;
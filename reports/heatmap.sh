@@ -0,0 +1,11 @@
+#!/bin/sh
+# Usage: reports/heatmap.sh [--segment name] (output prefix)
+# Writes (prefix).csv and (prefix).svg: requests by hour and weekday over the last four weeks.
+
+set -eu
+REPORTS="$(dirname "$0")"
+DB="$REPORTS/../quarantine/gcs.db"
+. "$REPORTS/segment.sh"
+sqlite3 -csv -header -cmd "$SEGMENT_SQL" -cmd ".read $REPORTS/joins.sql" "$DB" <"$REPORTS/heatmap.sql" >"$1.csv"
+cargo run --quiet --manifest-path "$REPORTS/../fetch/Cargo.toml" --bin report -- \
+    "$DB" "$REPORTS/heatmap.sql" ${SEGMENT:+--segment "$SEGMENT"} --init "$REPORTS/joins.sql" --template "$REPORTS/heatmap.svg.j2" >"$1.svg"
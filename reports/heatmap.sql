@@ -0,0 +1,26 @@
+-- Requests by hour of day and day of week, over the last four weeks (UTC).
+-- One row per hour, one column per weekday; run via heatmap.sh for CSV and SVG.
+WITH RECURSIVE
+    hours(hour) AS (SELECT 0 UNION ALL SELECT hour + 1 FROM hours WHERE hour < 23)
+,   counts AS (
+        SELECT
+            CAST(strftime('%H', time) AS INTEGER) AS hour
+        ,   CAST(strftime('%w', time) AS INTEGER) AS weekday
+        ,   COUNT(*) AS requests
+        FROM alltime
+        WHERE time > datetime('now', '-28 days')
+        GROUP BY 1, 2
+    )
+SELECT
+    hours.hour AS hour
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 1), 0) AS mon
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 2), 0) AS tue
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 3), 0) AS wed
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 4), 0) AS thu
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 5), 0) AS fri
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 6), 0) AS sat
+,   COALESCE(SUM(requests) FILTER (WHERE weekday = 0), 0) AS sun
+FROM hours LEFT JOIN counts USING (hour)
+GROUP BY hours.hour
+ORDER BY hours.hour
+;
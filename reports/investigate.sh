@@ -1,4 +1,9 @@
 #!/bin/sh
+# Usage: reports/investigate.sh [--segment name]
+# Interactive shell with the report views.
 
-exec sqlite3 -header -column -init reports/joins.sql quarantine/gcs.db
+set -eu
+DB=quarantine/gcs.db
+. reports/segment.sh
+exec sqlite3 -header -column -cmd "$SEGMENT_SQL" -cmd ".read reports/joins.sql" "$DB"
 
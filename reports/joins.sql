@@ -1,19 +1,50 @@
-CREATE TEMP VIEW reqs AS
+-- Every request. Reports go through reqs instead, which segment.sh can restrict to a segment.
+CREATE TEMP VIEW unsegmented AS
 SELECT
-    requests.response_status as status
+    requests.id as id
+,   requests.response_status as status
 ,   requests.client_ip as client_ip
 ,   requests.ipv6 as ipv6
 ,   requests.http2 as http2
 ,   requests.asn as client_asn
+,   requests.country_code as country
+,   requests.language as language
 ,   autonomous_systems.name as asn_name
+    -- Whether the AS was on a droplist when the request was stored; NULL where that isn't
+    -- known: for requests without an AS, or stored before the droplist was first synced.
+,   requests.droplisted as droplisted
 ,   requests.cache_state as cache_state
+    -- Seconds; NULL for entries logged before the format included them.
+,   requests.cache_age as cache_age
+,   requests.cache_ttl as cache_ttl
+    -- GET, POST, ...; and HTTP/1.1, HTTP/2, or HTTP/3. NULL for entries logged without them.
+,   requests.request_method as method
+,   requests.request_protocol as protocol
+    -- The Host header, for services that front several domains.
+,   hosts.host as host
+    -- The Fastly service, for databases that hold several.
+,   services.service_id as service_id
 ,   requests.response_bytes as size
+    -- Request sizes; NULL for entries logged before the format included them.
+,   requests.request_header_bytes as request_header_bytes
+,   requests.request_body_bytes as request_body_bytes
 ,   requests.request_start_time as time -- in RFC3339 format
+    -- How the log wrote it (rfc2822, unix_ms, ...); NULL for formats other than Fastly's.
+,   requests.time_format as time_format
 ,   requests.response_duration as duration
 ,   paths.path as url_path
+    -- Without the "?"; NULL unless the cruncher ran with --store-queries.
+,   requests.query as query
+    -- The path as logged, where --normalize-paths changed it; otherwise NULL.
+,   requests.raw_path as raw_path
 ,   referers.referer as referer
+,   referers.host as referer_host
+    -- Internal navigation, per the site_hosts from the config file.
+,   referers.host IN (SELECT host FROM site_hosts) as same_site
 ,   user_agents.user_agent as user_agent
 ,   date(requests.request_start_time) as date
+    -- Where it was fetched from, e.g. gs://bucket, in runs over several sources.
+,   sources.name as source
 FROM
     requests
     -- We don't have the same column name in the two tables,
@@ -22,13 +53,21 @@ FROM
     LEFT JOIN referers ON requests.referer = referers.id
     LEFT JOIN user_agents ON requests.user_agent = user_agents.id
     LEFT JOIN autonomous_systems ON requests.asn = autonomous_systems.asn
+    LEFT JOIN sources ON requests.source = sources.id
+    LEFT JOIN hosts ON requests.host = hosts.id
+    LEFT JOIN services ON requests.service = services.id
 ;
 
+-- Unless a segment already defined it.
+CREATE TEMP VIEW IF NOT EXISTS reqs AS
+SELECT * FROM unsegmented;
+
 -- without blackbox probes / my own link checking...
+-- (Requests without a user agent stay in; NULL NOT LIKE anything is NULL, not true.)
 CREATE TEMP VIEW alltime_allreq AS
 SELECT * FROM reqs
-WHERE user_agent NOT LIKE "%blackbox%"
-  AND user_agent NOT LIKE "%lychee%"
+WHERE COALESCE(user_agent, '') NOT LIKE "%blackbox%"
+  AND COALESCE(user_agent, '') NOT LIKE "%lychee%"
 ;
 
 -- ...and without spam traffic, where we can get rid of it.
@@ -36,7 +75,15 @@ CREATE TEMP VIEW alltime AS
 SELECT * FROM alltime_allreq
 WHERE
     status != 404
-AND user_agent NOT LIKE 'Mozlila%'
+AND COALESCE(user_agent, '') NOT LIKE 'Mozlila%'
+;
+
+-- Requests with their tags, from tagging rules or scripts; one row per tag.
+CREATE TEMP VIEW tagged AS
+SELECT reqs.*, tags.tag as tag
+FROM reqs
+    JOIN request_tags ON request_tags.request = reqs.id
+    JOIN tags ON request_tags.tag = tags.id
 ;
 
 -- Just the last week
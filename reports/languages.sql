@@ -0,0 +1,18 @@
+-- Audience by preferred language (from Accept-Language), over the last four weeks.
+-- Only entries from log formats that include acceptLanguage have a language.
+
+.read joins.sql
+.headers on
+.mode column
+
+SELECT
+    COALESCE(language, '(unknown)') AS language
+,   COUNT(*) AS requests
+,   COUNT(DISTINCT client_ip) AS clients
+,   printf('%.1f%%', 100.0 * COUNT(*) / SUM(COUNT(*)) OVER ()) AS share
+FROM alltime
+WHERE time > datetime('now', '-28 days')
+GROUP BY 1
+ORDER BY requests DESC
+LIMIT 20
+;
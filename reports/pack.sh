@@ -0,0 +1,9 @@
+#!/bin/sh
+# Usage: reports/pack.sh [--segment name] (pack) (report)
+# Runs packs/(pack)/reports/(report).sql against the database.
+# The pack's views are created when the cruncher runs with --packs.
+
+set -eu
+DB=quarantine/gcs.db
+. reports/segment.sh
+exec sqlite3 -header -column -cmd "$SEGMENT_SQL" -cmd ".read reports/joins.sql" "$DB" <"packs/$1/reports/$2.sql"
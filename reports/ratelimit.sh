@@ -0,0 +1,10 @@
+#!/bin/sh
+# Usage: reports/ratelimit.sh [--segment name] [days]
+# Peak per-minute request rates by client, network prefix, and user agent.
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+DAYS="${1:-7}"
+exec sqlite3 -cmd "$SEGMENT_SQL" -cmd ".parameter set @days $DAYS" "$DB" <ratelimit.sql
@@ -0,0 +1,80 @@
+-- Peak per-minute request rates, for tuning rate-limiting policies.
+-- Includes probes and 404s, since a rate limiter would see those too.
+-- Run via ratelimit.sh, which sets @days (default 7).
+
+.read joins.sql
+.headers on
+.mode column
+
+CREATE TEMP TABLE per_minute AS
+SELECT
+    reqs.client_ip AS client_ip
+,   COALESCE(ips.ipv4, ips.ipv6) AS address
+    -- Dropping the last octet's digits leaves "a.b.c.".
+,   rtrim(ips.ipv4, '0123456789') || '0/24' AS prefix
+,   reqs.client_asn AS asn
+,   reqs.user_agent AS user_agent
+,   strftime('%Y-%m-%d %H:%M', reqs.time) AS minute
+,   COUNT(*) AS requests
+FROM alltime_allreq AS reqs
+LEFT JOIN client_ips AS ips ON ips.id = reqs.client_ip
+WHERE reqs.time > datetime('now', '-' || @days || ' days')
+GROUP BY reqs.client_ip, reqs.client_asn, reqs.user_agent, minute
+;
+
+.print 'Clients over a per-minute limit, at some limits:'
+WITH peaks AS (
+    SELECT client_ip, max(requests) AS peak FROM per_minute GROUP BY client_ip
+)
+SELECT
+    limits.rpm AS limit_rpm
+,   (SELECT COUNT(*) FROM peaks WHERE peak > limits.rpm) AS clients_over
+,   (SELECT COUNT(*) FROM per_minute WHERE requests > limits.rpm) AS client_minutes_over
+FROM (SELECT 30 AS rpm UNION ALL SELECT 60 UNION ALL SELECT 120
+      UNION ALL SELECT 300 UNION ALL SELECT 600) AS limits
+ORDER BY limits.rpm;
+
+.print ''
+.print 'Top addresses by peak requests per minute:'
+WITH by_minute AS (
+    SELECT address, asn, minute, SUM(requests) AS requests
+    FROM per_minute GROUP BY address, minute
+)
+SELECT
+    address
+,   asn
+,   max(requests) AS peak_rpm
+,   minute AS peak_minute
+,   round(avg(requests), 1) AS mean_active_rpm
+FROM by_minute
+GROUP BY address
+ORDER BY peak_rpm DESC
+LIMIT 20;
+
+.print ''
+.print 'Top IPv4 /24 prefixes by peak requests per minute:'
+WITH by_minute AS (
+    SELECT prefix, minute, SUM(requests) AS requests, COUNT(DISTINCT client_ip) AS addresses
+    FROM per_minute WHERE prefix NOT NULL GROUP BY prefix, minute
+)
+SELECT prefix, max(requests) AS peak_rpm, minute AS peak_minute, addresses
+FROM by_minute
+GROUP BY prefix
+ORDER BY peak_rpm DESC
+LIMIT 20;
+
+.print ''
+.print 'Top user agents by peak requests per minute:'
+WITH by_minute AS (
+    SELECT user_agent, minute, SUM(requests) AS requests, COUNT(DISTINCT client_ip) AS clients
+    FROM per_minute GROUP BY user_agent, minute
+)
+SELECT
+    substr(user_agent, 0, 60) AS user_agent
+,   max(requests) AS peak_rpm
+,   minute AS peak_minute
+,   clients
+FROM by_minute
+GROUP BY user_agent
+ORDER BY peak_rpm DESC
+LIMIT 20;
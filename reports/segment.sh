@@ -0,0 +1,25 @@
+# Sourced by the report scripts, after setting DB: handles a leading `--segment NAME`.
+#
+# Sets SEGMENT_SQL to run before joins.sql (via -cmd; -init would run too early). With a
+# segment, it restricts the reqs view, and so every view built on it, to the segment's requests;
+# without one, it does nothing. Segments come from the [[segment]] sections of the config file.
+
+SEGMENT=""
+SEGMENT_SQL="SELECT 1 WHERE 0"
+if [ "${1:-}" = "--segment" ]; then
+    SEGMENT="${2:-}"
+    case "$SEGMENT" in
+    "" | *[!A-Za-z0-9_-]*)
+        echo "invalid segment name: $SEGMENT" >&2
+        exit 1
+        ;;
+    esac
+    SEGMENT_SQL="$(sqlite3 -list "$DB" \
+        "SELECT 'CREATE TEMP VIEW reqs AS SELECT * FROM unsegmented WHERE (' || filter || ');'
+         FROM segments WHERE name = '$SEGMENT'")"
+    if [ -z "$SEGMENT_SQL" ]; then
+        echo "no segment named $SEGMENT in $DB" >&2
+        exit 1
+    fi
+    shift 2
+fi
@@ -0,0 +1,22 @@
+-- Service-level objectives, as of the most recent evaluation of each.
+-- Burn rate is the observed error rate over the error budget:
+-- 1.0 means the budget is being spent exactly as fast as it's allowed.
+
+.print 'SLOs, as of the latest run:'
+WITH latest AS (
+    SELECT * FROM slo_results
+    WHERE run_id = (SELECT max(run_id) FROM slo_results AS s WHERE s.name = slo_results.name)
+)
+SELECT
+    name
+,   window_start
+,   requests
+,   round(1.0 * fast_requests / requests, 4) AS latency_sli
+,   latency_target
+,   round((1.0 - 1.0 * fast_requests / requests) / (1.0 - latency_target), 2) AS latency_burn
+,   round(1.0 * good_requests / requests, 4) AS error_sli
+,   error_target
+,   round((1.0 - 1.0 * good_requests / requests) / (1.0 - error_target), 2) AS error_burn
+FROM latest
+WHERE requests > 0
+ORDER BY name;
@@ -56,7 +56,7 @@ FROM r
 WHERE
     r.referer NOT NULL
 AND r.referer != ""
-AND r.referer NOT LIKE "%cceckman.com%"
+AND NOT r.same_site
 GROUP BY r.referer
 ORDER BY count DESC
 LIMIT 20;
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Usage: reports/transfer.sh [--segment name] [days]
+# Bytes in (request headers and bodies) and out (responses), by day.
+
+set -eu
+cd "$(dirname "$0")"
+DB=../quarantine/gcs.db
+. ./segment.sh
+DAYS="${1:-7}"
+exec sqlite3 -cmd "$SEGMENT_SQL" -cmd ".parameter set @days $DAYS" "$DB" <transfer.sql
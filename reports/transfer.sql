@@ -0,0 +1,21 @@
+-- Total transfer by day: ingress (request headers and bodies) and egress (responses).
+-- Ingress only counts requests logged with reqHeaderBytes / reqBodyBytes;
+-- "sized" is how many those were, so a partial day's ingress isn't mistaken for a quiet one.
+-- Run via transfer.sh, which sets @days (default 7).
+
+.read joins.sql
+.headers on
+.mode column
+
+SELECT
+    date
+,   COUNT(*) AS requests
+,   COUNT(request_header_bytes) AS sized
+,   SUM(COALESCE(request_header_bytes, 0) + COALESCE(request_body_bytes, 0)) AS ingress_bytes
+,   SUM(size) AS egress_bytes
+,   SUM(COALESCE(request_header_bytes, 0) + COALESCE(request_body_bytes, 0)) + SUM(size) AS total_bytes
+FROM reqs
+WHERE time > datetime('now', '-' || @days || ' days')
+GROUP BY date
+ORDER BY date
+;